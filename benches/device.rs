@@ -0,0 +1,256 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use wireguard_rs::configuration::{Configuration, WireGuardConfig};
+use wireguard_rs::platform::dummy;
+use wireguard_rs::wireguard::WireGuard;
+
+/* End-to-end benchmarks of full WireGuard devices on the dummy platform:
+ *
+ * IP packets are written to the (fake) TUN device of a client,
+ * routed, encrypted and sent over the emulated network (see platform/dummy/network.rs),
+ * then decrypted by the server and read from its TUN device.
+ * The devices are configured through WireGuardConfig and use the system clock and timer wheel.
+ *
+ * Profiles:
+ *
+ * - bulk: a single peer transferring packets of different sizes
+ * - server: many peers sending to a single device
+ * - handshake: establishing a session (from the first packet until its delivery)
+ *
+ * Run with: cargo bench --features benchmarking
+ */
+
+type Config = WireGuardConfig<dummy::TunTest, dummy::NetworkBind>;
+
+const MTU: usize = 1420;
+
+const PORT: u16 = 51820;
+
+// number of packets sent per iteration
+const BATCH: usize = 256;
+
+// time after which an outstanding packet is considered lost
+const LOSS_TIMEOUT: Duration = Duration::from_secs(1);
+
+// packets lost (dropped by a full queue) during the benchmarks
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+// create an IPv4 packet of the given (total) size
+fn make_packet(size: usize, src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+    let mut packet = vec![0u8; size];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+    packet
+}
+
+struct Device {
+    config: Config,
+    fake: Arc<Mutex<dummy::TunFakeIO>>,
+    pk: PublicKey,
+    outer: SocketAddr,
+    inner: Ipv4Addr,
+}
+
+impl Device {
+    fn new(network: &dummy::Network, id: u32) -> Device {
+        let (fake, tun_reader, tun_writer, _) = dummy::TunTest::create(true);
+        let wg: WireGuard<dummy::TunTest, dummy::NetworkBind> = WireGuard::new(tun_writer);
+        let config = WireGuardConfig::new(wg.clone());
+
+        // the server (id = 0) in 198.18.0.0/16 and 10.0.0.0/16, the clients in 198.19.0.0/16 and 10.1.0.0/16
+        let outer = Ipv4Addr::from(0xc612_0001 + (id.min(1) << 16) + id);
+        let inner = Ipv4Addr::from(0x0a00_0001 + (id.min(1) << 16) + id);
+
+        let mut sk = [0u8; 32];
+        StdRng::seed_from_u64(id as u64).fill_bytes(&mut sk);
+        let sk = StaticSecret::from(sk);
+        let pk = PublicKey::from(&sk);
+
+        network.enter(&[IpAddr::V4(outer)]);
+        config.set_private_key(Some(sk));
+        config.up(MTU).unwrap();
+        config.set_listen_port(PORT).unwrap();
+        wg.add_tun_reader(tun_reader);
+
+        Device {
+            config,
+            fake: Arc::new(Mutex::new(fake)),
+            pk,
+            outer: SocketAddr::new(IpAddr::V4(outer), PORT),
+            inner,
+        }
+    }
+
+    // configure the client to route everything to the server (and the server to accept the client)
+    fn connect(&self, server: &Device) {
+        self.config.add_peer(&server.pk);
+        self.config
+            .add_allowed_ip(&server.pk, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        self.config.set_endpoint(&server.pk, server.outer);
+
+        server.config.add_peer(&self.pk);
+        server
+            .config
+            .add_allowed_ip(&self.pk, IpAddr::V4(self.inner), 32);
+    }
+
+    // remove the peers added by connect
+    fn disconnect(&self, server: &Device) {
+        self.config.remove_peer(&server.pk);
+        server.config.remove_peer(&self.pk);
+    }
+
+    fn packet(&self, server: &Device, size: usize) -> Vec<u8> {
+        make_packet(size, self.inner, server.inner)
+    }
+
+    fn write(&self, packet: Vec<u8>) {
+        self.fake.lock().unwrap().write(packet)
+    }
+
+    fn read(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.fake.lock().unwrap().read_timeout(timeout)
+    }
+}
+
+struct Setup {
+    server: Device,
+    clients: Vec<Device>,
+    _network: dummy::Network,
+}
+
+impl Setup {
+    // create a server with the given number of clients (with established sessions)
+    fn new(peers: u32) -> Setup {
+        let network = dummy::Network::new(0);
+        let server = Device::new(&network, 0);
+        let clients: Vec<Device> = (1..=peers).map(|id| Device::new(&network, id)).collect();
+        for client in clients.iter() {
+            client.connect(&server);
+            client.write(client.packet(&server, 64));
+            server
+                .read(LOSS_TIMEOUT * 10)
+                .expect("failed to establish session");
+        }
+        Setup {
+            server,
+            clients,
+            _network: network,
+        }
+    }
+
+    // send BATCH packets (round-robin over the clients) for every iteration,
+    // returning the time until the last packet is received by the server
+    fn transfer(&self, size: usize, iters: u64) -> Duration {
+        let total = iters as usize * BATCH;
+        let packets: Vec<Vec<u8>> = self
+            .clients
+            .iter()
+            .map(|client| client.packet(&self.server, size))
+            .collect();
+
+        // receive concurrently (the TUN devices have bounded queues)
+        let start = Instant::now();
+        let fake = self.server.fake.clone();
+        let reader = thread::spawn(move || {
+            let fake = fake.lock().unwrap();
+            let mut last = start;
+            let mut received = 0;
+            while received < total {
+                match fake.read_timeout(LOSS_TIMEOUT) {
+                    Some(_) => {
+                        received += 1;
+                        last = Instant::now();
+                    }
+                    None => break,
+                }
+            }
+            (received, last)
+        });
+
+        for i in 0..total {
+            let n = i % self.clients.len();
+            self.clients[n].write(packets[n].clone());
+        }
+
+        let (received, last) = reader.join().unwrap();
+        LOST.fetch_add((total - received) as u64, Ordering::Relaxed);
+        last - start
+    }
+}
+
+fn report_loss() {
+    let lost = LOST.swap(0, Ordering::Relaxed);
+    if lost > 0 {
+        eprintln!("{} packets lost (dropped by full queues)", lost);
+    }
+}
+
+fn bulk(c: &mut Criterion) {
+    let setup = Setup::new(1);
+    let mut group = c.benchmark_group("bulk");
+    for size in [64, 512, MTU].iter() {
+        group.throughput(Throughput::Bytes((size * BATCH) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, &size| {
+            b.iter_custom(|iters| setup.transfer(size, iters))
+        });
+    }
+    group.finish();
+    report_loss();
+}
+
+fn server(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server");
+    group.sample_size(10);
+    for peers in [1, 16, 64].iter() {
+        let setup = Setup::new(*peers);
+        group.throughput(Throughput::Bytes((MTU * BATCH) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(peers), peers, |b, _| {
+            b.iter_custom(|iters| setup.transfer(MTU, iters))
+        });
+    }
+    group.finish();
+    report_loss();
+}
+
+fn handshake(c: &mut Criterion) {
+    let setup = Setup::new(1);
+    let client = &setup.clients[0];
+    let server = &setup.server;
+    let packet = client.packet(server, 64);
+
+    c.bench_function("handshake", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::from_secs(0);
+            for _ in 0..iters {
+                // forget the session (and the replay state of the responder)
+                client.disconnect(server);
+                client.connect(server);
+
+                let start = Instant::now();
+                client.write(packet.clone());
+                server
+                    .read(LOSS_TIMEOUT * 10)
+                    .expect("failed to establish session");
+                elapsed += start.elapsed();
+            }
+            elapsed
+        })
+    });
+}
+
+criterion_group!(benches, bulk, server, handshake);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use wireguard_rs::wireguard::fuzzing::handshake::{process, HandshakeInput};
+
+fuzz_target!(|input: HandshakeInput| {
+    process(&input);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use wireguard_rs::wireguard::fuzzing::handshake::parse;
+
+fuzz_target!(|data: &[u8]| {
+    parse(data);
+});
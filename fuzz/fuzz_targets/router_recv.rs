@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use wireguard_rs::wireguard::fuzzing::router::{recv, RecvInput};
+
+fuzz_target!(|input: RecvInput| {
+    let _ = recv(&input);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use wireguard_rs::wireguard::fuzzing::router::{route, RouteInput};
+
+fuzz_target!(|input: RouteInput| {
+    route(&input);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use wireguard_rs::configuration::fuzzing::uapi;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = uapi(data);
+});
@@ -26,6 +26,11 @@ pub struct PeerState {
     pub endpoint: Option<SocketAddr>,
     pub persistent_keepalive_interval: u64,
     pub preshared_key: [u8; 32], // 0^32 is the "default value" (though treated like any other psk)
+    pub shaping: Shaping,        // rate limits and shaping counters
+    pub packets: PacketCounters, // transport messages exchanged and dropped
+    pub handshakes: HandshakeCounters, // handshake messages dropped
+    pub name: Option<String>,
+    pub mtu: usize, // MTU override (0 for the MTU of the device)
 }
 
 pub struct WireGuardConfig<T: tun::Tun, B: udp::PlatformUDP>(Arc<Mutex<Inner<T, B>>>);
@@ -101,6 +106,27 @@ pub trait Configuration {
     /// "bind" implementation.
     fn set_fwmark(&self, mark: Option<u32>) -> Result<(), ConfigError>;
 
+    /// Enable/disable ICMP errors for packets without a cryptokey route
+    ///
+    /// # Arguments
+    ///
+    /// - `enabled`: Whether to respond (rate limited) to packets for destinations not allowed for any peer
+    ///   with an ICMP "destination unreachable" error (as the kernel implementation)
+    fn set_icmp_unreachable(&self, enabled: bool);
+
+    fn get_icmp_unreachable(&self) -> bool;
+
+    /// Enable/disable clamping of the TCP maximum segment size
+    ///
+    /// # Arguments
+    ///
+    /// - `enabled`: Whether to lower the MSS option of TCP SYN segments crossing the tunnel (in both directions)
+    ///   to fit the MTU of the device (or the MTU override of the peer),
+    ///   avoiding reliance on path MTU discovery where ICMP is filtered
+    fn set_mss_clamp(&self, enabled: bool);
+
+    fn get_mss_clamp(&self) -> bool;
+
     /// Removes all peers from the device
     fn replace_peers(&self);
 
@@ -180,6 +206,54 @@ pub trait Configuration {
     /// An error if the peer does not exist
     fn add_allowed_ip(&self, peer: &PublicKey, ip: IpAddr, masklen: u32);
 
+    /// Limit the bandwidth of the traffic sent to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `rate`: The limit in bytes per second (0 removes the limit)
+    fn set_tx_rate_limit(&self, peer: &PublicKey, rate: u64);
+
+    /// Limit the bandwidth of the traffic received from the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `rate`: The limit in bytes per second (0 removes the limit)
+    fn set_rx_rate_limit(&self, peer: &PublicKey, rate: u64);
+
+    /// Set the burst size of the rate limits of the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `burst`: The burst size in bytes (0 for a default relative to the rate)
+    fn set_rate_limit_burst(&self, peer: &PublicKey, burst: u64);
+
+    /// Set the treatment of packets exceeding the rate limits of the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `policy`: Drop the packets, or queue them (for a bounded delay)
+    fn set_rate_limit_policy(&self, peer: &PublicKey, policy: ShapingPolicy);
+
+    /// Set the name of a peer (used to label the peer in monitoring)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `name`: The new name or None if the name should be unset
+    fn set_name(&self, peer: &PublicKey, name: Option<String>);
+
+    /// Override the MTU of the device for a peer (e.g. for a peer behind a path with a lower MTU)
+    ///
+    /// # Arguments
+    ///
+    /// - `peer`: The public key of the peer
+    /// - `mtu`: The MTU of the path to the peer (0 to use the MTU of the device)
+    fn set_mtu(&self, peer: &PublicKey, mtu: usize);
+
     fn get_listen_port(&self) -> Option<u16>;
 
     /// Returns the state of all peers
@@ -190,6 +264,14 @@ pub trait Configuration {
     fn get_peers(&self) -> Vec<PeerState>;
 
     fn get_fwmark(&self) -> Option<u32>;
+
+    /// Returns the device-wide statistics
+    ///
+    /// # Returns
+    ///
+    /// The depth of the handshake and router queues, the time spent under load
+    /// and the number of messages dropped (which cannot be attributed to a peer)
+    fn get_stats(&self) -> DeviceStats;
 }
 
 fn start_listener<T: tun::Tun, B: udp::PlatformUDP>(
@@ -240,6 +322,28 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         self.lock().fwmark
     }
 
+    fn get_stats(&self) -> DeviceStats {
+        self.lock().wireguard.get_stats()
+    }
+
+    fn set_icmp_unreachable(&self, enabled: bool) {
+        log::info!("configuration, set icmp_unreachable {}", enabled);
+        self.lock().wireguard.set_icmp_unreachable(enabled);
+    }
+
+    fn get_icmp_unreachable(&self) -> bool {
+        self.lock().wireguard.get_icmp_unreachable()
+    }
+
+    fn set_mss_clamp(&self, enabled: bool) {
+        log::info!("configuration, set mss_clamp {}", enabled);
+        self.lock().wireguard.set_mss_clamp(enabled);
+    }
+
+    fn get_mss_clamp(&self) -> bool {
+        self.lock().wireguard.get_mss_clamp()
+    }
+
     fn set_private_key(&self, sk: Option<StaticSecret>) {
         log::info!("configuration, set private key");
         self.lock().wireguard.set_key(sk)
@@ -332,6 +436,42 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
         }
     }
 
+    fn set_tx_rate_limit(&self, peer: &PublicKey, rate: u64) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_tx_rate_limit(rate);
+        }
+    }
+
+    fn set_rx_rate_limit(&self, peer: &PublicKey, rate: u64) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_rx_rate_limit(rate);
+        }
+    }
+
+    fn set_rate_limit_burst(&self, peer: &PublicKey, burst: u64) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_rate_limit_burst(burst);
+        }
+    }
+
+    fn set_rate_limit_policy(&self, peer: &PublicKey, policy: ShapingPolicy) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_rate_limit_policy(policy);
+        }
+    }
+
+    fn set_name(&self, peer: &PublicKey, name: Option<String>) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            *peer.opaque().name.lock() = name;
+        }
+    }
+
+    fn set_mtu(&self, peer: &PublicKey, mtu: usize) {
+        if let Some(peer) = self.lock().wireguard.peers.read().get(peer) {
+            peer.set_mtu(mtu);
+        }
+    }
+
     /*
 
 
@@ -365,7 +505,8 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
                 (duration.as_secs(), duration.subsec_nanos() as u64)
             });
 
-            if let Some(psk) = cfg.wireguard.get_psk(&pk) {
+            let handshakes = cfg.wireguard.get_handshake_counters(&pk);
+            if let (Some(psk), Some(handshakes)) = (cfg.wireguard.get_psk(&pk), handshakes) {
                 // extract state into PeerState
                 state.push(PeerState {
                     preshared_key: psk,
@@ -376,6 +517,11 @@ impl<T: tun::Tun, B: udp::PlatformUDP> Configuration for WireGuardConfig<T, B> {
                     allowed_ips: p.list_allowed_ips(),
                     last_handshake_time,
                     public_key: pk,
+                    shaping: p.get_shaping(),
+                    packets: p.get_counters(),
+                    handshakes,
+                    name: p.name.lock().clone(),
+                    mtu: p.get_mtu(),
                 })
             }
         }
@@ -14,6 +14,9 @@ pub enum ConfigError {
     InvalidSocketAddr,
     InvalidKeepaliveInterval,
     InvalidAllowedIp,
+    InvalidRateLimit,
+    InvalidName,
+    InvalidMtu,
     InvalidOperation,
     LineTooLong,
     IOError,
@@ -52,6 +55,9 @@ impl ConfigError {
             ConfigError::InvalidSocketAddr => EINVAL,
             ConfigError::InvalidKeepaliveInterval => EINVAL,
             ConfigError::InvalidAllowedIp => EINVAL,
+            ConfigError::InvalidRateLimit => EINVAL,
+            ConfigError::InvalidName => EINVAL,
+            ConfigError::InvalidMtu => EINVAL,
             ConfigError::InvalidOperation => EINVAL,
             ConfigError::UnsupportedValue => EINVAL,
 
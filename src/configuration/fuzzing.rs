@@ -0,0 +1,101 @@
+use std::io::{self, Read, Write};
+
+use super::super::platform::dummy;
+use super::uapi::handle;
+use super::{Configuration, WireGuardConfig};
+
+use super::super::wireguard::WireGuard;
+
+// UAPI connection replaying the fuzzer input
+struct Stream<'a> {
+    input: &'a [u8],
+    output: Vec<u8>,
+}
+
+impl<'a> Read for Stream<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl<'a> Write for Stream<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+thread_local! {
+    // the device is reused between inputs, since the workers are never stopped
+    static CONFIG: WireGuardConfig<dummy::TunTest, dummy::PairBind> = {
+        let (_fake, _reader, tun_writer, _) = dummy::TunTest::create(false);
+        WireGuardConfig::new(WireGuard::new(tun_writer))
+    };
+}
+
+/// Handle a (raw) UAPI request
+///
+/// # Returns
+///
+/// The response written to the UAPI connection
+pub fn uapi(input: &[u8]) -> Vec<u8> {
+    CONFIG.with(|config| {
+        let mut stream = Stream {
+            input,
+            output: vec![],
+        };
+        handle(&mut stream, config);
+
+        // reset the device for the next input
+        config.replace_peers();
+        config.set_private_key(None);
+        let _ = config.set_fwmark(None);
+        stream.output
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::wireguard::fuzzing::write_corpus;
+    use super::*;
+
+    #[test]
+    fn fuzzing_uapi_seeds() {
+        let seeds: Vec<Vec<u8>> = vec![
+            "get=1\n\n",
+            "set=1\n\
+             private_key=a8dac1d8a70a751f0f699fb14ba1cff7b79cf4fbd8f09f44c6e6a90d0369604f\n\
+             listen_port=51820\n\
+             fwmark=0\n\
+             replace_peers=true\n\
+             public_key=25fa5ad52aa3c6ea73e3a1bb6ea4e2e1d5d4b5c1d0b3c4e1c7d8a4e3f1a6b5c4\n\
+             preshared_key=188515093e952f5f22e865cef3012e72f8b5f0b598ac0309d5dacce3b70fcf52\n\
+             replace_allowed_ips=true\n\
+             allowed_ip=192.168.4.4/32\n\
+             allowed_ip=fd00::/64\n\
+             endpoint=[abcd:23::33]:51820\n\
+             persistent_keepalive_interval=25\n\
+             public_key=58402e695ba1772b1cc9309755f043251ea77fdcf10fbe63989ceb7e19321376\n\
+             remove=true\n\
+             \n",
+            "set=1\n\
+             public_key=662e14fd594556f522604703340351258903b64f35553763f19426ab2a515c58\n\
+             endpoint=182.122.22.19:3233\n\
+             allowed_ip=0.0.0.0/0\n\
+             \n\
+             get=1\n\n",
+        ]
+        .into_iter()
+        .map(|seed| seed.as_bytes().to_vec())
+        .collect();
+
+        for seed in seeds.iter() {
+            let response = String::from_utf8(uapi(seed)).unwrap();
+            assert!(response.ends_with("errno=0\n\n"), "{}", response);
+        }
+        write_corpus("uapi", &seeds);
+    }
+}
@@ -0,0 +1,283 @@
+/* Exporter of the state of the device in the Prometheus text format:
+ *
+ * A (minimal) HTTP server answering "GET /metrics" with the per-peer transfer, packet and drop counters,
+ * the age of the last handshake and the endpoint of every peer,
+ * along with the device-wide queue depths, load and drop counters.
+ *
+ * Peers are labeled with their configured name (if any), otherwise with their public key (in base64).
+ */
+use std::fmt::{self, Display, Write as _};
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::config::PeerState;
+use super::Configuration;
+
+const MAX_REQUEST_LENGTH: usize = 8192;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// escape a label value
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+struct Exposition(String);
+
+impl Exposition {
+    // start a metric family (all samples of the family must follow)
+    fn family(&mut self, name: &str, kind: &str, help: &str) -> fmt::Result {
+        writeln!(self.0, "# HELP {} {}", name, help)?;
+        writeln!(self.0, "# TYPE {} {}", name, kind)
+    }
+
+    fn sample<V: Display>(&mut self, name: &str, labels: &str, value: V) -> fmt::Result {
+        if labels.is_empty() {
+            writeln!(self.0, "{} {}", name, value)
+        } else {
+            writeln!(self.0, "{}{{{}}} {}", name, labels, value)
+        }
+    }
+
+    // a family with a sample for every peer
+    fn peers<V: Display, F: Fn(&PeerState) -> V>(
+        &mut self,
+        peers: &[(String, PeerState)],
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: F,
+    ) -> fmt::Result {
+        self.family(name, kind, help)?;
+        for (labels, peer) in peers {
+            self.sample(name, labels, value(peer))?;
+        }
+        Ok(())
+    }
+}
+
+/// Serializes the state of the device in the Prometheus text format
+pub fn serialize<C: Configuration>(config: &C) -> Result<String, fmt::Error> {
+    let mut out = Exposition(String::new());
+
+    // label every peer (by name or public key)
+    let peers: Vec<(String, PeerState)> = config
+        .get_peers()
+        .into_iter()
+        .map(|peer| {
+            let public_key = base64::encode(peer.public_key.as_bytes());
+            let labels = format!(
+                "peer=\"{}\",public_key=\"{}\"",
+                escape(peer.name.as_ref().unwrap_or(&public_key)),
+                public_key
+            );
+            (labels, peer)
+        })
+        .collect();
+
+    // device-wide statistics
+    let stats = config.get_stats();
+    out.family("wireguard_peers", "gauge", "Number of peers")?;
+    out.sample("wireguard_peers", "", peers.len())?;
+    out.family(
+        "wireguard_handshake_queue_depth",
+        "gauge",
+        "Handshake messages waiting for a handshake worker",
+    )?;
+    out.sample(
+        "wireguard_handshake_queue_depth",
+        "",
+        stats.handshake_queue_depth,
+    )?;
+    out.family(
+        "wireguard_router_queue_depth",
+        "gauge",
+        "Jobs waiting for a router worker",
+    )?;
+    out.sample("wireguard_router_queue_depth", "", stats.router_queue_depth)?;
+    out.family(
+        "wireguard_under_load",
+        "gauge",
+        "Whether the device is under load (1) or not (0)",
+    )?;
+    out.sample("wireguard_under_load", "", stats.under_load as u8)?;
+    out.family(
+        "wireguard_under_load_seconds_total",
+        "counter",
+        "Time spent under load",
+    )?;
+    out.sample(
+        "wireguard_under_load_seconds_total",
+        "",
+        stats.under_load_time.as_secs_f64(),
+    )?;
+    out.family(
+        "wireguard_dropped_total",
+        "counter",
+        "Messages dropped by the device (not attributed to a peer)",
+    )?;
+    for (reason, value) in [
+        ("no_route", stats.no_route_drops),
+        ("mtu", stats.mtu_drops),
+        ("unknown_receiver", stats.unknown_receiver_drops),
+        ("malformed", stats.malformed_drops),
+    ]
+    .iter()
+    {
+        let labels = format!("reason=\"{}\"", reason);
+        out.sample("wireguard_dropped_total", &labels, value)?;
+    }
+
+    // per-peer transfer
+    out.peers(
+        &peers,
+        "wireguard_peer_rx_bytes_total",
+        "counter",
+        "Bytes received from the peer",
+        |p| p.rx_bytes,
+    )?;
+    out.peers(
+        &peers,
+        "wireguard_peer_tx_bytes_total",
+        "counter",
+        "Bytes sent to the peer",
+        |p| p.tx_bytes,
+    )?;
+    out.peers(
+        &peers,
+        "wireguard_peer_rx_packets_total",
+        "counter",
+        "Transport messages received from the peer",
+        |p| p.packets.rx_packets,
+    )?;
+    out.peers(
+        &peers,
+        "wireguard_peer_tx_packets_total",
+        "counter",
+        "Transport messages sent to the peer",
+        |p| p.packets.tx_packets,
+    )?;
+
+    // per-peer drops (by reason)
+    out.family(
+        "wireguard_peer_dropped_total",
+        "counter",
+        "Messages to or from the peer which were dropped",
+    )?;
+    for (labels, p) in peers.iter() {
+        for (reason, value) in [
+            ("tx_no_endpoint", p.packets.tx_no_endpoint_drops),
+            ("tx_send_error", p.packets.tx_send_error_drops),
+            ("tx_staged", p.packets.tx_staged_drops),
+            ("tx_queue", p.packets.tx_queue_drops),
+            ("tx_mtu", p.packets.tx_mtu_drops),
+            ("tx_shaped", p.shaping.tx_dropped),
+            ("rx_queue", p.packets.rx_queue_drops),
+            ("rx_decryption", p.packets.rx_decryption_drops),
+            ("rx_allowed_ips", p.packets.rx_allowed_ips_drops),
+            ("rx_replay", p.packets.rx_replay_drops),
+            ("rx_shaped", p.shaping.rx_dropped),
+            ("handshake_timestamp", p.handshakes.timestamp_drops),
+            ("handshake_flood", p.handshakes.flood_drops),
+            ("handshake_admission", p.handshakes.admission_drops),
+            ("handshake_response", p.handshakes.response_drops),
+        ]
+        .iter()
+        {
+            let labels = format!("{},reason=\"{}\"", labels, reason);
+            out.sample("wireguard_peer_dropped_total", &labels, value)?;
+        }
+    }
+
+    // age of the last handshake (for peers which completed a handshake)
+    let now = SystemTime::now();
+    out.family(
+        "wireguard_peer_last_handshake_age_seconds",
+        "gauge",
+        "Time since the last handshake with the peer",
+    )?;
+    for (labels, p) in peers.iter() {
+        if let Some((secs, nsecs)) = p.last_handshake_time {
+            let time = UNIX_EPOCH + Duration::new(secs, nsecs as u32);
+            let age = now.duration_since(time).unwrap_or_default();
+            out.sample(
+                "wireguard_peer_last_handshake_age_seconds",
+                labels,
+                age.as_secs_f64(),
+            )?;
+        }
+    }
+
+    // endpoint (for peers with an endpoint)
+    out.family(
+        "wireguard_peer_endpoint_info",
+        "gauge",
+        "Current endpoint of the peer",
+    )?;
+    for (labels, p) in peers.iter() {
+        if let Some(endpoint) = p.endpoint {
+            let labels = format!("{},endpoint=\"{}\"", labels, endpoint);
+            out.sample("wireguard_peer_endpoint_info", &labels, 1)?;
+        }
+    }
+
+    Ok(out.0)
+}
+
+/// Handles a single HTTP connection (of a Prometheus scraper)
+pub fn handle<S: Read + Write, C: Configuration>(stream: &mut S, config: &C) {
+    // read the request line and headers (up to the empty line)
+    fn read_request<R: Read>(reader: &mut R) -> Option<String> {
+        let mut request: Vec<u8> = Vec::with_capacity(256);
+        let mut m: [u8; 1] = [0u8];
+        while !request.ends_with(b"\r\n\r\n") && !request.ends_with(b"\n\n") {
+            reader.read_exact(&mut m).ok()?;
+            request.push(m[0]);
+            if request.len() > MAX_REQUEST_LENGTH {
+                return None;
+            }
+        }
+        String::from_utf8(request).ok()
+    }
+
+    let request = match read_request(stream) {
+        Some(request) => request,
+        None => return,
+    };
+
+    // only GET of the metrics path (ignoring any query)
+    let mut split = request.split_whitespace();
+    let (status, body) = match (split.next(), split.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            match serialize(config) {
+                Ok(body) => ("200 OK", body),
+                Err(_) => ("500 Internal Server Error", String::new()),
+            }
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    log::debug!(
+        "Metrics, request: {:?}, status: {}",
+        request.lines().next(),
+        status
+    );
+
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+}
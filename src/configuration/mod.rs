@@ -2,9 +2,19 @@ mod config;
 mod error;
 pub mod uapi;
 
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(test)]
+mod tests;
+
 use super::platform::Endpoint;
 use super::platform::{tun, udp};
 use super::wireguard::WireGuard;
+use super::wireguard::{DeviceStats, HandshakeCounters, PacketCounters, Shaping, ShapingPolicy};
 
 pub use error::ConfigError;
 
@@ -0,0 +1,1057 @@
+use super::super::platform::checksum::{checksum, pseudo_header, sum};
+use super::super::platform::dummy;
+#[cfg(target_os = "linux")]
+use super::super::platform::plt;
+use super::super::platform::udp::{PlatformUDP, Writer};
+use super::super::platform::Endpoint;
+use super::super::wireguard::constants::{KEEPALIVE_TIMEOUT, REKEY_TIMEOUT};
+use super::super::wireguard::tests::make_packet;
+use super::super::wireguard::{SimulatedWheel, VirtualClock, WireGuard};
+use super::uapi::handle;
+use super::{Configuration, DeviceStats, WireGuardConfig};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, UNIX_EPOCH};
+
+use hex;
+use rand_chacha::ChaCha8Rng;
+use rand_core::SeedableRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/* The scenarios of netns.sh (which requires root, network namespaces, wg and iperf3),
+ * ported to the dummy platform:
+ *
+ * Two devices are configured (like wg1 and wg2 of netns.sh) through the UAPI text protocol,
+ * bind their sockets in the emulated network of the dummy platform (see dummy/network.rs)
+ * and exchange IP packets ("pings") across the tunnel, while the configuration is changed.
+ *
+ * The devices share a virtual clock, which only moves when advanced by the scenario.
+ *
+ * The scenarios which do not depend on the emulated network (e.g. on changing addresses)
+ * are also run on the loopback interface, with the sockets of the Linux platform.
+ */
+
+type Config<B> = WireGuardConfig<dummy::TunTest, B>;
+
+// Time to wait for a packet (which is expected to arrive)
+const DEADLINE: Duration = Duration::from_secs(5);
+
+// Time to wait for a packet (which is expected to be lost)
+const QUIET: Duration = Duration::from_millis(200);
+
+// Sequence number of the next packet (distinguishes the packets of a scenario)
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+// Serializes the scenarios on the loopback interface (binding the same ports)
+static LOOPBACK: Mutex<()> = Mutex::new(());
+
+// The network connecting the devices of a scenario
+trait Network: Clone {
+    type Bind: PlatformUDP;
+
+    // Use the addresses of the interface for the sockets bound by the calling thread
+    fn enter(&self, ips: &[IpAddr]);
+}
+
+impl Network for dummy::Network {
+    type Bind = dummy::NetworkBind;
+
+    fn enter(&self, ips: &[IpAddr]) {
+        dummy::Network::enter(self, ips)
+    }
+}
+
+// The loopback interface, with the sockets of a platform
+struct Loopback<B> {
+    _guard: Arc<MutexGuard<'static, ()>>,
+    _bind: PhantomData<B>,
+}
+
+impl<B> Loopback<B> {
+    fn new() -> Loopback<B> {
+        Loopback {
+            _guard: Arc::new(LOOPBACK.lock().unwrap_or_else(|e| e.into_inner())),
+            _bind: PhantomData,
+        }
+    }
+}
+
+impl<B> Clone for Loopback<B> {
+    fn clone(&self) -> Loopback<B> {
+        Loopback {
+            _guard: self._guard.clone(),
+            _bind: PhantomData,
+        }
+    }
+}
+
+impl<B: PlatformUDP> Network for Loopback<B> {
+    type Bind = B;
+
+    // the sockets are bound on the addresses of the host
+    fn enter(&self, _ips: &[IpAddr]) {}
+}
+
+// UAPI connection (replaying the request)
+struct Stream<'a> {
+    input: &'a [u8],
+    output: Vec<u8>,
+}
+
+impl<'a> Read for Stream<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl<'a> Write for Stream<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn keypair(seed: u8) -> (String, PublicKey) {
+    let sk = StaticSecret::from([seed; 32]);
+    let pk = PublicKey::from(&sk);
+    (hex::encode(sk.to_bytes()), pk)
+}
+
+struct Interface<N: Network> {
+    config: Config<N::Bind>,
+    fake: dummy::TunFakeIO,
+    network: N,
+    ips: Vec<IpAddr>,
+}
+
+impl<N: Network> Interface<N> {
+    fn new(
+        network: &N,
+        wheel: &Arc<SimulatedWheel>,
+        clock: &Arc<VirtualClock>,
+        seed: u64,
+    ) -> Interface<N> {
+        let (fake, tun_reader, tun_writer, _) = dummy::TunTest::create(true);
+        let wg: WireGuard<dummy::TunTest, N::Bind> = WireGuard::with_sources(
+            tun_writer,
+            ChaCha8Rng::seed_from_u64(seed),
+            clock.clone(),
+            wheel.clone(),
+        );
+        let interface = Interface {
+            config: WireGuardConfig::new(wg.clone()),
+            fake,
+            network: network.clone(),
+            ips: vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()],
+        };
+        interface.up(1420);
+        wg.add_tun_reader(tun_reader);
+        interface
+    }
+
+    // Set the device up (binding the listen port on the addresses of the interface)
+    fn up(&self, mtu: usize) {
+        self.network.enter(&self.ips);
+        self.config.up(mtu).unwrap();
+    }
+
+    // Execute a UAPI operation, returning the response
+    fn request(&self, request: &str) -> String {
+        self.network.enter(&self.ips);
+        let mut stream = Stream {
+            input: request.as_bytes(),
+            output: vec![],
+        };
+        handle(&mut stream, &self.config);
+        String::from_utf8(stream.output).unwrap()
+    }
+
+    // Execute a UAPI operation, returning the (successful) response
+    fn uapi(&self, request: &str) -> String {
+        let response = self.request(request);
+        assert!(response.ends_with("errno=0\n\n"), "{}", response);
+        response
+    }
+
+    // Equivalent of "wg set"
+    fn set(&self, lines: &[&str]) {
+        let mut request = "set=1\n".to_owned();
+        for line in lines {
+            request.push_str(line);
+            request.push('\n');
+        }
+        request.push('\n');
+        self.uapi(&request);
+    }
+
+    // Equivalent of "wg show <interface> endpoints"
+    fn endpoints(&self) -> Vec<(String, SocketAddr)> {
+        let response = self.uapi("get=1\n\n");
+        let mut endpoints = vec![];
+        let mut peer = None;
+        for line in response.lines() {
+            let mut split = line.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some("public_key"), Some(pk)) => peer = Some(pk.to_owned()),
+                (Some("endpoint"), Some(addr)) => {
+                    endpoints.push((peer.clone().unwrap(), addr.parse().unwrap()))
+                }
+                _ => (),
+            }
+        }
+        endpoints
+    }
+
+    // Equivalent of "wg show <interface> transfer" for a single peer
+    fn transfer(&self) -> (u64, u64) {
+        let response = self.uapi("get=1\n\n");
+        let value = |key: &str| -> u64 {
+            response
+                .lines()
+                .find(|line| line.starts_with(key))
+                .and_then(|line| line[key.len() + 1..].parse().ok())
+                .unwrap()
+        };
+        (value("rx_bytes"), value("tx_bytes"))
+    }
+
+    // The value of a (numeric) key of the single peer
+    fn value(&self, key: &str) -> Option<u64> {
+        let response = self.uapi("get=1\n\n");
+        response
+            .lines()
+            .find(|line| line.starts_with(&format!("{}=", key)))
+            .and_then(|line| line[key.len() + 1..].parse().ok())
+    }
+}
+
+struct Netns<N: Network = dummy::Network> {
+    wheel: Arc<SimulatedWheel>,
+    wg1: Interface<N>,
+    wg2: Interface<N>,
+    key1: String,
+    pub1: String,
+    key2: String,
+    pub2: String,
+    psk: String,
+}
+
+impl Netns {
+    // Create the devices in the emulated network
+    fn new() -> Netns {
+        Netns::with_network(dummy::Network::new(0))
+    }
+
+    // Create the devices, connected over IPv4 (with a session established by wg1)
+    fn connected() -> Netns {
+        let netns = Netns::new();
+        netns.endpoints("127.0.0.1:10000", "127.0.0.1:20000");
+        assert!(netns.ping(true, "192.168.241.1", "192.168.241.2"));
+        netns
+    }
+}
+
+impl<N: Network> Netns<N> {
+    // Create the devices and configure the peers (as in "configure_peers" of netns.sh)
+    fn with_network(network: N) -> Netns<N> {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let clock = Arc::new(VirtualClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        ));
+        let wheel = Arc::new(SimulatedWheel::new(clock.clone()));
+
+        let wg1 = Interface::new(&network, &wheel, &clock, 1);
+        let wg2 = Interface::new(&network, &wheel, &clock, 2);
+
+        let (key1, pk1) = keypair(1);
+        let (key2, pk2) = keypair(2);
+        let netns = Netns {
+            wheel,
+            wg1,
+            wg2,
+            key1,
+            pub1: hex::encode(pk1.as_bytes()),
+            key2,
+            pub2: hex::encode(pk2.as_bytes()),
+            psk: hex::encode([3u8; 32]),
+        };
+
+        netns.wg1.set(&[
+            &format!("private_key={}", netns.key1),
+            "listen_port=10000",
+            &format!("public_key={}", netns.pub2),
+            &format!("preshared_key={}", netns.psk),
+            "allowed_ip=192.168.241.2/32",
+            "allowed_ip=fd00::2/128",
+        ]);
+        netns.wg2.set(&[
+            &format!("private_key={}", netns.key2),
+            "listen_port=20000",
+            &format!("public_key={}", netns.pub1),
+            &format!("preshared_key={}", netns.psk),
+            "allowed_ip=192.168.241.1/32",
+            "allowed_ip=fd00::1/128",
+        ]);
+        netns
+    }
+
+    // Set the endpoints of both peers
+    fn endpoints(&self, endpoint1: &str, endpoint2: &str) {
+        self.wg1.set(&[
+            &format!("public_key={}", self.pub2),
+            &format!("endpoint={}", endpoint2),
+        ]);
+        self.wg2.set(&[
+            &format!("public_key={}", self.pub1),
+            &format!("endpoint={}", endpoint1),
+        ]);
+    }
+
+    // Advance the virtual clock (firing the timers of the devices)
+    fn advance(&self, duration: Duration) {
+        self.wheel.advance(duration);
+    }
+
+    // Check that setting the key of the peer (of wg1) to the value is rejected
+    fn assert_rejected(&self, key: &str, value: &str) {
+        let request = format!("set=1\npublic_key={}\n{}={}\n\n", self.pub2, key, value);
+        let response = self.wg1.request(&request);
+        assert!(response.ends_with("errno=22\n\n"), "{}", response);
+    }
+
+    fn interfaces(&self, forward: bool) -> (&Interface<N>, &Interface<N>) {
+        if forward {
+            (&self.wg1, &self.wg2)
+        } else {
+            (&self.wg2, &self.wg1)
+        }
+    }
+
+    // Send a packet (of the given size) from src (on wg1 if forward, wg2 otherwise) to dst
+    fn send(&self, forward: bool, size: usize, src: &str, dst: &str) -> Vec<u8> {
+        let id = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let packet = make_packet(size, src.parse().unwrap(), dst.parse().unwrap(), id);
+        self.interfaces(forward).0.fake.write(packet.clone());
+        packet
+    }
+
+    // Check if the packet arrives (ignoring any earlier packets)
+    fn arrives(&self, forward: bool, packet: &[u8], timeout: Duration) -> bool {
+        let receiver = self.interfaces(forward).1;
+        while let Some(received) = receiver.fake.read_timeout(timeout) {
+            if received == packet {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Send a packet across the tunnel, checking that it arrives
+    fn ping(&self, forward: bool, src: &str, dst: &str) -> bool {
+        self.ping_size(forward, 56, src, dst)
+    }
+
+    fn ping_size(&self, forward: bool, size: usize, src: &str, dst: &str) -> bool {
+        let packet = self.send(forward, size, src, dst);
+        self.arrives(forward, &packet, DEADLINE)
+    }
+
+    // Ping until a packet arrives (e.g. while a new session is established)
+    fn reconnect(&self, forward: bool, src: &str, dst: &str) -> bool {
+        (0..10).any(|_| {
+            let packet = self.send(forward, 56, src, dst);
+            self.arrives(forward, &packet, QUIET)
+        })
+    }
+
+    // Check that the packet is lost
+    fn lost(&self, forward: bool, src: &str, dst: &str) -> bool {
+        let packet = self.send(forward, 56, src, dst);
+        !self.arrives(forward, &packet, QUIET)
+    }
+
+    // Ping over IPv4 and IPv6 in both directions (the "tests" of netns.sh)
+    fn tests(&self) {
+        for _ in 0..10 {
+            assert!(self.ping(false, "192.168.241.2", "192.168.241.1"));
+            assert!(self.ping(true, "192.168.241.1", "192.168.241.2"));
+            assert!(self.ping(false, "fd00::2", "fd00::1"));
+            assert!(self.ping(true, "fd00::1", "fd00::2"));
+        }
+    }
+
+    // Send a burst of packets from wg1 to wg2, returning the number of packets delivered
+    // (then advancing the clock by the given duration, which releases the packets delayed by shaping)
+    fn burst(&self, count: usize, advance: Duration) -> usize {
+        let sent: Vec<Vec<u8>> = (0..count)
+            .map(|_| self.send(true, 100, "192.168.241.1", "192.168.241.2"))
+            .collect();
+        let delivered = || {
+            let mut delivered = 0;
+            while let Some(packet) = self.wg2.fake.read_timeout(QUIET) {
+                if sent.contains(&packet) {
+                    delivered += 1;
+                }
+            }
+            delivered
+        };
+        let before = delivered();
+        self.advance(advance);
+        before + delivered()
+    }
+
+    // Send a stream of packets from wg1 to wg2, applying a change to the configuration midway
+    //
+    // Returns the indices of the delivered packets
+    fn stream<F: FnOnce(&Netns<N>)>(&self, count: usize, midway: usize, change: F) -> Vec<usize> {
+        let mut change = Some(change);
+        let mut sent: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut received = vec![];
+        for i in 0..count {
+            if i == midway {
+                (change.take().unwrap())(self);
+            }
+            sent.insert(self.send(true, 100, "192.168.241.1", "192.168.241.2"), i);
+            while let Some(packet) = self.wg2.fake.read_timeout(Duration::from_millis(1)) {
+                received.push(packet);
+            }
+        }
+        while let Some(packet) = self.wg2.fake.read_timeout(QUIET) {
+            received.push(packet);
+        }
+        let mut delivered: Vec<usize> = received.iter().map(|packet| sent[packet]).collect();
+        delivered.sort();
+        delivered
+    }
+}
+
+fn ping_scenario<N: Network>(netns: Netns<N>) {
+    // IPv4 as outer transport
+    netns.endpoints("127.0.0.1:10000", "127.0.0.1:20000");
+
+    // check the counters (the values of netns.sh, for 10 pings of 84 bytes)
+    for _ in 0..10 {
+        assert!(netns.ping(false, "192.168.241.2", "192.168.241.1"));
+        assert!(netns.ping(true, "192.168.241.1", "192.168.241.2"));
+    }
+    let (rx_bytes, tx_bytes) = netns.wg2.transfer();
+    assert!(rx_bytes >= 840 && tx_bytes >= 880 && rx_bytes < 2500 && tx_bytes < 2500);
+    assert!(netns.wg2.value("tx_packets").unwrap() >= 10);
+    assert!(netns.wg2.value("rx_packets").unwrap() >= 10);
+    assert_eq!(netns.wg2.value("rx_replay_drops"), Some(0));
+    assert_eq!(netns.wg2.value("handshake_flood_drops"), Some(0));
+    netns.tests();
+
+    // IPv6 as outer transport
+    netns.endpoints("[::1]:10000", "[::1]:20000");
+    netns.tests();
+}
+
+#[test]
+fn netns_ping() {
+    ping_scenario(Netns::new());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn netns_ping_loopback() {
+    ping_scenario(Netns::with_network(Loopback::<plt::UDP>::new()));
+    #[cfg(feature = "uring")]
+    ping_scenario(Netns::with_network(Loopback::<plt::uring::UDP>::new()));
+}
+
+#[test]
+fn netns_roaming() {
+    let mut netns = Netns::new();
+    netns.endpoints("127.0.0.1:10000", "127.0.0.1:20000");
+    netns.tests();
+
+    // roaming over IPv4: wg1 changes address and port
+    netns.wg1.ips = vec!["127.212.121.99".parse().unwrap(), "::1".parse().unwrap()];
+    netns.wg1.set(&["listen_port=9999"]);
+    netns.wg1.set(&[
+        &format!("public_key={}", netns.pub2),
+        "endpoint=127.0.0.1:20000",
+    ]);
+    assert!(netns.ping(true, "fd00::1", "fd00::2"));
+    assert_eq!(
+        netns.wg2.endpoints(),
+        vec![(netns.pub1.clone(), "127.212.121.99:9999".parse().unwrap())]
+    );
+
+    // roaming over IPv6: wg1 changes port
+    netns.wg1.set(&["listen_port=9998"]);
+    netns.wg1.set(&[
+        &format!("public_key={}", netns.pub2),
+        "endpoint=[::1]:20000",
+    ]);
+    assert!(netns.ping(true, "192.168.241.1", "192.168.241.2"));
+    assert_eq!(
+        netns.wg2.endpoints(),
+        vec![(netns.pub1.clone(), "[::1]:9998".parse().unwrap())]
+    );
+
+    // the responses follow the roaming peer
+    assert!(netns.ping(false, "192.168.241.2", "192.168.241.1"));
+}
+
+fn allowed_ips_scenario<N: Network>(netns: Netns<N>) {
+    netns.endpoints("[::1]:10000", "[::1]:20000");
+    netns.tests();
+
+    // crypto-key routing: packets from addresses allowed for the peer are accepted
+    netns.wg1.set(&[
+        &format!("public_key={}", netns.pub2),
+        "replace_allowed_ips=true",
+        "allowed_ip=192.168.241.0/24",
+    ]);
+    assert!(netns.ping(false, "192.168.241.2", "192.168.241.1"));
+
+    // a more specific route to another peer makes the source address invalid for wg2
+    let (_, more_specific) = keypair(4);
+    let more_specific = hex::encode(more_specific.as_bytes());
+    netns.wg1.set(&[
+        &format!("public_key={}", more_specific),
+        "allowed_ip=192.168.241.2/32",
+    ]);
+    netns.wg2.set(&["listen_port=9997"]);
+    assert!(netns.lost(false, "192.168.241.2", "192.168.241.1"));
+
+    // removing the route restores the path (and wg1 follows wg2 to the new port)
+    netns
+        .wg1
+        .set(&[&format!("public_key={}", more_specific), "remove=true"]);
+    assert!(netns.ping(false, "192.168.241.2", "192.168.241.1"));
+    assert_eq!(
+        netns.wg1.endpoints(),
+        vec![(netns.pub2.clone(), "[::1]:9997".parse().unwrap())]
+    );
+
+    // removing the allowed IPs of the peer makes the destination unroutable
+    netns.wg1.set(&[
+        &format!("public_key={}", netns.pub2),
+        "replace_allowed_ips=true",
+        "allowed_ip=fd00::2/128",
+    ]);
+    assert!(netns.lost(true, "192.168.241.1", "192.168.241.2"));
+    assert!(netns.ping(true, "fd00::1", "fd00::2"));
+}
+
+#[test]
+fn netns_allowed_ips() {
+    allowed_ips_scenario(Netns::new());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn netns_allowed_ips_loopback() {
+    allowed_ips_scenario(Netns::with_network(Loopback::<plt::UDP>::new()));
+    #[cfg(feature = "uring")]
+    allowed_ips_scenario(Netns::with_network(Loopback::<plt::uring::UDP>::new()));
+}
+
+fn key_change_scenario<N: Network>(netns: Netns<N>) {
+    netns.endpoints("127.0.0.1:10000", "127.0.0.1:20000");
+    netns.tests();
+
+    // replace the public key at wg2 (and the private key of wg1) while packets are sent
+    let (key3, pk3) = keypair(3);
+    let pub3 = hex::encode(pk3.as_bytes());
+    let delivered = netns.stream(100, 50, |netns| {
+        netns.wg2.set(&[
+            &format!("public_key={}", netns.pub1),
+            "remove=true",
+            &format!("public_key={}", pub3),
+            &format!("preshared_key={}", netns.psk),
+            "allowed_ip=192.168.241.1/32",
+            "allowed_ip=fd00::1/128",
+        ]);
+        netns.wg1.set(&[&format!("private_key={}", key3)]);
+    });
+    assert!(delivered.contains(&0));
+
+    // wg1 immediately establishes a session under the new key (the stream continues)
+    assert!(delivered.contains(&99));
+    assert!(netns.ping(true, "192.168.241.1", "192.168.241.2"));
+    assert!(netns.ping(false, "192.168.241.2", "192.168.241.1"));
+    assert_eq!(
+        netns.wg2.endpoints(),
+        vec![(pub3.clone(), "127.0.0.1:10000".parse().unwrap())]
+    );
+
+    // a mismatched preshared key prevents new sessions
+    let psk = hex::encode([5u8; 32]);
+    netns.wg1.set(&[
+        &format!("private_key={}", netns.key1),
+        &format!("public_key={}", netns.pub2),
+        &format!("preshared_key={}", psk),
+    ]);
+    netns.wg2.set(&[
+        &format!("public_key={}", pub3),
+        "remove=true",
+        &format!("public_key={}", netns.pub1),
+        "allowed_ip=192.168.241.1/32",
+        "allowed_ip=fd00::1/128",
+    ]);
+    assert!(!netns.reconnect(true, "192.168.241.1", "192.168.241.2"));
+    netns.advance(KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+    assert!(!netns.reconnect(true, "192.168.241.1", "192.168.241.2"));
+
+    // until the keys match again (and the initiation is retransmitted)
+    netns.wg2.set(&[
+        &format!("public_key={}", netns.pub1),
+        &format!("preshared_key={}", psk),
+    ]);
+    netns.advance(REKEY_TIMEOUT);
+    assert!(netns.reconnect(true, "192.168.241.1", "192.168.241.2"));
+    netns.tests();
+}
+
+#[test]
+fn netns_key_change() {
+    key_change_scenario(Netns::new());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn netns_key_change_loopback() {
+    key_change_scenario(Netns::with_network(Loopback::<plt::UDP>::new()));
+    #[cfg(feature = "uring")]
+    key_change_scenario(Netns::with_network(Loopback::<plt::uring::UDP>::new()));
+}
+
+fn mtu_scenario<N: Network>(netns: Netns<N>) {
+    netns.endpoints("127.0.0.1:10000", "127.0.0.1:20000");
+    netns.tests();
+
+    // packets larger than the MTU are not delivered
+    // (the first packet after a change is read with the buffer of the previous MTU)
+    for mtu in [1280, 1420].iter() {
+        netns.wg1.up(*mtu);
+        netns.wg2.up(*mtu);
+        assert!(netns.ping(true, "192.168.241.1", "192.168.241.2"));
+        assert!(netns.ping(false, "192.168.241.2", "192.168.241.1"));
+
+        assert!(netns.ping_size(true, *mtu - 20, "192.168.241.1", "192.168.241.2"));
+        let packet = netns.send(true, *mtu - 19, "192.168.241.1", "192.168.241.2");
+        assert!(!netns.arrives(true, &packet, QUIET));
+    }
+
+    // a large MTU (as the "big_mtu" of netns.sh)
+    let big_mtu = 34816 - 1500 + 1420;
+    netns.wg1.up(big_mtu);
+    netns.wg2.up(big_mtu);
+    assert!(netns.ping(true, "192.168.241.1", "192.168.241.2"));
+    assert!(netns.ping(false, "192.168.241.2", "192.168.241.1"));
+    assert!(netns.ping_size(true, big_mtu - 40, "fd00::1", "fd00::2"));
+    assert!(netns.ping_size(false, big_mtu - 40, "fd00::2", "fd00::1"));
+}
+
+#[test]
+fn netns_mtu() {
+    mtu_scenario(Netns::new());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn netns_mtu_loopback() {
+    mtu_scenario(Netns::with_network(Loopback::<plt::UDP>::new()));
+    #[cfg(feature = "uring")]
+    mtu_scenario(Netns::with_network(Loopback::<plt::uring::UDP>::new()));
+}
+
+fn remove_peer_scenario<N: Network>(netns: Netns<N>) {
+    netns.endpoints("127.0.0.1:10000", "127.0.0.1:20000");
+    netns.tests();
+
+    // remove the peer at the receiver while packets are sent
+    let delivered = netns.stream(100, 50, |netns| {
+        netns
+            .wg2
+            .set(&[&format!("public_key={}", netns.pub1), "remove=true"]);
+    });
+    assert!(delivered.contains(&0));
+    assert!(delivered.iter().all(|i| *i < 50));
+    assert_eq!(netns.wg2.endpoints(), vec![]);
+
+    // after re-adding the peer, the sender establishes a new session
+    // (having received nothing for KEEPALIVE_TIMEOUT + REKEY_TIMEOUT)
+    netns.wg2.set(&[
+        &format!("public_key={}", netns.pub1),
+        &format!("preshared_key={}", netns.psk),
+        "allowed_ip=192.168.241.1/32",
+        "allowed_ip=fd00::1/128",
+    ]);
+    netns.advance(KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+    assert!(netns.reconnect(true, "192.168.241.1", "192.168.241.2"));
+    netns.tests();
+
+    // remove the peer at the sender while packets are sent
+    let delivered = netns.stream(100, 50, |netns| {
+        netns
+            .wg1
+            .set(&[&format!("public_key={}", netns.pub2), "remove=true"]);
+    });
+    assert!(delivered.contains(&0));
+    assert!(delivered.iter().all(|i| *i < 50));
+    assert_eq!(netns.wg1.endpoints(), vec![]);
+}
+
+#[test]
+fn netns_remove_peer() {
+    remove_peer_scenario(Netns::new());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn netns_remove_peer_loopback() {
+    remove_peer_scenario(Netns::with_network(Loopback::<plt::UDP>::new()));
+    #[cfg(feature = "uring")]
+    remove_peer_scenario(Netns::with_network(Loopback::<plt::uring::UDP>::new()));
+}
+
+#[test]
+fn netns_rate_limit() {
+    let netns = Netns::connected();
+    assert_eq!(netns.wg1.value("tx_rate_limit"), None);
+
+    // packets beyond the burst are dropped by the sender
+    netns.wg1.set(&[
+        &format!("public_key={}", netns.pub2),
+        "tx_rate_limit=1000",
+        "rate_limit_burst=1000",
+    ]);
+    let delivered = netns.burst(50, Duration::from_secs(0)) as u64;
+    assert!(delivered > 0 && delivered < 20);
+    assert_eq!(netns.wg1.value("tx_shaped_drops"), Some(50 - delivered));
+    assert_eq!(netns.wg1.value("tx_shaped_packets"), Some(0));
+
+    // or delayed (for a bounded time)
+    netns.wg1.set(&[
+        &format!("public_key={}", netns.pub2),
+        "tx_rate_limit=100000",
+        "rate_limit_policy=queue",
+    ]);
+    assert_eq!(netns.burst(50, Duration::from_millis(100)), 50);
+    assert!(netns.wg1.value("tx_shaped_packets").unwrap() > 0);
+    assert_eq!(netns.wg1.value("tx_shaped_drops"), Some(50 - delivered));
+
+    // the receiver limits the inbound traffic
+    netns
+        .wg1
+        .set(&[&format!("public_key={}", netns.pub2), "tx_rate_limit=0"]);
+    netns.wg2.set(&[
+        &format!("public_key={}", netns.pub1),
+        "rx_rate_limit=1000",
+        "rate_limit_burst=1000",
+    ]);
+    let delivered = netns.burst(50, Duration::from_secs(0)) as u64;
+    assert!(delivered > 0 && delivered < 20);
+    assert_eq!(netns.wg2.value("rx_shaped_drops"), Some(50 - delivered));
+
+    // removing the limits restores the traffic
+    netns
+        .wg2
+        .set(&[&format!("public_key={}", netns.pub1), "rx_rate_limit=0"]);
+    assert_eq!(netns.wg2.value("rx_rate_limit"), None);
+    assert_eq!(netns.wg2.value("rate_limit_burst"), Some(1000));
+    netns.tests();
+
+    // invalid values are rejected
+    netns.assert_rejected("rate_limit_policy", "shape");
+}
+
+#[test]
+fn netns_stats() {
+    let netns = Netns::connected();
+
+    // packets without a cryptokey route are dropped
+    assert!(netns.lost(true, "192.168.241.1", "192.168.241.3"));
+
+    // messages from a host which is not a peer
+    let (_reader, writer) = netns.wg2.network.bind(
+        "127.0.0.9:30000".parse().unwrap(),
+        dummy::LinkConfig::default(),
+        None,
+    );
+    let mut dst = dummy::NetworkEndpoint::from_address("127.0.0.1:20000".parse().unwrap());
+    let unknown = [0xffu8; 4];
+    let messages: Vec<Vec<u8>> = vec![
+        vec![1, 0],                                         // too short for a type
+        vec![9, 0, 0, 0, 0, 0, 0, 0],                       // unknown type
+        [&[1, 0, 0, 0][..], &[0u8; 96]].concat(),           // truncated initiation
+        [&[3, 0, 0, 0][..], &unknown, &[0u8; 56]].concat(), // cookie reply
+        [&[4, 0, 0, 0][..], &unknown, &[0u8; 24]].concat(), // transport message
+    ];
+    for msg in messages.iter() {
+        writer.write(msg, &mut dst).unwrap();
+    }
+
+    // wait for the messages to be processed
+    let expected =
+        |stats: DeviceStats| stats.malformed_drops == 3 && stats.unknown_receiver_drops == 2;
+    assert!((0..100).any(|_| {
+        std::thread::sleep(Duration::from_millis(10));
+        expected(netns.wg2.config.get_stats())
+    }));
+
+    let stats = netns.wg1.config.get_stats();
+    assert_eq!(stats.no_route_drops, 1);
+    assert_eq!(stats.malformed_drops, 0);
+    assert_eq!(stats.handshake_queue_depth, 0);
+    assert!(!stats.under_load);
+    assert_eq!(stats.under_load_time, Duration::from_secs(0));
+
+    // reported over the UAPI
+    assert_eq!(netns.wg1.value("no_route_drops"), Some(1));
+    assert_eq!(netns.wg2.value("malformed_drops"), Some(3));
+    assert_eq!(netns.wg2.value("unknown_receiver_drops"), Some(2));
+    assert_eq!(netns.wg2.value("router_queue_depth"), Some(0));
+    assert_eq!(netns.wg2.value("under_load_time_sec"), Some(0));
+    netns.tests();
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn netns_metrics() {
+    use super::metrics;
+
+    let netns = Netns::connected();
+    assert!(netns.lost(true, "192.168.241.1", "192.168.241.3"));
+
+    let scrape = |request: &str| -> String {
+        let mut stream = Stream {
+            input: request.as_bytes(),
+            output: vec![],
+        };
+        metrics::handle(&mut stream, &netns.wg1.config);
+        String::from_utf8(stream.output).unwrap()
+    };
+
+    // peers are labeled by public key
+    let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let (_, pk2) = keypair(2);
+    let labels = format!(
+        "peer=\"{0}\",public_key=\"{0}\"",
+        base64::encode(pk2.as_bytes())
+    );
+    assert!(response.contains("\nwireguard_peers 1\n"));
+    assert!(response.contains("\nwireguard_dropped_total{reason=\"no_route\"} 1\n"));
+    assert!(response.contains(&format!("\nwireguard_peer_tx_packets_total{{{}}} ", labels)));
+    assert!(response.contains(&format!(
+        "\nwireguard_peer_endpoint_info{{{},endpoint=\"127.0.0.1:20000\"}} 1\n",
+        labels
+    )));
+    assert!(response.contains(&format!(
+        "\nwireguard_peer_last_handshake_age_seconds{{{}}} ",
+        labels
+    )));
+
+    // or by their configured name
+    netns
+        .wg1
+        .set(&[&format!("public_key={}", netns.pub2), "name=wg2"]);
+    assert!(netns.wg1.uapi("get=1\n\n").contains("\nname=wg2\n"));
+    let response = scrape("GET /metrics?name=x HTTP/1.1\r\n\r\n");
+    assert!(response.contains(&format!(
+        "\nwireguard_peer_rx_bytes_total{{peer=\"wg2\",public_key=\"{}\"}} ",
+        base64::encode(pk2.as_bytes())
+    )));
+
+    // only the metrics are served
+    assert!(scrape("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(scrape("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+}
+
+#[test]
+fn netns_icmp_unreachable() {
+    let netns = Netns::connected();
+
+    // packets without a cryptokey route are silently dropped by default
+    assert!(netns.lost(true, "192.168.241.1", "192.168.241.3"));
+    assert!(netns.wg1.fake.read_timeout(QUIET).is_none());
+
+    // once enabled, the source is notified
+    netns.wg1.set(&["icmp_unreachable=true"]);
+    assert!(netns
+        .wg1
+        .uapi("get=1\n\n")
+        .contains("\nicmp_unreachable=true\n"));
+    for (src, dst, version) in [
+        ("192.168.241.1", "192.168.241.3", 4),
+        ("fd00::1", "fd00::3", 6),
+    ]
+    .iter()
+    {
+        let packet = netns.send(true, 56, src, dst);
+        let error = netns.wg1.fake.read_timeout(DEADLINE).unwrap();
+        assert_eq!(error[0] >> 4, *version);
+        let (src, dst): (IpAddr, IpAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                assert_eq!(&error[12..16], &dst.octets());
+                assert_eq!(&error[16..20], &src.octets());
+                assert_eq!(&error[20..22], &[3, 1]);
+                assert_eq!(&error[28..], &packet[..]);
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                assert_eq!(&error[8..24], &dst.octets());
+                assert_eq!(&error[24..40], &src.octets());
+                assert_eq!(&error[40..42], &[1, 0]);
+                assert_eq!(&error[48..], &packet[..]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // while routed packets are unaffected
+    netns.tests();
+
+    netns.wg1.set(&["icmp_unreachable=false"]);
+    assert!(netns.lost(true, "192.168.241.1", "192.168.241.3"));
+    assert!(netns.wg1.fake.read_timeout(QUIET).is_none());
+}
+
+#[test]
+fn netns_pmtu() {
+    let netns = Netns::connected();
+
+    // send an IP packet (of the given total length) from wg1, setting "don't fragment" for IPv4
+    let send = |length: usize, src: &str, dst: &str, df: bool| -> Vec<u8> {
+        let (src, dst): (IpAddr, IpAddr) = (src.parse().unwrap(), dst.parse().unwrap());
+        let header = if dst.is_ipv4() { 20 } else { 40 };
+        let id = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let mut packet = make_packet(length - header, src, dst, id);
+        if df && dst.is_ipv4() {
+            packet[6] = 0x40;
+        }
+        netns.wg1.fake.write(packet.clone());
+        packet
+    };
+
+    // read the ICMP error (checking the type/code and next-hop MTU) written back to wg1
+    let too_big = |kind: [u8; 2], mtu: u32| -> Vec<u8> {
+        let error = netns.wg1.fake.read_timeout(DEADLINE).unwrap();
+        let offset = if error[0] >> 4 == 4 { 20 } else { 40 };
+        assert_eq!(&error[offset..offset + 2], &kind);
+        assert_eq!(&error[offset + 4..offset + 8], &mtu.to_be_bytes());
+        error
+    };
+
+    // packets exceeding the MTU of the device
+    let packet = send(1500, "192.168.241.1", "192.168.241.2", true);
+    let error = too_big([3, 4], 1420);
+    assert_eq!(&error[28..], &packet[..576 - 28]);
+    assert!(!netns.arrives(true, &packet, QUIET));
+    let packet = send(1500, "fd00::1", "fd00::2", false);
+    too_big([2, 0], 1420);
+    assert!(!netns.arrives(true, &packet, QUIET));
+    assert_eq!(netns.wg1.value("mtu_drops"), Some(2));
+
+    // packets exceeding the MTU of the peer (padding is limited to the MTU)
+    netns
+        .wg1
+        .set(&[&format!("public_key={}", netns.pub2), "mtu=1300"]);
+    assert_eq!(netns.wg1.value("mtu"), Some(1300));
+    for (src, dst, kind) in [
+        ("192.168.241.1", "192.168.241.2", [3, 4]),
+        ("fd00::1", "fd00::2", [2, 0]),
+    ]
+    .iter()
+    {
+        let packet = send(1301, src, dst, true);
+        too_big(*kind, 1300);
+        assert!(!netns.arrives(true, &packet, QUIET));
+        for length in [1297, 1300].iter() {
+            let packet = send(*length, src, dst, true);
+            assert!(netns.arrives(true, &packet, DEADLINE));
+        }
+    }
+
+    // IPv4 packets which may be fragmented are dropped silently
+    let packet = send(1301, "192.168.241.1", "192.168.241.2", false);
+    assert!(!netns.arrives(true, &packet, QUIET));
+    assert!(netns.wg1.fake.read_timeout(QUIET).is_none());
+    assert_eq!(netns.wg1.value("tx_mtu_drops"), Some(3));
+    assert_eq!(netns.wg1.value("mtu_drops"), Some(2));
+
+    // removing the override restores the MTU of the device
+    netns
+        .wg1
+        .set(&[&format!("public_key={}", netns.pub2), "mtu=0"]);
+    assert_eq!(netns.wg1.value("mtu"), None);
+    let packet = send(1420, "192.168.241.1", "192.168.241.2", true);
+    assert!(netns.arrives(true, &packet, DEADLINE));
+
+    // invalid values are rejected
+    netns.assert_rejected("mtu", "100");
+}
+
+#[test]
+fn netns_mss_clamp() {
+    let netns = Netns::connected();
+
+    // a TCP SYN (or SYN-ACK) with an MSS option, from src to dst over IPv4
+    fn syn(flags: u8, src: [u8; 4], dst: [u8; 4], mss: u16) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 44, 0, 0, 0x40, 0, 64, 6, 0, 0];
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(&[0x30, 0x39, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[0x60, flags, 0xff, 0xff, 0, 0, 0, 0, 2, 4]);
+        packet.extend_from_slice(&mss.to_be_bytes());
+        let csum = checksum(sum(&packet[20..], pseudo_header(&packet, 6, 24)));
+        packet[36..38].copy_from_slice(&csum.to_be_bytes());
+        packet
+    }
+
+    // the MSS of a received TCP segment (after checking the checksum)
+    fn mss(packet: &[u8]) -> u16 {
+        assert_eq!(
+            checksum(sum(&packet[20..], pseudo_header(packet, 6, 24))),
+            0
+        );
+        u16::from_be_bytes([packet[42], packet[43]])
+    }
+
+    let (a, b) = ([192, 168, 241, 1], [192, 168, 241, 2]);
+
+    // unmodified by default
+    netns.wg1.fake.write(syn(0x02, a, b, 1460));
+    assert_eq!(mss(&netns.wg2.fake.read_timeout(DEADLINE).unwrap()), 1460);
+
+    // clamped to the MTU of the device in both directions (of the device with clamping enabled)
+    netns.wg1.set(&["mss_clamp=true"]);
+    assert!(netns.wg1.uapi("get=1\n\n").contains("\nmss_clamp=true\n"));
+    netns.wg1.fake.write(syn(0x02, a, b, 1460));
+    assert_eq!(mss(&netns.wg2.fake.read_timeout(DEADLINE).unwrap()), 1380);
+    netns.wg2.fake.write(syn(0x12, b, a, 1460));
+    assert_eq!(mss(&netns.wg1.fake.read_timeout(DEADLINE).unwrap()), 1380);
+
+    // but never raised
+    netns.wg1.fake.write(syn(0x02, a, b, 536));
+    assert_eq!(mss(&netns.wg2.fake.read_timeout(DEADLINE).unwrap()), 536);
+
+    // or to the MTU of the peer
+    netns
+        .wg1
+        .set(&[&format!("public_key={}", netns.pub2), "mtu=1300"]);
+    netns.wg1.fake.write(syn(0x02, a, b, 1460));
+    assert_eq!(mss(&netns.wg2.fake.read_timeout(DEADLINE).unwrap()), 1260);
+    netns.wg2.fake.write(syn(0x12, b, a, 1460));
+    assert_eq!(mss(&netns.wg1.fake.read_timeout(DEADLINE).unwrap()), 1260);
+
+    netns.wg1.set(&["mss_clamp=false"]);
+    assert!(!netns.wg1.uapi("get=1\n\n").contains("mss_clamp"));
+    netns.wg1.fake.write(syn(0x02, a, b, 1460));
+    assert_eq!(mss(&netns.wg2.fake.read_timeout(DEADLINE).unwrap()), 1460);
+}
@@ -1,6 +1,6 @@
 use std::io;
 
-use super::Configuration;
+use super::{Configuration, ShapingPolicy};
 
 pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) -> io::Result<()> {
     let mut write = |key: &'static str, value: String| {
@@ -26,6 +26,38 @@ pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) ->
         .get_fwmark()
         .map(|fwmark| write("fwmark", fwmark.to_string()));
 
+    if config.get_icmp_unreachable() {
+        write("icmp_unreachable", "true".to_owned())?;
+    }
+
+    if config.get_mss_clamp() {
+        write("mss_clamp", "true".to_owned())?;
+    }
+
+    // device-wide statistics
+    let stats = config.get_stats();
+    write(
+        "handshake_queue_depth",
+        stats.handshake_queue_depth.to_string(),
+    )?;
+    write("router_queue_depth", stats.router_queue_depth.to_string())?;
+    write("under_load", stats.under_load.to_string())?;
+    write(
+        "under_load_time_sec",
+        stats.under_load_time.as_secs().to_string(),
+    )?;
+    write(
+        "under_load_time_nsec",
+        stats.under_load_time.subsec_nanos().to_string(),
+    )?;
+    write("no_route_drops", stats.no_route_drops.to_string())?;
+    write("mtu_drops", stats.mtu_drops.to_string())?;
+    write(
+        "unknown_receiver_drops",
+        stats.unknown_receiver_drops.to_string(),
+    )?;
+    write("malformed_drops", stats.malformed_drops.to_string())?;
+
     // serialize all peers
     let mut peers = config.get_peers();
     while let Some(p) = peers.pop() {
@@ -50,6 +82,82 @@ pub fn serialize<C: Configuration, W: io::Write>(writer: &mut W, config: &C) ->
         for (ip, cidr) in p.allowed_ips {
             write("allowed_ip", ip.to_string() + "/" + &cidr.to_string())?;
         }
+
+        if let Some(name) = p.name {
+            write("name", name)?;
+        }
+
+        if p.mtu > 0 {
+            write("mtu", p.mtu.to_string())?;
+        }
+
+        // packet and drop counters
+        let packets = p.packets;
+        write("tx_packets", packets.tx_packets.to_string())?;
+        write("rx_packets", packets.rx_packets.to_string())?;
+        write(
+            "tx_no_endpoint_drops",
+            packets.tx_no_endpoint_drops.to_string(),
+        )?;
+        write(
+            "tx_send_error_drops",
+            packets.tx_send_error_drops.to_string(),
+        )?;
+        write("tx_staged_drops", packets.tx_staged_drops.to_string())?;
+        write("tx_queue_drops", packets.tx_queue_drops.to_string())?;
+        write("tx_mtu_drops", packets.tx_mtu_drops.to_string())?;
+        write("rx_queue_drops", packets.rx_queue_drops.to_string())?;
+        write(
+            "rx_decryption_drops",
+            packets.rx_decryption_drops.to_string(),
+        )?;
+        write(
+            "rx_allowed_ips_drops",
+            packets.rx_allowed_ips_drops.to_string(),
+        )?;
+        write("rx_replay_drops", packets.rx_replay_drops.to_string())?;
+
+        let handshakes = p.handshakes;
+        write(
+            "handshake_timestamp_drops",
+            handshakes.timestamp_drops.to_string(),
+        )?;
+        write("handshake_flood_drops", handshakes.flood_drops.to_string())?;
+        write(
+            "handshake_admission_drops",
+            handshakes.admission_drops.to_string(),
+        )?;
+        write(
+            "handshake_response_drops",
+            handshakes.response_drops.to_string(),
+        )?;
+
+        // traffic shaping (for rate limited peers, the burst and policy also whenever configured)
+        let shaping = p.shaping;
+        let limited = shaping.tx_rate > 0 || shaping.rx_rate > 0;
+        if limited {
+            write("tx_rate_limit", shaping.tx_rate.to_string())?;
+            write("rx_rate_limit", shaping.rx_rate.to_string())?;
+        }
+        if limited || shaping.burst != 0 {
+            write("rate_limit_burst", shaping.burst.to_string())?;
+        }
+        if limited || shaping.policy != ShapingPolicy::Drop {
+            write(
+                "rate_limit_policy",
+                match shaping.policy {
+                    ShapingPolicy::Drop => "drop",
+                    ShapingPolicy::Queue => "queue",
+                }
+                .to_owned(),
+            )?;
+        }
+        if limited {
+            write("tx_shaped_packets", shaping.tx_shaped.to_string())?;
+            write("tx_shaped_drops", shaping.tx_dropped.to_string())?;
+            write("rx_shaped_packets", shaping.rx_shaped.to_string())?;
+            write("rx_shaped_drops", shaping.rx_dropped.to_string())?;
+        }
     }
 
     Ok(())
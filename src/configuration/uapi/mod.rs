@@ -3,7 +3,7 @@ mod set;
 
 use std::io::{Read, Write};
 
-use super::{ConfigError, Configuration};
+use super::{ConfigError, Configuration, ShapingPolicy};
 
 use get::serialize;
 use set::LineParser;
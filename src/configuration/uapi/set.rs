@@ -3,10 +3,16 @@ use std::net::{IpAddr, SocketAddr};
 use subtle::ConstantTimeEq;
 use x25519_dalek::{PublicKey, StaticSecret};
 
-use super::{ConfigError, Configuration};
+use super::{ConfigError, Configuration, ShapingPolicy};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+// range of the MTU override of a peer (the minimum MTU of IPv4 up to the maximum IP packet)
+const MIN_PEER_MTU: usize = 576;
+const MAX_PEER_MTU: usize = 65535;
 
 enum ParserState {
-    Peer(ParsedPeer),
+    Peer(Box<ParsedPeer>),
     Interface,
 }
 
@@ -20,6 +26,12 @@ struct ParsedPeer {
     persistent_keepalive_interval: Option<u64>,
     protocol_version: Option<usize>,
     endpoint: Option<SocketAddr>,
+    tx_rate_limit: Option<u64>,
+    rx_rate_limit: Option<u64>,
+    rate_limit_burst: Option<u64>,
+    rate_limit_policy: Option<ShapingPolicy>,
+    name: Option<Option<String>>,
+    mtu: Option<usize>,
 }
 
 pub struct LineParser<'a, C: Configuration> {
@@ -37,7 +49,7 @@ impl<'a, C: Configuration> LineParser<'a, C> {
 
     fn new_peer(value: &str) -> Result<ParserState, ConfigError> {
         match <[u8; 32]>::from_hex(value) {
-            Ok(pk) => Ok(ParserState::Peer(ParsedPeer {
+            Ok(pk) => Ok(ParserState::Peer(Box::new(ParsedPeer {
                 public_key: PublicKey::from(pk),
                 remove: false,
                 update_only: false,
@@ -47,7 +59,13 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 persistent_keepalive_interval: None,
                 protocol_version: None,
                 endpoint: None,
-            })),
+                tx_rate_limit: None,
+                rx_rate_limit: None,
+                rate_limit_burst: None,
+                rate_limit_policy: None,
+                name: None,
+                mtu: None,
+            }))),
             Err(_) => Err(ConfigError::InvalidHexValue),
         }
     }
@@ -73,6 +91,11 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 config.add_peer(&peer.public_key);
             }
 
+            if peer.replace_allowed_ips {
+                log::trace!("flush peer, replace allowed_ips");
+                config.replace_allowed_ips(&peer.public_key);
+            }
+
             for (ip, cidr) in &peer.allowed_ips {
                 log::trace!("flush peer, add allowed_ips : {}/{}", ip.to_string(), cidr);
                 config.add_allowed_ip(&peer.public_key, *ip, *cidr);
@@ -100,6 +123,36 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                 config.set_endpoint(&peer.public_key, endpoint);
             };
 
+            if let Some(burst) = peer.rate_limit_burst {
+                log::trace!("flush peer, set rate_limit_burst {}", burst);
+                config.set_rate_limit_burst(&peer.public_key, burst);
+            }
+
+            if let Some(policy) = peer.rate_limit_policy {
+                log::trace!("flush peer, set rate_limit_policy {:?}", policy);
+                config.set_rate_limit_policy(&peer.public_key, policy);
+            }
+
+            if let Some(rate) = peer.tx_rate_limit {
+                log::trace!("flush peer, set tx_rate_limit {}", rate);
+                config.set_tx_rate_limit(&peer.public_key, rate);
+            }
+
+            if let Some(rate) = peer.rx_rate_limit {
+                log::trace!("flush peer, set rx_rate_limit {}", rate);
+                config.set_rx_rate_limit(&peer.public_key, rate);
+            }
+
+            if let Some(mtu) = peer.mtu {
+                log::trace!("flush peer, set mtu {}", mtu);
+                config.set_mtu(&peer.public_key, mtu);
+            }
+
+            if let Some(name) = &peer.name {
+                log::trace!("flush peer, set name {:?}", name);
+                config.set_name(&peer.public_key, name.clone());
+            }
+
             None
         };
 
@@ -139,6 +192,32 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     Err(_) => Err(ConfigError::InvalidFwmark),
                 },
 
+                // opt: respond to packets without a cryptokey route with ICMP errors
+                "icmp_unreachable" => match value {
+                    "true" => {
+                        self.config.set_icmp_unreachable(true);
+                        Ok(())
+                    }
+                    "false" => {
+                        self.config.set_icmp_unreachable(false);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
+                // opt: clamp the MSS of TCP connections crossing the tunnel to the MTU
+                "mss_clamp" => match value {
+                    "true" => {
+                        self.config.set_mss_clamp(true);
+                        Ok(())
+                    }
+                    "false" => {
+                        self.config.set_mss_clamp(false);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::UnsupportedValue),
+                },
+
                 // opt: remove all peers
                 "replace_peers" => match value {
                     "true" => {
@@ -232,6 +311,67 @@ impl<'a, C: Configuration> LineParser<'a, C> {
                     }
                 }
 
+                // opt: limit the bandwidth to/from the peer (bytes per second)
+                "tx_rate_limit" => match value.parse() {
+                    Ok(rate) => {
+                        peer.tx_rate_limit = Some(rate);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidRateLimit),
+                },
+                "rx_rate_limit" => match value.parse() {
+                    Ok(rate) => {
+                        peer.rx_rate_limit = Some(rate);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidRateLimit),
+                },
+
+                // opt: burst size of the rate limits (bytes)
+                "rate_limit_burst" => match value.parse() {
+                    Ok(burst) => {
+                        peer.rate_limit_burst = Some(burst);
+                        Ok(())
+                    }
+                    Err(_) => Err(ConfigError::InvalidRateLimit),
+                },
+
+                // opt: treatment of packets exceeding the rate limits
+                "rate_limit_policy" => match value {
+                    "drop" => {
+                        peer.rate_limit_policy = Some(ShapingPolicy::Drop);
+                        Ok(())
+                    }
+                    "queue" => {
+                        peer.rate_limit_policy = Some(ShapingPolicy::Queue);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::InvalidRateLimit),
+                },
+
+                // opt: name the peer (empty removes the name)
+                "name" => {
+                    let valid = |c: char| c.is_ascii_graphic() && c != '"' && c != '\\';
+                    if value.len() > MAX_NAME_LENGTH || !value.chars().all(valid) {
+                        return Err(ConfigError::InvalidName);
+                    }
+                    peer.name = Some(if value.is_empty() {
+                        None
+                    } else {
+                        Some(value.to_owned())
+                    });
+                    Ok(())
+                }
+
+                // opt: override the MTU of the device for the peer (0 removes the override)
+                "mtu" => match value.parse() {
+                    Ok(mtu) if mtu == 0 || (MIN_PEER_MTU..=MAX_PEER_MTU).contains(&mtu) => {
+                        peer.mtu = Some(mtu);
+                        Ok(())
+                    }
+                    _ => Err(ConfigError::InvalidMtu),
+                },
+
                 // set protocol version of peer
                 "protocol_version" => {
                     let parse_res: Result<usize, _> = value.parse();
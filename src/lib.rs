@@ -0,0 +1,12 @@
+#![cfg_attr(feature = "unstable", feature(test))]
+
+/* The library exposes the WireGuard implementation
+ * used by the "wireguard-rs" binary (see main.rs),
+ * enabling it to be embedded and fuzzed (see fuzz/).
+ */
+
+extern crate alloc;
+
+pub mod configuration;
+pub mod platform;
+pub mod wireguard;
@@ -1,27 +1,36 @@
-#![cfg_attr(feature = "unstable", feature(test))]
-
-extern crate alloc;
-
 #[cfg(feature = "profiler")]
 extern crate cpuprofiler;
 
 #[cfg(feature = "profiler")]
 use cpuprofiler::PROFILER;
 
-mod configuration;
-mod platform;
-mod wireguard;
-
 mod util;
 
 use std::env;
+#[cfg(feature = "keylog")]
+use std::fs::OpenOptions;
+#[cfg(feature = "metrics")]
+use std::io;
+#[cfg(feature = "metrics")]
+use std::net::{SocketAddr, TcpListener};
+#[cfg(feature = "metrics")]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(feature = "metrics")]
+use std::os::unix::net::UnixListener;
+#[cfg(feature = "metrics")]
+use std::path::Path;
 use std::process::exit;
 use std::thread;
+#[cfg(feature = "metrics")]
+use std::time::Duration;
+
+use wireguard_rs::{configuration, platform, wireguard};
 
 use configuration::Configuration;
 
 use platform::tun::{PlatformTun, Status};
 use platform::uapi::{BindUAPI, PlatformUAPI};
+use platform::udp::PlatformUDP;
 use platform::*;
 
 use wireguard::WireGuard;
@@ -52,11 +61,105 @@ fn profiler_start(name: &str) {
     }
 }
 
+struct Options {
+    drop_privileges: bool,
+    foreground: bool,
+    queues: Option<usize>,
+    offload: bool,
+    #[cfg(feature = "keylog")]
+    keylog: Option<String>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<String>,
+}
+
+/* Listener of the Prometheus exporter:
+ * a TCP socket on a loopback address (e.g. "127.0.0.1:9586"), or a unix socket (at the given absolute path).
+ */
+// Timeout of the reads and writes of a scrape
+#[cfg(feature = "metrics")]
+const METRICS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "metrics")]
+enum MetricsListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsListener {
+    fn bind(addr: &str) -> io::Result<MetricsListener> {
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) if addr.ip().is_loopback() => {
+                Ok(MetricsListener::Tcp(TcpListener::bind(addr)?))
+            }
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only loopback addresses are supported",
+            )),
+            Err(_) => {
+                let path = Path::new(addr);
+                if !path.is_absolute() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the path of the unix socket must be absolute",
+                    ));
+                }
+
+                // replace a stale socket (but never any other file)
+                match std::fs::symlink_metadata(path) {
+                    Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            "the path exists and is not a socket",
+                        ))
+                    }
+                    Err(_) => (),
+                }
+                Ok(MetricsListener::Unix(UnixListener::bind(path)?))
+            }
+        }
+    }
+
+    // accept and handle scrapes (one at a time, disconnecting stalled scrapers)
+    fn serve<C: Configuration>(self, cfg: C) {
+        loop {
+            let res = match &self {
+                MetricsListener::Tcp(listener) => listener.accept().and_then(|(mut stream, _)| {
+                    stream.set_read_timeout(Some(METRICS_TIMEOUT))?;
+                    stream.set_write_timeout(Some(METRICS_TIMEOUT))?;
+                    configuration::metrics::handle(&mut stream, &cfg);
+                    Ok(())
+                }),
+                MetricsListener::Unix(listener) => listener.accept().and_then(|(mut stream, _)| {
+                    stream.set_read_timeout(Some(METRICS_TIMEOUT))?;
+                    stream.set_write_timeout(Some(METRICS_TIMEOUT))?;
+                    configuration::metrics::handle(&mut stream, &cfg);
+                    Ok(())
+                }),
+            };
+            if let Err(err) = res {
+                log::info!("Metrics connection error: {}", err);
+            }
+        }
+    }
+}
+
 fn main() {
     // parse command line arguments
     let mut name = None;
-    let mut drop_privileges = true;
-    let mut foreground = false;
+    let mut opts = Options {
+        drop_privileges: true,
+        foreground: false,
+        queues: None,
+        offload: false,
+        #[cfg(feature = "keylog")]
+        keylog: None,
+        #[cfg(feature = "metrics")]
+        metrics: None,
+    };
+    #[cfg(feature = "uring")]
+    let mut uring = false;
     let mut args = env::args();
 
     // skip path (argv[0])
@@ -64,10 +167,32 @@ fn main() {
     for arg in args {
         match arg.as_str() {
             "--foreground" | "-f" => {
-                foreground = true;
+                opts.foreground = true;
             }
             "--disable-drop-privileges" => {
-                drop_privileges = false;
+                opts.drop_privileges = false;
+            }
+            "--tun-offload" => {
+                opts.offload = true;
+            }
+            #[cfg(feature = "uring")]
+            "--io-uring" => {
+                uring = true;
+            }
+            arg if arg.starts_with("--tun-queues=") => match arg["--tun-queues=".len()..].parse() {
+                Ok(n) if n > 0 => opts.queues = Some(n),
+                _ => {
+                    eprintln!("Invalid number of TUN queues: {}", arg);
+                    exit(-1);
+                }
+            },
+            #[cfg(feature = "keylog")]
+            arg if arg.starts_with("--keylog=") => {
+                opts.keylog = Some(arg["--keylog=".len()..].to_owned());
+            }
+            #[cfg(feature = "metrics")]
+            arg if arg.starts_with("--metrics=") => {
+                opts.metrics = Some(arg["--metrics=".len()..].to_owned());
             }
             dev => name = Some(dev.to_owned()),
         }
@@ -88,14 +213,58 @@ fn main() {
         exit(-2);
     });
 
+    // start the device with the selected platform
+    // (io_uring falls back to the Linux platform if unsupported by the kernel)
+    #[cfg(feature = "uring")]
+    {
+        if uring {
+            return run::<plt::uring::Tun, plt::uring::UDP>(name, uapi, opts);
+        }
+    }
+    run::<plt::Tun, plt::UDP>(name, uapi, opts);
+}
+
+fn run<T: PlatformTun, B: PlatformUDP>(
+    name: String,
+    uapi: <plt::UAPI as PlatformUAPI>::Bind,
+    opts: Options,
+) {
     // create TUN device
-    let (mut readers, writer, status) = plt::Tun::create(name.as_str()).unwrap_or_else(|e| {
+    // (with a single queue, unless configured otherwise)
+    let tun = match (opts.queues, opts.offload) {
+        (queues, true) => T::create_offload(name.as_str(), queues.unwrap_or(1)),
+        (Some(queues), false) => T::create_queues(name.as_str(), queues),
+        (None, false) => T::create(name.as_str()),
+    };
+    let (mut readers, writer, status) = tun.unwrap_or_else(|e| {
         eprintln!("Failed to create TUN device: {}", e);
         exit(-3);
     });
 
+    // open session key log (before dropping privileges)
+    #[cfg(feature = "keylog")]
+    let keylog =
+        opts.keylog.map(
+            |path| match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => (path, file),
+                Err(e) => {
+                    eprintln!("Failed to open key log {}: {}", path, e);
+                    exit(-6);
+                }
+            },
+        );
+
+    // bind the listener of the Prometheus exporter (before dropping privileges)
+    #[cfg(feature = "metrics")]
+    let metrics = opts.metrics.map(|addr| {
+        MetricsListener::bind(&addr).unwrap_or_else(|e| {
+            eprintln!("Failed to bind metrics listener {}: {}", addr, e);
+            exit(-7);
+        })
+    });
+
     // drop privileges
-    if drop_privileges {
+    if opts.drop_privileges {
         match util::drop_privileges() {
             Ok(_) => (),
             Err(e) => {
@@ -106,7 +275,7 @@ fn main() {
     }
 
     // daemonize to background
-    if !foreground {
+    if !opts.foreground {
         match util::daemonize() {
             Ok(_) => (),
             Err(e) => {
@@ -128,7 +297,16 @@ fn main() {
     profiler_start(name.as_str());
 
     // create WireGuard device
-    let wg: WireGuard<plt::Tun, plt::UDP> = WireGuard::new(writer);
+    let wg: WireGuard<T, B> = WireGuard::new(writer);
+
+    // enable session key logging (if configured)
+    #[cfg(feature = "keylog")]
+    {
+        if let Some((path, file)) = keylog {
+            log::warn!("Logging session keys to {}", path);
+            wg.set_keylog(Some(Box::new(file)));
+        }
+    }
 
     // add all Tun readers
     while let Some(reader) = readers.pop() {
@@ -161,6 +339,15 @@ fn main() {
         });
     }
 
+    // start Prometheus exporter (if configured)
+    #[cfg(feature = "metrics")]
+    {
+        if let Some(listener) = metrics {
+            let cfg = cfg.clone();
+            thread::spawn(move || listener.serve(cfg));
+        }
+    }
+
     // start UAPI server
     thread::spawn(move || loop {
         // accept and handle UAPI config connections
@@ -0,0 +1,32 @@
+/* Internet checksum (RFC 1071) of IP headers and transport segments,
+ * as completed by the TUN offloads and computed for the ICMP errors and MSS clamping of the router.
+ */
+
+// one's complement sum of the 16-bit big-endian words (unfolded)
+pub(crate) fn sum(data: &[u8], mut acc: u64) -> u64 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        acc += u64::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        acc += u64::from(*last) << 8;
+    }
+    acc
+}
+
+// fold the sum into the (complemented) internet checksum
+pub(crate) fn checksum(mut acc: u64) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+// sum of the pseudo header of the transport protocol (for an IPv4 or IPv6 packet)
+pub(crate) fn pseudo_header(packet: &[u8], protocol: u8, length: usize) -> u64 {
+    let addrs = match packet[0] >> 4 {
+        4 => &packet[12..20],
+        _ => &packet[8..40],
+    };
+    sum(addrs, u64::from(protocol) + length as u64)
+}
@@ -1,4 +1,5 @@
 mod endpoint;
+mod network;
 mod tun;
 mod udp;
 
@@ -6,8 +7,11 @@ mod udp;
  *
  * The use of the dummy platform is to enable unit testing of full WireGuard,
  * the configuration interface and the UAPI parser.
+ *
+ * The emulated network (see network.rs) additionally enables testing under adverse network conditions.
  */
 
 pub use endpoint::*;
+pub use network::*;
 pub use tun::*;
 pub use udp::*;
@@ -0,0 +1,538 @@
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hex;
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::super::udp::*;
+use super::super::Endpoint;
+
+use super::BindError;
+
+/* An emulated network for the dummy platform
+ *
+ * Every host attaches to the network through a link,
+ * which (for the packets sent by the host) can drop, delay, duplicate and reorder packets
+ * and discards packets larger than the MTU of the link.
+ *
+ * Hosts can be placed behind a NAT, which rewrites the source endpoint of outgoing packets
+ * and forgets mappings which have been idle for longer than the timeout of the NAT.
+ * The NAT allocates a new port for every new mapping, hence the host roams after expiry.
+ *
+ * The random decisions of the network are seeded, but the delivery of packets happens in real time.
+ *
+ * Sockets are either bound explicitly (see Network::bind),
+ * or through the PlatformUDP interface (e.g. by the configuration interface),
+ * in which case the addresses of the host are set per thread (see Network::enter).
+ */
+
+thread_local! {
+    // the network and addresses used by NetworkBind::bind (on this thread)
+    static HOST: RefCell<Option<(Network, Vec<IpAddr>)>> = const { RefCell::new(None) };
+}
+
+// first port allocated when binding to port 0
+const EPHEMERAL_PORT: u16 = 49152;
+
+// a datagram delivered to a host (message, source)
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// The conditions of the link between a host and the network
+#[derive(Clone, Copy, Debug)]
+pub struct LinkConfig {
+    pub loss: f64,         // probability of dropping a packet
+    pub latency: Duration, // delay of every packet
+    pub jitter: Duration,  // additional (uniformly distributed) delay of every packet
+    pub duplicate: f64,    // probability of delivering a packet twice
+    pub reorder: f64, // probability of delivering a packet without delay (overtaking earlier packets)
+    pub mtu: usize,   // largest packet carried by the link
+}
+
+impl Default for LinkConfig {
+    fn default() -> LinkConfig {
+        LinkConfig {
+            loss: 0.0,
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            duplicate: 0.0,
+            reorder: 0.0,
+            mtu: 1 << 16,
+        }
+    }
+}
+
+/// An endpoint of the emulated network
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetworkEndpoint(SocketAddr);
+
+impl Endpoint for NetworkEndpoint {
+    fn from_address(addr: SocketAddr) -> NetworkEndpoint {
+        NetworkEndpoint(addr)
+    }
+
+    fn into_address(&self) -> SocketAddr {
+        self.0
+    }
+
+    fn clear_src(&mut self) {}
+}
+
+/// A NAT of the emulated network
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Nat(usize);
+
+struct Mapping {
+    port: u16,
+    used: Instant,
+}
+
+struct NatState {
+    public: IpAddr,
+    timeout: Duration,
+    next_port: u16,
+    outbound: HashMap<SocketAddr, Mapping>, // private endpoint -> public port
+    inbound: HashMap<u16, SocketAddr>,      // public port -> private endpoint
+}
+
+impl NatState {
+    // translate the source of an outgoing packet (allocating a new mapping if needed)
+    fn translate_outbound(&mut self, src: SocketAddr, now: Instant) -> SocketAddr {
+        let timeout = self.timeout;
+        let mapping = match self.outbound.get_mut(&src) {
+            Some(mapping) if now.saturating_duration_since(mapping.used) <= timeout => mapping,
+            _ => {
+                let port = self.next_port;
+                self.next_port = self.next_port.wrapping_add(1).max(1024);
+                if let Some(old) = self.outbound.insert(src, Mapping { port, used: now }) {
+                    self.inbound.remove(&old.port);
+                }
+                self.inbound.insert(port, src);
+                self.outbound.get_mut(&src).unwrap()
+            }
+        };
+        mapping.used = now;
+        SocketAddr::new(self.public, mapping.port)
+    }
+
+    // translate the destination of an incoming packet (if a live mapping exists)
+    fn translate_inbound(&self, dst: SocketAddr, now: Instant) -> Option<SocketAddr> {
+        let src = self.inbound.get(&dst.port())?;
+        let mapping = self.outbound.get(src)?;
+        if now.saturating_duration_since(mapping.used) > self.timeout {
+            return None;
+        }
+        Some(*src)
+    }
+}
+
+struct Host {
+    link: LinkConfig,
+    nat: Option<Nat>,
+    inbox: Sender<Datagram>,
+}
+
+struct State {
+    rng: StdRng,
+    hosts: HashMap<SocketAddr, Host>,
+    nats: Vec<NatState>,
+}
+
+impl State {
+    // find the host receiving a packet sent to the destination
+    fn route(&self, dst: SocketAddr, now: Instant) -> Option<&Host> {
+        if let Some(host) = self.hosts.get(&dst) {
+            // hosts behind a NAT are only reachable through the NAT
+            return if host.nat.is_none() { Some(host) } else { None };
+        }
+        self.nats
+            .iter()
+            .find(|nat| nat.public == dst.ip())
+            .and_then(|nat| nat.translate_inbound(dst, now))
+            .and_then(|dst| self.hosts.get(&dst))
+    }
+}
+
+// A packet in flight
+struct Delivery {
+    at: Instant,
+    seq: u64,
+    src: SocketAddr,
+    dst: SocketAddr,
+    msg: Vec<u8>,
+}
+
+/// An emulated network connecting hosts (by address)
+#[derive(Clone)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+    queue: Arc<Mutex<(u64, Sender<Delivery>)>>,
+}
+
+impl Network {
+    /// Create a new network
+    ///
+    /// # Arguments
+    ///
+    /// - `seed`: Seed for the random decisions of the links (loss, jitter, duplication and reordering)
+    pub fn new(seed: u64) -> Network {
+        let state = Arc::new(Mutex::new(State {
+            rng: StdRng::seed_from_u64(seed),
+            hosts: HashMap::new(),
+            nats: vec![],
+        }));
+
+        // deliver the packets in flight (in order of arrival)
+        let (tx, rx): (Sender<Delivery>, Receiver<Delivery>) = channel();
+        {
+            let state = Arc::downgrade(&state);
+            thread::spawn(move || {
+                let mut flight: BinaryHeap<Reverse<(Instant, u64)>> = BinaryHeap::new();
+                let mut packets: HashMap<u64, Delivery> = HashMap::new();
+                loop {
+                    // wait for the next packet to arrive (or be sent)
+                    let next = flight.peek().map(|Reverse((at, _))| *at);
+                    let recv = match next {
+                        Some(at) => rx.recv_timeout(at.saturating_duration_since(Instant::now())),
+                        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    };
+                    match recv {
+                        Ok(delivery) => {
+                            flight.push(Reverse((delivery.at, delivery.seq)));
+                            packets.insert(delivery.seq, delivery);
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => match next {
+                            Some(at) => thread::sleep(at.saturating_duration_since(Instant::now())),
+                            None => return,
+                        },
+                        Err(RecvTimeoutError::Timeout) => (),
+                    }
+
+                    // deliver every packet which has arrived
+                    let shared = match state.upgrade() {
+                        Some(shared) => shared,
+                        None => return,
+                    };
+                    let state = shared.lock().unwrap();
+                    let now = Instant::now();
+                    while let Some(Reverse((at, seq))) = flight.peek().cloned() {
+                        if at > now {
+                            break;
+                        }
+                        flight.pop();
+                        let delivery = packets.remove(&seq).unwrap();
+                        match state.route(delivery.dst, now) {
+                            Some(host) => {
+                                let _ = host.inbox.send((delivery.msg, delivery.src));
+                            }
+                            None => debug!("network: no route to {}", delivery.dst),
+                        }
+                    }
+                }
+            });
+        }
+
+        Network {
+            state,
+            queue: Arc::new(Mutex::new((0, tx))),
+        }
+    }
+
+    /// Create a new NAT
+    ///
+    /// # Arguments
+    ///
+    /// - `public`: The public address of the NAT
+    /// - `timeout`: The time after which an idle mapping is removed
+    pub fn add_nat(&self, public: IpAddr, timeout: Duration) -> Nat {
+        let mut state = self.state.lock().unwrap();
+        state.nats.push(NatState {
+            public,
+            timeout,
+            next_port: 1024,
+            outbound: HashMap::new(),
+            inbound: HashMap::new(),
+        });
+        Nat(state.nats.len() - 1)
+    }
+
+    /// Attach a new host to the network
+    ///
+    /// # Arguments
+    ///
+    /// - `addr`: The address of the host (the private address, if behind a NAT)
+    /// - `link`: The conditions of the link from the host
+    /// - `nat`: An optional NAT which the host is placed behind
+    pub fn bind(
+        &self,
+        addr: SocketAddr,
+        link: LinkConfig,
+        nat: Option<Nat>,
+    ) -> (NetworkReader, NetworkWriter) {
+        let (tx, rx) = channel();
+        let mut state = self.state.lock().unwrap();
+        assert!(
+            !state.hosts.contains_key(&addr),
+            "address {} already in use",
+            addr
+        );
+        state.hosts.insert(
+            addr,
+            Host {
+                link,
+                nat,
+                inbox: tx,
+            },
+        );
+        (
+            NetworkReader {
+                addr,
+                inbox: Arc::new(Mutex::new(rx)),
+            },
+            NetworkWriter {
+                addrs: vec![addr],
+                network: self.clone(),
+            },
+        )
+    }
+
+    /// Set the addresses of the host for the sockets subsequently bound by `NetworkBind::bind`
+    /// on the calling thread
+    ///
+    /// # Arguments
+    ///
+    /// - `ips`: The addresses of the host (a socket is bound to every address)
+    pub fn enter(&self, ips: &[IpAddr]) {
+        HOST.with(|host| *host.borrow_mut() = Some((self.clone(), ips.to_vec())));
+    }
+
+    // bind a socket to the port on every address (with the default link)
+    fn bind_all(
+        &self,
+        ips: &[IpAddr],
+        port: u16,
+    ) -> Result<(Vec<NetworkReader>, NetworkWriter, NetworkOwner), BindError> {
+        let mut state = self.state.lock().unwrap();
+
+        // pick an unused port (if none is given)
+        let in_use = |port| {
+            ips.iter()
+                .any(|ip| state.hosts.contains_key(&SocketAddr::new(*ip, port)))
+        };
+        let port = if port == 0 {
+            (EPHEMERAL_PORT..=u16::MAX)
+                .find(|port| !in_use(*port))
+                .ok_or(BindError::AddressInUse)?
+        } else if in_use(port) {
+            return Err(BindError::AddressInUse);
+        } else {
+            port
+        };
+
+        let addrs: Vec<SocketAddr> = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+        let mut readers = Vec::with_capacity(addrs.len());
+        for addr in addrs.iter() {
+            let (tx, rx) = channel();
+            state.hosts.insert(
+                *addr,
+                Host {
+                    link: LinkConfig::default(),
+                    nat: None,
+                    inbox: tx,
+                },
+            );
+            readers.push(NetworkReader {
+                addr: *addr,
+                inbox: Arc::new(Mutex::new(rx)),
+            });
+        }
+        Ok((
+            readers,
+            NetworkWriter {
+                addrs: addrs.clone(),
+                network: self.clone(),
+            },
+            NetworkOwner {
+                port,
+                addrs,
+                network: self.clone(),
+            },
+        ))
+    }
+
+    /// Detach a host from the network (closing the socket)
+    pub fn unbind(&self, addr: SocketAddr) {
+        self.state.lock().unwrap().hosts.remove(&addr);
+    }
+
+    /// Change the conditions of the link from a host
+    pub fn set_link(&self, addr: SocketAddr, link: LinkConfig) {
+        if let Some(host) = self.state.lock().unwrap().hosts.get_mut(&addr) {
+            host.link = link;
+        }
+    }
+
+    // send a packet from the host with the given address
+    fn send(&self, src: SocketAddr, dst: SocketAddr, msg: &[u8]) {
+        let now = Instant::now();
+        let mut deliveries = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            let State { rng, hosts, nats } = &mut *state;
+            let host = match hosts.get(&src) {
+                Some(host) => host,
+                None => return,
+            };
+            let link = host.link;
+
+            if msg.len() > link.mtu {
+                debug!("network: {} -> {}, packet exceeds MTU", src, dst);
+                return;
+            }
+
+            if rng.gen_bool(link.loss) {
+                debug!("network: {} -> {}, packet lost", src, dst);
+                return;
+            }
+
+            // rewrite the source endpoint
+            let src = match host.nat {
+                Some(Nat(nat)) => nats[nat].translate_outbound(src, now),
+                None => src,
+            };
+
+            let copies = if rng.gen_bool(link.duplicate) { 2 } else { 1 };
+            for _ in 0..copies {
+                let delay = if rng.gen_bool(link.reorder) {
+                    Duration::from_millis(0)
+                } else {
+                    link.latency + link.jitter.mul_f64(rng.gen::<f64>())
+                };
+                deliveries.push((now + delay, src));
+            }
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+        for (at, src) in deliveries {
+            queue.0 += 1;
+            let _ = queue.1.send(Delivery {
+                at,
+                seq: queue.0,
+                src,
+                dst,
+                msg: msg.to_owned(),
+            });
+        }
+    }
+}
+
+pub struct NetworkReader {
+    addr: SocketAddr,
+    inbox: Arc<Mutex<Receiver<Datagram>>>,
+}
+
+impl Reader<NetworkEndpoint> for NetworkReader {
+    type Error = BindError;
+
+    fn read(&self, buf: &mut [u8]) -> Result<(usize, NetworkEndpoint), Self::Error> {
+        let (msg, src) = self
+            .inbox
+            .lock()
+            .unwrap()
+            .recv()
+            .map_err(|_| BindError::Disconnected)?;
+        let len = msg.len().min(buf.len());
+        buf[..len].copy_from_slice(&msg[..len]);
+        debug!(
+            "network({}): read from {} ({}, {})",
+            self.addr,
+            src,
+            len,
+            hex::encode(&buf[..len])
+        );
+        Ok((len, NetworkEndpoint(src)))
+    }
+}
+
+#[derive(Clone)]
+pub struct NetworkWriter {
+    addrs: Vec<SocketAddr>,
+    network: Network,
+}
+
+impl Writer<NetworkEndpoint> for NetworkWriter {
+    type Error = BindError;
+
+    fn write(&self, buf: &[u8], dst: &mut NetworkEndpoint) -> Result<(), Self::Error> {
+        // send from the address of the same family as the destination
+        let src = self
+            .addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() == dst.0.is_ipv4())
+            .unwrap_or(&self.addrs[0]);
+        debug!(
+            "network({}): write to {} ({}, {})",
+            src,
+            dst.0,
+            buf.len(),
+            hex::encode(buf)
+        );
+        self.network.send(*src, dst.0, buf);
+        Ok(())
+    }
+}
+
+/// Closes the sockets (bound by `NetworkBind::bind`) when dropped
+pub struct NetworkOwner {
+    port: u16,
+    addrs: Vec<SocketAddr>,
+    network: Network,
+}
+
+impl Drop for NetworkOwner {
+    fn drop(&mut self) {
+        for addr in self.addrs.iter() {
+            self.network.unbind(*addr);
+        }
+    }
+}
+
+impl Owner for NetworkOwner {
+    type Error = BindError;
+
+    fn set_fwmark(&mut self, _value: Option<u32>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn get_port(&self) -> u16 {
+        self.port
+    }
+}
+
+#[derive(Clone)]
+pub struct NetworkBind {}
+
+impl UDP for NetworkBind {
+    type Error = BindError;
+    type Endpoint = NetworkEndpoint;
+    type Reader = NetworkReader;
+    type Writer = NetworkWriter;
+}
+
+impl PlatformUDP for NetworkBind {
+    type Owner = NetworkOwner;
+    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        let (network, ips) = HOST
+            .with(|host| host.borrow().clone())
+            .ok_or(BindError::Disconnected)?;
+        network.bind_all(&ips, port)
+    }
+}
@@ -86,9 +86,15 @@ impl fmt::Display for TunError {
 impl Reader for TunReader {
     type Error = TunError;
 
-    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+    fn read(
+        &self,
+        bufs: &mut [&mut [u8]],
+        sizes: &mut Vec<usize>,
+        offset: usize,
+    ) -> Result<(), Self::Error> {
         match self.rx.recv() {
             Ok(msg) => {
+                let buf = &mut bufs[0];
                 let n = min(buf.len() - offset, msg.len());
                 buf[offset..offset + n].copy_from_slice(&msg[..n]);
                 debug!(
@@ -97,7 +103,8 @@ impl Reader for TunReader {
                     n,
                     hex::encode(&buf[offset..offset + n])
                 );
-                Ok(n)
+                sizes.push(n);
+                Ok(())
             }
             Err(_) => Err(TunError::Disconnected),
         }
@@ -157,6 +164,10 @@ impl TunFakeIO {
     pub fn read(&self) -> Vec<u8> {
         self.rx.recv().unwrap()
     }
+
+    pub fn read_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.rx.recv_timeout(timeout).ok()
+    }
 }
 
 impl TunTest {
@@ -20,6 +20,7 @@ pub struct VoidOwner {}
 #[derive(Debug)]
 pub enum BindError {
     Disconnected,
+    AddressInUse,
 }
 
 impl Error for BindError {
@@ -36,6 +37,7 @@ impl fmt::Display for BindError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             BindError::Disconnected => write!(f, "PairBind disconnected"),
+            BindError::AddressInUse => write!(f, "Address in use"),
         }
     }
 }
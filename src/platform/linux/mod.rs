@@ -1,7 +1,11 @@
+mod offload;
 mod tun;
 mod uapi;
 mod udp;
 
+#[cfg(feature = "uring")]
+pub mod uring;
+
 pub use tun::LinuxTun as Tun;
 pub use uapi::LinuxUAPI as UAPI;
 pub use udp::LinuxUDP as UDP;
@@ -0,0 +1,468 @@
+/* Segmentation and checksum offload for TUN devices with virtio-net headers (IFF_VNET_HDR).
+ *
+ * Every packet read from the device is prefaced by a virtio_net_hdr, which may describe:
+ *
+ * - A TCP (TSO) or UDP (USO) super-packet of up to 64KB,
+ *   to be segmented into packets of at most gso_size bytes of payload.
+ * - A partial checksum (NEEDS_CSUM) to be completed.
+ *
+ * The segments are constructed (with complete checksums) right before encryption,
+ * saving a read (and the associated processing by the kernel) for every segment.
+ */
+
+use super::super::checksum::{checksum, pseudo_header, sum};
+
+use std::cmp::min;
+
+// size of the virtio_net_hdr (without the num_buffers field)
+pub const VNET_HDR_SIZE: usize = 10;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_UDP_L4: u8 = 5;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_CWR: u8 = 0x80;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+// largest packet handed to the reader (the IPv4/IPv6 length fields are 16-bit)
+const MAX_PACKET_SIZE: usize = 1 << 16;
+
+fn set_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+/* Complete a partial checksum:
+ * the checksum field (at csum_start + csum_offset) holds the sum of the pseudo header,
+ * the checksum is computed over the remaining packet from csum_start.
+ */
+fn complete_checksum(packet: &mut [u8], start: usize, offset: usize) -> bool {
+    if start + offset + 2 > packet.len() {
+        return false;
+    }
+    let csum = match checksum(sum(&packet[start..], 0)) {
+        0 if offset == 6 => 0xffff, // UDP (a zero checksum means no checksum)
+        csum => csum,
+    };
+    set_u16(packet, start + offset, csum);
+    true
+}
+
+// a super-packet to be segmented (described by the virtio_net_hdr)
+struct Segmentation {
+    protocol: u8, // transport protocol
+    l4: usize,    // offset of the transport header
+    hlen: usize,  // length of the IP and transport headers
+    size: usize,  // maximum payload of a segment
+}
+
+impl Segmentation {
+    fn new(packet: &[u8], gso_type: u8, start: usize, size: usize) -> Option<Segmentation> {
+        // check the IP version
+        let version = packet.first()? >> 4;
+        match (gso_type, version) {
+            (VIRTIO_NET_HDR_GSO_TCPV4, 4) | (VIRTIO_NET_HDR_GSO_UDP_L4, 4) => {
+                if packet.len() < 20 || start < usize::from(packet[0] & 0xf) * 4 {
+                    return None;
+                }
+            }
+            (VIRTIO_NET_HDR_GSO_TCPV6, 6) | (VIRTIO_NET_HDR_GSO_UDP_L4, 6) => {
+                if start < 40 {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+
+        // length of the transport header (at least the fixed TCP header)
+        let (protocol, l4len) = if gso_type == VIRTIO_NET_HDR_GSO_UDP_L4 {
+            (PROTOCOL_UDP, 8)
+        } else {
+            let l4len = usize::from(packet.get(start + 12)? >> 4) * 4;
+            if l4len < 20 {
+                return None;
+            }
+            (PROTOCOL_TCP, l4len)
+        };
+        let hlen = start + l4len;
+        if size == 0 || hlen > packet.len() {
+            return None;
+        }
+        Some(Segmentation {
+            protocol,
+            l4: start,
+            hlen,
+            size,
+        })
+    }
+
+    fn segments(&self, packet: &[u8]) -> usize {
+        match packet.len() - self.hlen {
+            0 => 1,
+            payload => 1 + (payload - 1) / self.size,
+        }
+    }
+
+    // construct the i'th segment in dst, returning its length
+    fn segment(&self, packet: &[u8], i: usize, dst: &mut [u8]) -> Option<usize> {
+        let payload = &packet[self.hlen..];
+        let start = i * self.size;
+        let end = min(start + self.size, payload.len());
+        let last = end == payload.len();
+        let len = self.hlen + end - start;
+        let seg = dst.get_mut(..len)?;
+
+        // copy the headers and the payload
+        seg[..self.hlen].copy_from_slice(&packet[..self.hlen]);
+        seg[self.hlen..].copy_from_slice(&payload[start..end]);
+
+        // update the IP header
+        match seg[0] >> 4 {
+            4 => {
+                let ihl = usize::from(seg[0] & 0xf) * 4;
+                let id = get_u16(seg, 4).wrapping_add(i as u16);
+                set_u16(seg, 2, len as u16);
+                set_u16(seg, 4, id);
+                set_u16(seg, 10, 0);
+                let csum = checksum(sum(&seg[..ihl], 0));
+                set_u16(seg, 10, csum);
+            }
+            _ => set_u16(seg, 4, (len - 40) as u16),
+        }
+
+        // update the transport header
+        let l4 = self.l4;
+        let offset = if self.protocol == PROTOCOL_TCP {
+            let seq = u32::from_be_bytes([seg[l4 + 4], seg[l4 + 5], seg[l4 + 6], seg[l4 + 7]]);
+            let seq = seq.wrapping_add(start as u32);
+            seg[l4 + 4..l4 + 8].copy_from_slice(&seq.to_be_bytes());
+            if !last {
+                seg[l4 + 13] &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+            }
+            if i > 0 {
+                seg[l4 + 13] &= !TCP_FLAG_CWR;
+            }
+            16
+        } else {
+            set_u16(seg, l4 + 4, (len - l4) as u16);
+            6
+        };
+
+        // compute the transport checksum
+        set_u16(seg, l4 + offset, 0);
+        let acc = sum(&seg[l4..], pseudo_header(seg, self.protocol, len - l4));
+        let csum = match checksum(acc) {
+            0 if self.protocol == PROTOCOL_UDP => 0xffff,
+            csum => csum,
+        };
+        set_u16(seg, l4 + offset, csum);
+        Some(len)
+    }
+}
+
+/* A packet read from a TUN device with virtio-net headers,
+ * handed out one segment at a time.
+ */
+pub struct SuperPacket {
+    buf: Vec<u8>, // virtio_net_hdr followed by the packet
+    len: usize,
+    segmentation: Option<Segmentation>,
+    segments: usize,
+    next: usize,
+}
+
+impl SuperPacket {
+    pub fn new() -> SuperPacket {
+        SuperPacket {
+            buf: vec![0; VNET_HDR_SIZE + MAX_PACKET_SIZE],
+            len: 0,
+            segmentation: None,
+            segments: 0,
+            next: 0,
+        }
+    }
+
+    /// Buffer to read the next packet (prefaced by the virtio_net_hdr) into
+    pub fn buffer(&mut self) -> &mut [u8] {
+        &mut self.buf[..]
+    }
+
+    /// Returns true if every segment of the packet has been handed out
+    pub fn is_empty(&self) -> bool {
+        self.next >= self.segments
+    }
+
+    /// Parse the virtio_net_hdr of the packet read into the buffer
+    /// (completing the checksum, if partial)
+    ///
+    /// Malformed packets and unsupported GSO types are discarded.
+    pub fn load(&mut self, len: usize) {
+        self.len = len;
+        self.next = 0;
+        self.segments = 0;
+        self.segmentation = None;
+        if len < VNET_HDR_SIZE {
+            return;
+        }
+
+        // parse virtio_net_hdr (in native byte-order)
+        let (hdr, packet) = self.buf[..len].split_at_mut(VNET_HDR_SIZE);
+        let flags = hdr[0];
+        let gso_type = hdr[1] & !VIRTIO_NET_HDR_GSO_ECN;
+        let gso_size = usize::from(u16::from_ne_bytes([hdr[4], hdr[5]]));
+        let csum_start = usize::from(u16::from_ne_bytes([hdr[6], hdr[7]]));
+        let csum_offset = usize::from(u16::from_ne_bytes([hdr[8], hdr[9]]));
+
+        if gso_type == VIRTIO_NET_HDR_GSO_NONE {
+            if flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0
+                && !complete_checksum(packet, csum_start, csum_offset)
+            {
+                log::debug!("TUN offload, discarding packet with invalid checksum offsets");
+                return;
+            }
+            self.segments = 1;
+            return;
+        }
+
+        match Segmentation::new(packet, gso_type, csum_start, gso_size) {
+            Some(segmentation) => {
+                self.segments = segmentation.segments(packet);
+                log::trace!(
+                    "TUN offload, super-packet of {} bytes ({} segments)",
+                    packet.len(),
+                    self.segments
+                );
+                self.segmentation = Some(segmentation);
+            }
+            None => log::debug!(
+                "TUN offload, discarding malformed super-packet (gso_type = {})",
+                gso_type
+            ),
+        }
+    }
+
+    /// Construct the next segment in dst, returning its length
+    ///
+    /// Segments which do not fit in dst are discarded.
+    pub fn next(&mut self, dst: &mut [u8]) -> Option<usize> {
+        while self.next < self.segments {
+            let i = self.next;
+            self.next += 1;
+
+            let packet = &self.buf[VNET_HDR_SIZE..self.len];
+            let size = match &self.segmentation {
+                None => dst.get_mut(..packet.len()).map(|dst| {
+                    dst.copy_from_slice(packet);
+                    packet.len()
+                }),
+                Some(segmentation) => segmentation.segment(packet, i, dst),
+            };
+            match size {
+                Some(size) => return Some(size),
+                None => log::debug!("TUN offload, discarding segment exceeding the buffer"),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // check the IP and transport checksums of a (complete) packet
+    fn verify(packet: &[u8], protocol: u8) {
+        let l4 = match packet[0] >> 4 {
+            4 => {
+                let ihl = usize::from(packet[0] & 0xf) * 4;
+                assert_eq!(checksum(sum(&packet[..ihl], 0)), 0, "IP checksum");
+                assert_eq!(usize::from(get_u16(packet, 2)), packet.len());
+                ihl
+            }
+            _ => {
+                assert_eq!(usize::from(get_u16(packet, 4)), packet.len() - 40);
+                40
+            }
+        };
+        let acc = sum(
+            &packet[l4..],
+            pseudo_header(packet, protocol, packet.len() - l4),
+        );
+        assert_eq!(checksum(acc), 0, "transport checksum");
+    }
+
+    fn super_packet(hdr: [u8; VNET_HDR_SIZE], packet: &[u8]) -> SuperPacket {
+        let mut sp = SuperPacket::new();
+        sp.buffer()[..VNET_HDR_SIZE].copy_from_slice(&hdr);
+        sp.buffer()[VNET_HDR_SIZE..VNET_HDR_SIZE + packet.len()].copy_from_slice(packet);
+        sp.load(VNET_HDR_SIZE + packet.len());
+        sp
+    }
+
+    fn vnet_hdr(flags: u8, gso_type: u8, gso_size: u16, start: u16, offset: u16) -> [u8; 10] {
+        let mut hdr = [0u8; VNET_HDR_SIZE];
+        hdr[0] = flags;
+        hdr[1] = gso_type;
+        hdr[4..6].copy_from_slice(&gso_size.to_ne_bytes());
+        hdr[6..8].copy_from_slice(&start.to_ne_bytes());
+        hdr[8..10].copy_from_slice(&offset.to_ne_bytes());
+        hdr
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn tcp4(payload: &[u8], flags: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        set_u16(&mut packet, 2, (40 + payload.len()) as u16);
+        set_u16(&mut packet, 4, 0x1234);
+        packet[8] = 64;
+        packet[9] = PROTOCOL_TCP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        set_u16(&mut packet, 20, 4000);
+        set_u16(&mut packet, 22, 80);
+        packet[24..28].copy_from_slice(&0xffff_f000u32.to_be_bytes());
+        packet[32] = 5 << 4;
+        packet[33] = flags;
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn udp6(payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 48];
+        packet[0] = 0x60;
+        set_u16(&mut packet, 4, (8 + payload.len()) as u16);
+        packet[6] = PROTOCOL_UDP;
+        packet[7] = 64;
+        packet[8..24].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        packet[24..40].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        set_u16(&mut packet, 40, 5000);
+        set_u16(&mut packet, 42, 53);
+        set_u16(&mut packet, 44, (8 + payload.len()) as u16);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn segments(sp: &mut SuperPacket) -> Vec<Vec<u8>> {
+        let mut segments = vec![];
+        let mut buf = vec![0u8; 2000];
+        while let Some(size) = sp.next(&mut buf[..]) {
+            segments.push(buf[..size].to_vec());
+        }
+        segments
+    }
+
+    #[test]
+    fn test_tso_ipv4() {
+        let data = payload(3000);
+        let flags = TCP_FLAG_FIN | TCP_FLAG_PSH | TCP_FLAG_CWR | 0x10;
+        let hdr = vnet_hdr(
+            VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            VIRTIO_NET_HDR_GSO_TCPV4,
+            1400,
+            20,
+            16,
+        );
+        let mut sp = super_packet(hdr, &tcp4(&data, flags));
+
+        let segments = segments(&mut sp);
+        assert!(sp.is_empty());
+        assert_eq!(
+            segments.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![1440, 1440, 240]
+        );
+        for (i, seg) in segments.iter().enumerate() {
+            verify(seg, PROTOCOL_TCP);
+            assert_eq!(get_u16(seg, 4), 0x1234 + i as u16, "IP identification");
+            let seq = u32::from_be_bytes([seg[24], seg[25], seg[26], seg[27]]);
+            assert_eq!(seq, 0xffff_f000u32.wrapping_add(1400 * i as u32));
+            assert_eq!(&seg[40..], &data[1400 * i..min(1400 * (i + 1), data.len())]);
+        }
+        assert_eq!(segments[0][33], TCP_FLAG_CWR | 0x10);
+        assert_eq!(segments[1][33], 0x10);
+        assert_eq!(segments[2][33], TCP_FLAG_FIN | TCP_FLAG_PSH | 0x10);
+    }
+
+    #[test]
+    fn test_uso_ipv6() {
+        let data = payload(2500);
+        let hdr = vnet_hdr(
+            VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            VIRTIO_NET_HDR_GSO_UDP_L4,
+            1000,
+            40,
+            6,
+        );
+        let mut sp = super_packet(hdr, &udp6(&data));
+
+        let segments = segments(&mut sp);
+        assert_eq!(
+            segments.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![1048, 1048, 548]
+        );
+        for (i, seg) in segments.iter().enumerate() {
+            verify(seg, PROTOCOL_UDP);
+            assert_eq!(usize::from(get_u16(seg, 44)), seg.len() - 40, "UDP length");
+            assert_eq!(&seg[48..], &data[1000 * i..min(1000 * (i + 1), data.len())]);
+        }
+    }
+
+    #[test]
+    fn test_complete_checksum() {
+        // the checksum field holds the sum of the pseudo header
+        let mut packet = udp6(&payload(333));
+        let partial = !checksum(pseudo_header(&packet, PROTOCOL_UDP, 8 + 333));
+        set_u16(&mut packet, 46, partial);
+
+        let hdr = vnet_hdr(
+            VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            VIRTIO_NET_HDR_GSO_NONE,
+            0,
+            40,
+            6,
+        );
+        let mut sp = super_packet(hdr, &packet);
+        let segments = segments(&mut sp);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].len(), packet.len());
+        verify(&segments[0], PROTOCOL_UDP);
+    }
+
+    #[test]
+    fn test_discard_malformed() {
+        // transport header beyond the end of the packet
+        let hdr = vnet_hdr(0, VIRTIO_NET_HDR_GSO_TCPV4, 1400, 200, 16);
+        let mut sp = super_packet(hdr, &tcp4(&payload(100), 0));
+        assert!(sp.is_empty());
+        assert_eq!(sp.next(&mut [0u8; 2000]), None);
+
+        // TCP header shorter than the fixed header (data offset of 2 words)
+        let mut packet = tcp4(&[], 0);
+        packet[32] = 2 << 4;
+        packet.truncate(34);
+        let hdr = vnet_hdr(0, VIRTIO_NET_HDR_GSO_TCPV4, 1400, 20, 16);
+        let mut sp = super_packet(hdr, &packet);
+        assert!(sp.is_empty());
+        assert_eq!(sp.next(&mut [0u8; 2000]), None);
+
+        // segments exceeding the buffer are discarded
+        let hdr = vnet_hdr(0, VIRTIO_NET_HDR_GSO_TCPV4, 1400, 20, 16);
+        let mut sp = super_packet(hdr, &tcp4(&payload(1500), 0));
+        assert_eq!(sp.next(&mut [0u8; 1000]), Some(140));
+        assert!(sp.is_empty());
+    }
+}
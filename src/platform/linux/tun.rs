@@ -1,12 +1,25 @@
 use super::super::tun::*;
+use super::offload::{SuperPacket, VNET_HDR_SIZE};
 
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::mem;
-use std::os::raw::c_short;
+use std::os::raw::{c_short, c_uint};
 use std::os::unix::io::RawFd;
+use std::sync::Mutex;
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const IFF_MULTI_QUEUE: c_short = 0x0100;
+const IFF_VNET_HDR: c_short = 0x4000;
+
+// offloads accepted from the kernel (in vnet header mode)
+const TUN_F_CSUM: c_uint = 0x01;
+const TUN_F_TSO4: c_uint = 0x02;
+const TUN_F_TSO6: c_uint = 0x04;
+const TUN_F_USO4: c_uint = 0x20;
+const TUN_F_USO6: c_uint = 0x40;
 const CLONE_DEVICE_PATH: &[u8] = b"/dev/net/tun\0";
 
 #[repr(C)]
@@ -32,10 +45,12 @@ pub struct LinuxTun {}
 
 pub struct LinuxTunReader {
     fd: RawFd,
+    vnet: Option<Mutex<SuperPacket>>, // packet being segmented (in vnet header mode)
 }
 
 pub struct LinuxTunWriter {
-    fd: RawFd,
+    fds: Vec<RawFd>,
+    vnet: bool,
 }
 
 pub struct LinuxTunStatus {
@@ -52,6 +67,7 @@ pub enum LinuxTunError {
     SetIFFIoctlFailed,
     GetMTUIoctlFailed,
     NetlinkFailure,
+    OffloadUnsupported,
     Closed, // TODO
 }
 
@@ -68,6 +84,7 @@ impl fmt::Display for LinuxTunError {
             LinuxTunError::Closed => write!(f, "The tunnel has been closed"),
             LinuxTunError::GetMTUIoctlFailed => write!(f, "ifmtu ioctl failed"),
             LinuxTunError::NetlinkFailure => write!(f, "Netlink listener error"),
+            LinuxTunError::OffloadUnsupported => write!(f, "TUN offloads unsupported"),
         }
     }
 }
@@ -82,23 +99,70 @@ impl Error for LinuxTunError {
     }
 }
 
+impl LinuxTunReader {
+    /// The file descriptor of the queue, unless packets are read with a virtio-net header
+    /// (for reading with an external event loop, e.g. io_uring)
+    #[cfg(feature = "uring")]
+    pub(super) fn plain_fd(&self) -> Option<RawFd> {
+        match self.vnet {
+            None => Some(self.fd),
+            Some(_) => None,
+        }
+    }
+}
+
 impl Reader for LinuxTunReader {
     type Error = LinuxTunError;
 
-    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
-        /*
-        debug_assert!(
-            offset < buf.len(),
-            "There is no space for the body of the read"
-        );
-        */
-        let n: isize =
-            unsafe { libc::read(self.fd, buf[offset..].as_mut_ptr() as _, buf.len() - offset) };
-        if n < 0 {
-            Err(LinuxTunError::Closed)
-        } else {
-            // conversion is safe
-            Ok(n as usize)
+    fn read(
+        &self,
+        bufs: &mut [&mut [u8]],
+        sizes: &mut Vec<usize>,
+        offset: usize,
+    ) -> Result<(), Self::Error> {
+        let packet = match &self.vnet {
+            Some(packet) => packet,
+            None => {
+                /*
+                debug_assert!(
+                    offset < buf.len(),
+                    "There is no space for the body of the read"
+                );
+                */
+                let buf = &mut bufs[0];
+                let n: isize = unsafe {
+                    libc::read(self.fd, buf[offset..].as_mut_ptr() as _, buf.len() - offset)
+                };
+                return if n < 0 {
+                    Err(LinuxTunError::Closed)
+                } else {
+                    // conversion is safe
+                    sizes.push(n as usize);
+                    Ok(())
+                };
+            }
+        };
+
+        // hand out the segments of the last packet, reading a new packet if none remain
+        let mut packet = packet.lock().unwrap();
+        loop {
+            if packet.is_empty() {
+                let buf = packet.buffer();
+                let n: isize = unsafe { libc::read(self.fd, buf.as_mut_ptr() as _, buf.len()) };
+                if n < 0 {
+                    return Err(LinuxTunError::Closed);
+                }
+                packet.load(n as usize);
+            }
+            for buf in bufs.iter_mut() {
+                match packet.next(&mut buf[offset..]) {
+                    Some(size) => sizes.push(size),
+                    None => break,
+                }
+            }
+            if !sizes.is_empty() {
+                return Ok(());
+            }
         }
     }
 }
@@ -107,13 +171,55 @@ impl Writer for LinuxTunWriter {
     type Error = LinuxTunError;
 
     fn write(&self, src: &[u8]) -> Result<(), Self::Error> {
-        match unsafe { libc::write(self.fd, src.as_ptr() as _, src.len() as _) } {
+        let fd = self.fds[queue(src, self.fds.len())];
+        let res = if self.vnet {
+            // preface the packet by an empty vnet header (no offloads)
+            let hdr = [0u8; VNET_HDR_SIZE];
+            let iovs = [
+                libc::iovec {
+                    iov_base: hdr.as_ptr() as _,
+                    iov_len: hdr.len(),
+                },
+                libc::iovec {
+                    iov_base: src.as_ptr() as _,
+                    iov_len: src.len(),
+                },
+            ];
+            unsafe { libc::writev(fd, iovs.as_ptr(), iovs.len() as _) }
+        } else {
+            unsafe { libc::write(fd, src.as_ptr() as _, src.len() as _) }
+        };
+        match res {
             -1 => Err(LinuxTunError::Closed),
             _ => Ok(()),
         }
     }
 }
 
+/* Selects the queue for an inbound IP packet:
+ * packets with the same source and destination address are written to the same queue,
+ * preserving the order of packets within a flow.
+ */
+fn queue(packet: &[u8], queues: usize) -> usize {
+    if queues == 1 {
+        return 0;
+    }
+    let addrs = match packet.first().map(|v| v >> 4) {
+        Some(4) => packet.get(12..20),
+        Some(6) => packet.get(8..40),
+        _ => None,
+    };
+
+    // FNV-1a hash of the addresses
+    let hash = addrs
+        .unwrap_or(&[])
+        .iter()
+        .fold(0x811c_9dc5u32, |hash, byte| {
+            (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+        });
+    hash as usize % queues
+}
+
 fn get_ifindex(name: &[u8; libc::IFNAMSIZ]) -> i32 {
     debug_assert_eq!(
         name[libc::IFNAMSIZ - 1],
@@ -315,14 +421,63 @@ impl Tun for LinuxTun {
     type Error = LinuxTunError;
 }
 
-impl PlatformTun for LinuxTun {
-    type Status = LinuxTunStatus;
+impl LinuxTun {
+    // open a queue of the TUN device (creating the device if it does not exist)
+    fn open(req: &Ifreq) -> Result<RawFd, LinuxTunError> {
+        // open clone device
+        let fd: RawFd = match unsafe { libc::open(CLONE_DEVICE_PATH.as_ptr() as _, libc::O_RDWR) } {
+            -1 => return Err(LinuxTunError::FailedToOpenCloneDevice),
+            fd => fd,
+        };
+        assert!(fd >= 0);
 
-    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        // create TUN device (or attach queue)
+        // (EINVAL with IFF_VNET_HDR, e.g. if the device exists without, means no offloads)
+        if unsafe { libc::ioctl(fd, TUNSETIFF as _, req) } < 0 {
+            let einval = io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL);
+            unsafe { libc::close(fd) };
+            return Err(if einval && req.flags & IFF_VNET_HDR != 0 {
+                LinuxTunError::OffloadUnsupported
+            } else {
+                LinuxTunError::SetIFFIoctlFailed
+            });
+        }
+        Ok(fd)
+    }
+
+    // close the queues opened (on failure to open the device)
+    fn close(fds: &[RawFd]) {
+        for fd in fds {
+            unsafe { libc::close(*fd) };
+        }
+    }
+
+    // enable offloads on a queue opened with IFF_VNET_HDR (USO requires Linux 6.2)
+    fn set_offload(fd: RawFd) -> Result<(), LinuxTunError> {
+        let tso = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+        for offload in [tso | TUN_F_USO4 | TUN_F_USO6, tso].iter() {
+            if unsafe { libc::ioctl(fd, TUNSETOFFLOAD as _, *offload) } == 0 {
+                log::debug!("TUN offloads enabled (flags = {:#x})", offload);
+                return Ok(());
+            }
+        }
+        if io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+            Err(LinuxTunError::OffloadUnsupported)
+        } else {
+            Err(LinuxTunError::SetIFFIoctlFailed)
+        }
+    }
+
+    fn open_queues(
+        name: &str,
+        queues: usize,
+        vnet: bool,
+    ) -> Result<(Vec<LinuxTunReader>, LinuxTunWriter, LinuxTunStatus), LinuxTunError> {
         // construct request struct
         let mut req = Ifreq {
             name: [0u8; libc::IFNAMSIZ],
-            flags: (libc::IFF_TUN | libc::IFF_NO_PI) as c_short,
+            flags: (libc::IFF_TUN | libc::IFF_NO_PI) as c_short
+                | if vnet { IFF_VNET_HDR } else { 0 },
             _pad: [0u8; 64],
         };
 
@@ -333,23 +488,96 @@ impl PlatformTun for LinuxTun {
         }
         req.name[..bs.len()].copy_from_slice(bs);
 
-        // open clone device
-        let fd: RawFd = match unsafe { libc::open(CLONE_DEVICE_PATH.as_ptr() as _, libc::O_RDWR) } {
-            -1 => return Err(LinuxTunError::FailedToOpenCloneDevice),
-            fd => fd,
-        };
-        assert!(fd >= 0);
-
-        // create TUN device
-        if unsafe { libc::ioctl(fd, TUNSETIFF as _, &req) } < 0 {
-            return Err(LinuxTunError::SetIFFIoctlFailed);
+        // open a queue per reader
+        // (falling back to a single queue, e.g. if the device exists without IFF_MULTI_QUEUE)
+        let mut fds = vec![];
+        if queues > 1 {
+            let mq = Ifreq {
+                name: req.name,
+                flags: req.flags | IFF_MULTI_QUEUE,
+                _pad: [0u8; 64],
+            };
+            match LinuxTun::open(&mq) {
+                Ok(fd) => {
+                    fds.push(fd);
+                    for _ in 1..queues {
+                        match LinuxTun::open(&mq) {
+                            Ok(fd) => fds.push(fd),
+                            Err(e) => {
+                                LinuxTun::close(&fds);
+                                return Err(e);
+                            }
+                        }
+                    }
+                }
+                Err(_) => log::info!("multi-queue TUN unsupported, using a single queue"),
+            }
+        }
+        if fds.is_empty() {
+            fds.push(LinuxTun::open(&req)?);
+        }
+        log::debug!("TUN device {} opened with {} queues", name, fds.len());
+
+        // enable offloads
+        if vnet {
+            for fd in fds.iter() {
+                if let Err(e) = LinuxTun::set_offload(*fd) {
+                    LinuxTun::close(&fds);
+                    return Err(e);
+                }
+            }
         }
 
         // create PlatformTunMTU instance
+        let status = match LinuxTunStatus::new(req.name) {
+            Ok(status) => status,
+            Err(e) => {
+                LinuxTun::close(&fds);
+                return Err(e);
+            }
+        };
         Ok((
-            vec![LinuxTunReader { fd }], // TODO: use multi-queue for Linux
-            LinuxTunWriter { fd },
-            LinuxTunStatus::new(req.name)?,
+            fds.iter()
+                .map(|fd| LinuxTunReader {
+                    fd: *fd,
+                    vnet: if vnet {
+                        Some(Mutex::new(SuperPacket::new()))
+                    } else {
+                        None
+                    },
+                })
+                .collect(),
+            LinuxTunWriter { fds, vnet },
+            status,
         ))
     }
 }
+
+impl PlatformTun for LinuxTun {
+    type Status = LinuxTunStatus;
+
+    fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        Self::create_queues(name, 1)
+    }
+
+    fn create_queues(
+        name: &str,
+        queues: usize,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        Self::open_queues(name, queues, false)
+    }
+
+    fn create_offload(
+        name: &str,
+        queues: usize,
+    ) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error> {
+        // fall back to a device without offloads (e.g. if it exists without IFF_VNET_HDR)
+        match Self::open_queues(name, queues, true) {
+            Err(LinuxTunError::OffloadUnsupported) => {
+                log::info!("TUN offloads unsupported, using a device without");
+                Self::open_queues(name, queues, false)
+            }
+            res => res,
+        }
+    }
+}
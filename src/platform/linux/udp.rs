@@ -1,13 +1,25 @@
 use super::super::udp::*;
 use super::super::Endpoint;
 
+use std::cmp::min;
 use std::convert::TryInto;
 use std::io;
 use std::mem;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::unix::io::RawFd;
 use std::ptr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// socket options for UDP segmentation offload (GSO) and receive coalescing (GRO)
+const UDP_SEGMENT: libc::c_int = 103;
+const UDP_GRO: libc::c_int = 104;
+
+// maximum number of segments in a single GSO send (UDP_MAX_SEGMENTS in the kernel)
+const MAX_GSO_SEGMENTS: usize = 64;
+
+// maximum size of a GSO send / GRO receive (the largest UDP payload over IPv4)
+const MAX_GSO_SIZE: usize = 65507;
 
 pub struct FD(RawFd);
 
@@ -34,11 +46,13 @@ struct ControlHeaderV6 {
     info: libc::in6_pktinfo,
 }
 
+#[derive(Clone)]
 pub struct EndpointV4 {
     dst: libc::sockaddr_in, // destination IP
     info: libc::in_pktinfo, // src & ifindex
 }
 
+#[derive(Clone)]
 pub struct EndpointV6 {
     dst: libc::sockaddr_in6, // destination IP
     info: libc::in6_pktinfo, // src & zone id
@@ -52,17 +66,43 @@ pub struct LinuxOwner {
     sock6: Option<Arc<FD>>,
 }
 
+/* Datagram received with UDP_GRO:
+ * the coalesced segments are handed out one at a time by subsequent reads.
+ */
+pub struct Coalesced {
+    buf: Vec<u8>,
+    len: usize,
+    offset: usize,
+    segment: usize,
+    src: Option<LinuxEndpoint>,
+}
+
+impl Coalesced {
+    fn new() -> Coalesced {
+        Coalesced {
+            buf: vec![0; 1 << 16], // any UDP payload
+            len: 0,
+            offset: 0,
+            segment: 0,
+            src: None,
+        }
+    }
+}
+
 pub enum LinuxUDPReader {
-    V4(Arc<FD>),
-    V6(Arc<FD>),
+    V4(Arc<FD>, Option<Mutex<Coalesced>>), // GRO state (if enabled)
+    V6(Arc<FD>, Option<Mutex<Coalesced>>),
 }
 
 #[derive(Clone)]
 pub struct LinuxUDPWriter {
     sock4: Arc<FD>,
     sock6: Arc<FD>,
+    gso4: Arc<AtomicBool>, // GSO supported (and not disabled after a failure)
+    gso6: Arc<AtomicBool>,
 }
 
+#[derive(Clone)]
 pub enum LinuxEndpoint {
     V4(EndpointV4),
     V6(EndpointV6),
@@ -114,6 +154,30 @@ fn setsockopt_int(
     setsockopt(fd, level, name, &value)
 }
 
+// check if the kernel supports UDP segmentation offload (GSO) on the socket
+fn supports_gso(fd: RawFd) -> bool {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of_val(&value) as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            UDP_SEGMENT,
+            safe_cast(&mut value),
+            &mut len as *mut libc::socklen_t,
+        )
+    };
+    log::debug!("linux udp, GSO supported: {} (fd = {})", res == 0, fd);
+    res == 0
+}
+
+// enable UDP receive coalescing (GRO) on the socket, returns false if unsupported
+fn enable_gro(fd: RawFd) -> bool {
+    let res = setsockopt_int(fd, libc::SOL_UDP, UDP_GRO, 1).is_ok();
+    log::debug!("linux udp, GRO enabled: {} (fd = {})", res, fd);
+    res
+}
+
 #[allow(non_snake_case)]
 const fn CMSG_ALIGN(len: usize) -> usize {
     ((len) + mem::size_of::<u32>() - 1) & !(mem::size_of::<u32>() - 1)
@@ -129,6 +193,355 @@ fn safe_cast<T, D>(v: &mut T) -> *mut D {
     (v as *mut T) as *mut D
 }
 
+/* Receive a batch of messages with recvmmsg (blocking until at least one message is available).
+ *
+ * Arguments:
+ *
+ * - 'bufs', buffers to receive the messages into (at most one message per buffer)
+ * - 'msgs', the length and source endpoint of every received message is pushed to this vector
+ * - 'endpoint', creates the endpoint from the source address and the control message (pktinfo)
+ */
+fn recv_mmsg<A, C>(
+    fd: RawFd,
+    bufs: &mut [&mut [u8]],
+    msgs: &mut Vec<(usize, LinuxEndpoint)>,
+    endpoint: fn(&A, &C) -> LinuxEndpoint,
+) -> Result<(), io::Error> {
+    let n = bufs.len();
+    log::trace!("receive batch (block), (fd {}, max-msgs {})", fd, n);
+
+    let mut iovs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|buf| {
+            debug_assert!(!buf.is_empty(), "reading into empty buffer (will fail)");
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
+                iov_len: buf.len(),
+            }
+        })
+        .collect();
+    let mut srcs: Vec<A> = (0..n).map(|_| unsafe { mem::zeroed() }).collect();
+    let mut controls: Vec<C> = (0..n).map(|_| unsafe { mem::zeroed() }).collect();
+    let mut hdrs: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .zip(srcs.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((iov, src), control)| libc::mmsghdr {
+            msg_hdr: libc::msghdr {
+                msg_name: safe_cast(src),
+                msg_namelen: mem::size_of::<A>() as u32,
+                msg_iov: iov as *mut libc::iovec,
+                msg_iovlen: 1,
+                msg_control: safe_cast(control),
+                msg_controllen: mem::size_of::<C>(),
+                msg_flags: 0,
+            },
+            msg_len: 0,
+        })
+        .collect();
+
+    let ret = unsafe {
+        libc::recvmmsg(
+            fd,
+            hdrs.as_mut_ptr(),
+            hdrs.len() as libc::c_uint,
+            libc::MSG_WAITFORONE,
+            ptr::null_mut(),
+        )
+    };
+
+    if ret <= 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!(
+                "failed to receive (ret = {}, fd = {}, errno = {})",
+                ret,
+                fd,
+                errno()
+            ),
+        ));
+    }
+
+    // our future destination is the source address (with the sticky source from pktinfo)
+    for i in 0..ret as usize {
+        msgs.push((hdrs[i].msg_len as usize, endpoint(&srcs[i], &controls[i])));
+    }
+    Ok(())
+}
+
+/* Submits a batch of prepared messages (in order),
+ * returning the number of messages sent (stopping at the first failure),
+ * or the errno if none could be sent.
+ */
+pub(super) type SendBatch = fn(RawFd, &mut [libc::mmsghdr]) -> Result<usize, libc::c_int>;
+
+pub(super) fn sendmmsg_all(fd: RawFd, hdrs: &mut [libc::mmsghdr]) -> Result<usize, libc::c_int> {
+    // sendmmsg stops at the first message which fails (reporting the error if it was the first)
+    let mut sent = 0;
+    while sent < hdrs.len() {
+        let ret = unsafe {
+            libc::sendmmsg(
+                fd,
+                hdrs[sent..].as_mut_ptr(),
+                (hdrs.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if ret < 0 {
+            return if sent == 0 { Err(errno()) } else { Ok(sent) };
+        }
+        sent += ret as usize;
+    }
+    Ok(sent)
+}
+
+/* Send a batch of messages to the same destination.
+ *
+ * Arguments:
+ *
+ * - 'dst', the destination address
+ * - 'control', the control message (pktinfo) setting the source, if any
+ * - 'send', submits the prepared messages (e.g. with sendmmsg)
+ *
+ * Returns:
+ *
+ * The number of messages sent, or the errno if none could be sent.
+ */
+fn send_mmsg<A, C>(
+    fd: RawFd,
+    bufs: &[&[u8]],
+    dst: &mut A,
+    control: Option<&mut C>,
+    send: SendBatch,
+) -> Result<usize, libc::c_int> {
+    log::debug!("sending batch ({} fd, {} messages)", fd, bufs.len());
+
+    let name: *mut core::ffi::c_void = safe_cast(dst);
+    let (control, controllen) = match control {
+        Some(control) => (safe_cast(control), mem::size_of::<C>()),
+        None => (ptr::null_mut(), 0),
+    };
+    let mut iovs: Vec<libc::iovec> = bufs
+        .iter()
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_ptr() as *mut core::ffi::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut hdrs: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .map(|iov| libc::mmsghdr {
+            msg_hdr: libc::msghdr {
+                msg_name: name,
+                msg_namelen: mem::size_of::<A>() as u32,
+                msg_iov: iov as *mut libc::iovec,
+                msg_iovlen: 1,
+                msg_control: control,
+                msg_controllen: controllen,
+                msg_flags: 0,
+            },
+            msg_len: 0,
+        })
+        .collect();
+    send(fd, &mut hdrs[..])
+}
+
+/* Send consecutive segments (equally sized, except the last which may be shorter)
+ * as a single datagram segmented by the kernel (UDP_SEGMENT),
+ * passing the segments to the kernel as an iovec each (without copying).
+ *
+ * Arguments:
+ *
+ * - 'dst', the destination address
+ * - 'info', the pktinfo setting the source (cleared and retried without on EINVAL)
+ * - 'pktinfo', the level and type of the pktinfo control message
+ *
+ * Returns:
+ *
+ * The number of segments sent, or the number of segments sent and the errno of the failed send.
+ */
+fn send_gso<A, P: Copy>(
+    fd: RawFd,
+    bufs: &[&[u8]],
+    dst: &mut A,
+    info: &mut P,
+    pktinfo: (libc::c_int, libc::c_int),
+) -> Result<usize, (usize, libc::c_int)> {
+    let segment = bufs[0].len();
+    let max_segments = min(MAX_GSO_SEGMENTS, MAX_GSO_SIZE / segment);
+    debug_assert!(max_segments > 0);
+    log::debug!(
+        "sending segmented ({} fd, {} bytes per segment, {} segments)",
+        fd,
+        segment,
+        bufs.len()
+    );
+
+    // control messages: pktinfo, followed by the segment size
+    let info_space = unsafe { libc::CMSG_SPACE(mem::size_of::<P>() as u32) } as usize;
+    let segment_space = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as usize;
+    let mut control = [0u64; 16];
+    debug_assert!(info_space + segment_space <= mem::size_of_val(&control));
+
+    let mut sent = 0;
+    let mut retry = true;
+    let mut iovs: [libc::iovec; MAX_GSO_SEGMENTS] = unsafe { mem::zeroed() };
+    while sent < bufs.len() {
+        let part = &bufs[sent..min(sent + max_segments, bufs.len())];
+        for (iov, buf) in iovs.iter_mut().zip(part) {
+            iov.iov_base = buf.as_ptr() as *mut core::ffi::c_void;
+            iov.iov_len = buf.len();
+        }
+        let hdr = libc::msghdr {
+            msg_name: safe_cast(dst),
+            msg_namelen: mem::size_of::<A>() as u32,
+            msg_iov: iovs.as_mut_ptr(),
+            msg_iovlen: part.len(),
+            msg_control: safe_cast(&mut control),
+            msg_controllen: info_space + segment_space,
+            msg_flags: 0,
+        };
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+            (*cmsg).cmsg_level = pktinfo.0;
+            (*cmsg).cmsg_type = pktinfo.1;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<P>() as u32) as usize;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut P, *info);
+
+            let cmsg = (hdr.msg_control as *mut u8).add(info_space) as *mut libc::cmsghdr;
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as usize;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment as u16);
+        }
+
+        if unsafe { libc::sendmsg(fd, &hdr, 0) } < 0 {
+            let err = errno();
+            if err == libc::EINVAL && retry {
+                log::trace!("clear source and retry");
+                *info = unsafe { mem::zeroed() };
+                retry = false;
+                continue;
+            }
+            return Err((sent, err));
+        }
+        sent += part.len();
+    }
+    Ok(sent)
+}
+
+// segments too large to send (at least) two in a single datagram are sent as a batch instead
+fn gso_fits(bufs: &[&[u8]]) -> bool {
+    matches!(bufs.first(), Some(buf) if buf.len() * 2 <= MAX_GSO_SIZE)
+}
+
+/* Parse the control messages of a received datagram.
+ *
+ * Returns:
+ *
+ * The segment size (0 if the datagram was not coalesced with UDP_GRO) and the pktinfo.
+ */
+fn parse_control<P>(hdr: &libc::msghdr, pktinfo: (libc::c_int, libc::c_int)) -> (usize, P) {
+    let mut segment = 0;
+    let mut info: P = unsafe { mem::zeroed() };
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            let kind = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+            if kind == (libc::SOL_UDP, UDP_GRO) {
+                segment = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) as usize;
+            } else if kind == pktinfo {
+                info = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const P);
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+    }
+    (segment, info)
+}
+
+/* Receive a datagram with UDP_GRO enabled (blocking if no coalesced segments remain),
+ * copying the segments into the buffers (at most one segment per buffer).
+ *
+ * Arguments:
+ *
+ * - 'bufs', buffers to copy the segments into
+ * - 'msgs', the length and source endpoint of every segment is pushed to this vector
+ * - 'pktinfo', the level and type of the pktinfo control message
+ * - 'endpoint', creates the endpoint from the source address and the pktinfo
+ */
+fn recv_gro<A, P>(
+    fd: RawFd,
+    coalesced: &mut Coalesced,
+    bufs: &mut [&mut [u8]],
+    msgs: &mut Vec<(usize, LinuxEndpoint)>,
+    pktinfo: (libc::c_int, libc::c_int),
+    endpoint: fn(&A, P) -> LinuxEndpoint,
+) -> Result<(), io::Error> {
+    if coalesced.offset >= coalesced.len {
+        log::trace!("receive coalesced (block), (fd {})", fd);
+
+        let mut iovs: [libc::iovec; 1] = [libc::iovec {
+            iov_base: coalesced.buf.as_mut_ptr() as *mut core::ffi::c_void,
+            iov_len: coalesced.buf.len(),
+        }];
+        let mut src: A = unsafe { mem::zeroed() };
+        let mut control = [0u64; 16];
+        let mut hdr = libc::msghdr {
+            msg_name: safe_cast(&mut src),
+            msg_namelen: mem::size_of::<A>() as u32,
+            msg_iov: iovs.as_mut_ptr(),
+            msg_iovlen: iovs.len(),
+            msg_control: safe_cast(&mut control),
+            msg_controllen: mem::size_of_val(&control),
+            msg_flags: 0,
+        };
+
+        let len = unsafe { libc::recvmsg(fd, &mut hdr as *mut libc::msghdr, 0) };
+
+        // (a zero length receive without a source: the socket has been shut down)
+        if len < 0 || (len == 0 && hdr.msg_namelen == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!(
+                    "failed to receive (len = {}, fd = {}, errno = {})",
+                    len,
+                    fd,
+                    errno()
+                ),
+            ));
+        }
+
+        // parse the control messages (pktinfo and the segment size, if coalesced)
+        let len = len as usize;
+        let (segment, info) = parse_control::<P>(&hdr, pktinfo);
+
+        // an empty datagram (as returned by recv_mmsg)
+        if len == 0 {
+            msgs.push((0, endpoint(&src, info)));
+            return Ok(());
+        }
+
+        coalesced.len = len;
+        coalesced.offset = 0;
+        coalesced.segment = if segment == 0 { len } else { segment };
+        coalesced.src = Some(endpoint(&src, info)); // save pktinfo (sticky source)
+    }
+
+    // hand out the segments
+    for buf in bufs.iter_mut() {
+        if coalesced.offset >= coalesced.len {
+            break;
+        }
+        let size = min(coalesced.segment, coalesced.len - coalesced.offset);
+        let copy = min(size, buf.len()); // truncate (like recvmsg)
+        buf[..copy].copy_from_slice(&coalesced.buf[coalesced.offset..coalesced.offset + copy]);
+        msgs.push((copy, coalesced.src.clone().unwrap()));
+        coalesced.offset += size;
+    }
+    Ok(())
+}
+
 impl Endpoint for LinuxEndpoint {
     fn from_address(addr: SocketAddr) -> Self {
         match addr {
@@ -174,8 +587,8 @@ impl Endpoint for LinuxEndpoint {
                 ))
             }
             LinuxEndpoint::V6(EndpointV6 { ref dst, .. }) => SocketAddr::V6(SocketAddrV6::new(
-                u128::from_ne_bytes(dst.sin6_addr.s6_addr).into(), // IPv6 addr
-                u16::from_be(dst.sin6_port), // convert back to native byte-order
+                Ipv6Addr::from(dst.sin6_addr.s6_addr), // IPv6 addr (network byte-order)
+                u16::from_be(dst.sin6_port),           // convert back to native byte-order
                 dst.sin6_flowinfo,
                 dst.sin6_scope_id,
             )),
@@ -197,6 +610,37 @@ impl Endpoint for LinuxEndpoint {
 }
 
 impl LinuxUDPReader {
+    /// The socket of the reader (for receiving with an external event loop, e.g. io_uring)
+    #[cfg(feature = "uring")]
+    pub(super) fn socket(&self) -> RawFd {
+        match self {
+            Self::V4(fd, _) | Self::V6(fd, _) => fd.0,
+        }
+    }
+
+    /* Parse a datagram received by an external event loop (with recvmsg semantics).
+     *
+     * Returns:
+     *
+     * The segment size (0 if not coalesced with UDP_GRO) and the endpoint of the sender.
+     */
+    #[cfg(feature = "uring")]
+    pub(super) fn received(&self, hdr: &libc::msghdr) -> (usize, LinuxEndpoint) {
+        match self {
+            Self::V4(..) => {
+                let (segment, info) = parse_control(hdr, (libc::IPPROTO_IP, libc::IP_PKTINFO));
+                let dst = unsafe { ptr::read_unaligned(hdr.msg_name as *const libc::sockaddr_in) };
+                (segment, LinuxEndpoint::V4(EndpointV4 { info, dst }))
+            }
+            Self::V6(..) => {
+                let pktinfo = (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO);
+                let (segment, info) = parse_control(hdr, pktinfo);
+                let dst = unsafe { ptr::read_unaligned(hdr.msg_name as *const libc::sockaddr_in6) };
+                (segment, LinuxEndpoint::V6(EndpointV6 { info, dst }))
+            }
+        }
+    }
+
     fn read6(fd: RawFd, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), io::Error> {
         log::trace!(
             "receive IPv6 packet (block), (fd {}, max-len {})",
@@ -310,13 +754,70 @@ impl Reader<LinuxEndpoint> for LinuxUDPReader {
 
     fn read(&self, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), Self::Error> {
         match self {
-            Self::V4(fd) => Self::read4(fd.0, buf),
-            Self::V6(fd) => Self::read6(fd.0, buf),
+            Self::V4(fd, None) => Self::read4(fd.0, buf),
+            Self::V6(fd, None) => Self::read6(fd.0, buf),
+            _ => {
+                let mut msgs = Vec::with_capacity(1);
+                self.read_batch(&mut [buf], &mut msgs)?;
+                Ok(msgs.pop().unwrap())
+            }
+        }
+    }
+
+    fn read_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+        msgs: &mut Vec<(usize, LinuxEndpoint)>,
+    ) -> Result<(), Self::Error> {
+        match self {
+            Self::V4(fd, Some(coalesced)) => recv_gro(
+                fd.0,
+                &mut coalesced.lock().unwrap(),
+                bufs,
+                msgs,
+                (libc::IPPROTO_IP, libc::IP_PKTINFO),
+                |src, info| LinuxEndpoint::V4(EndpointV4 { info, dst: *src }),
+            ),
+            Self::V6(fd, Some(coalesced)) => recv_gro(
+                fd.0,
+                &mut coalesced.lock().unwrap(),
+                bufs,
+                msgs,
+                (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO),
+                |src, info| LinuxEndpoint::V6(EndpointV6 { info, dst: *src }),
+            ),
+            Self::V4(fd, None) => recv_mmsg(fd.0, bufs, msgs, |src, control: &ControlHeaderV4| {
+                LinuxEndpoint::V4(EndpointV4 {
+                    info: control.info,
+                    dst: *src,
+                })
+            }),
+            Self::V6(fd, None) => recv_mmsg(fd.0, bufs, msgs, |src, control: &ControlHeaderV6| {
+                LinuxEndpoint::V6(EndpointV6 {
+                    info: control.info,
+                    dst: *src,
+                })
+            }),
         }
     }
 }
 
 impl LinuxUDPWriter {
+    /* Send a batch of messages to the same destination (see Writer::write_batch),
+     * with the prepared messages submitted by 'send' (e.g. to an io_uring instance).
+     */
+    pub(super) fn write_batch_with(
+        &self,
+        bufs: &[&[u8]],
+        dst: &mut LinuxEndpoint,
+        send: SendBatch,
+    ) -> Result<usize, io::Error> {
+        match dst {
+            LinuxEndpoint::V4(ref mut end) => Self::write_batch4(self.sock4.0, bufs, end, send),
+            LinuxEndpoint::V6(ref mut end) => Self::write_batch6(self.sock6.0, bufs, end, send),
+        }
+    }
+
     fn write6(fd: RawFd, buf: &[u8], dst: &mut EndpointV6) -> Result<(), io::Error> {
         log::debug!("sending IPv6 packet ({} fd, {} bytes)", fd, buf.len());
 
@@ -446,6 +947,167 @@ impl LinuxUDPWriter {
 
         Ok(())
     }
+
+    fn write_segmented6(
+        fd: RawFd,
+        gso: &AtomicBool,
+        bufs: &[&[u8]],
+        dst: &mut EndpointV6,
+    ) -> Result<usize, io::Error> {
+        let mut sent = 0;
+        if gso.load(Ordering::Relaxed) && gso_fits(bufs) {
+            let pktinfo = (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO);
+            match send_gso(fd, bufs, &mut dst.dst, &mut dst.info, pktinfo) {
+                Ok(n) => return Ok(n),
+                Err((n, err)) => {
+                    if !gso_fallback(gso, err) {
+                        return if n > 0 {
+                            Ok(n)
+                        } else {
+                            Err(io::Error::new(
+                                io::ErrorKind::NotConnected,
+                                "failed to send IPv6 packet",
+                            ))
+                        };
+                    }
+                    sent = n;
+                }
+            }
+        }
+
+        // fall back to a batch of the remaining segments
+        match Self::write_batch6(fd, &bufs[sent..], dst, sendmmsg_all) {
+            Ok(n) => Ok(sent + n),
+            Err(e) if sent == 0 => Err(e),
+            Err(_) => Ok(sent),
+        }
+    }
+
+    fn write_segmented4(
+        fd: RawFd,
+        gso: &AtomicBool,
+        bufs: &[&[u8]],
+        dst: &mut EndpointV4,
+    ) -> Result<usize, io::Error> {
+        let mut sent = 0;
+        if gso.load(Ordering::Relaxed) && gso_fits(bufs) {
+            let pktinfo = (libc::IPPROTO_IP, libc::IP_PKTINFO);
+            match send_gso(fd, bufs, &mut dst.dst, &mut dst.info, pktinfo) {
+                Ok(n) => return Ok(n),
+                Err((n, err)) => {
+                    if !gso_fallback(gso, err) {
+                        return if n > 0 {
+                            Ok(n)
+                        } else {
+                            Err(io::Error::new(
+                                io::ErrorKind::NotConnected,
+                                "failed to send IPv4 packet",
+                            ))
+                        };
+                    }
+                    sent = n;
+                }
+            }
+        }
+
+        // fall back to a batch of the remaining segments
+        match Self::write_batch4(fd, &bufs[sent..], dst, sendmmsg_all) {
+            Ok(n) => Ok(sent + n),
+            Err(e) if sent == 0 => Err(e),
+            Err(_) => Ok(sent),
+        }
+    }
+
+    fn write_batch6(
+        fd: RawFd,
+        bufs: &[&[u8]],
+        dst: &mut EndpointV6,
+        send: SendBatch,
+    ) -> Result<usize, io::Error> {
+        let mut control = ControlHeaderV6 {
+            hdr: libc::cmsghdr {
+                cmsg_len: CMSG_LEN(mem::size_of::<libc::in6_pktinfo>()),
+                cmsg_level: libc::IPPROTO_IPV6,
+                cmsg_type: libc::IPV6_PKTINFO,
+            },
+            info: dst.info,
+        };
+
+        debug_assert_eq!(
+            dst.dst.sin6_family,
+            libc::AF_INET6 as libc::sa_family_t,
+            "this method only handles IPv6 destinations"
+        );
+
+        match send_mmsg(fd, bufs, &mut dst.dst, Some(&mut control), send) {
+            Ok(sent) => Ok(sent),
+            Err(libc::EINVAL) => {
+                log::trace!("clear source and retry");
+                dst.info = unsafe { mem::zeroed() };
+                send_mmsg::<_, ControlHeaderV6>(fd, bufs, &mut dst.dst, None, send).map_err(|_| {
+                    io::Error::new(io::ErrorKind::NotConnected, "failed to send IPv6 packet")
+                })
+            }
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "failed to send IPv6 packet",
+            )),
+        }
+    }
+
+    fn write_batch4(
+        fd: RawFd,
+        bufs: &[&[u8]],
+        dst: &mut EndpointV4,
+        send: SendBatch,
+    ) -> Result<usize, io::Error> {
+        let mut control = ControlHeaderV4 {
+            hdr: libc::cmsghdr {
+                cmsg_len: CMSG_LEN(mem::size_of::<libc::in_pktinfo>()),
+                cmsg_level: libc::IPPROTO_IP,
+                cmsg_type: libc::IP_PKTINFO,
+            },
+            info: dst.info,
+        };
+
+        debug_assert_eq!(
+            dst.dst.sin_family,
+            libc::AF_INET as libc::sa_family_t,
+            "this method only handles IPv4 destinations"
+        );
+
+        match send_mmsg(fd, bufs, &mut dst.dst, Some(&mut control), send) {
+            Ok(sent) => Ok(sent),
+            Err(libc::EINVAL) => {
+                log::trace!("clear source and retry");
+                dst.info = unsafe { mem::zeroed() };
+                send_mmsg::<_, ControlHeaderV4>(fd, bufs, &mut dst.dst, None, send).map_err(|_| {
+                    io::Error::new(io::ErrorKind::NotConnected, "failed to send IPv4 packet")
+                })
+            }
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "failed to send IPv4 packet",
+            )),
+        }
+    }
+}
+
+/* Decide if the segments of a failed GSO send should be sent without GSO:
+ *
+ * - EIO: the device cannot offload the checksum, GSO is disabled for the socket.
+ * - EINVAL: the segments cannot be sent with GSO (e.g. a segment exceeds the path MTU).
+ */
+fn gso_fallback(gso: &AtomicBool, err: libc::c_int) -> bool {
+    match err {
+        libc::EIO => {
+            log::info!("UDP GSO failed (errno = {}), disabling it", err);
+            gso.store(false, Ordering::Relaxed);
+            true
+        }
+        libc::EINVAL => true,
+        _ => false,
+    }
 }
 
 impl Writer<LinuxEndpoint> for LinuxUDPWriter {
@@ -457,6 +1119,32 @@ impl Writer<LinuxEndpoint> for LinuxUDPWriter {
             LinuxEndpoint::V6(ref mut end) => Self::write6(self.sock6.0, buf, end),
         }
     }
+
+    fn write_batch(&self, bufs: &[&[u8]], dst: &mut LinuxEndpoint) -> Result<usize, Self::Error> {
+        self.write_batch_with(bufs, dst, sendmmsg_all)
+    }
+
+    fn segmentation(&self, dst: &LinuxEndpoint) -> bool {
+        match dst {
+            LinuxEndpoint::V4(_) => self.gso4.load(Ordering::Relaxed),
+            LinuxEndpoint::V6(_) => self.gso6.load(Ordering::Relaxed),
+        }
+    }
+
+    fn write_segmented(
+        &self,
+        bufs: &[&[u8]],
+        dst: &mut LinuxEndpoint,
+    ) -> Result<usize, Self::Error> {
+        match dst {
+            LinuxEndpoint::V4(ref mut end) => {
+                Self::write_segmented4(self.sock4.0, &self.gso4, bufs, end)
+            }
+            LinuxEndpoint::V6(ref mut end) => {
+                Self::write_segmented6(self.sock6.0, &self.gso6, bufs, end)
+            }
+        }
+    }
 }
 
 impl Owner for LinuxOwner {
@@ -697,18 +1385,30 @@ impl PlatformUDP for LinuxUDP {
             sock4: sock4.clone(),
         };
 
+        // detect support for segmentation offload and receive coalescing (fall back if unsupported)
+        let gso = |sock: &Option<Arc<FD>>| sock.as_ref().map(|fd| supports_gso(fd.0)) == Some(true);
+        let gro = |sock: &Arc<FD>| {
+            if enable_gro(sock.0) {
+                Some(Mutex::new(Coalesced::new()))
+            } else {
+                None
+            }
+        };
+
         // create readers
         let mut readers: Vec<Self::Reader> = Vec::with_capacity(2);
         if let Some(sock) = sock6.clone() {
-            readers.push(LinuxUDPReader::V6(sock))
+            readers.push(LinuxUDPReader::V6(sock.clone(), gro(&sock)))
         }
         if let Some(sock) = sock4.clone() {
-            readers.push(LinuxUDPReader::V4(sock))
+            readers.push(LinuxUDPReader::V4(sock.clone(), gro(&sock)))
         }
         debug_assert!(!readers.is_empty());
 
         // create writer
         let writer = LinuxUDPWriter {
+            gso4: Arc::new(AtomicBool::new(gso(&sock4))),
+            gso6: Arc::new(AtomicBool::new(gso(&sock6))),
             sock4: sock4.unwrap_or_else(|| Arc::new(FD(-1))),
             sock6: sock6.unwrap_or_else(|| Arc::new(FD(-1))),
         };
@@ -716,3 +1416,34 @@ impl PlatformUDP for LinuxUDP {
         Ok((readers, writer, owner))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segmented_oversized() {
+        let (readers, _, peer) = LinuxUDP::bind(0).unwrap();
+        let (_, writer, owner) = LinuxUDP::bind(0).unwrap();
+
+        // segments too large to segment (even two at a time) are sent as a batch
+        let addr: SocketAddr = format!("[::1]:{}", peer.get_port()).parse().unwrap();
+        let mut dst = LinuxEndpoint::from_address(addr);
+        let msgs = [vec![1u8; 65510], vec![2u8; 65510], vec![3u8; 100]];
+        let bufs: Vec<&[u8]> = msgs.iter().map(|msg| &msg[..]).collect();
+        assert_eq!(writer.write_segmented(&bufs, &mut dst).unwrap(), 3);
+
+        // readers are ordered as: IPv6, IPv4
+        let mut buf = vec![0u8; 1 << 16];
+        for msg in msgs.iter() {
+            let (len, src) = readers[0].read(&mut buf).unwrap();
+            assert_eq!(src.into_address().port(), owner.get_port());
+            assert_eq!(&buf[..len], &msg[..]);
+        }
+
+        // or fail (rather than sending empty datagrams) when exceeding the largest datagram
+        let addr: SocketAddr = format!("127.0.0.1:{}", peer.get_port()).parse().unwrap();
+        let mut dst = LinuxEndpoint::from_address(addr);
+        assert!(writer.write_segmented(&bufs[..2], &mut dst).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+/* Linux platform performing the I/O of the data path with io_uring.
+ *
+ * The TUN queues and UDP sockets are created (and configured) by the Linux platform,
+ * after which every reader keeps a number of reads in flight on its own io_uring instance:
+ * a read returns all the packets completed so far and resubmits their buffers with the next wait,
+ * hence a single system call per batch of packets.
+ * Batches of UDP messages are submitted as linked send requests (preserving their order).
+ *
+ * On kernels without the required io_uring support (before 5.6, or when disabled by policy)
+ * the readers and writers of the Linux platform are used instead.
+ */
+mod tun;
+mod udp;
+
+pub use tun::UringTun as Tun;
+pub use udp::UringUDP as UDP;
+
+use std::io;
+
+use io_uring::{opcode, IoUring, Probe};
+
+// user data of cancellation requests (distinct from any buffer index)
+const CANCEL: u64 = u64::MAX;
+
+/* Create an io_uring instance, if the kernel supports the given operations.
+ *
+ * Arguments:
+ *
+ * - 'entries', the size of the submission queue
+ * - 'opcodes', the operations to be submitted to the instance
+ */
+fn ring(entries: u32, opcodes: &[u8]) -> Option<IoUring> {
+    let ring = match IoUring::new(entries) {
+        Ok(ring) => ring,
+        Err(e) => {
+            log::debug!("io_uring unavailable: {}", e);
+            return None;
+        }
+    };
+
+    let mut probe = Probe::new();
+    if let Err(e) = ring.submitter().register_probe(&mut probe) {
+        log::debug!("io_uring unavailable, failed to probe operations: {}", e);
+        return None;
+    }
+    if !opcodes.iter().all(|op| probe.is_supported(*op)) {
+        log::debug!("io_uring unavailable, unsupported operations");
+        return None;
+    }
+    Some(ring)
+}
+
+/* Wait for at least 'want' completions (submitting any queued requests),
+ * retrying if interrupted by a signal.
+ */
+fn wait(ring: &IoUring, want: usize) -> io::Result<()> {
+    loop {
+        match ring.submit_and_wait(want) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+            Ok(_) => return Ok(()),
+        }
+    }
+}
+
+/* Cancel the requests in flight, identified by the buffer indices 0..buffers,
+ * and wait for their completion (after which the buffers can be released).
+ *
+ * Returns false if the requests could not be awaited (the buffers must then be leaked).
+ */
+fn cancel(ring: &mut IoUring, buffers: usize, mut inflight: usize) -> bool {
+    // submit queued requests (so that every request can be cancelled), followed by the cancellations
+    // (the submission queue holds an entry for every buffer)
+    if ring.submit().is_err() {
+        return false;
+    }
+    for index in 0..buffers {
+        let entry = opcode::AsyncCancel::new(index as u64)
+            .build()
+            .user_data(CANCEL);
+        if unsafe { ring.submission().push(&entry) }.is_err() {
+            return false;
+        }
+    }
+
+    // await the completion of the cancelled requests
+    while inflight > 0 {
+        if wait(ring, 1).is_err() {
+            return false;
+        }
+        for cqe in ring.completion() {
+            if cqe.user_data() != CANCEL {
+                inflight -= 1;
+            }
+        }
+    }
+    true
+}
+
+/* Errors after which a request is simply resubmitted
+ * (any other error is reported by the reader, e.g. when the device is removed).
+ */
+fn transient(err: i32) -> bool {
+    err == libc::EAGAIN || err == libc::EINTR || err == libc::ECANCELED
+}
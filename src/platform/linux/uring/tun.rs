@@ -0,0 +1,295 @@
+use super::super::super::tun::*;
+use super::super::tun::{LinuxTun, LinuxTunError, LinuxTunReader, LinuxTunStatus, LinuxTunWriter};
+use super::{cancel, ring, transient, wait};
+
+use std::cmp::min;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+// number of reads in flight (for every queue)
+const RING_ENTRIES: u32 = 16;
+
+// size of the registered buffers (any IP packet)
+const BUFFER_SIZE: usize = 1 << 16;
+
+pub struct UringTun {}
+
+pub enum UringTunReader {
+    Uring(Box<Mutex<TunRing>>),
+    Linux(LinuxTunReader), // io_uring unavailable (or packets read with a virtio-net header)
+}
+
+pub struct TunRing {
+    ring: IoUring,
+    fd: RawFd,
+    bufs: Vec<Vec<u8>>, // registered buffers (the index is the user data of the read)
+    inflight: usize,    // reads submitted (or queued) and not yet reaped
+    _reader: LinuxTunReader,
+}
+
+fn read_fixed(fd: RawFd, buf: &mut [u8], index: usize) -> squeue::Entry {
+    opcode::ReadFixed::new(
+        types::Fd(fd),
+        buf.as_mut_ptr(),
+        buf.len() as u32,
+        index as u16,
+    )
+    .offset(u64::MAX) // current position (the TUN device is not seekable)
+    .build()
+    .user_data(index as u64)
+}
+
+impl TunRing {
+    fn new(reader: LinuxTunReader) -> Result<TunRing, LinuxTunReader> {
+        let fd = match reader.plain_fd() {
+            Some(fd) => fd,
+            None => return Err(reader),
+        };
+        let mut ring = match ring(
+            RING_ENTRIES,
+            &[opcode::ReadFixed::CODE, opcode::AsyncCancel::CODE],
+        ) {
+            Some(ring) => ring,
+            None => return Err(reader),
+        };
+
+        // register the buffers
+        let mut bufs: Vec<Vec<u8>> = (0..RING_ENTRIES).map(|_| vec![0; BUFFER_SIZE]).collect();
+        let iovs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut core::ffi::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        if let Err(e) = unsafe { ring.submitter().register_buffers(&iovs) } {
+            log::debug!("io_uring, failed to register TUN buffers: {}", e);
+            return Err(reader);
+        }
+
+        // queue a read for every buffer (submitted by the first wait)
+        for (index, buf) in bufs.iter_mut().enumerate() {
+            unsafe {
+                ring.submission()
+                    .push(&read_fixed(fd, buf, index))
+                    .expect("a submission queue entry for every buffer");
+            }
+        }
+
+        Ok(TunRing {
+            ring,
+            fd,
+            inflight: bufs.len(),
+            bufs,
+            _reader: reader,
+        })
+    }
+
+    fn read(
+        &mut self,
+        bufs: &mut [&mut [u8]],
+        sizes: &mut Vec<usize>,
+        offset: usize,
+    ) -> Result<(), io::Error> {
+        let fd = self.fd;
+        let mut n = 0;
+        loop {
+            // resubmit the buffers handed out by the last read and wait for a completed read
+            wait(&self.ring, 1)?;
+
+            // hand out the completed reads (the remaining are handed out by the next read)
+            let (_, mut sq, cq) = self.ring.split();
+            for cqe in cq.take(bufs.len() - n) {
+                let index = cqe.user_data() as usize;
+                self.inflight -= 1;
+                match cqe.result() {
+                    res if res >= 0 => {
+                        let dst = &mut bufs[n][offset..];
+                        let len = min(res as usize, dst.len()); // truncate (like read)
+                        dst[..len].copy_from_slice(&self.bufs[index][..len]);
+                        sizes.push(len);
+                        n += 1;
+                    }
+                    err if transient(-err) => (),
+                    err => {
+                        log::debug!("io_uring, failed to read from TUN (errno = {})", -err);
+                        return Err(io::Error::from_raw_os_error(-err));
+                    }
+                }
+                unsafe {
+                    sq.push(&read_fixed(fd, &mut self.bufs[index], index))
+                        .expect("a submission queue entry for every buffer");
+                }
+                self.inflight += 1;
+            }
+            if n > 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Drop for TunRing {
+    fn drop(&mut self) {
+        // the kernel must not write to the buffers after they are released
+        if !cancel(&mut self.ring, self.bufs.len(), self.inflight) {
+            log::debug!("io_uring, failed to cancel TUN reads (leaking the buffers)");
+            mem::forget(mem::take(&mut self.bufs));
+        }
+    }
+}
+
+impl UringTunReader {
+    fn new(reader: LinuxTunReader) -> UringTunReader {
+        match TunRing::new(reader) {
+            Ok(ring) => UringTunReader::Uring(Box::new(Mutex::new(ring))),
+            Err(reader) => {
+                log::info!("TUN queue read without io_uring");
+                UringTunReader::Linux(reader)
+            }
+        }
+    }
+}
+
+impl Reader for UringTunReader {
+    type Error = LinuxTunError;
+
+    fn read(
+        &self,
+        bufs: &mut [&mut [u8]],
+        sizes: &mut Vec<usize>,
+        offset: usize,
+    ) -> Result<(), Self::Error> {
+        match self {
+            UringTunReader::Linux(reader) => reader.read(bufs, sizes, offset),
+            UringTunReader::Uring(ring) => ring
+                .lock()
+                .unwrap()
+                .read(bufs, sizes, offset)
+                .map_err(|_| LinuxTunError::Closed),
+        }
+    }
+}
+
+impl Tun for UringTun {
+    type Writer = LinuxTunWriter;
+    type Reader = UringTunReader;
+    type Error = LinuxTunError;
+}
+
+type Device = (Vec<UringTunReader>, LinuxTunWriter, LinuxTunStatus);
+
+// read the queues of a device created by the Linux platform with io_uring
+fn uring(
+    (readers, writer, status): (Vec<LinuxTunReader>, LinuxTunWriter, LinuxTunStatus),
+) -> Device {
+    let readers = readers.into_iter().map(UringTunReader::new).collect();
+    (readers, writer, status)
+}
+
+impl PlatformTun for UringTun {
+    type Status = LinuxTunStatus;
+
+    fn create(name: &str) -> Result<Device, Self::Error> {
+        LinuxTun::create(name).map(uring)
+    }
+
+    fn create_queues(name: &str, queues: usize) -> Result<Device, Self::Error> {
+        LinuxTun::create_queues(name, queues).map(uring)
+    }
+
+    fn create_offload(name: &str, queues: usize) -> Result<Device, Self::Error> {
+        LinuxTun::create_offload(name, queues).map(uring)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::UdpSocket;
+    use std::process::Command;
+    use std::time::Duration;
+
+    // configure the address of the device and set it up (requires CAP_NET_ADMIN)
+    fn configure(name: &str, addr: &str) -> bool {
+        let ip = |args: &[&str]| {
+            Command::new("ip")
+                .args(args)
+                .status()
+                .map_or(false, |status| status.success())
+        };
+        ip(&["addr", "add", addr, "dev", name]) && ip(&["link", "set", name, "up"])
+    }
+
+    #[test]
+    fn test_ring_read_write() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        // the test requires permission to create (and configure) a TUN device
+        let name = format!("wgring{}", std::process::id() % 10000);
+        let (readers, writer, _status) = match UringTun::create(&name) {
+            Ok(device) => device,
+            Err(e) => {
+                log::info!("skipping the test, failed to create TUN device: {}", e);
+                return;
+            }
+        };
+        if !configure(&name, "10.213.0.1/24") {
+            log::info!("skipping the test, failed to configure TUN device");
+            return;
+        }
+        if let UringTunReader::Linux(_) = readers[0] {
+            log::info!("skipping the test, io_uring unavailable");
+            return;
+        }
+
+        // a datagram routed to the device is read from the ring
+        let socket = UdpSocket::bind("10.213.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        socket.send_to(b"ping", "10.213.0.2:9").unwrap();
+        let mut store = vec![vec![0u8; 2000]; 4];
+        let mut packet = loop {
+            let mut bufs: Vec<&mut [u8]> = store.iter_mut().map(|buf| &mut buf[..]).collect();
+            let mut sizes = vec![];
+            readers[0].read(&mut bufs, &mut sizes, 16).unwrap();
+
+            // ignore other packets (e.g. IPv6 router solicitations)
+            let found = store.iter().zip(sizes).find(|(buf, size)| {
+                let packet = &buf[16..16 + size];
+                packet[0] >> 4 == 4 && packet[9] == 17 && packet.ends_with(b"ping")
+            });
+            if let Some((buf, size)) = found {
+                break buf[16..16 + size].to_vec();
+            }
+        };
+
+        // reflect the datagram (swapping the addresses and ports preserves the checksums)
+        let ihl = ((packet[0] & 0xf) as usize) * 4;
+        for (a, b) in [
+            (12, 16),
+            (13, 17),
+            (14, 18),
+            (15, 19),
+            (ihl, ihl + 2),
+            (ihl + 1, ihl + 3),
+        ]
+        .iter()
+        {
+            packet.swap(*a, *b);
+        }
+        writer.write(&packet).unwrap();
+
+        // which arrives at the socket
+        let mut buf = [0u8; 16];
+        let (len, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(src, "10.213.0.2:9".parse().unwrap());
+    }
+}
@@ -0,0 +1,445 @@
+use super::super::super::udp::*;
+use super::super::udp::{
+    sendmmsg_all, LinuxEndpoint, LinuxOwner, LinuxUDP, LinuxUDPReader, LinuxUDPWriter,
+};
+use super::{cancel, ring, transient, wait};
+
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+// number of receives in flight (for every socket)
+const RECV_RING_ENTRIES: u32 = 16;
+
+// maximum number of linked sends submitted at once
+const SEND_RING_ENTRIES: u32 = 64;
+
+// size of the receive buffers (any UDP payload, or datagram coalesced by UDP_GRO)
+const BUFFER_SIZE: usize = 1 << 16;
+
+pub struct UringUDP {}
+
+pub enum UringUDPReader {
+    Uring(Box<Mutex<UDPRing>>),
+    Linux(LinuxUDPReader), // io_uring unavailable
+}
+
+#[derive(Clone)]
+pub struct UringUDPWriter(LinuxUDPWriter);
+
+/* A receive buffer along with the message header of its recvmsg request
+ * (the header points into the slot, hence slots are never moved while in flight).
+ */
+struct Slot {
+    buf: Vec<u8>,
+    iov: libc::iovec,
+    name: libc::sockaddr_in6, // large enough for either address family
+    control: [u64; 16],
+    hdr: libc::msghdr,
+}
+
+// received datagram (the segments of a coalesced datagram are handed out one at a time)
+struct Received {
+    index: usize,
+    len: usize,
+    offset: usize,
+    segment: usize,
+    src: LinuxEndpoint,
+}
+
+pub struct UDPRing {
+    ring: IoUring,
+    slots: Vec<Slot>,          // the index is the user data of the receive
+    ready: VecDeque<Received>, // completed receives (in order of completion)
+    inflight: usize,           // receives submitted (or queued) and not yet reaped
+    reader: LinuxUDPReader,    // owns the socket (and parses the received messages)
+}
+
+// the raw pointers of the slots only point into the slots
+unsafe impl Send for UDPRing {}
+
+impl Slot {
+    fn new() -> Slot {
+        Slot {
+            buf: vec![0; BUFFER_SIZE],
+            iov: unsafe { mem::zeroed() },
+            name: unsafe { mem::zeroed() },
+            control: [0; 16],
+            hdr: unsafe { mem::zeroed() },
+        }
+    }
+
+    // prepare a recvmsg request (the slot must not be moved until it completes)
+    fn recvmsg(&mut self, fd: RawFd, index: usize) -> squeue::Entry {
+        self.iov = libc::iovec {
+            iov_base: self.buf.as_mut_ptr() as *mut core::ffi::c_void,
+            iov_len: self.buf.len(),
+        };
+        self.control = [0; 16];
+        self.hdr = libc::msghdr {
+            msg_name: &mut self.name as *mut libc::sockaddr_in6 as *mut core::ffi::c_void,
+            msg_namelen: mem::size_of_val(&self.name) as u32,
+            msg_iov: &mut self.iov as *mut libc::iovec,
+            msg_iovlen: 1,
+            msg_control: self.control.as_mut_ptr() as *mut core::ffi::c_void,
+            msg_controllen: mem::size_of_val(&self.control),
+            msg_flags: 0,
+        };
+        opcode::RecvMsg::new(types::Fd(fd), &mut self.hdr as *mut libc::msghdr)
+            .build()
+            .user_data(index as u64)
+    }
+}
+
+impl UDPRing {
+    fn new(mut ring: IoUring, reader: LinuxUDPReader) -> UDPRing {
+        let fd = reader.socket();
+
+        // queue a receive for every slot (submitted by the first wait)
+        let mut slots: Vec<Slot> = (0..RECV_RING_ENTRIES).map(|_| Slot::new()).collect();
+        for (index, slot) in slots.iter_mut().enumerate() {
+            unsafe {
+                ring.submission()
+                    .push(&slot.recvmsg(fd, index))
+                    .expect("a submission queue entry for every slot");
+            }
+        }
+
+        UDPRing {
+            ring,
+            inflight: slots.len(),
+            ready: VecDeque::with_capacity(slots.len()),
+            slots,
+            reader,
+        }
+    }
+
+    fn read_batch(
+        &mut self,
+        bufs: &mut [&mut [u8]],
+        msgs: &mut Vec<(usize, LinuxEndpoint)>,
+    ) -> Result<(), io::Error> {
+        let fd = self.reader.socket();
+        loop {
+            // hand out the segments of the received datagrams
+            let mut n = 0;
+            while n < bufs.len() {
+                let msg = match self.ready.front_mut() {
+                    Some(msg) => msg,
+                    None => break,
+                };
+                let size = min(msg.segment, msg.len - msg.offset);
+                let copy = min(size, bufs[n].len()); // truncate (like recvmsg)
+                let buf = &self.slots[msg.index].buf;
+                bufs[n][..copy].copy_from_slice(&buf[msg.offset..msg.offset + copy]);
+                msgs.push((copy, msg.src.clone()));
+                msg.offset += size;
+                n += 1;
+
+                // resubmit the slot once all the segments are handed out
+                if msg.offset >= msg.len {
+                    let index = msg.index;
+                    self.ready.pop_front();
+                    self.resubmit(fd, index);
+                }
+            }
+            if n > 0 {
+                return Ok(());
+            }
+
+            // resubmit the slots handed out by the last read and wait for a received datagram
+            wait(&self.ring, 1)?;
+            let (_, mut sq, cq) = self.ring.split();
+            for cqe in cq {
+                let index = cqe.user_data() as usize;
+                self.inflight -= 1;
+                match cqe.result() {
+                    // (an empty datagram is handed out as a zero length message,
+                    //  a zero length receive without a source means the socket has been shut down)
+                    len if len > 0 || (len == 0 && self.slots[index].hdr.msg_namelen != 0) => {
+                        let (segment, src) = self.reader.received(&self.slots[index].hdr);
+                        let len = len as usize;
+                        self.ready.push_back(Received {
+                            index,
+                            len,
+                            offset: 0,
+                            segment: if segment == 0 { len } else { segment },
+                            src,
+                        });
+                    }
+                    err if err < 0 && transient(-err) => {
+                        unsafe {
+                            sq.push(&self.slots[index].recvmsg(fd, index))
+                                .expect("a submission queue entry for every slot");
+                        }
+                        self.inflight += 1;
+                    }
+                    err => {
+                        log::debug!("io_uring, failed to receive (errno = {})", -err);
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotConnected,
+                            format!("failed to receive (ret = {}, fd = {})", err, fd),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    // queue a receive into the slot (submitted by the next wait)
+    fn resubmit(&mut self, fd: RawFd, index: usize) {
+        unsafe {
+            self.ring
+                .submission()
+                .push(&self.slots[index].recvmsg(fd, index))
+                .expect("a submission queue entry for every slot");
+        }
+        self.inflight += 1;
+    }
+}
+
+impl Drop for UDPRing {
+    fn drop(&mut self) {
+        // the kernel must not write to the slots after they are released
+        if !cancel(&mut self.ring, self.slots.len(), self.inflight) {
+            log::debug!("io_uring, failed to cancel UDP receives (leaking the buffers)");
+            mem::forget(mem::take(&mut self.slots));
+        }
+    }
+}
+
+impl UringUDPReader {
+    fn new(reader: LinuxUDPReader) -> UringUDPReader {
+        let opcodes = [opcode::RecvMsg::CODE, opcode::AsyncCancel::CODE];
+        match ring(RECV_RING_ENTRIES, &opcodes) {
+            Some(ring) => UringUDPReader::Uring(Box::new(Mutex::new(UDPRing::new(ring, reader)))),
+            None => {
+                log::info!("UDP socket read without io_uring");
+                UringUDPReader::Linux(reader)
+            }
+        }
+    }
+}
+
+impl Reader<LinuxEndpoint> for UringUDPReader {
+    type Error = io::Error;
+
+    fn read(&self, buf: &mut [u8]) -> Result<(usize, LinuxEndpoint), Self::Error> {
+        let mut msgs = Vec::with_capacity(1);
+        self.read_batch(&mut [buf], &mut msgs)?;
+        Ok(msgs.pop().unwrap())
+    }
+
+    fn read_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+        msgs: &mut Vec<(usize, LinuxEndpoint)>,
+    ) -> Result<(), Self::Error> {
+        match self {
+            UringUDPReader::Linux(reader) => reader.read_batch(bufs, msgs),
+            UringUDPReader::Uring(ring) => ring.lock().unwrap().read_batch(bufs, msgs),
+        }
+    }
+}
+
+thread_local! {
+    // io_uring instance for sending (created by the first batch sent from the thread)
+    static SEND_RING: RefCell<Option<IoUring>> =
+        RefCell::new(ring(SEND_RING_ENTRIES, &[opcode::SendMsg::CODE]));
+}
+
+/* Submit a batch of messages as linked sendmsg requests:
+ * the messages are sent in order and the first failure cancels the remaining.
+ *
+ * Returns:
+ *
+ * The number of messages sent, or the errno if none could be sent.
+ */
+fn send_linked(fd: RawFd, hdrs: &mut [libc::mmsghdr]) -> Result<usize, libc::c_int> {
+    SEND_RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.is_none() {
+            return sendmmsg_all(fd, hdrs);
+        }
+
+        let mut sent = 0;
+        let mut results = [0i32; SEND_RING_ENTRIES as usize];
+        for batch in hdrs.chunks(SEND_RING_ENTRIES as usize) {
+            log::trace!(
+                "io_uring, sending batch ({} fd, {} messages)",
+                fd,
+                batch.len()
+            );
+            if let Err(e) = submit_linked(ring.as_mut().unwrap(), fd, batch, &mut results) {
+                // release the ring (the thread sends without io_uring from now on)
+                log::debug!("io_uring, failed to await sends: {}", e);
+                *ring = None;
+                return if sent > 0 {
+                    Ok(sent)
+                } else {
+                    Err(e.raw_os_error().unwrap_or(libc::EIO))
+                };
+            }
+
+            // the sends before the first failure succeeded
+            match results[..batch.len()].iter().position(|res| *res < 0) {
+                None => sent += batch.len(),
+                Some(0) if sent == 0 => return Err(-results[0]),
+                Some(n) => return Ok(sent + n),
+            }
+        }
+        Ok(sent)
+    })
+}
+
+/* Submit the linked sendmsg requests of a batch and await their completion,
+ * storing the result of every send.
+ *
+ * The messages are referenced by the kernel until completed,
+ * hence the sends are cancelled (and awaited) if the completions cannot be awaited.
+ */
+fn submit_linked(
+    ring: &mut IoUring,
+    fd: RawFd,
+    batch: &[libc::mmsghdr],
+    results: &mut [i32],
+) -> Result<(), io::Error> {
+    for (i, hdr) in batch.iter().enumerate() {
+        let flags = if i + 1 < batch.len() {
+            squeue::Flags::IO_LINK
+        } else {
+            squeue::Flags::empty()
+        };
+        let entry = opcode::SendMsg::new(types::Fd(fd), &hdr.msg_hdr)
+            .build()
+            .flags(flags)
+            .user_data(i as u64);
+        unsafe {
+            ring.submission()
+                .push(&entry)
+                .expect("a submission queue entry for every message");
+        }
+    }
+
+    // await every send (wait retries if interrupted)
+    let mut done = 0;
+    while done < batch.len() {
+        if let Err(e) = wait(ring, batch.len() - done) {
+            if !cancel(ring, batch.len(), batch.len() - done) {
+                log::debug!("io_uring, failed to cancel sends");
+            }
+            return Err(e);
+        }
+        for cqe in ring.completion() {
+            results[cqe.user_data() as usize] = cqe.result();
+            done += 1;
+        }
+    }
+    Ok(())
+}
+
+impl Writer<LinuxEndpoint> for UringUDPWriter {
+    type Error = io::Error;
+
+    fn write(&self, buf: &[u8], dst: &mut LinuxEndpoint) -> Result<(), Self::Error> {
+        self.0.write(buf, dst)
+    }
+
+    fn write_batch(&self, bufs: &[&[u8]], dst: &mut LinuxEndpoint) -> Result<usize, Self::Error> {
+        self.0.write_batch_with(bufs, dst, send_linked)
+    }
+
+    fn segmentation(&self, dst: &LinuxEndpoint) -> bool {
+        self.0.segmentation(dst)
+    }
+
+    fn write_segmented(
+        &self,
+        bufs: &[&[u8]],
+        dst: &mut LinuxEndpoint,
+    ) -> Result<usize, Self::Error> {
+        self.0.write_segmented(bufs, dst)
+    }
+}
+
+impl UDP for UringUDP {
+    type Error = io::Error;
+    type Endpoint = LinuxEndpoint;
+    type Writer = UringUDPWriter;
+    type Reader = UringUDPReader;
+}
+
+impl PlatformUDP for UringUDP {
+    type Owner = LinuxOwner;
+
+    fn bind(port: u16) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Owner), Self::Error> {
+        let (readers, writer, owner) = LinuxUDP::bind(port)?;
+        let readers = readers.into_iter().map(UringUDPReader::new).collect();
+        Ok((readers, UringUDPWriter(writer), owner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::super::Endpoint;
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_batch_loopback() {
+        let (_, writer, owner) = UringUDP::bind(0).unwrap();
+        let (readers, _, peer) = UringUDP::bind(0).unwrap();
+
+        // readers are ordered as: IPv6, IPv4
+        for (ip, reader) in ["[::1]", "127.0.0.1"].iter().zip(readers.iter()) {
+            let addr: SocketAddr = format!("{}:{}", ip, peer.get_port()).parse().unwrap();
+            let mut dst = LinuxEndpoint::from_address(addr);
+
+            // more messages than submitted at once
+            let msgs: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; 100 + i]).collect();
+            let bufs: Vec<&[u8]> = msgs.iter().map(|msg| &msg[..]).collect();
+            assert_eq!(writer.write_batch(&bufs, &mut dst).unwrap(), msgs.len());
+
+            // messages are received in order, from the writer
+            let mut store = vec![vec![0u8; 1024]; 8];
+            let mut received = vec![];
+            while received.len() < msgs.len() {
+                let mut slices: Vec<&mut [u8]> = store.iter_mut().map(|buf| &mut buf[..]).collect();
+                let mut sizes = vec![];
+                reader.read_batch(&mut slices, &mut sizes).unwrap();
+                for (buf, (size, src)) in store.iter().zip(sizes) {
+                    assert_eq!(src.into_address().port(), owner.get_port());
+                    received.push(buf[..size].to_vec());
+                }
+            }
+            assert_eq!(received, msgs);
+        }
+    }
+
+    #[test]
+    fn test_empty_datagram() {
+        let (_, writer, owner) = UringUDP::bind(0).unwrap();
+        let (readers, _, peer) = UringUDP::bind(0).unwrap();
+
+        // an empty datagram is read as a zero length message
+        let addr: SocketAddr = format!("127.0.0.1:{}", peer.get_port()).parse().unwrap();
+        let mut dst = LinuxEndpoint::from_address(addr);
+        writer.write(&[], &mut dst).unwrap();
+        writer.write(&[1, 2, 3], &mut dst).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, src) = readers[1].read(&mut buf).unwrap();
+        assert_eq!((len, src.into_address().port()), (0, owner.get_port()));
+        assert_eq!(readers[1].read(&mut buf).unwrap().0, 3);
+
+        // reads fail once the socket is closed
+        drop(peer);
+        assert!(readers[1].read(&mut buf).is_err());
+    }
+}
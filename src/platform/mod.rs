@@ -1,3 +1,4 @@
+pub(crate) mod checksum;
 mod endpoint;
 
 pub mod tun;
@@ -9,7 +10,7 @@ pub use endpoint::Endpoint;
 #[cfg(target_os = "linux")]
 pub mod linux;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "fuzzing", feature = "benchmarking"))]
 pub mod dummy;
 
 #[cfg(target_os = "linux")]
@@ -31,21 +31,31 @@ pub trait Writer: Send + Sync + 'static {
 pub trait Reader: Send + 'static {
     type Error: Error;
 
-    /// Reads an IP packet into dst[offset:] from the tunnel device
+    /// Reads IP packets into bufs[i][offset:] from the tunnel device
     ///
     /// The reason for providing space for a prefix
     /// is to efficiently accommodate platforms on which the packet is prefaced by a header.
     /// This space is later used to construct the transport message inplace.
     ///
+    /// A single read may produce several IP packets (at most one per buffer),
+    /// e.g. on platforms segmenting super-packets offloaded by the kernel.
+    ///
     /// # Arguments
     ///
-    /// - buf: Destination buffer (enough space for MTU bytes + header)
-    /// - offset: Offset for the beginning of the IP packet
+    /// - bufs: Destination buffers (each with enough space for MTU bytes + header)
+    /// - sizes: The size of every IP packet read (ignoring the header) is pushed to this vector,
+    ///   the i'th packet is read into bufs[i]
+    /// - offset: Offset for the beginning of the IP packets
     ///
     /// # Returns
     ///
-    /// The size of the IP packet (ignoring the header) or an std::error::Error instance:
-    fn read(&self, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error>;
+    /// Unit type (after reading at least one IP packet) or an std::error::Error instance:
+    fn read(
+        &self,
+        bufs: &mut [&mut [u8]],
+        sizes: &mut Vec<usize>,
+        offset: usize,
+    ) -> Result<(), Self::Error>;
 }
 
 pub trait Tun: Send + Sync + 'static {
@@ -54,9 +64,33 @@ pub trait Tun: Send + Sync + 'static {
     type Error: Error;
 }
 
+// The readers (one per queue), the writer and the status of a created TUN device
+type Device<T> = (
+    Vec<<T as Tun>::Reader>,
+    <T as Tun>::Writer,
+    <T as PlatformTun>::Status,
+);
+
 /// On some platforms the application can create the TUN device itself.
 pub trait PlatformTun: Tun {
     type Status: Status;
 
     fn create(name: &str) -> Result<(Vec<Self::Reader>, Self::Writer, Self::Status), Self::Error>;
+
+    /// Create the TUN device with the given number of queues (one reader per queue)
+    ///
+    /// Platforms without multi-queue support create a single queue.
+    fn create_queues(name: &str, queues: usize) -> Result<Device<Self>, Self::Error> {
+        let _ = queues;
+        Self::create(name)
+    }
+
+    /// Create the TUN device with segmentation and checksum offload
+    /// (the readers may produce several IP packets per read),
+    /// with the given number of queues.
+    ///
+    /// Platforms without offload support create the device without.
+    fn create_offload(name: &str, queues: usize) -> Result<Device<Self>, Self::Error> {
+        Self::create_queues(name, queues)
+    }
 }
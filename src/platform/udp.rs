@@ -5,12 +5,57 @@ pub trait Reader<E: Endpoint>: Send + Sync {
     type Error: Error;
 
     fn read(&self, buf: &mut [u8]) -> Result<(usize, E), Self::Error>;
+
+    /// Read a batch of messages (at most one into every buffer), blocking until at least one is received.
+    ///
+    /// The length and source of the i'th message (read into `bufs[i]`) is pushed to `msgs`.
+    /// Platforms without batched I/O read a single message.
+    fn read_batch(
+        &self,
+        bufs: &mut [&mut [u8]],
+        msgs: &mut Vec<(usize, E)>,
+    ) -> Result<(), Self::Error> {
+        if let Some(buf) = bufs.first_mut() {
+            msgs.push(self.read(buf)?);
+        }
+        Ok(())
+    }
 }
 
 pub trait Writer<E: Endpoint>: Send + Sync + 'static {
     type Error: Error;
 
     fn write(&self, buf: &[u8], dst: &mut E) -> Result<(), Self::Error>;
+
+    /// Write a batch of messages to the same destination (in-order).
+    ///
+    /// Returns the number of messages written (a prefix of `bufs`),
+    /// fails only if the first message could not be written.
+    /// Platforms without batched I/O write the messages one at a time.
+    fn write_batch(&self, bufs: &[&[u8]], dst: &mut E) -> Result<usize, Self::Error> {
+        for (i, buf) in bufs.iter().enumerate() {
+            if let Err(e) = self.write(buf, dst) {
+                return if i == 0 { Err(e) } else { Ok(i) };
+            }
+        }
+        Ok(bufs.len())
+    }
+
+    /// Returns true if messages to the destination can be written with segmentation offload
+    /// (making write_segmented cheaper than write_batch).
+    fn segmentation(&self, _dst: &E) -> bool {
+        false
+    }
+
+    /// Write consecutive messages to the same destination (in-order),
+    /// every message has the size of the first (except the last, which may be shorter).
+    ///
+    /// Returns the number of messages written,
+    /// fails only if the first message could not be written.
+    /// Platforms without segmentation offload write the messages as a batch.
+    fn write_segmented(&self, bufs: &[&[u8]], dst: &mut E) -> Result<usize, Self::Error> {
+        self.write_batch(bufs, dst)
+    }
 }
 
 pub trait UDP: Send + Sync + 'static {
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use spin::Mutex;
+
+/* The source of time used by the device:
+ * by the timers (key lifetimes, rate limiting and cookies) and handshake timestamps.
+ *
+ * Enables tests to replace the system clock with a virtual clock,
+ * in order to make the behavior of the device reproducible.
+ */
+pub trait Clock: Send + Sync {
+    /// Return the current monotonic time
+    fn now(&self) -> Instant;
+
+    /// Return the current wall-clock time
+    /// (used for handshake timestamps and reporting the time of the last handshake)
+    fn system_time(&self) -> SystemTime;
+}
+
+/// The clock of the operating system
+pub struct SystemClock();
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock which only moves when advanced (e.g. by a test)
+pub struct VirtualClock {
+    start: Instant,
+    walltime: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    /// Create a new virtual clock
+    ///
+    /// # Arguments
+    ///
+    /// - `walltime`: The wall-clock time at the creation of the clock
+    pub fn new(walltime: SystemTime) -> VirtualClock {
+        VirtualClock {
+            start: Instant::now(),
+            walltime,
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Move the clock forward
+    ///
+    /// # Arguments
+    ///
+    /// - `duration`: The time to add to the clock
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.walltime + *self.elapsed.lock()
+    }
+}
@@ -23,11 +23,39 @@ pub const MAX_QUEUED_INCOMING_HANDSHAKES: usize = 4096;
 // the device is considered under load and DoS mitigation is triggered.
 pub const THRESHOLD_UNDER_LOAD: usize = MAX_QUEUED_INCOMING_HANDSHAKES / 8;
 
+// Performance:
+// Capacity of the queue between the UDP readers and the handshake workers.
+pub const HANDSHAKE_QUEUE_SIZE: usize = 128;
+
+// Performance:
+// Number of handshake message buffers retained for reuse,
+// enough to fill the handshake queue without allocating.
+pub const HANDSHAKE_BUFFER_POOL_SIZE: usize = 2 * HANDSHAKE_QUEUE_SIZE;
+
+// Performance:
+// Maximum number of messages read from a UDP socket at once
+// (on platforms supporting batched I/O).
+pub const UDP_BATCH_SIZE: usize = 32;
+
+// Performance:
+// Maximum number of IP packets read from a TUN queue at once
+// (on platforms segmenting super-packets offloaded by the kernel).
+pub const TUN_BATCH_SIZE: usize = 64;
+
 // Semantics:
 // When a device is detected to go under load,
 // it will remain under load for at least the following duration.
 pub const DURATION_UNDER_LOAD: Duration = Duration::from_secs(1);
 
+// Semantics:
+// Interval between scans for idle (on-demand provisioned) peers to evict
+pub const PROVISIONING_EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+
+// Semantics:
+// Size of the largest IP packet read from the TUN device
+// (the read buffer is sized for this while the device is down, since the MTU is unknown)
+pub const MAX_IP_PACKET_SIZE: usize = 1 << 16;
+
 // Semantics:
 // The payload of transport messages are padded to this multiple
 pub const MESSAGE_PADDING_MULTIPLE: usize = 16;
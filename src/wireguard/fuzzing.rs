@@ -0,0 +1,41 @@
+/* Entry points for the fuzzing harnesses (see fuzz/).
+ *
+ * The inputs of the harnesses are structure-aware:
+ * rather than having the fuzzer produce raw messages,
+ * it controls the keys and randomness used to create valid messages,
+ * which are subsequently mutated.
+ * This enables the fuzzer to explore the code beyond the MAC/AEAD checks.
+ *
+ * Every input type can be encoded to bytes,
+ * which is used to generate the seed corpora from the unit tests:
+ *
+ * WIREGUARD_FUZZ_CORPUS=fuzz/corpus cargo test --features fuzzing
+ */
+
+pub use super::handshake::fuzzing as handshake;
+pub use super::router::fuzzing as router;
+
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
+use std::path::Path;
+
+/// Write seeds to the corpus of a fuzzing target
+/// (if the WIREGUARD_FUZZ_CORPUS environment variable is set)
+///
+/// # Arguments
+///
+/// - `target`: Name of the fuzzing target
+/// - `seeds`: Encoded inputs for the target
+#[cfg(test)]
+pub fn write_corpus(target: &str, seeds: &[Vec<u8>]) {
+    let dir = match std::env::var_os("WIREGUARD_FUZZ_CORPUS") {
+        Some(dir) => Path::new(&dir).join(target),
+        None => return,
+    };
+    fs::create_dir_all(&dir).unwrap();
+    for seed in seeds {
+        let name = hex::encode(&ring::digest::digest(&ring::digest::SHA256, seed).as_ref()[..8]);
+        fs::write(dir.join(name), seed).unwrap();
+    }
+}
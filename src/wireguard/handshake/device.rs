@@ -1,7 +1,9 @@
 use std::collections::hash_map;
 use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use byteorder::{ByteOrder, LittleEndian};
 use dashmap::mapref::entry::Entry;
@@ -16,22 +18,96 @@ use clear_on_drop::clear::Clear;
 use x25519_dalek::PublicKey;
 use x25519_dalek::StaticSecret;
 
+use super::super::clock::{Clock, SystemClock};
+use super::super::provision::{PeerResolver, ProvisionedPeer};
+use super::keylog::KeyLog;
 use super::macs;
 use super::messages::{CookieReply, Initiation, Response};
 use super::messages::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
-use super::noise;
+use super::noise::{self, TemporaryState};
 use super::peer::Peer;
 use super::ratelimiter::RateLimiter;
+use super::timestamp::{TimestampStore, TAI64N};
 use super::types::*;
 
 const MAX_PEER_PER_DEVICE: usize = 1 << 20;
 
+// Copy a handshake message into the caller supplied buffer
+#[inline(always)]
+fn write_reply(reply: &mut [u8], msg: &[u8]) -> usize {
+    reply[..msg.len()].copy_from_slice(msg);
+    msg.len()
+}
+
+/// Policy consulted before responding to an authenticated initiation,
+/// e.g. to reject known peers connecting from blocked address ranges.
+pub trait Admission: Send + Sync {
+    /// Decide if a handshake response should be created for the initiator
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the (authenticated) initiator
+    /// * `src` - The source address of the initiation
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the handshake should proceed
+    fn admit(&self, pk: &PublicKey, src: &SocketAddr) -> bool;
+}
+
+impl<F: Fn(&PublicKey, &SocketAddr) -> bool + Send + Sync> Admission for F {
+    fn admit(&self, pk: &PublicKey, src: &SocketAddr) -> bool {
+        self(pk, src)
+    }
+}
+
 pub struct KeyState {
     pub(super) sk: StaticSecret, // static secret key
     pub(super) pk: PublicKey,    // static public key
     macs: macs::Validator,       // validator for the mac fields
 }
 
+/// An authenticated initiation from a public key not in the device,
+/// for which the resolver returned a configuration.
+///
+/// Holds the state of the consumed initiation,
+/// such that the response can be created (once the peer has been added)
+/// without consuming the initiation again.
+pub struct Provisional {
+    pub(super) pk: PublicKey,
+    pub(super) config: ProvisionedPeer,
+    pub(super) device: PublicKey, // public key of the device when consumed
+    pub(super) ss: [u8; 32],      // DH(static, static)
+    pub(super) ts: TAI64N,
+    pub(super) state: TemporaryState,
+}
+
+impl Provisional {
+    /// The public key of the initiator
+    pub fn public_key(&self) -> &PublicKey {
+        &self.pk
+    }
+
+    /// The configuration returned by the resolver
+    pub fn config(&self) -> &ProvisionedPeer {
+        &self.config
+    }
+}
+
+impl Drop for Provisional {
+    fn drop(&mut self) {
+        self.ss.clear();
+        self.state.2.clear();
+        self.state.3.clear();
+    }
+}
+
+impl fmt::Debug for Provisional {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Provisional(pk = {})", hex::encode(self.pk.as_bytes()))
+    }
+}
+
 /// The device is generic over an "opaque" type
 /// which can be used to associate the public key with this value.
 /// (the instance is a Peer object in the parent module)
@@ -40,6 +116,13 @@ pub struct Device<O> {
     id_map: DashMap<u32, [u8; 32]>, // concurrent map
     pk_map: HashMap<[u8; 32], Peer<O>>,
     limiter: Mutex<RateLimiter>,
+    resolutions: Mutex<RateLimiter>, // limits resolver calls (per source address)
+    pub(super) clock: Arc<dyn Clock>,
+    pub(super) keylog: KeyLog,
+    resolver: Option<Arc<dyn PeerResolver>>,
+    admission: Option<Arc<dyn Admission>>,
+    admission_rejected: AtomicU64, // number of initiations rejected by the admission policy
+    timestamps: Option<Arc<dyn TimestampStore>>,
 }
 
 pub struct Iter<'a, O> {
@@ -95,12 +178,132 @@ impl<O> Device<O> {
 impl<O> Device<O> {
     /// Initialize a new handshake state machine
     pub fn new() -> Device<O> {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock());
         Device {
             keyst: None,
             id_map: DashMap::new(),
             pk_map: HashMap::new(),
-            limiter: Mutex::new(RateLimiter::new()),
+            limiter: Mutex::new(RateLimiter::new(clock.clone())),
+            resolutions: Mutex::new(RateLimiter::new(clock.clone())),
+            clock,
+            keylog: KeyLog::new(),
+            resolver: None,
+            admission: None,
+            admission_rejected: AtomicU64::new(0),
+            timestamps: None,
+        }
+    }
+
+    /// Set the source of time for the handshake
+    /// (cookies, rate limiting, flood protection and timestamps)
+    ///
+    /// # Arguments
+    ///
+    /// * `clock` - The clock used by the device
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.limiter = Mutex::new(RateLimiter::new(clock.clone()));
+        self.resolutions = Mutex::new(RateLimiter::new(clock.clone()));
+        if let Some(keyst) = self.keyst.as_mut() {
+            keyst.macs = macs::Validator::new(keyst.pk, clock.now());
+        }
+        self.clock = clock;
+    }
+
+    /// Set (or remove) the persistent storage for initiation timestamps
+    ///
+    /// The stored timestamps of peers already in the device are restored immediately,
+    /// peers added later are restored when added.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Storage for the greatest accepted timestamp of every peer,
+    ///   None keeps the timestamps in memory only
+    pub fn set_timestamp_store(&mut self, store: Option<Arc<dyn TimestampStore>>) {
+        if let Some(store) = store.as_ref() {
+            for (pk, peer) in self.pk_map.iter() {
+                peer.restore_timestamp(store.load(&PublicKey::from(*pk)));
+            }
+        }
+        self.timestamps = store;
+    }
+
+    // Internal function
+    //
+    // Persist the timestamp of an accepted initiation (if storage is configured)
+    pub(super) fn store_timestamp(&self, pk: &PublicKey, timestamp: &TAI64N) {
+        if let Some(store) = self.timestamps.as_ref() {
+            store.store(pk, timestamp);
+        }
+    }
+
+    /// Set (or remove) the admission policy
+    ///
+    /// # Arguments
+    ///
+    /// * `admission` - Policy consulted for every authenticated initiation,
+    ///   None admits every initiation
+    pub fn set_admission(&mut self, admission: Option<Arc<dyn Admission>>) {
+        self.admission = admission;
+    }
+
+    /// Return the number of initiations rejected by the admission policy
+    pub fn admission_rejections(&self) -> u64 {
+        self.admission_rejected.load(Ordering::Relaxed)
+    }
+
+    /// Set (or remove) the resolver used to provision unknown initiators
+    ///
+    /// The resolver is consulted (at a limited rate per source address)
+    /// for authenticated initiations from public keys not in the device.
+    /// If it returns a configuration, the initiation fails with HandshakeError::UnknownInitiator
+    /// (carrying the consumed initiation), allowing the caller to add the peer
+    /// and respond using Device::complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - The resolver, None ignores unknown initiators
+    pub fn set_resolver(&mut self, resolver: Option<Arc<dyn PeerResolver>>) {
+        self.resolver = resolver;
+    }
+
+    // Internal function
+    //
+    // Resolve the configuration of an (authenticated) initiator not in the device
+    pub(super) fn resolve(
+        &self,
+        pk: &PublicKey,
+        src: &SocketAddr,
+    ) -> Result<ProvisionedPeer, HandshakeError> {
+        let resolver = self
+            .resolver
+            .as_ref()
+            .ok_or(HandshakeError::UnknownPublicKey)?;
+
+        // limit the rate of resolutions (and the cost of forged initiators)
+        if !self.resolutions.lock().unwrap().allow(&src.ip()) {
+            return Err(HandshakeError::RateLimited);
+        }
+
+        // apply admission policy before resolving
+        if let Some(admission) = self.admission.as_ref() {
+            if !admission.admit(pk, src) {
+                self.admission_rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(HandshakeError::AdmissionDenied);
+            }
         }
+
+        resolver.resolve(pk).ok_or(HandshakeError::UnknownPublicKey)
+    }
+
+    /// Set the sink for session key logging (debugging only)
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - Writer receiving the secrets of every completed handshake,
+    ///   None disables key logging
+    #[cfg(feature = "keylog")]
+    pub fn set_keylog(&self, sink: Option<Box<dyn std::io::Write + Send>>) {
+        self.keylog.set(sink)
     }
 
     fn update_ss(&mut self) -> (Vec<u32>, Option<PublicKey>) {
@@ -135,7 +338,7 @@ impl<O> Device<O> {
         // update secret and public key
         self.keyst = sk.map(|sk| {
             let pk = PublicKey::from(&sk);
-            let macs = macs::Validator::new(pk);
+            let macs = macs::Validator::new(pk, self.clock.now());
             KeyState { pk, sk, macs }
         });
 
@@ -184,19 +387,65 @@ impl<O> Device<O> {
             }
         }
 
-        // pre-compute shared secret and add to pk_map
-        self.pk_map.insert(
-            *pk.as_bytes(),
-            Peer::new(
-                pk,
-                self.keyst
-                    .as_ref()
-                    .map(|key| *key.sk.diffie_hellman(&pk).as_bytes())
-                    .unwrap_or([0u8; 32]),
-                opaque,
-            ),
+        // pre-compute shared secret
+        let peer = Peer::new(
+            pk,
+            self.keyst
+                .as_ref()
+                .map(|key| *key.sk.diffie_hellman(&pk).as_bytes())
+                .unwrap_or([0u8; 32]),
+            opaque,
         );
 
+        // restore the greatest timestamp accepted (before a restart)
+        if let Some(store) = self.timestamps.as_ref() {
+            peer.restore_timestamp(store.load(&pk));
+        }
+
+        // add to pk_map
+        self.pk_map.insert(*pk.as_bytes(), peer);
+
+        Ok(())
+    }
+
+    /// Add the initiator of a consumed initiation (see Device::set_resolver)
+    ///
+    /// Unlike Device::add, the shared secret computed when consuming the initiation is reused
+    /// and the pre-shared key is set from the configuration returned by the resolver.
+    ///
+    /// # Arguments
+    ///
+    /// * `provisional` - The consumed initiation
+    /// * `opaque` - Associated identifier which can be used to distinguish the peers
+    pub fn add_provisional(
+        &mut self,
+        provisional: &Provisional,
+        opaque: O,
+    ) -> Result<(), ConfigError> {
+        // ensure less than 2^20 peers
+        if self.pk_map.len() > MAX_PEER_PER_DEVICE {
+            return Err(ConfigError::new("Too many peers for device"));
+        }
+
+        // the shared secret is only valid for the key it was computed with
+        match self.keyst.as_ref() {
+            Some(key) if key.pk.as_bytes() == provisional.device.as_bytes() => (),
+            _ => {
+                return Err(ConfigError::new(
+                    "Key of device changed since the initiation",
+                ))
+            }
+        }
+
+        let mut peer = Peer::new(provisional.pk, provisional.ss, opaque);
+        peer.psk = provisional.config.preshared_key;
+
+        // restore the greatest timestamp accepted (before the peer was evicted)
+        if let Some(store) = self.timestamps.as_ref() {
+            peer.restore_timestamp(store.load(&provisional.pk));
+        }
+
+        self.pk_map.insert(*provisional.pk.as_bytes(), peer);
         Ok(())
     }
 
@@ -260,6 +509,20 @@ impl<O> Device<O> {
         }
     }
 
+    /// Return the counters of dropped handshake messages for the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer
+    ///
+    /// The call might fail if the public key is not found
+    pub fn get_counters(&self, pk: &PublicKey) -> Result<HandshakeCounters, ConfigError> {
+        match self.pk_map.get(pk.as_bytes()) {
+            Some(peer) => Ok(peer.counters()),
+            _ => Err(ConfigError::new("No such public key")),
+        }
+    }
+
     /// Release an id back to the pool
     ///
     /// # Arguments
@@ -275,11 +538,18 @@ impl<O> Device<O> {
     /// # Arguments
     ///
     /// * `pk` - Public key of peer to initiate handshake for
+    /// * `reply` - Buffer to write the initiation message into,
+    ///   must be at least MAX_HANDSHAKE_MSG_SIZE bytes
+    ///
+    /// # Returns
+    ///
+    /// The length of the initiation message written to the buffer
     pub fn begin<R: RngCore + CryptoRng>(
         &self,
         rng: &mut R,
         pk: &PublicKey,
-    ) -> Result<Vec<u8>, HandshakeError> {
+        reply: &mut [u8],
+    ) -> Result<usize, HandshakeError> {
         match (self.keyst.as_ref(), self.pk_map.get(pk.as_bytes())) {
             (_, None) => Err(HandshakeError::UnknownPublicKey),
             (None, _) => Err(HandshakeError::UnknownPublicKey),
@@ -288,14 +558,14 @@ impl<O> Device<O> {
                 let mut msg = Initiation::default();
 
                 // create noise part of initation
-                noise::create_initiation(rng, keyst, peer, pk, local, &mut msg.noise)?;
+                noise::create_initiation(rng, self, keyst, peer, pk, local, &mut msg.noise)?;
 
                 // add macs to initation
                 peer.macs
                     .lock()
-                    .generate(msg.noise.as_bytes(), &mut msg.macs);
+                    .generate(msg.noise.as_bytes(), &mut msg.macs, self.clock.now());
 
-                Ok(msg.as_bytes().to_owned())
+                Ok(write_reply(reply, msg.as_bytes()))
             }
         }
     }
@@ -305,11 +575,22 @@ impl<O> Device<O> {
     /// # Arguments
     ///
     /// * `msg` - Byte slice containing the message (untrusted input)
+    /// * `src` - Source address of the message
+    /// * `under_load` - Validate the source address (using cookies) and apply rate limiting
+    /// * `reply` - Buffer to write any reply into,
+    ///   must be at least MAX_HANDSHAKE_MSG_SIZE bytes
+    ///
+    /// # Returns
+    ///
+    /// The opaque value of the peer, the length of the reply written
+    /// to the reply buffer (if any) and the derived key-pair (if any)
     pub fn process<'a, R: RngCore + CryptoRng>(
         &'a self,
-        rng: &mut R,             // rng instance to sample randomness from
-        msg: &[u8],              // message buffer
-        src: Option<SocketAddr>, // optional source endpoint, set when "under load"
+        rng: &mut R,      // rng instance to sample randomness from
+        msg: &[u8],       // message buffer
+        src: SocketAddr,  // source endpoint
+        under_load: bool, // is the device "under load"
+        reply: &mut [u8], // buffer for the reply
     ) -> Result<Output<'a, O>, HandshakeError> {
         // ensure type read in-range
         if msg.len() < 4 {
@@ -335,18 +616,23 @@ impl<O> Device<O> {
                 keyst.macs.check_mac1(msg.noise.as_bytes(), &msg.macs)?;
 
                 // address validation & DoS mitigation
-                if let Some(src) = src {
+                if under_load {
                     // check mac2 field
-                    if !keyst.macs.check_mac2(msg.noise.as_bytes(), &src, &msg.macs) {
-                        let mut reply = Default::default();
+                    let now = self.clock.now();
+                    if !keyst
+                        .macs
+                        .check_mac2(msg.noise.as_bytes(), &src, &msg.macs, now)
+                    {
+                        let mut cookie = CookieReply::default();
                         keyst.macs.create_cookie_reply(
                             rng,
                             msg.noise.f_sender.get(),
                             &src,
                             &msg.macs,
-                            &mut reply,
+                            &mut cookie,
+                            now,
                         );
-                        return Ok((None, Some(reply.as_bytes().to_owned()), None));
+                        return Ok((None, Some(write_reply(reply, cookie.as_bytes())), None));
                     }
 
                     // check ratelimiter
@@ -356,32 +642,27 @@ impl<O> Device<O> {
                 }
 
                 // consume the initiation
-                let (peer, pk, st) = noise::consume_initiation(self, keyst, &msg.noise)?;
-
-                // allocate new index for response
-                let local = self.allocate(rng, &pk);
-
-                // prepare memory for response, TODO: take slice for zero allocation
-                let mut resp = Response::default();
+                let (peer, pk, ts, st) = noise::consume_initiation(self, keyst, &msg.noise, &src)?;
+
+                // apply admission policy
+                // (rejected initiations leave the timestamp and state of the peer unchanged)
+                if let Some(admission) = self.admission.as_ref() {
+                    if !admission.admit(&pk, &src) {
+                        self.admission_rejected.fetch_add(1, Ordering::Relaxed);
+                        peer.admission_drops.fetch_add(1, Ordering::Relaxed);
+                        return Err(HandshakeError::AdmissionDenied);
+                    }
+                }
 
-                // create response (release id on error)
-                let keys = noise::create_response(rng, peer, &pk, local, st, &mut resp.noise)
-                    .map_err(|e| {
-                        self.release(local);
-                        e
-                    })?;
+                // update timestamp (and reset the state of the peer)
+                peer.update_replay_flood(self, &ts)?;
 
-                // add macs to response
-                peer.macs
-                    .lock()
-                    .generate(resp.noise.as_bytes(), &mut resp.macs);
+                // create response
+                let output = self.respond(rng, peer, &pk, st, reply)?;
 
-                // return unconfirmed keypair and the response as vector
-                Ok((
-                    Some(&peer.opaque),
-                    Some(resp.as_bytes().to_owned()),
-                    Some(keys),
-                ))
+                // persist the timestamp of the accepted initiation
+                self.store_timestamp(&pk, &ts);
+                Ok(output)
             }
             TYPE_RESPONSE => {
                 let msg = Response::parse(msg)?;
@@ -390,18 +671,23 @@ impl<O> Device<O> {
                 keyst.macs.check_mac1(msg.noise.as_bytes(), &msg.macs)?;
 
                 // address validation & DoS mitigation
-                if let Some(src) = src {
+                if under_load {
                     // check mac2 field
-                    if !keyst.macs.check_mac2(msg.noise.as_bytes(), &src, &msg.macs) {
-                        let mut reply = Default::default();
+                    let now = self.clock.now();
+                    if !keyst
+                        .macs
+                        .check_mac2(msg.noise.as_bytes(), &src, &msg.macs, now)
+                    {
+                        let mut cookie = CookieReply::default();
                         keyst.macs.create_cookie_reply(
                             rng,
                             msg.noise.f_sender.get(),
                             &src,
                             &msg.macs,
-                            &mut reply,
+                            &mut cookie,
+                            now,
                         );
-                        return Ok((None, Some(reply.as_bytes().to_owned()), None));
+                        return Ok((None, Some(write_reply(reply, cookie.as_bytes())), None));
                     }
 
                     // check ratelimiter
@@ -411,7 +697,12 @@ impl<O> Device<O> {
                 }
 
                 // consume inner playload
-                noise::consume_response(self, keyst, &msg.noise)
+                noise::consume_response(self, keyst, &msg.noise).inspect_err(|_| {
+                    // attribute the failure to the peer (if the receiver id is allocated)
+                    if let Ok((peer, _)) = self.lookup_id(msg.noise.f_receiver.get()) {
+                        peer.response_drops.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
             }
             TYPE_COOKIE_REPLY => {
                 let msg = CookieReply::parse(msg)?;
@@ -420,7 +711,7 @@ impl<O> Device<O> {
                 let (peer, _) = self.lookup_id(msg.f_receiver.get())?;
 
                 // validate cookie reply
-                peer.macs.lock().process(&msg)?;
+                peer.macs.lock().process(&msg, self.clock.now())?;
 
                 // this prompts no new message and
                 // DOES NOT cryptographically verify the peer
@@ -456,6 +747,75 @@ impl<O> Device<O> {
         }
     }
 
+    /// Respond to an initiation which failed with HandshakeError::UnknownInitiator,
+    /// after the initiator has been added (see Device::add_provisional).
+    ///
+    /// # Arguments
+    ///
+    /// * `rng` - Random number generator
+    /// * `provisional` - The consumed initiation
+    /// * `reply` - Buffer for the handshake response
+    ///
+    /// # Returns
+    ///
+    /// The same output as Device::process for the initiation
+    pub fn complete<'a, R: RngCore + CryptoRng>(
+        &'a self,
+        rng: &mut R,
+        provisional: &Provisional,
+        reply: &mut [u8],
+    ) -> Result<Output<'a, O>, HandshakeError> {
+        match self.keyst.as_ref() {
+            Some(key) if key.pk.as_bytes() == provisional.device.as_bytes() => (),
+            _ => return Err(HandshakeError::InvalidState),
+        };
+        let peer = self.lookup_pk(&provisional.pk)?;
+
+        // check and update timestamp
+        peer.update_replay_flood(self, &provisional.ts)?;
+
+        // create response
+        let output = self.respond(rng, peer, &provisional.pk, provisional.state, reply)?;
+
+        // persist the timestamp of the accepted initiation
+        self.store_timestamp(&provisional.pk, &provisional.ts);
+        Ok(output)
+    }
+
+    // Internal function
+    //
+    // Create the response to a consumed initiation
+    fn respond<'a, R: RngCore + CryptoRng>(
+        &'a self,
+        rng: &mut R,
+        peer: &'a Peer<O>,
+        pk: &PublicKey,
+        st: TemporaryState,
+        reply: &mut [u8],
+    ) -> Result<Output<'a, O>, HandshakeError> {
+        // allocate new index for response
+        let local = self.allocate(rng, pk);
+
+        // prepare memory for response
+        let mut resp = Response::default();
+
+        // create response (release id on error)
+        let keys = noise::create_response(rng, self, peer, pk, local, st, &mut resp.noise)
+            .inspect_err(|_| self.release(local))?;
+
+        // add macs to response
+        peer.macs
+            .lock()
+            .generate(resp.noise.as_bytes(), &mut resp.macs, self.clock.now());
+
+        // return unconfirmed keypair and the length of the response
+        Ok((
+            Some(&peer.opaque),
+            Some(write_reply(reply, resp.as_bytes())),
+            Some(keys),
+        ))
+    }
+
     // Internal function
     //
     // Allocated a new receiver identifier for the peer.
@@ -0,0 +1,249 @@
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use zerocopy::AsBytes;
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::super::clock::{Clock, VirtualClock};
+use super::device::Device;
+use super::macs;
+use super::messages::{CookieReply, Initiation, MacsFooter, Response, MAX_HANDSHAKE_MSG_SIZE};
+
+/// The kind of message processed by the device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Initiation,  // initiation from the peer
+    Response,    // response from the peer to an initiation from the device
+    CookieReply, // cookie reply from the peer to an initiation from the device
+    Raw,         // message provided by the fuzzer
+}
+
+const KINDS: [Kind; 4] = [
+    Kind::Initiation,
+    Kind::Response,
+    Kind::CookieReply,
+    Kind::Raw,
+];
+
+const FLAG_FIX_MAC1: u8 = 1 << 0;
+const FLAG_UNDER_LOAD: u8 = 1 << 1;
+
+#[derive(Clone, Debug)]
+pub struct HandshakeInput {
+    pub seed: [u8; 32],      // seed for the keys and randomness of the device and peer
+    pub kind: Kind,          // message to process
+    pub fix_mac1: bool,      // recompute mac1 after mutation
+    pub under_load: bool,    // process the message "under load"
+    pub src: ([u8; 4], u16), // source address of the message
+    pub data: Vec<u8>,       // (offset, xor) mutations or the raw message
+}
+
+impl HandshakeInput {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.fix_mac1 {
+            flags |= FLAG_FIX_MAC1;
+        }
+        if self.under_load {
+            flags |= FLAG_UNDER_LOAD;
+        }
+        let mut bytes = self.seed.to_vec();
+        bytes.push(KINDS.iter().position(|k| *k == self.kind).unwrap() as u8);
+        bytes.push(flags);
+        bytes.extend_from_slice(&self.src.0);
+        bytes.extend_from_slice(&self.src.1.to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+impl Arbitrary for HandshakeInput {
+    fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self> {
+        let seed = u.arbitrary()?;
+        let kind = KINDS[u.arbitrary::<u8>()? as usize % KINDS.len()];
+        let flags: u8 = u.arbitrary()?;
+        let src = u.arbitrary()?;
+        let data = u.get_bytes(u.len())?.to_vec();
+        Ok(HandshakeInput {
+            seed,
+            kind,
+            fix_mac1: flags & FLAG_FIX_MAC1 != 0,
+            under_load: flags & FLAG_UNDER_LOAD != 0,
+            src,
+            data,
+        })
+    }
+}
+
+// Recompute mac1 (for the receiver) of an initiation/response sized message
+fn fix_mac1(clock: &dyn Clock, pk: PublicKey, msg: &mut [u8]) {
+    let len = msg.len();
+    if len != mem::size_of::<Initiation>() && len != mem::size_of::<Response>() {
+        return;
+    }
+    let (inner, footer) = msg.split_at_mut(len - mem::size_of::<MacsFooter>());
+    let mut macs = MacsFooter::default();
+    macs::Generator::new(pk).generate(inner, &mut macs, clock.now());
+    let mac1 = macs.f_mac1;
+    footer[..mac1.len()].copy_from_slice(&mac1);
+}
+
+/// Process a (mutated) handshake message
+///
+/// # Returns
+///
+/// A bool indicating if the message was processed successfully
+pub fn process(input: &HandshakeInput) -> bool {
+    let mut rng = StdRng::from_seed(input.seed);
+
+    // create the device and the peer
+    let sk1 = StaticSecret::new(&mut rng);
+    let pk1 = PublicKey::from(&sk1);
+    let sk2 = StaticSecret::new(&mut rng);
+    let pk2 = PublicKey::from(&sk2);
+    let psk: [u8; 32] = rng.gen();
+
+    // virtual clock (for reproducible timestamps)
+    let clock = Arc::new(VirtualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+
+    let mut dev: Device<()> = Device::new();
+    dev.set_clock(clock.clone());
+    dev.set_sk(Some(sk1));
+    dev.add(pk2, ()).unwrap();
+    dev.set_psk(pk2, psk).unwrap();
+
+    let mut peer: Device<()> = Device::new();
+    peer.set_clock(clock.clone());
+    peer.set_sk(Some(sk2));
+    peer.add(pk1, ()).unwrap();
+    peer.set_psk(pk1, psk).unwrap();
+
+    let src = SocketAddr::from((Ipv4Addr::from(input.src.0), input.src.1));
+    let mut buf = [0u8; MAX_HANDSHAKE_MSG_SIZE];
+    let mut reply = [0u8; MAX_HANDSHAKE_MSG_SIZE];
+
+    // create the message
+    let mut msg = match input.kind {
+        Kind::Initiation => {
+            let len = peer.begin(&mut rng, &pk1, &mut buf).unwrap();
+            buf[..len].to_vec()
+        }
+        Kind::Response | Kind::CookieReply => {
+            // a missing mac2 field prompts a cookie reply from the peer (when under load)
+            let under_load = input.kind == Kind::CookieReply;
+            let len = dev.begin(&mut rng, &pk2, &mut buf).unwrap();
+            match peer.process(&mut rng, &buf[..len], src, under_load, &mut reply) {
+                Ok((_, Some(len), _)) => reply[..len].to_vec(),
+                _ => unreachable!("peer failed to reply to initiation"),
+            }
+        }
+        Kind::Raw => input.data.clone(),
+    };
+
+    // apply mutations
+    if input.kind != Kind::Raw {
+        for mutation in input.data.chunks_exact(2) {
+            let offset = mutation[0] as usize % msg.len();
+            msg[offset] ^= mutation[1];
+        }
+    }
+
+    if input.fix_mac1 {
+        fix_mac1(&*clock, pk1, &mut msg);
+    }
+
+    dev.process(&mut rng, &msg, src, input.under_load, &mut reply)
+        .is_ok()
+}
+
+/// Parse a (raw) handshake message
+pub fn parse(msg: &[u8]) {
+    if let Ok(init) = Initiation::parse(msg) {
+        assert_eq!(init.as_bytes(), msg);
+    }
+    if let Ok(resp) = Response::parse(msg) {
+        assert_eq!(resp.as_bytes(), msg);
+    }
+    if let Ok(reply) = CookieReply::parse(msg) {
+        assert_eq!(reply.as_bytes(), msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::fuzzing::write_corpus;
+    use super::super::tests::{begin, process as process_message, setup_devices};
+    use super::*;
+
+    use rand::rngs::OsRng;
+
+    // seeds for every kind of message
+    fn structured() -> Vec<HandshakeInput> {
+        let mut inputs = vec![];
+        for (i, kind) in KINDS.iter().enumerate() {
+            if *kind == Kind::Raw {
+                continue;
+            }
+            for under_load in [false, true].iter() {
+                inputs.push(HandshakeInput {
+                    seed: [i as u8; 32],
+                    kind: *kind,
+                    fix_mac1: true,
+                    under_load: *under_load,
+                    src: ([172, 16, 0, 1], 8080),
+                    data: vec![],
+                });
+            }
+        }
+        inputs
+    }
+
+    // raw messages from a full handshake (as in the handshake tests)
+    fn messages() -> Vec<Vec<u8>> {
+        let (_pk1, dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+        let src: SocketAddr = "172.16.0.1:8080".parse().unwrap();
+
+        let init = begin(&dev1, &pk2).unwrap();
+        let cookie = process_message(&dev2, &init, Some(src)).unwrap().1.unwrap();
+        let resp = process_message(&dev2, &init, None).unwrap().1.unwrap();
+        vec![init, cookie, resp]
+    }
+
+    #[test]
+    fn fuzzing_handshake_seeds() {
+        // unmutated messages are processed successfully
+        let inputs = structured();
+        for input in inputs.iter() {
+            let decoded = HandshakeInput::arbitrary_take_rest(Unstructured::new(&input.encode()));
+            assert_eq!(format!("{:?}", decoded.unwrap()), format!("{:?}", input));
+            assert!(process(input), "failed to process seed: {:?}", input);
+        }
+
+        let mut seeds: Vec<Vec<u8>> = inputs.iter().map(|input| input.encode()).collect();
+        for msg in messages() {
+            parse(&msg);
+            seeds.push(
+                HandshakeInput {
+                    seed: [0u8; 32],
+                    kind: Kind::Raw,
+                    fix_mac1: true,
+                    under_load: false,
+                    src: ([172, 16, 0, 1], 8080),
+                    data: msg,
+                }
+                .encode(),
+            );
+        }
+
+        write_corpus("handshake", &seeds);
+        write_corpus("messages", &messages());
+    }
+}
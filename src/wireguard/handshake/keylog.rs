@@ -0,0 +1,91 @@
+/* Session key logging for debugging captures.
+ *
+ * When the "keylog" feature is enabled and a sink has been explicitly configured,
+ * every completed handshake is written to the sink in the key log format
+ * accepted by the WireGuard dissector in Wireshark (wg.keylog_file):
+ *
+ * LOCAL_STATIC_PRIVATE_KEY = <base64>
+ * REMOTE_STATIC_PUBLIC_KEY = <base64>
+ * LOCAL_EPHEMERAL_PRIVATE_KEY = <base64>
+ * PRESHARED_KEY = <base64>
+ *
+ * From these Wireshark derives the transport keys of the session.
+ *
+ * THIS DISCLOSES ALL SESSION SECRETS: never enable it outside of debugging.
+ * Without the feature the sink is a zero-sized noop.
+ */
+
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::types::Psk;
+
+#[cfg(feature = "keylog")]
+use std::io::Write;
+
+#[cfg(feature = "keylog")]
+use std::sync::Mutex;
+
+#[cfg(feature = "keylog")]
+pub struct KeyLog {
+    sink: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+#[cfg(not(feature = "keylog"))]
+pub struct KeyLog {}
+
+#[cfg(feature = "keylog")]
+impl KeyLog {
+    pub fn new() -> KeyLog {
+        KeyLog {
+            sink: Mutex::new(None),
+        }
+    }
+
+    /// Set (or remove) the sink to which session secrets are written
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - Writer receiving the key log lines, None disables logging
+    pub fn set(&self, sink: Option<Box<dyn Write + Send>>) {
+        *self.sink.lock().unwrap() = sink;
+    }
+
+    /// Log the secrets of a completed handshake
+    ///
+    /// # Arguments
+    ///
+    /// * `sk` - Static private key of the device
+    /// * `pk` - Static public key of the remote peer
+    /// * `eph_sk` - Ephemeral private key generated by the device for this handshake
+    /// * `psk` - Pre-shared key of the peer
+    pub fn log(&self, sk: &StaticSecret, pk: &PublicKey, eph_sk: &StaticSecret, psk: &Psk) {
+        let mut sink = self.sink.lock().unwrap();
+        if let Some(sink) = sink.as_mut() {
+            let res = write!(
+                sink,
+                "LOCAL_STATIC_PRIVATE_KEY = {}\n\
+                 REMOTE_STATIC_PUBLIC_KEY = {}\n\
+                 LOCAL_EPHEMERAL_PRIVATE_KEY = {}\n\
+                 PRESHARED_KEY = {}\n",
+                base64::encode(sk.to_bytes()),
+                base64::encode(pk.as_bytes()),
+                base64::encode(eph_sk.to_bytes()),
+                base64::encode(psk),
+            )
+            .and_then(|_| sink.flush());
+            if let Err(e) = res {
+                log::warn!("failed to write to key log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "keylog"))]
+impl KeyLog {
+    pub fn new() -> KeyLog {
+        KeyLog {}
+    }
+
+    #[inline(always)]
+    pub fn log(&self, _sk: &StaticSecret, _pk: &PublicKey, _eph_sk: &StaticSecret, _psk: &Psk) {}
+}
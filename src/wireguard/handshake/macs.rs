@@ -92,19 +92,19 @@ pub struct Generator {
     cookie: Option<Cookie>,
 }
 
-fn addr_to_mac_bytes(addr: &SocketAddr) -> Vec<u8> {
+// Serialize the address into the (stack allocated) buffer,
+// returning the slice of the buffer used.
+fn addr_to_mac_bytes<'a>(addr: &SocketAddr, buf: &'a mut [u8; 16 + 2]) -> &'a [u8] {
     match addr {
         SocketAddr::V4(addr) => {
-            let mut res = Vec::with_capacity(4 + 2);
-            res.extend(&addr.ip().octets());
-            res.extend(&addr.port().to_le_bytes());
-            res
+            buf[..4].copy_from_slice(&addr.ip().octets());
+            buf[4..6].copy_from_slice(&addr.port().to_le_bytes());
+            &buf[..6]
         }
         SocketAddr::V6(addr) => {
-            let mut res = Vec::with_capacity(16 + 2);
-            res.extend(&addr.ip().octets());
-            res.extend(&addr.port().to_le_bytes());
-            res
+            buf[..16].copy_from_slice(&addr.ip().octets());
+            buf[16..18].copy_from_slice(&addr.port().to_le_bytes());
+            &buf[..18]
         }
     }
 }
@@ -133,12 +133,13 @@ impl Generator {
     /// # Arguments
     ///
     /// - reply: CookieReply to process
+    /// - now: The current time
     ///
     /// # Returns
     ///
     /// Can fail if the cookie reply fails to validate
     /// (either indicating that it is outdated or malformed)
-    pub fn process(&mut self, reply: &CookieReply) -> Result<(), HandshakeError> {
+    pub fn process(&mut self, reply: &CookieReply, now: Instant) -> Result<(), HandshakeError> {
         let mac1 = self.last_mac1.ok_or(HandshakeError::InvalidState)?;
         let mut tau = [0u8; SIZE_COOKIE];
         XOPEN!(
@@ -149,7 +150,7 @@ impl Generator {
             &reply.f_cookie   // ct || tag
         )?;
         self.cookie = Some(Cookie {
-            birth: Instant::now(),
+            birth: now,
             value: tau,
         });
         Ok(())
@@ -161,11 +162,12 @@ impl Generator {
     ///
     /// - inner: A byteslice representing the inner message to be covered
     /// - macs: The destination mac footer for the resulting macs
-    pub fn generate(&mut self, inner: &[u8], macs: &mut MacsFooter) {
+    /// - now: The current time
+    pub fn generate(&mut self, inner: &[u8], macs: &mut MacsFooter, now: Instant) {
         macs.f_mac1 = MAC!(&self.mac1_key, inner);
         macs.f_mac2 = match &self.cookie {
             Some(cookie) => {
-                if cookie.birth.elapsed() > COOKIE_UPDATE_INTERVAL {
+                if now.saturating_duration_since(cookie.birth) > COOKIE_UPDATE_INTERVAL {
                     self.cookie = None;
                     [0u8; SIZE_MAC]
                 } else {
@@ -190,31 +192,36 @@ pub struct Validator {
 }
 
 impl Validator {
-    pub fn new(pk: PublicKey) -> Validator {
+    pub fn new(pk: PublicKey, now: Instant) -> Validator {
         Validator {
             mac1_key: HASH!(LABEL_MAC1, pk.as_bytes()).into(),
             cookie_key: HASH!(LABEL_COOKIE, pk.as_bytes()).into(),
             secret: RwLock::new(Secret {
                 value: [0u8; SIZE_SECRET],
-                birth: Instant::now() - Duration::new(86400, 0),
+                birth: now - Duration::new(86400, 0),
             }),
         }
     }
 
-    fn get_tau(&self, src: &[u8]) -> Option<[u8; SIZE_COOKIE]> {
+    fn get_tau(&self, src: &[u8], now: Instant) -> Option<[u8; SIZE_COOKIE]> {
         let secret = self.secret.read();
-        if secret.birth.elapsed() < COOKIE_UPDATE_INTERVAL {
+        if now.saturating_duration_since(secret.birth) < COOKIE_UPDATE_INTERVAL {
             Some(MAC!(&secret.value, src))
         } else {
             None
         }
     }
 
-    fn get_set_tau<R: RngCore + CryptoRng>(&self, rng: &mut R, src: &[u8]) -> [u8; SIZE_COOKIE] {
+    fn get_set_tau<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        src: &[u8],
+        now: Instant,
+    ) -> [u8; SIZE_COOKIE] {
         // check if current value is still valid
         {
             let secret = self.secret.read();
-            if secret.birth.elapsed() < COOKIE_UPDATE_INTERVAL {
+            if now.saturating_duration_since(secret.birth) < COOKIE_UPDATE_INTERVAL {
                 return MAC!(&secret.value, src);
             };
         }
@@ -222,13 +229,13 @@ impl Validator {
         // take write lock, check again
         {
             let mut secret = self.secret.write();
-            if secret.birth.elapsed() < COOKIE_UPDATE_INTERVAL {
+            if now.saturating_duration_since(secret.birth) < COOKIE_UPDATE_INTERVAL {
                 return MAC!(&secret.value, src);
             };
 
             // set new random cookie secret
             rng.fill_bytes(&mut secret.value);
-            secret.birth = Instant::now();
+            secret.birth = now;
             MAC!(&secret.value, src)
         }
     }
@@ -240,17 +247,19 @@ impl Validator {
         src: &SocketAddr,      // source address of incoming message
         macs: &MacsFooter,     // footer of incoming message
         msg: &mut CookieReply, // resulting cookie reply
+        now: Instant,          // current time
     ) {
-        let src = addr_to_mac_bytes(src);
+        let mut buf = [0u8; 16 + 2];
+        let src = addr_to_mac_bytes(src, &mut buf);
         msg.f_type.set(TYPE_COOKIE_REPLY as u32);
         msg.f_receiver.set(receiver);
         rng.fill_bytes(&mut msg.f_nonce);
         XSEAL!(
-            &self.cookie_key,             // key
-            &msg.f_nonce,                 // nonce
-            &macs.f_mac1,                 // ad
-            &self.get_set_tau(rng, &src), // pt
-            &mut msg.f_cookie             // ct || tag
+            &self.cookie_key,                 // key
+            &msg.f_nonce,                     // nonce
+            &macs.f_mac1,                     // ad
+            &self.get_set_tau(rng, src, now), // pt
+            &mut msg.f_cookie                 // ct || tag
         );
     }
 
@@ -269,9 +278,16 @@ impl Validator {
         }
     }
 
-    pub fn check_mac2(&self, inner: &[u8], src: &SocketAddr, macs: &MacsFooter) -> bool {
-        let src = addr_to_mac_bytes(src);
-        match self.get_tau(&src) {
+    pub fn check_mac2(
+        &self,
+        inner: &[u8],
+        src: &SocketAddr,
+        macs: &MacsFooter,
+        now: Instant,
+    ) -> bool {
+        let mut buf = [0u8; 16 + 2];
+        let src = addr_to_mac_bytes(src, &mut buf);
+        match self.get_tau(src, now) {
             Some(tau) => MAC!(&tau, inner, macs.f_mac1).ct_eq(&macs.f_mac2).into(),
             None => false,
         }
@@ -288,7 +304,7 @@ mod tests {
     fn new_validator_generator() -> (Validator, Generator) {
         let sk = StaticSecret::new(&mut OsRng);
         let pk = PublicKey::from(&sk);
-        (Validator::new(pk), Generator::new(pk))
+        (Validator::new(pk, Instant::now()), Generator::new(pk))
     }
 
     proptest! {
@@ -298,28 +314,37 @@ mod tests {
             let mut macs = MacsFooter::default();
             let src = "192.0.2.16:8080".parse().unwrap();
             let (validator, mut generator) = new_validator_generator();
+            let now = Instant::now();
 
             // generate mac1 for first message
-            generator.generate(&inner1[..], &mut macs);
+            generator.generate(&inner1[..], &mut macs, now);
             assert_ne!(macs.f_mac1, [0u8; SIZE_MAC], "mac1 should be set");
             assert_eq!(macs.f_mac2, [0u8; SIZE_MAC], "mac2 should not be set");
 
             // check validity of mac1
             validator.check_mac1(&inner1[..], &macs).expect("mac1 of inner1 did not validate");
-            assert_eq!(validator.check_mac2(&inner1[..], &src, &macs), false, "mac2 of inner2 did not validate");
-            validator.create_cookie_reply(&mut OsRng, receiver, &src, &macs, &mut msg);
+            assert_eq!(validator.check_mac2(&inner1[..], &src, &macs, now), false, "mac2 of inner2 did not validate");
+            validator.create_cookie_reply(&mut OsRng, receiver, &src, &macs, &mut msg, now);
 
             // consume cookie reply
-            generator.process(&msg).expect("failed to process CookieReply");
+            generator.process(&msg, now).expect("failed to process CookieReply");
 
             // generate mac2 & mac2 for second message
-            generator.generate(&inner2[..], &mut macs);
+            generator.generate(&inner2[..], &mut macs, now);
             assert_ne!(macs.f_mac1, [0u8; SIZE_MAC], "mac1 should be set");
             assert_ne!(macs.f_mac2, [0u8; SIZE_MAC], "mac2 should be set");
 
             // check validity of mac1 and mac2
             validator.check_mac1(&inner2[..], &macs).expect("mac1 of inner2 did not validate");
-            assert!(validator.check_mac2(&inner2[..], &src, &macs), "mac2 of inner2 did not validate");
+            assert!(validator.check_mac2(&inner2[..], &src, &macs, now), "mac2 of inner2 did not validate");
+
+            // the cookie secret is rotated
+            let later = now + COOKIE_UPDATE_INTERVAL;
+            assert!(!validator.check_mac2(&inner2[..], &src, &macs, later), "mac2 validated after rotation");
+
+            // the cookie expires
+            generator.generate(&inner2[..], &mut macs, later + Duration::from_secs(1));
+            assert_eq!(macs.f_mac2, [0u8; SIZE_MAC], "mac2 should not be set after expiry");
         }
     }
 }
@@ -7,6 +7,7 @@
  */
 
 mod device;
+mod keylog;
 mod macs;
 mod messages;
 mod noise;
@@ -18,7 +19,12 @@ mod types;
 #[cfg(test)]
 mod tests;
 
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+
 // publicly exposed interface
 
-pub use device::Device;
+pub use device::{Admission, Device, Provisional};
 pub use messages::{MAX_HANDSHAKE_MSG_SIZE, TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+pub use timestamp::{FileTimestampStore, TimestampStore, TAI64N};
+pub use types::{HandshakeCounters, HandshakeError};
@@ -1,5 +1,3 @@
-use std::time::Instant;
-
 // DH
 use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
 
@@ -13,6 +11,8 @@ use chacha20poly1305::ChaCha20Poly1305;
 
 use rand::prelude::{CryptoRng, RngCore};
 
+use std::net::SocketAddr;
+
 use generic_array::typenum::*;
 use generic_array::*;
 
@@ -21,7 +21,7 @@ use clear_on_drop::clear_stack_on_return;
 
 use subtle::ConstantTimeEq;
 
-use super::device::{Device, KeyState};
+use super::device::{Device, KeyState, Provisional};
 use super::messages::{NoiseInitiation, NoiseResponse};
 use super::messages::{TYPE_INITIATION, TYPE_RESPONSE};
 use super::peer::{Peer, State};
@@ -36,7 +36,7 @@ type HMACBlake2s = Hmac<Blake2s>;
 
 // convenient alias to pass state temporarily into device.rs and back
 
-type TemporaryState = (u32, PublicKey, GenericArray<u8, U32>, GenericArray<u8, U32>);
+pub(super) type TemporaryState = (u32, PublicKey, GenericArray<u8, U32>, GenericArray<u8, U32>);
 
 const SIZE_CK: usize = 32;
 const SIZE_HS: usize = 32;
@@ -229,6 +229,7 @@ fn shared_secret(sk: &StaticSecret, pk: &PublicKey) -> Result<SharedSecret, Hand
 
 pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
     rng: &mut R,
+    device: &Device<O>,
     keyst: &KeyState,
     peer: &Peer<O>,
     pk: &PublicKey,
@@ -292,10 +293,11 @@ pub(super) fn create_initiation<R: RngCore + CryptoRng, O>(
 
         // msg.timestamp := Aead(k, 0, Timestamp(), H)
 
+        let now = timestamp::from_system_time(device.clock.system_time());
         SEAL!(
             &key,
             &hs,                  // ad
-            &timestamp::now(),    // pt
+            &now,                 // pt
             &mut msg.f_timestamp  // ct || tag
         );
 
@@ -320,7 +322,8 @@ pub(super) fn consume_initiation<'a, O>(
     device: &'a Device<O>,
     keyst: &KeyState,
     msg: &NoiseInitiation,
-) -> Result<(&'a Peer<O>, PublicKey, TemporaryState), HandshakeError> {
+    src: &SocketAddr,
+) -> Result<(&'a Peer<O>, PublicKey, timestamp::TAI64N, TemporaryState), HandshakeError> {
     log::debug!("consume initiation");
 
     clear_stack_on_return(CLEAR_PAGES, || {
@@ -354,17 +357,24 @@ pub(super) fn consume_initiation<'a, O>(
             &msg.f_static  // ct || tag
         )?;
 
-        let peer = device.lookup_pk(&PublicKey::from(pk))?;
+        // lookup the peer (or resolve the configuration of an unknown initiator)
 
-        // check for zero shared-secret (see "shared_secret" note).
-
-        if peer.ss.ct_eq(&[0u8; 32]).into() {
-            return Err(HandshakeError::InvalidSharedSecret);
-        }
+        let pk = PublicKey::from(pk);
+        let (peer, ss) = match device.lookup_pk(&pk) {
+            Ok(peer) => {
+                // check for zero shared-secret (see "shared_secret" note).
 
-        // reset initiation state
+                if peer.ss.ct_eq(&[0u8; 32]).into() {
+                    return Err(HandshakeError::InvalidSharedSecret);
+                }
 
-        *peer.state.lock() = State::Reset;
+                (Ok(peer), peer.ss)
+            }
+            Err(_) => {
+                let config = device.resolve(&pk, src)?;
+                (Err(config), *shared_secret(&keyst.sk, &pk)?.as_bytes())
+            }
+        };
 
         // H := Hash(H || msg.static)
 
@@ -372,7 +382,7 @@ pub(super) fn consume_initiation<'a, O>(
 
         // (C, k) := Kdf2(C, DH(S_priv, S_pub))
 
-        let (ck, key) = KDF2!(&ck, &peer.ss);
+        let (ck, key) = KDF2!(&ck, &ss);
 
         // msg.timestamp := Aead(k, 0, Timestamp(), H)
 
@@ -385,26 +395,41 @@ pub(super) fn consume_initiation<'a, O>(
             &msg.f_timestamp  // ct || tag
         )?;
 
-        // check and update timestamp
-
-        peer.check_replay_flood(device, &ts)?;
-
         // H := Hash(H || msg.timestamp)
 
         let hs = HASH!(&hs, &msg.f_timestamp);
-
-        // return state (to create response)
-
-        Ok((
-            peer,
-            PublicKey::from(pk),
-            (msg.f_sender.get(), eph_r_pk, hs, ck),
-        ))
+        let state = (msg.f_sender.get(), eph_r_pk, hs, ck);
+
+        match peer {
+            Ok(peer) => {
+                // check timestamp
+                // (updated, along with the state of the peer, by the caller once the initiation is accepted)
+
+                peer.check_replay_flood(device, &ts)?;
+
+                // return state (to create response)
+
+                Ok((peer, pk, ts, state))
+            }
+            Err(config) => {
+                // return state (to create response once the peer is added)
+
+                Err(HandshakeError::UnknownInitiator(Box::new(Provisional {
+                    pk,
+                    config,
+                    device: keyst.pk,
+                    ss,
+                    ts,
+                    state,
+                })))
+            }
+        }
     })
 }
 
 pub(super) fn create_response<R: RngCore + CryptoRng, O>(
     rng: &mut R,
+    device: &Device<O>,
     peer: &Peer<O>,
     pk: &PublicKey,
     local: u32,              // sending identifier
@@ -470,10 +495,16 @@ pub(super) fn create_response<R: RngCore + CryptoRng, O>(
 
         let (key_recv, key_send) = KDF2!(&ck, &[]);
 
+        // log session secrets (if enabled)
+
+        if let Some(sk) = device.get_sk() {
+            device.keylog.log(sk, pk, &eph_sk, &peer.psk);
+        }
+
         // return unconfirmed key-pair
 
         Ok(KeyPair {
-            birth: Instant::now(),
+            birth: device.clock.now(),
             initiator: false,
             send: Key {
                 id: receiver,
@@ -499,7 +530,7 @@ pub(super) fn consume_response<'a, O>(
     log::debug!("consume response");
     clear_stack_on_return(CLEAR_PAGES, || {
         // retrieve peer and copy initiation state
-        let (peer, _) = device.lookup_id(msg.f_receiver.get())?;
+        let (peer, pk) = device.lookup_id(msg.f_receiver.get())?;
 
         let (hs, ck, local, eph_sk) = match *peer.state.lock() {
             State::InitiationSent {
@@ -547,7 +578,7 @@ pub(super) fn consume_response<'a, O>(
 
         // derive key-pair
 
-        let birth = Instant::now();
+        let birth = device.clock.now();
         let (key_send, key_recv) = KDF2!(&ck, &[]);
 
         // check for new initiation sent while lock released
@@ -566,6 +597,9 @@ pub(super) fn consume_response<'a, O>(
             *state = State::Reset;
             let remote = msg.f_sender.get();
 
+            // log session secrets (if enabled)
+            device.keylog.log(&keyst.sk, &pk, &eph_sk, &peer.psk);
+
             // return confirmed key-pair
             Ok((
                 Some(&peer.opaque),
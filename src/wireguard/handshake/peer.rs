@@ -1,6 +1,7 @@
 use spin::Mutex;
 
 use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 use generic_array::typenum::U32;
@@ -33,6 +34,12 @@ pub(super) struct Peer<O> {
     // state related to DoS mitigation fields
     pub macs: Mutex<macs::Generator>,
 
+    // dropped handshake messages
+    pub timestamp_drops: AtomicU64,
+    pub flood_drops: AtomicU64,
+    pub admission_drops: AtomicU64,
+    pub response_drops: AtomicU64,
+
     // constant state
     pub ss: [u8; 32], // precomputed DH(static, static)
     pub psk: Psk,     // psk of peer
@@ -66,6 +73,10 @@ impl<O> Peer<O> {
             state: Mutex::new(State::Reset),
             timestamp: Mutex::new(None),
             last_initiation_consumption: Mutex::new(None),
+            timestamp_drops: AtomicU64::new(0),
+            flood_drops: AtomicU64::new(0),
+            admission_drops: AtomicU64::new(0),
+            response_drops: AtomicU64::new(0),
             ss,
             psk: [0u8; 32],
         }
@@ -78,44 +89,105 @@ impl<O> Peer<O> {
         }
     }
 
-    /// Set the mutable state of the peer conditioned on the timestamp being newer
+    /// Restore the greatest timestamp accepted from the peer (e.g. after a restart)
     ///
     /// # Arguments
     ///
-    /// * st_new - The updated state of the peer
-    /// * ts_new - The associated timestamp
+    /// * timestamp - The stored timestamp
+    pub fn restore_timestamp(&self, timestamp: Option<timestamp::TAI64N>) {
+        let mut current = self.timestamp.lock();
+        if let Some(new) = timestamp {
+            match *current {
+                Some(old) if !timestamp::compare(&old, &new) => (),
+                _ => *current = Some(new),
+            }
+        }
+    }
+
+    /// Check that the timestamp is newer and that the initiation is not part of a flood
+    /// (without updating the state of the peer)
+    ///
+    /// # Arguments
+    ///
+    /// * device - The device of the peer
+    /// * timestamp_new - The timestamp of the initiation
     pub fn check_replay_flood(
         &self,
         device: &Device<O>,
         timestamp_new: &timestamp::TAI64N,
+    ) -> Result<(), HandshakeError> {
+        let timestamp = self.timestamp.lock();
+        let last_initiation_consumption = self.last_initiation_consumption.lock();
+        self.check(
+            &timestamp,
+            &last_initiation_consumption,
+            device.clock.now(),
+            timestamp_new,
+        )
+    }
+
+    /// Set the mutable state of the peer conditioned on the timestamp being newer
+    ///
+    /// # Arguments
+    ///
+    /// * device - The device of the peer
+    /// * timestamp_new - The timestamp of the accepted initiation
+    pub fn update_replay_flood(
+        &self,
+        device: &Device<O>,
+        timestamp_new: &timestamp::TAI64N,
     ) -> Result<(), HandshakeError> {
         let mut state = self.state.lock();
         let mut timestamp = self.timestamp.lock();
         let mut last_initiation_consumption = self.last_initiation_consumption.lock();
+        let now = device.clock.now();
+
+        // check again (another initiation may have been accepted since)
+        self.check(&timestamp, &last_initiation_consumption, now, timestamp_new)?;
+
+        // reset state
+        if let State::InitiationSent { local, .. } = *state {
+            device.release(local)
+        }
 
+        // update replay & flood protection
+        *state = State::Reset;
+        *timestamp = Some(*timestamp_new);
+        *last_initiation_consumption = Some(now);
+        Ok(())
+    }
+
+    fn check(
+        &self,
+        timestamp: &Option<timestamp::TAI64N>,
+        last_initiation_consumption: &Option<Instant>,
+        now: Instant,
+        timestamp_new: &timestamp::TAI64N,
+    ) -> Result<(), HandshakeError> {
         // check replay attack
-        if let Some(timestamp_old) = *timestamp {
-            if !timestamp::compare(&timestamp_old, &timestamp_new) {
+        if let Some(timestamp_old) = timestamp {
+            if !timestamp::compare(timestamp_old, timestamp_new) {
+                self.timestamp_drops.fetch_add(1, Ordering::Relaxed);
                 return Err(HandshakeError::OldTimestamp);
             }
         };
 
         // check flood attack
-        if let Some(last) = *last_initiation_consumption {
-            if last.elapsed() < TIME_BETWEEN_INITIATIONS {
+        if let Some(last) = last_initiation_consumption {
+            if now.saturating_duration_since(*last) < TIME_BETWEEN_INITIATIONS {
+                self.flood_drops.fetch_add(1, Ordering::Relaxed);
                 return Err(HandshakeError::InitiationFlood);
             }
         }
+        Ok(())
+    }
 
-        // reset state
-        if let State::InitiationSent { local, .. } = *state {
-            device.release(local)
+    pub fn counters(&self) -> HandshakeCounters {
+        HandshakeCounters {
+            timestamp_drops: self.timestamp_drops.load(Ordering::Relaxed),
+            flood_drops: self.flood_drops.load(Ordering::Relaxed),
+            admission_drops: self.admission_drops.load(Ordering::Relaxed),
+            response_drops: self.response_drops.load(Ordering::Relaxed),
         }
-
-        // update replay & flood protection
-        *state = State::Reset;
-        *timestamp = Some(*timestamp_new);
-        *last_initiation_consumption = Some(Instant::now());
-        Ok(())
     }
 }
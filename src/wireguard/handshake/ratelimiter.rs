@@ -5,6 +5,8 @@ use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use super::super::clock::Clock;
+
 const PACKETS_PER_SECOND: u64 = 20;
 const PACKETS_BURSTABLE: u64 = 5;
 const PACKET_COST: u64 = 1_000_000_000 / PACKETS_PER_SECOND;
@@ -20,6 +22,7 @@ struct Entry {
 pub struct RateLimiter(Arc<RateLimiterInner>);
 
 struct RateLimiterInner {
+    clock: Arc<dyn Clock>,
     gc_running: AtomicBool,
     gc_dropped: (Mutex<bool>, Condvar),
     table: spin::RwLock<HashMap<IpAddr, spin::Mutex<Entry>>>,
@@ -36,8 +39,9 @@ impl Drop for RateLimiter {
 }
 
 impl RateLimiter {
-    pub fn new() -> Self {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
         RateLimiter(Arc::new(RateLimiterInner {
+            clock,
             gc_dropped: (Mutex::new(false), Condvar::new()),
             gc_running: AtomicBool::from(false),
             table: spin::RwLock::new(HashMap::new()),
@@ -45,6 +49,8 @@ impl RateLimiter {
     }
 
     pub fn allow(&self, addr: &IpAddr) -> bool {
+        let now = self.0.clock.now();
+
         // check if allowed
         let allowed = {
             // check for existing entry (only requires read lock)
@@ -53,9 +59,9 @@ impl RateLimiter {
                 let mut entry = entry.lock();
 
                 // add tokens earned since last time
-                entry.tokens = MAX_TOKENS
-                    .min(entry.tokens + u64::from(entry.last_time.elapsed().subsec_nanos()));
-                entry.last_time = Instant::now();
+                let elapsed = now.saturating_duration_since(entry.last_time);
+                entry.tokens = MAX_TOKENS.min(entry.tokens + u64::from(elapsed.subsec_nanos()));
+                entry.last_time = now;
 
                 // subtract cost of packet
                 if entry.tokens > PACKET_COST {
@@ -70,7 +76,7 @@ impl RateLimiter {
             self.0.table.write().insert(
                 *addr,
                 spin::Mutex::new(Entry {
-                    last_time: Instant::now(),
+                    last_time: now,
                     tokens: MAX_TOKENS - PACKET_COST,
                 }),
             );
@@ -87,8 +93,9 @@ impl RateLimiter {
                     // garbage collect
                     {
                         let mut tw = limiter.table.write();
+                        let now = limiter.clock.now();
                         tw.retain(|_, ref mut entry| {
-                            entry.lock().last_time.elapsed() <= GC_INTERVAL
+                            now.saturating_duration_since(entry.lock().last_time) <= GC_INTERVAL
                         });
                         if tw.len() == 0 {
                             limiter.gc_running.store(false, Ordering::Relaxed);
@@ -109,6 +116,7 @@ impl RateLimiter {
 
 #[cfg(test)]
 mod tests {
+    use super::super::super::clock::SystemClock;
     use super::*;
     use std;
 
@@ -120,7 +128,7 @@ mod tests {
 
     #[test]
     fn test_ratelimiter() {
-        let ratelimiter = RateLimiter::new();
+        let ratelimiter = RateLimiter::new(Arc::new(SystemClock()));
         let mut expected = vec![];
         let ips = vec![
             "127.0.0.1".parse().unwrap(),
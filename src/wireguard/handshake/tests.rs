@@ -1,8 +1,10 @@
 use super::*;
 
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
 use hex;
 
@@ -13,8 +15,39 @@ use x25519_dalek::PublicKey;
 use x25519_dalek::StaticSecret;
 
 use super::messages::{Initiation, Response};
+use super::peer::State;
+use super::types::HandshakeError;
+
+use super::super::clock::VirtualClock;
+use super::super::provision::{PeerResolver, ProvisionedPeer};
+use super::super::types::KeyPair;
+
+// begin a handshake, returning the initiation as a vector
+pub(super) fn begin<O>(dev: &Device<O>, pk: &PublicKey) -> Result<Vec<u8>, HandshakeError> {
+    let mut buf = [0u8; MAX_HANDSHAKE_MSG_SIZE];
+    let len = dev.begin(&mut OsRng, pk, &mut buf)?;
+    Ok(buf[..len].to_vec())
+}
+
+// process a handshake message, returning any reply as a vector
+// (the device is considered under load if a source address is provided)
+pub(super) fn process<'a, O>(
+    dev: &'a Device<O>,
+    msg: &[u8],
+    src: Option<SocketAddr>,
+) -> Result<(Option<&'a O>, Option<Vec<u8>>, Option<KeyPair>), HandshakeError> {
+    let mut buf = [0u8; MAX_HANDSHAKE_MSG_SIZE];
+    let (peer, len, keypair) = dev.process(
+        &mut OsRng,
+        msg,
+        src.unwrap_or_else(|| "127.0.0.1:51820".parse().unwrap()),
+        src.is_some(),
+        &mut buf,
+    )?;
+    Ok((peer, len.map(|len| buf[..len].to_vec()), keypair))
+}
 
-fn setup_devices<R: RngCore + CryptoRng, O: Default>(
+pub(super) fn setup_devices<R: RngCore + CryptoRng, O: Default>(
     rng: &mut R,
 ) -> (PublicKey, Device<O>, PublicKey, Device<O>) {
     // generate new key pairs
@@ -69,16 +102,16 @@ fn handshake_under_load() {
     let src2: SocketAddr = "172.16.0.2:7070".parse().unwrap();
 
     // 1. device-1 : create first initiation
-    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let msg_init = begin(&dev1, &pk2).unwrap();
 
     // 2. device-2 : responds with CookieReply
-    let msg_cookie = match dev2.process(&mut OsRng, &msg_init, Some(src1)).unwrap() {
+    let msg_cookie = match process(&dev2, &msg_init, Some(src1)).unwrap() {
         (None, Some(msg), None) => msg,
         _ => panic!("unexpected response"),
     };
 
     // device-1 : processes CookieReply (no response)
-    match dev1.process(&mut OsRng, &msg_cookie, Some(src2)).unwrap() {
+    match process(&dev1, &msg_cookie, Some(src2)).unwrap() {
         (None, None, None) => (),
         _ => panic!("unexpected response"),
     }
@@ -87,10 +120,10 @@ fn handshake_under_load() {
     wait();
 
     // 3. device-1 : create second initiation
-    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let msg_init = begin(&dev1, &pk2).unwrap();
 
     // 4. device-2 : responds with noise response
-    let msg_response = match dev2.process(&mut OsRng, &msg_init, Some(src1)).unwrap() {
+    let msg_response = match process(&dev2, &msg_init, Some(src1)).unwrap() {
         (Some(_), Some(msg), Some(kp)) => {
             assert_eq!(kp.initiator, false);
             msg
@@ -99,13 +132,13 @@ fn handshake_under_load() {
     };
 
     // 5. device-1 : responds with CookieReply
-    let msg_cookie = match dev1.process(&mut OsRng, &msg_response, Some(src2)).unwrap() {
+    let msg_cookie = match process(&dev1, &msg_response, Some(src2)).unwrap() {
         (None, Some(msg), None) => msg,
         _ => panic!("unexpected response"),
     };
 
     // device-2 : processes CookieReply (no response)
-    match dev2.process(&mut OsRng, &msg_cookie, Some(src1)).unwrap() {
+    match process(&dev2, &msg_cookie, Some(src1)).unwrap() {
         (None, None, None) => (),
         _ => panic!("unexpected response"),
     }
@@ -114,10 +147,10 @@ fn handshake_under_load() {
     wait();
 
     // 6. device-1 : create third initiation
-    let msg_init = dev1.begin(&mut OsRng, &pk2).unwrap();
+    let msg_init = begin(&dev1, &pk2).unwrap();
 
     // 7. device-2 : responds with noise response
-    let (msg_response, kp1) = match dev2.process(&mut OsRng, &msg_init, Some(src1)).unwrap() {
+    let (msg_response, kp1) = match process(&dev2, &msg_init, Some(src1)).unwrap() {
         (Some(_), Some(msg), Some(kp)) => {
             assert_eq!(kp.initiator, false);
             (msg, kp)
@@ -126,7 +159,7 @@ fn handshake_under_load() {
     };
 
     // device-1 : process noise response
-    let kp2 = match dev1.process(&mut OsRng, &msg_response, Some(src2)).unwrap() {
+    let kp2 = match process(&dev1, &msg_response, Some(src2)).unwrap() {
         (Some(_), None, Some(kp)) => {
             assert_eq!(kp.initiator, true);
             kp
@@ -149,7 +182,7 @@ fn handshake_no_load() {
 
         // create initiation
 
-        let msg1 = dev1.begin(&mut OsRng, &pk2).unwrap();
+        let msg1 = begin(&dev1, &pk2).unwrap();
 
         println!("msg1 = {} : {} bytes", hex::encode(&msg1[..]), msg1.len());
         println!(
@@ -159,9 +192,7 @@ fn handshake_no_load() {
 
         // process initiation and create response
 
-        let (_, msg2, ks_r) = dev2
-            .process(&mut OsRng, &msg1, None)
-            .expect("failed to process initiation");
+        let (_, msg2, ks_r) = process(&dev2, &msg1, None).expect("failed to process initiation");
 
         let ks_r = ks_r.unwrap();
         let msg2 = msg2.unwrap();
@@ -176,9 +207,7 @@ fn handshake_no_load() {
 
         // process response and obtain confirmed key-pair
 
-        let (_, msg3, ks_i) = dev1
-            .process(&mut OsRng, &msg2, None)
-            .expect("failed to process response");
+        let (_, msg3, ks_i) = process(&dev1, &msg2, None).expect("failed to process response");
         let ks_i = ks_i.unwrap();
 
         assert!(msg3.is_none(), "Returned message after response");
@@ -197,3 +226,334 @@ fn handshake_no_load() {
     dev1.remove(&pk2).unwrap();
     dev2.remove(&pk1).unwrap();
 }
+
+#[cfg(feature = "keylog")]
+#[test]
+fn handshake_keylog() {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entries(log: &SharedLog) -> Vec<(String, [u8; 32])> {
+        let log = log.0.lock().unwrap();
+        String::from_utf8(log.clone())
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let mut split = line.split(" = ");
+                let name = split.next().unwrap().to_owned();
+                let value = base64::decode(split.next().unwrap()).unwrap();
+                let mut key = [0u8; 32];
+                key.copy_from_slice(&value[..]);
+                (name, key)
+            })
+            .collect()
+    }
+
+    let (pk1, dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let log1 = SharedLog::default();
+    let log2 = SharedLog::default();
+    dev1.set_keylog(Some(Box::new(log1.clone())));
+    dev2.set_keylog(Some(Box::new(log2.clone())));
+
+    // complete a handshake
+
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    let (_, msg2, _) = process(&dev2, &msg1, None).unwrap();
+    process(&dev1, &msg2.unwrap(), None).unwrap();
+
+    // both sides log their own secrets and the key of the remote
+
+    let names = [
+        "LOCAL_STATIC_PRIVATE_KEY",
+        "REMOTE_STATIC_PUBLIC_KEY",
+        "LOCAL_EPHEMERAL_PRIVATE_KEY",
+        "PRESHARED_KEY",
+    ];
+
+    for (log, dev, pk, remote) in [(&log1, &dev1, &pk1, &pk2), (&log2, &dev2, &pk2, &pk1)].iter() {
+        let entries = entries(log);
+        assert_eq!(entries.len(), names.len());
+        for (entry, name) in entries.iter().zip(names.iter()) {
+            assert_eq!(entry.0.as_str(), *name);
+        }
+        assert_eq!(entries[0].1, dev.get_sk().unwrap().to_bytes());
+        assert_eq!(&entries[1].1, remote.as_bytes());
+        assert_eq!(entries[3].1, dev.get_psk(remote).unwrap());
+        assert_ne!(&entries[2].1, pk.as_bytes());
+    }
+
+    // disabling the sink stops logging
+
+    dev1.set_keylog(None);
+    wait();
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    let (_, msg2, _) = process(&dev2, &msg1, None).unwrap();
+    process(&dev1, &msg2.unwrap(), None).unwrap();
+    assert_eq!(entries(&log1).len(), names.len());
+    assert_eq!(entries(&log2).len(), 2 * names.len());
+}
+
+#[test]
+fn handshake_admission() {
+    let (pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // reject the initiator (by public key and source address)
+    let blocked: SocketAddr = "172.16.0.1:8080".parse().unwrap();
+    let allowed: SocketAddr = "172.16.0.2:8080".parse().unwrap();
+    dev2.set_admission(Some(Arc::new(move |pk: &PublicKey, src: &SocketAddr| {
+        assert_eq!(pk.as_bytes(), pk1.as_bytes());
+        *src != blocked
+    })));
+
+    let mut buf = [0u8; MAX_HANDSHAKE_MSG_SIZE];
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    match dev2.process(&mut OsRng, &msg1, blocked, false, &mut buf) {
+        Err(HandshakeError::AdmissionDenied) => (),
+        _ => panic!("initiation should be rejected by the admission policy"),
+    }
+    assert_eq!(dev2.admission_rejections(), 1);
+
+    // admitted initiations complete the handshake
+    wait();
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    let (_, len, ks_r) = dev2
+        .process(&mut OsRng, &msg1, allowed, false, &mut buf)
+        .expect("initiation should be admitted");
+    let (_, _, ks_i) = process(&dev1, &buf[..len.unwrap()], None).unwrap();
+    let (ks_i, ks_r) = (ks_i.unwrap(), ks_r.unwrap());
+    assert_eq!(ks_i.send, ks_r.recv, "KeyI.send != KeyR.recv");
+    assert_eq!(ks_i.recv, ks_r.send, "KeyI.recv != KeyR.send");
+    assert_eq!(dev2.admission_rejections(), 1);
+}
+
+/* Test that an initiation rejected by the admission policy has no side effects:
+ * the timestamp (replay and flood protection) and the state of the peer are unchanged.
+ */
+#[test]
+fn handshake_admission_unchanged() {
+    let (pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // the responder has a handshake of its own in flight
+    let msg2 = begin(&dev2, &pk1).unwrap();
+
+    // reject the initiation
+    dev2.set_admission(Some(Arc::new(|_: &PublicKey, _: &SocketAddr| false)));
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    match process(&dev2, &msg1, None) {
+        Err(HandshakeError::AdmissionDenied) => (),
+        _ => panic!("initiation should be rejected by the admission policy"),
+    }
+    {
+        let peer = dev2.lookup_pk(&pk1).unwrap();
+        assert!(peer.timestamp.lock().is_none());
+        assert!(peer.last_initiation_consumption.lock().is_none());
+        assert!(matches!(*peer.state.lock(), State::InitiationSent { .. }));
+    }
+
+    // the handshake in flight completes
+    let (_, msg3, _) = process(&dev1, &msg2, None).unwrap();
+    let (_, _, ks) = process(&dev2, &msg3.unwrap(), None).unwrap();
+    assert!(ks.is_some());
+
+    // the rejected initiation is accepted once admitted (without waiting)
+    dev2.set_admission(None);
+    process(&dev2, &msg1, None).expect("initiation should be admitted");
+}
+
+/* Test that a captured initiation can not be replayed
+ * after the responder is restarted (with persistent timestamps),
+ * while the timestamps of rejected initiations are not persisted.
+ */
+#[test]
+fn handshake_replay_after_restart() {
+    let (pk1, dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    let dir = std::env::temp_dir().join(format!("wireguard-timestamps-{}", OsRng.next_u64()));
+    dev2.set_timestamp_store(Some(Arc::new(FileTimestampStore::new(&dir).unwrap())));
+
+    // capture an initiation consumed by the responder
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    process(&dev2, &msg1, None).unwrap();
+
+    // an initiation rejected by the admission policy
+    wait();
+    let msg_rejected = begin(&dev1, &pk2).unwrap();
+    dev2.set_admission(Some(Arc::new(|_: &PublicKey, _: &SocketAddr| false)));
+    match process(&dev2, &msg_rejected, None) {
+        Err(HandshakeError::AdmissionDenied) => (),
+        _ => panic!("initiation should be rejected by the admission policy"),
+    }
+
+    // restart the responder (restoring the peer from the configuration)
+    let sk = dev2.get_sk().map(|sk| StaticSecret::from(sk.to_bytes()));
+    let psk = dev2.get_psk(&pk1).unwrap();
+    drop(dev2);
+
+    let mut dev3: Device<usize> = Device::new();
+    dev3.set_sk(sk);
+    dev3.set_timestamp_store(Some(Arc::new(FileTimestampStore::new(&dir).unwrap())));
+    dev3.add(pk1, 0).unwrap();
+    dev3.set_psk(pk1, psk).unwrap();
+
+    // the replayed initiation is rejected
+    match process(&dev3, &msg1, None) {
+        Err(HandshakeError::OldTimestamp) => (),
+        _ => panic!("replayed initiation should be rejected"),
+    }
+
+    // the rejected initiation was not persisted
+    process(&dev3, &msg_rejected, None).unwrap();
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn handshake_counters() {
+    let (pk1, mut dev1, pk2, mut dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // on a virtual clock (initiations are in rapid succession regardless of the load)
+    let clock = Arc::new(VirtualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+    dev1.set_clock(clock.clone());
+    dev2.set_clock(clock.clone());
+
+    // complete a handshake
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    let (_, msg2, _) = process(&dev2, &msg1, None).unwrap();
+    let msg2 = msg2.unwrap();
+    process(&dev1, &msg2, None).unwrap();
+    assert_eq!(
+        dev1.get_counters(&pk2).unwrap(),
+        HandshakeCounters::default()
+    );
+    assert_eq!(
+        dev2.get_counters(&pk1).unwrap(),
+        HandshakeCounters::default()
+    );
+
+    // the replayed initiation and response are dropped
+    clock.advance(Duration::from_secs(1));
+    match process(&dev2, &msg1, None) {
+        Err(HandshakeError::OldTimestamp) => (),
+        _ => panic!("replayed initiation should be rejected"),
+    }
+    match process(&dev1, &msg2, None) {
+        Err(HandshakeError::InvalidState) => (),
+        _ => panic!("replayed response should be rejected"),
+    }
+
+    // as are initiations in rapid succession
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    process(&dev2, &msg1, None).unwrap();
+    clock.advance(Duration::from_millis(1));
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    match process(&dev2, &msg1, None) {
+        Err(HandshakeError::InitiationFlood) => (),
+        _ => panic!("initiation flood should be rejected"),
+    }
+
+    assert_eq!(
+        dev1.get_counters(&pk2).unwrap(),
+        HandshakeCounters {
+            response_drops: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        dev2.get_counters(&pk1).unwrap(),
+        HandshakeCounters {
+            timestamp_drops: 1,
+            flood_drops: 1,
+            ..Default::default()
+        }
+    );
+}
+
+/* Test that an initiation from an unknown public key is consumed once:
+ * the resolver is consulted (at a limited rate) while consuming the initiation
+ * and the response is created from the consumed initiation.
+ */
+#[test]
+fn handshake_provisioning() {
+    struct Resolver(PublicKey, [u8; 32], AtomicUsize);
+
+    impl PeerResolver for Resolver {
+        fn resolve(&self, pk: &PublicKey) -> Option<ProvisionedPeer> {
+            self.2.fetch_add(1, Ordering::SeqCst);
+            if pk.as_bytes() != self.0.as_bytes() {
+                return None;
+            }
+            Some(ProvisionedPeer {
+                preshared_key: self.1,
+                ..Default::default()
+            })
+        }
+    }
+
+    let (pk1, dev1, pk2, dev2): (_, Device<usize>, _, _) = setup_devices(&mut OsRng);
+
+    // responder without the initiator
+    let mut dev3: Device<usize> = Device::new();
+    dev3.set_sk(dev2.get_sk().map(|sk| StaticSecret::from(sk.to_bytes())));
+    let resolver = Arc::new(Resolver(
+        pk1,
+        dev2.get_psk(&pk1).unwrap(),
+        AtomicUsize::new(0),
+    ));
+    dev3.set_resolver(Some(resolver.clone()));
+
+    // the initiation is consumed (resolving the initiator)
+    let msg1 = begin(&dev1, &pk2).unwrap();
+    let provisional = match process(&dev3, &msg1, None) {
+        Err(HandshakeError::UnknownInitiator(provisional)) => provisional,
+        _ => panic!("initiation should be from an unknown initiator"),
+    };
+    assert_eq!(provisional.public_key().as_bytes(), pk1.as_bytes());
+    assert_eq!(resolver.2.load(Ordering::SeqCst), 1);
+
+    // add the initiator and respond to the consumed initiation
+    let mut buf = [0u8; MAX_HANDSHAKE_MSG_SIZE];
+    dev3.add_provisional(&provisional, 1).unwrap();
+    let (peer, len, ks_r) = dev3.complete(&mut OsRng, &provisional, &mut buf).unwrap();
+    assert_eq!(peer, Some(&1));
+    let (_, _, ks_i) = process(&dev1, &buf[..len.unwrap()], None).unwrap();
+    let (ks_i, ks_r) = (ks_i.unwrap(), ks_r.unwrap());
+    assert_eq!(ks_i.send, ks_r.recv, "KeyI.send != KeyR.recv");
+    assert_eq!(ks_i.recv, ks_r.send, "KeyI.recv != KeyR.send");
+    assert_eq!(resolver.2.load(Ordering::SeqCst), 1);
+
+    // resolutions are rate limited (per source address)
+    let mut dev4: Device<usize> = Device::new();
+    dev4.set_sk(dev2.get_sk().map(|sk| StaticSecret::from(sk.to_bytes())));
+    dev4.set_resolver(Some(Arc::new(Resolver(
+        pk2,
+        [0u8; 32],
+        AtomicUsize::new(0),
+    ))));
+    let mut limited = false;
+    for _ in 0..20 {
+        let msg1 = begin(&dev1, &pk2).unwrap();
+        match process(&dev4, &msg1, None) {
+            Err(HandshakeError::UnknownPublicKey) => (),
+            Err(HandshakeError::RateLimited) => limited = true,
+            _ => panic!("initiation should be rejected"),
+        }
+    }
+    assert!(limited, "resolutions should be rate limited");
+}
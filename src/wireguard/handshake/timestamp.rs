@@ -1,15 +1,170 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use x25519_dalek::PublicKey;
+
 pub type TAI64N = [u8; 12];
 
 const TAI64_EPOCH: u64 = 0x400000000000000a;
 
 pub const ZERO: TAI64N = [0u8; 12];
 
-pub fn now() -> TAI64N {
+/// Storage for the greatest accepted initiation timestamp of every peer,
+/// enabling replay protection across restarts of the device.
+pub trait TimestampStore: Send + Sync {
+    /// Load the greatest timestamp accepted from the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer
+    fn load(&self, pk: &PublicKey) -> Option<TAI64N>;
+
+    /// Store the greatest timestamp accepted from the peer
+    ///
+    /// # Arguments
+    ///
+    /// * `pk` - The public key of the peer
+    /// * `timestamp` - The timestamp of the latest accepted initiation
+    fn store(&self, pk: &PublicKey, timestamp: &TAI64N);
+}
+
+/// Stores the timestamps in a directory (one file per peer)
+///
+/// Timestamps are written (and synced to disk) by a background thread,
+/// such that storing a timestamp does not block the handshake.
+/// Pending timestamps are written before the store is dropped.
+pub struct FileTimestampStore {
+    inner: Arc<FileTimestampStoreInner>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+struct FileTimestampStoreInner {
+    dir: PathBuf,
+    pending: (Mutex<Pending>, Condvar),
+}
+
+#[derive(Default)]
+struct Pending {
+    timestamps: HashMap<[u8; 32], TAI64N>, // timestamps not yet written
+    dropped: bool,
+}
+
+impl Drop for FileTimestampStore {
+    fn drop(&mut self) {
+        // wake up the writer & wait for the pending timestamps to be written
+        let (lock, cvar) = &self.inner.pending;
+        lock.lock().unwrap().dropped = true;
+        cvar.notify_all();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl FileTimestampStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let inner = Arc::new(FileTimestampStoreInner {
+            dir,
+            pending: (Mutex::new(Pending::default()), Condvar::new()),
+        });
+        let writer = {
+            let inner = inner.clone();
+            thread::spawn(move || inner.writer())
+        };
+        Ok(FileTimestampStore {
+            inner,
+            writer: Some(writer),
+        })
+    }
+}
+
+impl FileTimestampStoreInner {
+    fn path(&self, pk: &[u8; 32]) -> PathBuf {
+        self.dir.join(hex::encode(pk))
+    }
+
+    // write to a temporary file, sync and rename (atomic replacement)
+    fn write(&self, pk: &[u8; 32], timestamp: &TAI64N) -> io::Result<()> {
+        let path = self.path(pk);
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(timestamp)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &path)?;
+        fs::File::open(&self.dir)?.sync_data()
+    }
+
+    // writes pending timestamps until the store is dropped
+    fn writer(&self) {
+        let (lock, cvar) = &self.pending;
+        loop {
+            let (timestamps, dropped) = {
+                let mut pending = lock.lock().unwrap();
+                while pending.timestamps.is_empty() && !pending.dropped {
+                    pending = cvar.wait(pending).unwrap();
+                }
+                (mem::take(&mut pending.timestamps), pending.dropped)
+            };
+            for (pk, timestamp) in timestamps.iter() {
+                if let Err(e) = self.write(pk, timestamp) {
+                    log::warn!("Failed to store timestamp {:?}: {}", self.path(pk), e);
+                }
+            }
+            if dropped {
+                return;
+            }
+        }
+    }
+}
+
+impl TimestampStore for FileTimestampStore {
+    fn load(&self, pk: &PublicKey) -> Option<TAI64N> {
+        // timestamp not yet written
+        if let Some(ts) = self
+            .inner
+            .pending
+            .0
+            .lock()
+            .unwrap()
+            .timestamps
+            .get(pk.as_bytes())
+        {
+            return Some(*ts);
+        }
+
+        let path = self.inner.path(pk.as_bytes());
+        let data = fs::read(&path).ok()?;
+        if data.len() != ZERO.len() {
+            log::warn!("Ignoring corrupt timestamp file: {:?}", path);
+            return None;
+        }
+        let mut ts = ZERO;
+        ts.copy_from_slice(&data[..]);
+        Some(ts)
+    }
+
+    fn store(&self, pk: &PublicKey, timestamp: &TAI64N) {
+        // queue for the writer (replacing any pending timestamp)
+        let (lock, cvar) = &self.inner.pending;
+        lock.lock()
+            .unwrap()
+            .timestamps
+            .insert(*pk.as_bytes(), *timestamp);
+        cvar.notify_one();
+    }
+}
+
+pub fn from_system_time(time: SystemTime) -> TAI64N {
     // get system time as duration
-    let sysnow = SystemTime::now();
-    let delta = sysnow.duration_since(UNIX_EPOCH).unwrap();
+    let delta = time.duration_since(UNIX_EPOCH).unwrap();
 
     // convert to tai64n
     let tai64_secs = delta.as_secs() + TAI64_EPOCH;
@@ -23,10 +178,6 @@ pub fn now() -> TAI64N {
 }
 
 pub fn compare(old: &TAI64N, new: &TAI64N) -> bool {
-    for i in 0..12 {
-        if new[i] > old[i] {
-            return true;
-        }
-    }
-    false
+    // big-endian encoding: lexicographic order is chronological order
+    new > old
 }
@@ -1,4 +1,5 @@
 use super::super::types::KeyPair;
+use super::device::Provisional;
 
 use std::error::Error;
 use std::fmt;
@@ -32,12 +33,24 @@ impl Error for ConfigError {
     }
 }
 
+// handshake counters
+
+/// Handshake messages from (or for) a peer which were dropped, by reason
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandshakeCounters {
+    pub timestamp_drops: u64, // initiations with a replayed (old) timestamp
+    pub flood_drops: u64,     // initiations exceeding the initiation rate
+    pub admission_drops: u64, // initiations rejected by the admission policy
+    pub response_drops: u64,  // responses not matching the initiation (or failing to decrypt)
+}
+
 // handshake error
 
 #[derive(Debug)]
 pub enum HandshakeError {
     DecryptionFailure,
     UnknownPublicKey,
+    UnknownInitiator(Box<Provisional>), // authenticated initiation from a peer not in the device
     UnknownReceiverId,
     InvalidMessageFormat,
     InvalidSharedSecret,
@@ -46,6 +59,7 @@ pub enum HandshakeError {
     InvalidMac1,
     RateLimited,
     InitiationFlood,
+    AdmissionDenied,
 }
 
 impl fmt::Display for HandshakeError {
@@ -54,6 +68,9 @@ impl fmt::Display for HandshakeError {
             HandshakeError::InvalidSharedSecret => write!(f, "Zero shared secret"),
             HandshakeError::DecryptionFailure => write!(f, "Failed to AEAD:OPEN"),
             HandshakeError::UnknownPublicKey => write!(f, "Unknown public key"),
+            HandshakeError::UnknownInitiator(_) => {
+                write!(f, "Initiation from public key not in device")
+            }
             HandshakeError::UnknownReceiverId => {
                 write!(f, "Receiver id not allocated to any handshake")
             }
@@ -65,6 +82,9 @@ impl fmt::Display for HandshakeError {
             HandshakeError::InitiationFlood => {
                 write!(f, "Message was dropped because of initiation flood")
             }
+            HandshakeError::AdmissionDenied => {
+                write!(f, "Initiation was rejected by the admission policy")
+            }
         }
     }
 }
@@ -81,7 +101,7 @@ impl Error for HandshakeError {
 
 pub type Output<'a, O> = (
     Option<&'a O>,   // external identifier associated with peer
-    Option<Vec<u8>>, // message to send
+    Option<usize>,   // length of message to send (written to the reply buffer)
     Option<KeyPair>, // resulting key-pair of successful handshake
 );
 
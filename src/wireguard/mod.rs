@@ -7,23 +7,52 @@
  * and the crypto-key router code together,
  * e.g. every WireGuard peer consists of a handshake and router peer.
  */
-mod constants;
+mod clock;
+pub(crate) mod constants;
 mod handshake;
 mod peer;
+mod pool;
+mod provision;
 mod queue;
 mod router;
+mod stats;
 mod timers;
 mod types;
+mod wheel;
 mod wireguard;
 mod workers;
 
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
 
 // represents a WireGuard interface
-pub use wireguard::WireGuard;
+pub use wireguard::{DeviceRng, WireGuard};
 
-#[cfg(test)]
+// injectable sources of time
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use wheel::{HjulWheel, SimulatedWheel, Timer, TimerWheel};
+
+// on-demand provisioning of peers
+pub use provision::{PeerResolver, ProvisionedPeer};
+
+// handshake admission policy and persistent replay protection
+pub use handshake::{Admission, FileTimestampStore, TimestampStore, TAI64N};
+
+// per-peer traffic shaping
+pub use router::{Shaping, ShapingPolicy};
+
+// per-peer packet and drop counters
+pub use handshake::HandshakeCounters;
+pub use router::PacketCounters;
+
+// device-wide statistics
+pub use stats::DeviceStats;
+
+// entry points for the fuzzing harnesses
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+
+#[cfg(any(test, feature = "fuzzing"))]
 use super::platform::dummy;
 
 use super::platform::{tun, udp, Endpoint};
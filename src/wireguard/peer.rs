@@ -30,9 +30,14 @@ pub struct PeerInner<T: Tun, B: UDP> {
     pub last_handshake_sent: Mutex<Instant>,                // instant for last handshake
     pub handshake_queued: AtomicBool,                       // is a handshake job currently queued?
 
+    // provisioned on-demand (evicted when idle)
+    pub provisioned: AtomicBool,
+    pub last_activity: Mutex<Instant>, // instant of the last authenticated packet sent/received
+
     // stats and configuration
-    pub rx_bytes: AtomicU64, // received bytes
-    pub tx_bytes: AtomicU64, // transmitted bytes
+    pub rx_bytes: AtomicU64,         // received bytes
+    pub tx_bytes: AtomicU64,         // transmitted bytes
+    pub name: Mutex<Option<String>>, // name of the peer (for monitoring)
 
     // timer model
     pub timers: RwLock<Timers>,
@@ -49,12 +54,13 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
 
         // the function is rate limited
         {
+            let now = self.wg.clock.now();
             let mut lhs = self.last_handshake_sent.lock();
-            if lhs.elapsed() < REKEY_TIMEOUT {
+            if now.saturating_duration_since(*lhs) < REKEY_TIMEOUT {
                 log::trace!("{} : packet_send_handshake_initiation, rate-limited!", self);
                 return;
             }
-            *lhs = Instant::now();
+            *lhs = now;
         }
 
         // create a new handshake job for the peer
@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+/* A pool of reusable buffers.
+ *
+ * Buffers are handed out empty, but with (at least) the capacity of the pool,
+ * hence extending a buffer up to this capacity does not allocate.
+ * Buffers are returned to the pool explicitly (by a call to put),
+ * if the pool is full the buffer is simply deallocated.
+ *
+ * The capacity can be raised while buffers are in use (e.g. when the MTU changes):
+ * retained buffers are then grown when handed out again.
+ */
+pub struct BufferPool {
+    capacity: AtomicUsize,
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// Create a new BufferPool instance
+    ///
+    /// # Arguments
+    ///
+    /// - `buffers`: maximum number of buffers retained by the pool
+    /// - `capacity`: capacity of each buffer
+    pub fn new(buffers: usize, capacity: usize) -> Self {
+        let (tx, rx) = bounded(buffers);
+        BufferPool {
+            capacity: AtomicUsize::new(capacity),
+            tx,
+            rx,
+        }
+    }
+
+    /// Change the capacity of the buffers handed out by the pool
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    /// Obtain an empty buffer from the pool,
+    /// allocates a new buffer if the pool is empty.
+    pub fn get(&self) -> Vec<u8> {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        match self.rx.try_recv() {
+            Ok(mut buf) => {
+                buf.reserve(capacity);
+                buf
+            }
+            Err(_) => Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Return a buffer to the pool
+    pub fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() >= self.capacity.load(Ordering::Relaxed) {
+            buf.clear();
+            let _ = self.tx.try_send(buf);
+        }
+    }
+}
+
+/* Empties a vector, returning its allocation as a vector of another element type.
+ *
+ * Enables vectors of borrowed values (e.g. the slices handed to a batched read)
+ * to be reused between iterations of a loop, although the borrows end every iteration.
+ * The allocation is reused in-place when the element types have the same layout
+ * (e.g. references with different lifetimes).
+ */
+pub fn recycle<A, B>(mut vec: Vec<A>) -> Vec<B> {
+    vec.clear();
+    vec.into_iter().map(|_| unreachable!()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(2, 64);
+
+        // buffers are reused
+        let mut buf = pool.get();
+        assert!(buf.capacity() >= 64);
+        buf.extend_from_slice(&[1u8; 64]);
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        let buf = pool.get();
+        assert_eq!(buf.len(), 0);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // buffers too small are discarded
+        pool.put(Vec::with_capacity(8));
+        assert!(pool.get().capacity() >= 64);
+
+        // excess buffers are discarded
+        pool.put(Vec::with_capacity(64));
+        pool.put(Vec::with_capacity(64));
+        pool.put(Vec::with_capacity(64));
+        assert_eq!(pool.rx.len(), 2);
+    }
+
+    #[test]
+    fn test_recycle() {
+        let mut buf = vec![0u8; 64];
+        let mut slices: Vec<&mut [u8]> = Vec::with_capacity(16);
+        let ptr = slices.as_ptr() as usize;
+
+        slices.extend(buf.chunks_mut(8));
+        assert_eq!(slices.len(), 8);
+        let slices: Vec<&[u8]> = recycle(slices);
+        assert_eq!(slices.len(), 0);
+        assert_eq!(slices.capacity(), 16);
+        assert_eq!(slices.as_ptr() as usize, ptr);
+
+        // the buffer is no longer borrowed
+        buf[0] = 1;
+    }
+
+    #[test]
+    fn test_buffer_pool_capacity() {
+        let pool = BufferPool::new(2, 64);
+        pool.put(Vec::with_capacity(64));
+
+        // retained buffers are grown when the capacity is raised
+        pool.set_capacity(128);
+        assert!(pool.get().capacity() >= 128);
+        assert!(pool.get().capacity() >= 128);
+
+        // buffers below the new capacity are discarded
+        pool.put(Vec::with_capacity(64));
+        assert_eq!(pool.rx.len(), 0);
+    }
+}
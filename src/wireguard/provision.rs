@@ -0,0 +1,151 @@
+/* On-demand provisioning of peers.
+ *
+ * Rather than configuring every peer in advance,
+ * an application may provide a resolver which is consulted
+ * whenever an authenticated handshake initiation arrives from a public key unknown to the device.
+ * The resolver is called while consuming the initiation (reusing the decrypted static key),
+ * at a limited rate per source address and only if the admission policy admits the initiator.
+ * If the resolver returns a configuration, the peer is inserted
+ * and the response is created from the consumed initiation.
+ *
+ * Provisioned peers are evicted after being idle (not sending/receiving any authenticated packet)
+ * for a configurable duration. Peers explicitly added through the configuration interface
+ * are never evicted.
+ */
+
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+use x25519_dalek::PublicKey;
+
+use super::constants::PROVISIONING_EVICTION_INTERVAL;
+use super::handshake::Provisional;
+use super::tun::Tun;
+use super::udp::UDP;
+use super::wireguard::{WireGuard, WireguardInner};
+
+/// Configuration of a peer returned by a resolver
+#[derive(Clone, Debug, Default)]
+pub struct ProvisionedPeer {
+    pub preshared_key: [u8; 32],
+    pub allowed_ips: Vec<(IpAddr, u32)>,
+    pub persistent_keepalive_interval: u64,
+}
+
+/// Resolves the configuration of a peer from its public key,
+/// e.g. by querying a database.
+///
+/// The resolver is called from the handshake workers while holding the peer map (read) lock,
+/// a slow resolver hence delays the processing of other handshake messages
+/// and changes to the configuration.
+pub trait PeerResolver: Send + Sync {
+    /// Lookup the configuration of a peer
+    ///
+    /// # Arguments
+    ///
+    /// - `pk`: The public key of the (authenticated) initiator
+    ///
+    /// # Returns
+    ///
+    /// The configuration of the peer,
+    /// or None if the peer should not be allowed to connect.
+    fn resolve(&self, pk: &PublicKey) -> Option<ProvisionedPeer>;
+}
+
+pub struct Provisioning {
+    pub idle_timeout: Duration,
+}
+
+impl<T: Tun, B: UDP> WireGuard<T, B> {
+    /// Set (or remove) the resolver used to provision unknown initiators.
+    ///
+    /// # Arguments
+    ///
+    /// - `resolver`: The resolver, None disables on-demand provisioning
+    /// - `idle_timeout`: Duration after which an idle provisioned peer is removed
+    pub fn set_peer_resolver(
+        &self,
+        resolver: Option<Arc<dyn PeerResolver>>,
+        idle_timeout: Duration,
+    ) {
+        let mut peers = self.peers.write();
+        *self.provisioning.write() = resolver.as_ref().map(|_| Provisioning { idle_timeout });
+        peers.set_resolver(resolver);
+
+        // start eviction thread (once)
+        if !self.evictor_running.swap(true, Ordering::SeqCst) {
+            let wg = self.downgrade();
+            thread::spawn(move || evictor(wg));
+        }
+    }
+
+    /// Add the initiator of a consumed initiation permitted by the resolver
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the peer is now present in the device
+    pub fn provision_peer(&self, provisional: &Provisional) -> bool {
+        let pk = provisional.public_key();
+
+        // add the peer (could have been added concurrently)
+        if !self.add_peer_inner(*pk, Some(provisional)) {
+            return self.peers.read().contains_key(pk);
+        }
+        log::debug!("{} : provisioned new peer", self);
+
+        // configure the new peer
+        let config = provisional.config();
+        if let Some(peer) = self.peers.read().get(pk) {
+            for (ip, masklen) in config.allowed_ips.iter() {
+                peer.add_allowed_ip(*ip, *masklen);
+            }
+            peer.opaque()
+                .set_persistent_keepalive_interval(config.persistent_keepalive_interval);
+        }
+        true
+    }
+
+    /// Remove every provisioned peer which has been idle for longer than the idle timeout
+    pub fn evict_idle_peers(&self) {
+        let idle_timeout = match self.provisioning.read().as_ref() {
+            Some(provisioning) => provisioning.idle_timeout,
+            None => return,
+        };
+
+        // collect idle peers
+        let idle: Vec<PublicKey> = self
+            .peers
+            .read()
+            .iter()
+            .filter(|(_, peer)| {
+                peer.provisioned.load(Ordering::Relaxed)
+                    && self
+                        .clock
+                        .now()
+                        .saturating_duration_since(*peer.last_activity.lock())
+                        > idle_timeout
+            })
+            .map(|(pk, _)| pk)
+            .collect();
+
+        // remove idle peers
+        for pk in idle.iter() {
+            log::debug!("{} : evicting idle provisioned peer", self);
+            self.remove_peer(pk);
+        }
+    }
+}
+
+// Periodically evicts idle peers until the device is dropped
+fn evictor<T: Tun, B: UDP>(wg: Weak<WireguardInner<T, B>>) {
+    loop {
+        thread::sleep(PROVISIONING_EVICTION_INTERVAL);
+        match wg.upgrade() {
+            Some(inner) => WireGuard::from_inner(inner).evict_idle_peers(),
+            None => return,
+        }
+    }
+}
@@ -32,6 +32,16 @@ impl<T> ParallelQueue<T> {
         }
     }
 
+    /// Number of elements waiting in the queue
+    pub fn queued(&self) -> usize {
+        self.queue
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|s| s.len())
+            .unwrap_or(0)
+    }
+
     pub fn close(&self) {
         *self.queue.lock().unwrap() = None;
     }
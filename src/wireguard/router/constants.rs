@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 // WireGuard semantics constants
 
 pub const MAX_QUEUED_PACKETS: usize = 1024;
@@ -7,3 +9,23 @@ pub const MAX_QUEUED_PACKETS: usize = 1024;
 pub const PARALLEL_QUEUE_SIZE: usize = 4 * MAX_QUEUED_PACKETS;
 
 pub const INORDER_QUEUE_SIZE: usize = MAX_QUEUED_PACKETS;
+
+pub const SEQUENTIAL_BATCH_SIZE: usize = 32;
+
+pub const BUFFER_POOL_SIZE: usize = PARALLEL_QUEUE_SIZE + INORDER_QUEUE_SIZE;
+
+// traffic shaping constants
+
+pub const SHAPING_BURST: Duration = Duration::from_millis(50); // default burst (transmission time at the rate limit)
+
+pub const SHAPING_MAX_DELAY: Duration = Duration::from_millis(100);
+
+pub const SHAPING_QUEUE_SIZE: usize = MAX_QUEUED_PACKETS;
+
+// ICMP constants
+
+pub const ICMP_BURST: u32 = 10; // errors sent to a source in a burst
+
+pub const ICMP_INTERVAL: Duration = Duration::from_millis(100); // interval between errors to a source (after a burst)
+
+pub const ICMP_MAX_SOURCES: usize = 4096; // sources tracked by the rate limit
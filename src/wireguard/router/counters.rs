@@ -0,0 +1,72 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Transport messages exchanged with a peer and dropped, by reason
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketCounters {
+    pub tx_packets: u64,           // messages sent (including keepalives)
+    pub rx_packets: u64,           // messages received (including keepalives)
+    pub tx_no_endpoint_drops: u64, // messages encrypted for a peer without an endpoint
+    pub tx_send_error_drops: u64,  // messages which could not be written to the bind
+    pub tx_staged_drops: u64,      // packets evicted from the (full) staging queue
+    pub tx_queue_drops: u64,       // messages dropped since the outbound in-order queue was full
+    pub tx_mtu_drops: u64,         // packets exceeding the MTU of the peer
+    pub rx_queue_drops: u64,       // messages dropped since the inbound in-order queue was full
+    pub rx_decryption_drops: u64,  // messages failing authentication
+    pub rx_allowed_ips_drops: u64, // packets from a source address not allowed for the peer
+    pub rx_replay_drops: u64,      // messages rejected by the anti-replay window
+}
+
+pub(super) struct Counters {
+    pub tx_packets: AtomicU64,
+    pub rx_packets: AtomicU64,
+    pub tx_no_endpoint_drops: AtomicU64,
+    pub tx_send_error_drops: AtomicU64,
+    pub tx_staged_drops: AtomicU64,
+    pub tx_queue_drops: AtomicU64,
+    pub tx_mtu_drops: AtomicU64,
+    pub rx_queue_drops: AtomicU64,
+    pub rx_decryption_drops: AtomicU64,
+    pub rx_allowed_ips_drops: AtomicU64,
+    pub rx_replay_drops: AtomicU64,
+}
+
+// increment a counter
+#[inline(always)]
+pub(super) fn count(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters {
+            tx_packets: AtomicU64::new(0),
+            rx_packets: AtomicU64::new(0),
+            tx_no_endpoint_drops: AtomicU64::new(0),
+            tx_send_error_drops: AtomicU64::new(0),
+            tx_staged_drops: AtomicU64::new(0),
+            tx_queue_drops: AtomicU64::new(0),
+            tx_mtu_drops: AtomicU64::new(0),
+            rx_queue_drops: AtomicU64::new(0),
+            rx_decryption_drops: AtomicU64::new(0),
+            rx_allowed_ips_drops: AtomicU64::new(0),
+            rx_replay_drops: AtomicU64::new(0),
+        }
+    }
+
+    pub fn snapshot(&self) -> PacketCounters {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        PacketCounters {
+            tx_packets: load(&self.tx_packets),
+            rx_packets: load(&self.rx_packets),
+            tx_no_endpoint_drops: load(&self.tx_no_endpoint_drops),
+            tx_send_error_drops: load(&self.tx_send_error_drops),
+            tx_staged_drops: load(&self.tx_staged_drops),
+            tx_queue_drops: load(&self.tx_queue_drops),
+            tx_mtu_drops: load(&self.tx_mtu_drops),
+            rx_queue_drops: load(&self.rx_queue_drops),
+            rx_decryption_drops: load(&self.rx_decryption_drops),
+            rx_allowed_ips_drops: load(&self.rx_allowed_ips_drops),
+            rx_replay_drops: load(&self.rx_replay_drops),
+        }
+    }
+}
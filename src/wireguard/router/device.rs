@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::ops::Deref;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 
@@ -9,16 +9,23 @@ use zerocopy::LayoutVerified;
 
 use super::anti_replay::AntiReplay;
 
-use super::constants::PARALLEL_QUEUE_SIZE;
+use super::constants::{BUFFER_POOL_SIZE, PARALLEL_QUEUE_SIZE};
+use super::counters::count;
+use super::icmp::Icmp;
+use super::ip::inner_length;
 use super::messages::{TransportHeader, TYPE_TRANSPORT};
+use super::mss::MssClamp;
 use super::peer::{new_peer, Peer, PeerHandle};
 use super::types::{Callbacks, RouterError};
 use super::SIZE_MESSAGE_PREFIX;
 
 use super::receive::ReceiveJob;
 use super::route::RoutingTable;
+use super::shaper::{delay_worker, DelayLine};
 use super::worker::{worker, JobUnion};
 
+use super::super::clock::{Clock, SystemClock};
+use super::super::pool::BufferPool;
 use super::super::{tun, udp, Endpoint, KeyPair};
 use super::ParallelQueue;
 
@@ -32,9 +39,25 @@ pub struct DeviceInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer
     // routing
     pub(super) recv: RwLock<HashMap<u32, Arc<DecryptionState<E, C, T, B>>>>, /* receiver id -> decryption state */
     pub(super) table: RoutingTable<Peer<E, C, T, B>>,
+    pub(super) peers: Mutex<Vec<Peer<E, C, T, B>>>, /* every peer (removed when the handle is dropped) */
 
     // work queue
     pub(super) work: ParallelQueue<JobUnion<E, C, T, B>>,
+
+    // packet buffers (returned once the message has been written)
+    pub(super) buffers: BufferPool,
+
+    // packets delayed by traffic shaping
+    pub(super) delayed: DelayLine<E, C, T, B>,
+
+    // ICMP errors for packets which cannot be routed (or exceed the MTU)
+    pub(super) icmp: Icmp,
+
+    // clamping of the TCP MSS to the MTU
+    pub(super) mss: MssClamp,
+
+    // source of time (traffic shaping and rate limiting of ICMP errors)
+    pub(super) clock: Arc<dyn Clock>,
 }
 
 pub struct EncryptionState {
@@ -89,8 +112,9 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop
     fn drop(&mut self) {
         log::debug!("router: dropping device");
 
-        // close worker queue
+        // close worker queue and delay line
         self.state.work.close();
+        self.state.delayed.close();
 
         // join all worker threads
         while let Some(handle) = self.handles.pop() {
@@ -103,6 +127,15 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<E, C, T, B> {
     pub fn new(num_workers: usize, tun: T) -> DeviceHandle<E, C, T, B> {
+        Self::with_clock(num_workers, tun, Arc::new(SystemClock()))
+    }
+
+    /// Create a new router with the given source of time
+    pub fn with_clock(
+        num_workers: usize,
+        tun: T,
+        clock: Arc<dyn Clock>,
+    ) -> DeviceHandle<E, C, T, B> {
         let (work, mut consumers) = ParallelQueue::new(num_workers, PARALLEL_QUEUE_SIZE);
         let device = Device {
             inner: Arc::new(DeviceInner {
@@ -111,6 +144,12 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
                 outbound: RwLock::new((true, None)),
                 recv: RwLock::new(HashMap::new()),
                 table: RoutingTable::new(),
+                peers: Mutex::new(vec![]),
+                buffers: BufferPool::new(BUFFER_POOL_SIZE, 0),
+                delayed: DelayLine::new(),
+                icmp: Icmp::new(),
+                mss: MssClamp::new(),
+                clock,
             }),
         };
 
@@ -126,6 +165,12 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
             "workers does not match consumers"
         );
 
+        // start delay line worker
+        {
+            let device = device.clone();
+            threads.push(thread::spawn(move || delay_worker(device)));
+        }
+
         // return exported device handle
         DeviceHandle {
             state: device,
@@ -156,11 +201,81 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         self.state.outbound.write().0 = true;
     }
 
+    /// Obtain an empty packet buffer from the pool of the router.
+    ///
+    /// Buffers passed to `send` and `recv` are returned to the pool once processed,
+    /// hence reading packets into buffers from the pool avoids allocation on the data path.
+    pub fn buffer(&self) -> Vec<u8> {
+        self.state.buffers.get()
+    }
+
+    /// Number of jobs waiting for a worker (in the parallel queue)
+    pub fn queue_depth(&self) -> usize {
+        self.state.work.queued()
+    }
+
+    /// Enable/disable ICMP "destination unreachable" errors for packets without a cryptokey route
+    /// (written back to the TUN device, rate limited per source)
+    pub fn set_icmp_unreachable(&self, enabled: bool) {
+        self.state.icmp.set_enabled(enabled);
+    }
+
+    pub fn icmp_unreachable(&self) -> bool {
+        self.state.icmp.enabled()
+    }
+
+    /// Enable/disable clamping of the MSS of TCP SYN segments crossing the tunnel (in both directions)
+    /// to the MTU of the device, or the MTU override of the peer
+    pub fn set_mss_clamp(&self, enabled: bool) {
+        self.state.mss.set_enabled(enabled);
+    }
+
+    pub fn mss_clamp(&self) -> bool {
+        self.state.mss.enabled()
+    }
+
+    /// Set the MTU of the device (used to clamp the MSS)
+    pub fn set_mtu(&self, mtu: usize) {
+        self.state.mss.set_mtu(mtu);
+    }
+
+    /// Reject a packet exceeding the MTU:
+    /// writes an ICMP "fragmentation needed" (IPv4 with DF set) or "packet too big" (IPv6) error
+    /// back to the TUN device (rate limited per source)
+    ///
+    /// # Arguments
+    ///
+    /// - packet: The IP packet
+    /// - mtu: The MTU exceeded by the packet
+    pub fn packet_too_big(&self, packet: &[u8], mtu: usize) {
+        if let Some(error) = self.state.icmp.too_big(packet, mtu, self.state.clock.now()) {
+            let _ = self.state.inbound.write(&error).map_err(|e| {
+                log::debug!("failed to write ICMP packet too big to TUN: {:?}", e);
+            });
+        }
+    }
+
+    /// Set the (minimum) capacity of the packet buffers handed out by `buffer`
+    pub fn set_buffer_capacity(&self, capacity: usize) {
+        self.state.buffers.set_capacity(capacity);
+    }
+
     /// A new secret key has been set for the device.
     /// According to WireGuard semantics, this should cause all "sending" keys to be discarded.
+    ///
+    /// The key-material of every peer is zeroed
+    /// (sessions derived from the old key must neither be used for encryption nor decryption),
+    /// while staged packets are kept and a new key is requested for every peer which held a session.
     pub fn clear_sending_keys(&self) {
         log::debug!("Clear sending keys");
-        // TODO: Implement. Consider: The device does not have an explicit list of peers
+
+        // release the lock before the callbacks
+        let peers = self.state.peers.lock().clone();
+        for peer in peers {
+            if peer.zero_keys() {
+                C::need_key(&peer.opaque);
+            }
+        }
     }
 
     /// Adds a new peer to the device
@@ -177,7 +292,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
     /// # Arguments
     ///
     /// - msg: IP packet to crypt-key route
-    pub fn send(&self, msg: Vec<u8>) -> Result<(), RouterError> {
+    pub fn send(&self, mut msg: Vec<u8>) -> Result<(), RouterError> {
         debug_assert!(msg.len() > SIZE_MESSAGE_PREFIX);
         log::trace!(
             "send, packet = {}",
@@ -188,14 +303,39 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         let packet = &msg[SIZE_MESSAGE_PREFIX..];
 
         // lookup peer based on IP packet destination address
-        let peer = self
-            .state
-            .table
-            .get_route(packet)
-            .ok_or(RouterError::NoCryptoKeyRoute)?;
-
-        // schedule for encryption and transmission to peer
-        peer.send(msg, true);
+        let peer = match self.state.table.get_route(packet) {
+            Some(peer) => peer,
+            None => {
+                // notify the source (if enabled)
+                if let Some(error) = self.state.icmp.unreachable(packet, self.state.clock.now()) {
+                    let _ = self.state.inbound.write(&error).map_err(|e| {
+                        log::debug!("failed to write ICMP unreachable to TUN: {:?}", e);
+                    });
+                }
+                self.state.buffers.put(msg);
+                return Err(RouterError::NoCryptoKeyRoute);
+            }
+        };
+
+        // enforce the MTU of the peer (if overridden)
+        let mtu = peer.mtu.load(Ordering::Relaxed);
+        if mtu > 0 && packet.len() > mtu {
+            if !matches!(inner_length(packet), Some(len) if len <= mtu) {
+                self.packet_too_big(packet, mtu);
+                count(&peer.counters.tx_mtu_drops, 1);
+                self.state.buffers.put(msg);
+                return Err(RouterError::PacketTooBig);
+            }
+
+            // the packet fits: limit the padding to the MTU
+            msg.truncate(SIZE_MESSAGE_PREFIX + mtu);
+        }
+
+        // clamp the MSS of TCP connections to the MTU
+        self.state.mss.apply(&mut msg[SIZE_MESSAGE_PREFIX..], mtu);
+
+        // shape, then schedule for encryption and transmission to peer
+        peer.send_shaped(msg);
         Ok(())
     }
 
@@ -214,11 +354,13 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         let (header, _) = match LayoutVerified::new_from_prefix(&msg[..]) {
             Some(v) => v,
             None => {
+                self.state.buffers.put(msg);
                 return Err(RouterError::MalformedTransportMessage);
             }
         };
 
         let header: LayoutVerified<&[u8], TransportHeader> = header;
+        let receiver = header.f_receiver.get();
 
         debug_assert!(
             header.f_type.get() == TYPE_TRANSPORT as u32,
@@ -233,9 +375,13 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
 
         // lookup peer based on receiver id
         let dec = self.state.recv.read();
-        let dec = dec
-            .get(&header.f_receiver.get())
-            .ok_or(RouterError::UnknownReceiverId)?;
+        let dec = match dec.get(&receiver) {
+            Some(dec) => dec,
+            None => {
+                self.state.buffers.put(msg);
+                return Err(RouterError::UnknownReceiverId);
+            }
+        };
 
         // create inbound job
         let job = ReceiveJob::new(msg, dec.clone(), src);
@@ -244,6 +390,8 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DeviceHandle<
         // 2. then add to parallel work queue (wait if full)
         if dec.peer.inbound.push(job.clone()) {
             self.state.work.send(JobUnion::Inbound(job));
+        } else {
+            count(&dec.peer.counters.rx_queue_drops, 1);
         }
         Ok(())
     }
@@ -0,0 +1,300 @@
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Instant;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use zerocopy::LayoutVerified;
+
+use super::super::dummy;
+use super::super::types::{Key, KeyPair};
+use super::messages::{TransportHeader, TYPE_TRANSPORT};
+use super::route::RoutingTable;
+use super::types::{Callbacks, RouterError};
+use super::Device;
+
+// keys of the (single) peer of the router
+const RECV_KEY: [u8; 32] = [0x52u8; 32];
+const RECV_ID: u32 = 0x76636572;
+
+const FLAG_ENCRYPT: u8 = 1 << 0;
+
+struct FuzzCallbacks();
+
+impl Callbacks for FuzzCallbacks {
+    type Opaque = ();
+    fn send(_: &Self::Opaque, _: usize, _: bool, _: &Arc<KeyPair>, _: u64) {}
+    fn recv(_: &Self::Opaque, _: usize, _: bool, _: &Arc<KeyPair>) {}
+    fn need_key(_: &Self::Opaque) {}
+    fn key_confirmed(_: &Self::Opaque) {}
+}
+
+#[derive(Clone, Debug)]
+pub struct RecvInput {
+    pub encrypt: bool, // encrypt the data as a valid transport message for the peer
+    pub counter: u64,  // counter of the transport message (when encrypting)
+    pub data: Vec<u8>, // inner packet or the raw transport message
+}
+
+impl RecvInput {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![if self.encrypt { FLAG_ENCRYPT } else { 0 }];
+        bytes.extend_from_slice(&self.counter.to_le_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+}
+
+impl Arbitrary for RecvInput {
+    fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self> {
+        let flags: u8 = u.arbitrary()?;
+        let counter = u.arbitrary()?;
+        let data = u.get_bytes(u.len())?.to_vec();
+        Ok(RecvInput {
+            encrypt: flags & FLAG_ENCRYPT != 0,
+            counter,
+            data,
+        })
+    }
+}
+
+// Create a transport message for the peer (as done by the sender of the peer)
+fn seal(counter: u64, packet: &[u8]) -> Vec<u8> {
+    let mut msg = vec![0u8; mem::size_of::<TransportHeader>()];
+    {
+        let mut header: LayoutVerified<&mut [u8], TransportHeader> =
+            LayoutVerified::new(&mut msg[..]).unwrap();
+        header.f_type.set(TYPE_TRANSPORT);
+        header.f_receiver.set(RECV_ID);
+        header.f_counter.set(counter);
+    }
+
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &RECV_KEY[..]).unwrap());
+    let mut ct = packet.to_vec();
+    let tag = key
+        .seal_in_place_separate_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut ct)
+        .unwrap();
+    msg.extend_from_slice(&ct);
+    msg.extend_from_slice(tag.as_ref());
+    msg
+}
+
+/// Receive a (possibly valid) transport message
+///
+/// # Returns
+///
+/// The result of router::Device::recv
+/// (the message is subsequently decrypted by the workers of the router)
+pub fn recv(input: &RecvInput) -> std::result::Result<(), RouterError> {
+    let msg = if input.encrypt {
+        seal(input.counter, &input.data)
+    } else {
+        input.data.clone()
+    };
+
+    // the message type is checked by the demultiplexer
+    if msg.len() < 4 || msg[..4] != TYPE_TRANSPORT.to_le_bytes() {
+        return Err(RouterError::MalformedTransportMessage);
+    }
+
+    // create router with a single peer
+    let (_fake, _reader, tun_writer, _) = dummy::TunTest::create(false);
+    let router: Device<_, FuzzCallbacks, _, _> = Device::new(1, tun_writer);
+    router.set_outbound_writer(dummy::VoidBind::new());
+
+    let peer = router.new_peer(());
+    peer.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+    peer.add_allowed_ip("fd00::".parse().unwrap(), 8);
+    peer.add_keypair(KeyPair {
+        birth: Instant::now(),
+        initiator: false,
+        send: Key {
+            key: [0x53u8; 32],
+            id: 0x646e6573,
+        },
+        recv: Key {
+            key: RECV_KEY,
+            id: RECV_ID,
+        },
+    });
+
+    // dropping the router waits for the workers to process the message
+    router.recv(dummy::UnitEndpoint::new(), msg)
+}
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub ip: IpAddr,
+    pub masklen: u32,
+    pub peer: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct RouteInput {
+    pub routes: Vec<Route>, // allowed IPs of the peers
+    pub packet: Vec<u8>,    // packet to route
+}
+
+const SIZE_ROUTE: usize = 1 + 16 + 1 + 1;
+
+impl RouteInput {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.routes.len() as u8];
+        for route in self.routes.iter() {
+            let mut ip = [0u8; 16];
+            match route.ip {
+                IpAddr::V4(addr) => {
+                    bytes.push(0);
+                    ip[..4].copy_from_slice(&addr.octets());
+                }
+                IpAddr::V6(addr) => {
+                    bytes.push(1);
+                    ip.copy_from_slice(&addr.octets());
+                }
+            }
+            bytes.extend_from_slice(&ip);
+            bytes.push(route.masklen as u8);
+            bytes.push(route.peer as u8);
+        }
+        bytes.extend_from_slice(&self.packet);
+        bytes
+    }
+}
+
+impl Arbitrary for RouteInput {
+    fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self> {
+        let num: u8 = u.arbitrary()?;
+        let mut routes = Vec::with_capacity(num as usize);
+        for _ in 0..num {
+            if u.len() < SIZE_ROUTE {
+                break;
+            }
+            let version: u8 = u.arbitrary()?;
+            let ip: [u8; 16] = u.arbitrary()?;
+            let masklen: u8 = u.arbitrary()?;
+            let peer: u8 = u.arbitrary()?;
+
+            // the configuration interface ensures that the mask length is valid
+            let (ip, masklen) = if version & 1 == 0 {
+                let mut v4 = [0u8; 4];
+                v4.copy_from_slice(&ip[..4]);
+                (IpAddr::V4(Ipv4Addr::from(v4)), masklen as u32 % 33)
+            } else {
+                (IpAddr::V6(Ipv6Addr::from(ip)), masklen as u32 % 129)
+            };
+            routes.push(Route {
+                ip,
+                masklen,
+                peer: peer as usize,
+            });
+        }
+        let packet = u.get_bytes(u.len())?.to_vec();
+        Ok(RouteInput { routes, packet })
+    }
+}
+
+/// Route a packet using the crypto-key routing table
+pub fn route(input: &RouteInput) {
+    let table: RoutingTable<usize> = RoutingTable::new();
+    for route in input.routes.iter() {
+        table.insert(route.ip, route.masklen, route.peer);
+    }
+
+    table.get_route(&input.packet);
+    for route in input.routes.iter() {
+        table.check_route(&route.peer, &input.packet);
+        table.list(&route.peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::fuzzing::write_corpus;
+    use super::super::super::tests::make_packet;
+    use super::*;
+
+    // packets to and from the subnets used in the router tests
+    fn packets() -> Vec<Vec<u8>> {
+        let addrs = [
+            ("192.168.1.20", "192.168.1.21"),
+            ("172.133.133.133", "172.133.133.132"),
+            ("fd00::1", "fd00::2"),
+            ("2001:db8::ff00:42:3242", "2001:db8::ff00:42:0660"),
+        ];
+        addrs
+            .iter()
+            .enumerate()
+            .map(|(id, (src, dst))| {
+                make_packet(100, src.parse().unwrap(), dst.parse().unwrap(), id as u64)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fuzzing_router_seeds() {
+        // valid transport messages
+        let mut inputs = vec![];
+        for (counter, packet) in packets().into_iter().enumerate() {
+            inputs.push(RecvInput {
+                encrypt: true,
+                counter: counter as u64,
+                data: packet.clone(),
+            });
+            inputs.push(RecvInput {
+                encrypt: false,
+                counter: 0,
+                data: seal(counter as u64, &packet),
+            });
+        }
+        for input in inputs.iter() {
+            let decoded = RecvInput::arbitrary_take_rest(Unstructured::new(&input.encode()));
+            assert_eq!(format!("{:?}", decoded.unwrap()), format!("{:?}", input));
+            assert!(recv(input).is_ok());
+        }
+        write_corpus(
+            "router_recv",
+            &inputs
+                .iter()
+                .map(|input| input.encode())
+                .collect::<Vec<_>>(),
+        );
+
+        // routing tables (as in the router tests)
+        let routes = vec![
+            ("192.168.1.0", 24, 0),
+            ("172.133.133.133", 32, 1),
+            ("2001:db8::ff00:42:0000", 112, 0),
+            ("2001:db8::ff00:42:8000", 113, 1),
+        ];
+        let routes: Vec<Route> = routes
+            .into_iter()
+            .map(|(ip, masklen, peer)| Route {
+                ip: ip.parse().unwrap(),
+                masklen,
+                peer,
+            })
+            .collect();
+        let inputs: Vec<RouteInput> = packets()
+            .into_iter()
+            .map(|packet| RouteInput {
+                routes: routes.clone(),
+                packet,
+            })
+            .collect();
+        for input in inputs.iter() {
+            let decoded = RouteInput::arbitrary_take_rest(Unstructured::new(&input.encode()));
+            assert_eq!(format!("{:?}", decoded.unwrap()), format!("{:?}", input));
+            route(input);
+        }
+        write_corpus(
+            "routing_table",
+            &inputs
+                .iter()
+                .map(|input| input.encode())
+                .collect::<Vec<_>>(),
+        );
+    }
+}
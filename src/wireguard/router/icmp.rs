@@ -0,0 +1,423 @@
+/* ICMP errors for packets which cannot be cryptokey routed (as sent by the kernel implementation),
+ * or which exceed the MTU of the device or peer (path MTU discovery, RFC 1191 and RFC 8201):
+ *
+ * The error is addressed to the source of the packet and written back to the TUN device,
+ * quoting as much of the packet as fits in the minimum MTU of the protocol (576 bytes for IPv4, 1280 for IPv6).
+ * Since the device has no address of its own, the error is sourced from the destination of the packet.
+ *
+ * As required by RFC 1122 and RFC 4443, no error is sent in response to an ICMP error,
+ * a fragment (other than the first), or a packet from/to an unspecified, multicast or broadcast address.
+ * Errors are rate limited per source address: every source is limited by a generic cell rate algorithm
+ * (as in traffic shaping), permitting a burst of ICMP_BURST errors, then one every ICMP_INTERVAL.
+ */
+use super::constants::{ICMP_BURST, ICMP_INTERVAL, ICMP_MAX_SOURCES};
+use super::ip::{inner_length, VERSION_IP4, VERSION_IP6};
+
+use crate::platform::checksum::{checksum, pseudo_header, sum};
+
+use core::cmp::{max, min};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// TODO: consider no_std alternatives
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use spin::Mutex;
+
+const PROTOCOL_ICMP4: u8 = 1;
+const PROTOCOL_ICMP6: u8 = 58;
+const PROTOCOL_FRAGMENT6: u8 = 44;
+
+const SIZE_IP4_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+const SIZE_ICMP_HEADER: usize = 8;
+
+const MIN_MTU_IP4: usize = 576;
+const MIN_MTU_IP6: usize = 1280;
+
+const TTL: u8 = 64;
+
+// ICMPv4 "destination unreachable" (host unreachable)
+const ICMP4_UNREACHABLE: (u8, u8) = (3, 1);
+
+// ICMPv6 "destination unreachable" (no route to destination)
+const ICMP6_UNREACHABLE: (u8, u8) = (1, 0);
+
+// ICMPv4 "destination unreachable" (fragmentation needed and DF set)
+const ICMP4_FRAGMENTATION_NEEDED: (u8, u8) = (3, 4);
+
+// ICMPv6 "packet too big"
+const ICMP6_PACKET_TOO_BIG: (u8, u8) = (2, 0);
+
+pub(super) struct Icmp {
+    enabled: AtomicBool,
+    limiter: Mutex<HashMap<IpAddr, Instant>>, // source -> theoretical arrival time
+}
+
+/* Constructs an ICMPv4 error in response to the packet.
+ *
+ * # Returns
+ *
+ * The source of the packet and the error (if the packet warrants an error).
+ */
+fn error4(packet: &[u8], kind: (u8, u8), rest: [u8; 4]) -> Option<(IpAddr, Vec<u8>)> {
+    let ihl = ((*packet.first()? & 0xf) as usize) * 4;
+    if ihl < SIZE_IP4_HEADER || packet.len() < ihl {
+        return None;
+    }
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    if src.is_unspecified() || src.is_broadcast() || src.is_multicast() {
+        return None;
+    }
+    if dst.is_broadcast() || dst.is_multicast() {
+        return None;
+    }
+
+    // only the first fragment
+    if u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0 {
+        return None;
+    }
+
+    // not in response to an ICMP error (destination unreachable, source quench, redirect, time exceeded, parameter problem)
+    if packet[9] == PROTOCOL_ICMP4 && matches!(*packet.get(ihl)?, 3 | 4 | 5 | 11 | 12) {
+        return None;
+    }
+
+    // quote as much of the packet as fits the minimum MTU
+    let quoted = &packet[..min(
+        packet.len(),
+        MIN_MTU_IP4 - SIZE_IP4_HEADER - SIZE_ICMP_HEADER,
+    )];
+    let total = SIZE_IP4_HEADER + SIZE_ICMP_HEADER + quoted.len();
+    let mut error = Vec::with_capacity(total);
+
+    // IPv4 header
+    error.extend_from_slice(&[0x45, 0]);
+    error.extend_from_slice(&(total as u16).to_be_bytes());
+    error.extend_from_slice(&[0, 0, 0, 0, TTL, PROTOCOL_ICMP4, 0, 0]);
+    error.extend_from_slice(&dst.octets());
+    error.extend_from_slice(&src.octets());
+    let csum = checksum(sum(&error[..SIZE_IP4_HEADER], 0));
+    error[10..12].copy_from_slice(&csum.to_be_bytes());
+
+    // ICMP header and quoted packet
+    error.extend_from_slice(&[kind.0, kind.1, 0, 0]);
+    error.extend_from_slice(&rest);
+    error.extend_from_slice(quoted);
+    let csum = checksum(sum(&error[SIZE_IP4_HEADER..], 0));
+    error[SIZE_IP4_HEADER + 2..SIZE_IP4_HEADER + 4].copy_from_slice(&csum.to_be_bytes());
+    Some((IpAddr::V4(src), error))
+}
+
+/* Constructs an ICMPv6 error in response to the packet.
+ *
+ * # Returns
+ *
+ * The source of the packet and the error (if the packet warrants an error).
+ */
+fn error6(packet: &[u8], kind: (u8, u8), rest: [u8; 4]) -> Option<(IpAddr, Vec<u8>)> {
+    if packet.len() < SIZE_IP6_HEADER {
+        return None;
+    }
+    let mut addrs = [[0u8; 16]; 2];
+    addrs[0].copy_from_slice(&packet[8..24]);
+    addrs[1].copy_from_slice(&packet[24..40]);
+    let (src, dst) = (Ipv6Addr::from(addrs[0]), Ipv6Addr::from(addrs[1]));
+    if src.is_unspecified() || src.is_multicast() || dst.is_multicast() {
+        return None;
+    }
+
+    // not in response to an ICMPv6 error (types below 128), or a fragment (other than the first)
+    match packet[6] {
+        PROTOCOL_ICMP6 if *packet.get(SIZE_IP6_HEADER)? < 128 => return None,
+        PROTOCOL_FRAGMENT6 => {
+            let offset = packet.get(SIZE_IP6_HEADER + 2..SIZE_IP6_HEADER + 4)?;
+            if u16::from_be_bytes([offset[0], offset[1]]) >> 3 != 0 {
+                return None;
+            }
+        }
+        _ => (),
+    }
+
+    // quote as much of the packet as fits the minimum MTU
+    let quoted = &packet[..min(
+        packet.len(),
+        MIN_MTU_IP6 - SIZE_IP6_HEADER - SIZE_ICMP_HEADER,
+    )];
+    let length = SIZE_ICMP_HEADER + quoted.len();
+    let mut error = Vec::with_capacity(SIZE_IP6_HEADER + length);
+
+    // IPv6 header
+    error.extend_from_slice(&[0x60, 0, 0, 0]);
+    error.extend_from_slice(&(length as u16).to_be_bytes());
+    error.extend_from_slice(&[PROTOCOL_ICMP6, TTL]);
+    error.extend_from_slice(&dst.octets());
+    error.extend_from_slice(&src.octets());
+
+    // ICMPv6 header and quoted packet (the checksum covers the pseudo header)
+    error.extend_from_slice(&[kind.0, kind.1, 0, 0]);
+    error.extend_from_slice(&rest);
+    error.extend_from_slice(quoted);
+    let pseudo = pseudo_header(&error, PROTOCOL_ICMP6, length);
+    let csum = checksum(sum(&error[SIZE_IP6_HEADER..], pseudo));
+    error[SIZE_IP6_HEADER + 2..SIZE_IP6_HEADER + 4].copy_from_slice(&csum.to_be_bytes());
+    Some((IpAddr::V6(src), error))
+}
+
+impl Icmp {
+    pub fn new() -> Icmp {
+        Icmp {
+            enabled: AtomicBool::new(false),
+            limiter: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // check (and update) the rate limit of the source
+    fn permit(&self, src: IpAddr, now: Instant) -> bool {
+        let mut limiter = self.limiter.lock();
+
+        // forget the sources which have recovered their burst (if tracking too many sources)
+        if limiter.len() >= ICMP_MAX_SOURCES && !limiter.contains_key(&src) {
+            limiter.retain(|_, tat| *tat > now);
+            if limiter.len() >= ICMP_MAX_SOURCES {
+                return false;
+            }
+        }
+
+        let tat = limiter.get(&src).map_or(now, |tat| max(*tat, now));
+        if tat > now + ICMP_INTERVAL * (ICMP_BURST - 1) {
+            return false;
+        }
+        limiter.insert(src, tat + ICMP_INTERVAL);
+        true
+    }
+
+    // construct an error in response to the packet (subject to the rate limit of its source)
+    fn error(
+        &self,
+        packet: &[u8],
+        now: Instant,
+        kind4: (u8, u8),
+        kind6: (u8, u8),
+        rest: [u8; 4],
+    ) -> Option<Vec<u8>> {
+        let packet = &packet[..min(packet.len(), inner_length(packet)?)];
+        let (src, error) = match *packet.first()? >> 4 {
+            VERSION_IP4 => error4(packet, kind4, rest)?,
+            VERSION_IP6 => error6(packet, kind6, rest)?,
+            _ => return None,
+        };
+        if self.permit(src, now) {
+            Some(error)
+        } else {
+            None
+        }
+    }
+
+    /// Returns a "destination unreachable" error in response to a packet without a cryptokey route
+    /// (if enabled, and the packet warrants an error)
+    pub fn unreachable(&self, packet: &[u8], now: Instant) -> Option<Vec<u8>> {
+        if !self.enabled() {
+            return None;
+        }
+        self.error(packet, now, ICMP4_UNREACHABLE, ICMP6_UNREACHABLE, [0; 4])
+    }
+
+    /// Returns a "fragmentation needed" (IPv4) or "packet too big" (IPv6) error
+    /// in response to a packet exceeding the MTU (if the packet warrants an error)
+    ///
+    /// IPv4 packets without the "don't fragment" flag warrant no error (they are dropped silently).
+    pub fn too_big(&self, packet: &[u8], mtu: usize, now: Instant) -> Option<Vec<u8>> {
+        if *packet.first()? >> 4 == VERSION_IP4 && *packet.get(6)? & 0x40 == 0 {
+            return None;
+        }
+
+        // the next-hop MTU (in the low 16 bits for IPv4, the full 32 bits for IPv6)
+        let mtu = min(mtu, 0xffff) as u32;
+        self.error(
+            packet,
+            now,
+            ICMP4_FRAGMENTATION_NEEDED,
+            ICMP6_PACKET_TOO_BIG,
+            mtu.to_be_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn packet4(src: [u8; 4], dst: [u8; 4], protocol: u8, payload: &[u8]) -> Vec<u8> {
+        let total = (SIZE_IP4_HEADER + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn packet6(src: Ipv6Addr, dst: Ipv6Addr, next: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next, 64]);
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_unreachable4() {
+        let icmp = Icmp::new();
+        let now = Instant::now();
+        let packet = packet4([10, 0, 0, 1], [10, 0, 0, 9], 17, &[1u8; 1000]);
+
+        // disabled by default
+        assert!(icmp.unreachable(&packet, now).is_none());
+        icmp.set_enabled(true);
+
+        // the error quotes the packet (truncated to the minimum MTU, excluding any padding)
+        let mut padded = packet.clone();
+        padded.extend_from_slice(&[0u8; 16]);
+        let error = icmp.unreachable(&padded, now).unwrap();
+        assert_eq!(error.len(), MIN_MTU_IP4);
+        assert_eq!(inner_length(&error), Some(MIN_MTU_IP4));
+        assert_eq!(&error[12..16], &[10, 0, 0, 9]);
+        assert_eq!(&error[16..20], &[10, 0, 0, 1]);
+        assert_eq!(error[9], PROTOCOL_ICMP4);
+        assert_eq!(&error[20..22], &[3, 1]);
+        assert_eq!(&error[28..], &packet[..MIN_MTU_IP4 - 28]);
+
+        // valid checksums
+        assert_eq!(checksum(sum(&error[..20], 0)), 0);
+        assert_eq!(checksum(sum(&error[20..], 0)), 0);
+
+        // not in response to ICMP errors, fragments or broadcasts
+        let error = packet4([10, 0, 0, 1], [10, 0, 0, 9], 1, &[3, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(icmp.unreachable(&error, now).is_none());
+        let echo = packet4([10, 0, 0, 1], [10, 0, 0, 9], 1, &[8, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(icmp.unreachable(&echo, now).is_some());
+        let mut fragment = packet.clone();
+        fragment[7] = 1;
+        assert!(icmp.unreachable(&fragment, now).is_none());
+        let broadcast = packet4([10, 0, 0, 1], [255, 255, 255, 255], 17, &[0; 8]);
+        assert!(icmp.unreachable(&broadcast, now).is_none());
+        let unspecified = packet4([0, 0, 0, 0], [10, 0, 0, 9], 17, &[0; 8]);
+        assert!(icmp.unreachable(&unspecified, now).is_none());
+    }
+
+    #[test]
+    fn test_unreachable6() {
+        let icmp = Icmp::new();
+        icmp.set_enabled(true);
+        let now = Instant::now();
+        let src: Ipv6Addr = "fd00::1".parse().unwrap();
+        let dst: Ipv6Addr = "fd00::9".parse().unwrap();
+        let packet = packet6(src, dst, 17, &[1u8; 101]);
+
+        let error = icmp.unreachable(&packet, now).unwrap();
+        assert_eq!(
+            error.len(),
+            SIZE_IP6_HEADER + SIZE_ICMP_HEADER + packet.len()
+        );
+        assert_eq!(inner_length(&error), Some(error.len()));
+        assert_eq!(&error[8..24], &dst.octets());
+        assert_eq!(&error[24..40], &src.octets());
+        assert_eq!(&error[40..42], &[1, 0]);
+        assert_eq!(&error[48..], &packet[..]);
+
+        // valid checksum (including the pseudo header)
+        let length = (error.len() - SIZE_IP6_HEADER) as u64;
+        let pseudo = sum(&error[8..40], length + PROTOCOL_ICMP6 as u64);
+        assert_eq!(checksum(sum(&error[40..], pseudo)), 0);
+
+        // not in response to ICMPv6 errors or multicast
+        let error = packet6(src, dst, PROTOCOL_ICMP6, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(icmp.unreachable(&error, now).is_none());
+        let echo = packet6(src, dst, PROTOCOL_ICMP6, &[128, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(icmp.unreachable(&echo, now).is_some());
+        let multicast = packet6(src, "ff02::1".parse().unwrap(), 17, &[0; 8]);
+        assert!(icmp.unreachable(&multicast, now).is_none());
+    }
+
+    #[test]
+    fn test_too_big() {
+        let icmp = Icmp::new();
+        let now = Instant::now();
+
+        // IPv4 only with "don't fragment" (regardless of whether unreachable errors are enabled)
+        let mut packet = packet4([10, 0, 0, 1], [10, 0, 0, 9], 17, &[1u8; 1400]);
+        assert!(icmp.too_big(&packet, 1300, now).is_none());
+        packet[6] = 0x40;
+        let error = icmp.too_big(&packet, 1300, now).unwrap();
+        assert_eq!(error.len(), MIN_MTU_IP4);
+        assert_eq!(&error[20..22], &[3, 4]);
+        assert_eq!(&error[24..28], &[0, 0, 0x05, 0x14]);
+        assert_eq!(checksum(sum(&error[20..], 0)), 0);
+
+        // IPv6
+        let src: Ipv6Addr = "fd00::1".parse().unwrap();
+        let dst: Ipv6Addr = "fd00::9".parse().unwrap();
+        let packet = packet6(src, dst, 17, &[1u8; 1400]);
+        let error = icmp.too_big(&packet, 1300, now).unwrap();
+        assert_eq!(error.len(), MIN_MTU_IP6);
+        assert_eq!(&error[40..42], &[2, 0]);
+        assert_eq!(&error[44..48], &[0, 0, 0x05, 0x14]);
+        let length = (error.len() - SIZE_IP6_HEADER) as u64;
+        let pseudo = sum(&error[8..40], length + PROTOCOL_ICMP6 as u64);
+        assert_eq!(checksum(sum(&error[40..], pseudo)), 0);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let icmp = Icmp::new();
+        icmp.set_enabled(true);
+        let now = Instant::now();
+        let packet = packet4([10, 0, 0, 1], [10, 0, 0, 9], 17, &[0; 8]);
+        let other = packet4([10, 0, 0, 2], [10, 0, 0, 9], 17, &[0; 8]);
+
+        // a burst, then an error per interval
+        for _ in 0..ICMP_BURST {
+            assert!(icmp.unreachable(&packet, now).is_some());
+        }
+        assert!(icmp.unreachable(&packet, now).is_none());
+        assert!(icmp.unreachable(&other, now).is_some());
+        let later = now + ICMP_INTERVAL;
+        assert!(icmp.unreachable(&packet, later).is_some());
+        assert!(icmp.unreachable(&packet, later).is_none());
+
+        // the burst is recovered
+        let later = later + ICMP_INTERVAL * ICMP_BURST;
+        for _ in 0..ICMP_BURST {
+            assert!(icmp.unreachable(&packet, later).is_some());
+        }
+
+        // idle sources are forgotten when tracking too many sources
+        let later = later + Duration::from_secs(3600);
+        for i in 0..ICMP_MAX_SOURCES {
+            let src = (i as u32 + 0x10000).to_be_bytes();
+            let packet = packet4([10, src[1], src[2], src[3]], [10, 0, 0, 9], 17, &[0; 8]);
+            assert!(icmp.unreachable(&packet, later).is_some());
+        }
+        assert_eq!(icmp.limiter.lock().len(), ICMP_MAX_SOURCES);
+        assert!(icmp.unreachable(&other, later).is_none());
+        assert!(icmp
+            .unreachable(&other, later + ICMP_INTERVAL * ICMP_BURST)
+            .is_some());
+    }
+}
@@ -1,10 +1,14 @@
 mod anti_replay;
 mod constants;
+mod counters;
 mod device;
+mod icmp;
 mod ip;
 mod messages;
+mod mss;
 mod peer;
 mod route;
+mod shaper;
 mod types;
 
 mod queue;
@@ -15,6 +19,9 @@ mod worker;
 #[cfg(test)]
 mod tests;
 
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+
 use messages::TransportHeader;
 
 use super::constants::REJECT_AFTER_MESSAGES;
@@ -31,7 +38,9 @@ pub const fn message_data_len(payload: usize) -> usize {
     payload + mem::size_of::<TransportHeader>() + SIZE_TAG
 }
 
+pub use counters::PacketCounters;
 pub use device::DeviceHandle as Device;
 pub use messages::TYPE_TRANSPORT;
 pub use peer::PeerHandle;
-pub use types::Callbacks;
+pub use shaper::{Shaping, ShapingPolicy};
+pub use types::{Callbacks, RouterError};
@@ -0,0 +1,248 @@
+/* Clamping of the TCP maximum segment size (MSS) of connections crossing the tunnel:
+ *
+ * The MSS option of TCP SYN (and SYN-ACK) segments is lowered to fit the MTU of the device
+ * (or the MTU override of the peer), such that TCP connections do not depend on path MTU discovery
+ * (which fails when ICMP is filtered). The checksum is updated incrementally (RFC 1624).
+ *
+ * The MSS is derived from the MTU as by "iptables -j TCPMSS --clamp-mss-to-pmtu":
+ * the MTU less the IP header (20 bytes for IPv4, 40 for IPv6) and the TCP header (20 bytes).
+ */
+use super::ip::{inner_length, VERSION_IP4, VERSION_IP6};
+
+use core::cmp::min;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const PROTOCOL_TCP: u8 = 6;
+
+const SIZE_IP4_HEADER: usize = 20;
+const SIZE_IP6_HEADER: usize = 40;
+const SIZE_TCP_HEADER: usize = 20;
+
+const TCP_FLAG_SYN: u8 = 0x02;
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+pub(super) struct MssClamp {
+    enabled: AtomicBool,
+    mtu: AtomicUsize, // MTU of the device
+}
+
+// update the internet checksum for a changed 16-bit word (RFC 1624, eqn. 3)
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut acc = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+/* Lowers the MSS option of a TCP SYN segment to fit the MTU.
+ *
+ * # Returns
+ *
+ * The original MSS (if it was lowered).
+ */
+fn clamp(packet: &mut [u8], mtu: usize) -> Option<u16> {
+    // locate the TCP header (only in the first fragment, without IPv6 extension headers)
+    let (offset, header) = match *packet.first()? >> 4 {
+        VERSION_IP4 => {
+            let ihl = ((packet[0] & 0xf) as usize) * 4;
+            if ihl < SIZE_IP4_HEADER || packet.len() < ihl || packet[9] != PROTOCOL_TCP {
+                return None;
+            }
+            if u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0 {
+                return None;
+            }
+            (ihl, SIZE_IP4_HEADER)
+        }
+        VERSION_IP6 => {
+            if packet.len() < SIZE_IP6_HEADER || packet[6] != PROTOCOL_TCP {
+                return None;
+            }
+            (SIZE_IP6_HEADER, SIZE_IP6_HEADER)
+        }
+        _ => return None,
+    };
+    let tcp = &mut packet[offset..];
+    if tcp.len() < SIZE_TCP_HEADER || tcp[13] & TCP_FLAG_SYN == 0 {
+        return None;
+    }
+    let options = ((tcp[12] >> 4) as usize) * 4;
+    if options < SIZE_TCP_HEADER || tcp.len() < options {
+        return None;
+    }
+    let mss = min(mtu.saturating_sub(header + SIZE_TCP_HEADER), 0xffff) as u16;
+
+    // find the MSS option
+    let mut i = SIZE_TCP_HEADER;
+    while i < options {
+        match tcp[i] {
+            TCP_OPTION_END => return None,
+            TCP_OPTION_NOP => i += 1,
+            kind => {
+                let length = *tcp.get(i + 1)? as usize;
+                if length < 2 || i + length > options {
+                    return None;
+                }
+                if kind == TCP_OPTION_MSS && length == 4 {
+                    break;
+                }
+                i += length;
+            }
+        }
+    }
+    if i >= options {
+        return None;
+    }
+
+    // lower the MSS
+    let old = u16::from_be_bytes([tcp[i + 2], tcp[i + 3]]);
+    if old <= mss {
+        return None;
+    }
+    tcp[i + 2..i + 4].copy_from_slice(&mss.to_be_bytes());
+
+    // update the checksum
+    // (a value at an odd offset contributes to the sum with its bytes swapped)
+    let (a, b) = if i & 1 == 0 {
+        (old, mss)
+    } else {
+        (old.swap_bytes(), mss.swap_bytes())
+    };
+    let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+    tcp[16..18].copy_from_slice(&update_checksum(checksum, a, b).to_be_bytes());
+    Some(old)
+}
+
+impl MssClamp {
+    pub fn new() -> MssClamp {
+        MssClamp {
+            enabled: AtomicBool::new(false),
+            mtu: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_mtu(&self, mtu: usize) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    /// Clamps the MSS of a TCP SYN crossing the tunnel (if enabled)
+    ///
+    /// # Arguments
+    ///
+    /// - `packet`, the IP packet (possibly followed by padding)
+    /// - `peer_mtu`, the MTU override of the peer (0 for the MTU of the device)
+    pub fn apply(&self, packet: &mut [u8], peer_mtu: usize) -> Option<u16> {
+        if !self.enabled() {
+            return None;
+        }
+        let mtu = match (self.mtu.load(Ordering::Relaxed), peer_mtu) {
+            (0, _) => return None,
+            (mtu, 0) => mtu,
+            (mtu, peer_mtu) => min(mtu, peer_mtu),
+        };
+        let length = min(packet.len(), inner_length(packet)?);
+        clamp(&mut packet[..length], mtu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::platform::checksum::{checksum, pseudo_header, sum};
+
+    // a TCP segment (with a valid checksum) in an IPv4 packet
+    fn syn4(flags: u8, options: &[u8]) -> Vec<u8> {
+        let tcp = SIZE_TCP_HEADER + options.len();
+        let total = SIZE_IP4_HEADER + tcp;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&(total as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, PROTOCOL_TCP, 0, 0]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&[0x30, 0x39, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[((tcp / 4) as u8) << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(options);
+
+        let pseudo = pseudo_header(&packet, PROTOCOL_TCP, tcp);
+        let csum = checksum(sum(&packet[SIZE_IP4_HEADER..], pseudo));
+        packet[36..38].copy_from_slice(&csum.to_be_bytes());
+        packet
+    }
+
+    fn valid4(packet: &[u8]) -> bool {
+        let tcp = packet.len() - SIZE_IP4_HEADER;
+        let pseudo = pseudo_header(packet, PROTOCOL_TCP, tcp);
+        checksum(sum(&packet[SIZE_IP4_HEADER..], pseudo)) == 0
+    }
+
+    #[test]
+    fn test_clamp() {
+        let clamp = MssClamp::new();
+        clamp.set_mtu(1420);
+
+        // disabled by default
+        let mut packet = syn4(TCP_FLAG_SYN, &[2, 4, 0x05, 0xb4]);
+        assert!(valid4(&packet));
+        assert_eq!(clamp.apply(&mut packet, 0), None);
+        clamp.set_enabled(true);
+
+        // lowered to the MTU of the device (or the peer)
+        assert_eq!(clamp.apply(&mut packet, 0), Some(1460));
+        assert_eq!(&packet[42..44], &1380u16.to_be_bytes());
+        assert!(valid4(&packet));
+        assert_eq!(clamp.apply(&mut packet, 1300), Some(1380));
+        assert_eq!(&packet[42..44], &1260u16.to_be_bytes());
+        assert!(valid4(&packet));
+
+        // but never raised
+        assert_eq!(clamp.apply(&mut packet, 0), None);
+        assert_eq!(&packet[42..44], &1260u16.to_be_bytes());
+
+        // at an odd offset (following other options), with padding after the packet
+        let mut packet = syn4(TCP_FLAG_SYN | 0x10, &[1, 2, 4, 0x05, 0xb4, 3, 3, 7]);
+        packet.extend_from_slice(&[0; 12]);
+        assert_eq!(clamp.apply(&mut packet, 0), Some(1460));
+        assert_eq!(&packet[43..45], &1380u16.to_be_bytes());
+        assert!(valid4(&packet[..packet.len() - 12]));
+
+        // only SYN segments with an MSS option
+        let mut packet = syn4(0x10, &[2, 4, 0x05, 0xb4]);
+        assert_eq!(clamp.apply(&mut packet, 0), None);
+        let mut packet = syn4(TCP_FLAG_SYN, &[1, 1, 0, 0, 2, 4, 0x05, 0xb4]);
+        assert_eq!(clamp.apply(&mut packet, 0), None);
+        let mut packet = syn4(TCP_FLAG_SYN, &[3, 5, 2, 4, 0x05, 0xb4, 0, 0]);
+        assert_eq!(clamp.apply(&mut packet, 0), None);
+    }
+
+    #[test]
+    fn test_clamp6() {
+        let clamp = MssClamp::new();
+        clamp.set_enabled(true);
+        clamp.set_mtu(1420);
+
+        let mut packet = vec![0x60, 0, 0, 0, 0, 24, PROTOCOL_TCP, 64];
+        packet.extend_from_slice(&[0xfd; 16]);
+        packet.extend_from_slice(&[0xfe; 16]);
+        packet.extend_from_slice(&[0x30, 0x39, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0]);
+        packet.extend_from_slice(&[0x60, TCP_FLAG_SYN, 0xff, 0xff, 0x12, 0x34, 0, 0]);
+        packet.extend_from_slice(&[2, 4, 0x05, 0xa0]);
+        let before = checksum(sum(&packet[SIZE_IP6_HEADER..], 0));
+
+        assert_eq!(clamp.apply(&mut packet, 0), Some(1440));
+        assert_eq!(&packet[62..64], &1360u16.to_be_bytes());
+
+        // the sum (including the checksum) is unchanged
+        assert_eq!(checksum(sum(&packet[SIZE_IP6_HEADER..], 0)), before);
+    }
+}
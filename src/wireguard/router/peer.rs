@@ -7,17 +7,20 @@ use super::device::Device;
 use super::device::EncryptionState;
 
 use super::constants::*;
+use super::counters::{count, Counters, PacketCounters};
+use super::ip::inner_length;
 use super::types::{Callbacks, RouterError};
 use super::SIZE_MESSAGE_PREFIX;
 
 use super::queue::Queue;
 use super::receive::ReceiveJob;
 use super::send::SendJob;
+use super::shaper::{Delayed, Shaper, Shaping, ShapingPolicy, Verdict};
 use super::worker::JobUnion;
 
 use core::mem;
 use core::ops::Deref;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use alloc::sync::Arc;
 
@@ -44,6 +47,9 @@ pub struct PeerInner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E
     pub(super) keys: Mutex<KeyWheel>,
     pub(super) enc_key: Mutex<Option<EncryptionState>>,
     pub(super) endpoint: Mutex<Option<E>>,
+    pub(super) shaper: Shaper,
+    pub(super) counters: Counters,
+    pub(super) mtu: AtomicUsize, // MTU override (0 for the MTU of the device)
 }
 
 /// A Peer dereferences to its opaque type:
@@ -130,6 +136,45 @@ impl EncryptionState {
     }
 }
 
+/* Write a batch of messages, handing every run of equally sized messages
+ * (the last of which may be shorter) to the writer as a single segmented write.
+ *
+ * Returns the number of messages written (a prefix of the batch).
+ */
+fn write_runs<E: Endpoint, B: udp::Writer<E>>(
+    writer: &B,
+    msgs: &[&[u8]],
+    dst: &mut E,
+) -> Result<usize, B::Error> {
+    let mut sent = 0;
+    while sent < msgs.len() {
+        // find the end of the run
+        let segment = msgs[sent].len();
+        let mut end = sent + 1;
+        while end < msgs.len() && msgs[end - 1].len() == segment && msgs[end].len() <= segment {
+            end += 1;
+        }
+
+        // write the run
+        let res = if end - sent == 1 {
+            writer.write(msgs[sent], dst).map(|_| 1)
+        } else {
+            writer.write_segmented(&msgs[sent..end], dst)
+        };
+        match res {
+            Ok(n) => {
+                sent += n;
+                if sent < end {
+                    break;
+                }
+            }
+            Err(e) if sent == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(sent)
+}
+
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DecryptionState<E, C, T, B> {
     fn new(peer: Peer<E, C, T, B>, keypair: &Arc<KeyPair>) -> DecryptionState<E, C, T, B> {
         DecryptionState {
@@ -149,6 +194,14 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Drop for Peer
 
         self.peer.device.table.remove(peer);
 
+        // remove from the peers of the device
+
+        self.peer.device.peers.lock().retain(|p| p != peer);
+
+        // discard delayed packets
+
+        self.peer.device.delayed.purge(peer);
+
         // release ids from the receiver map
 
         let mut keys = peer.keys.lock();
@@ -205,10 +258,16 @@ pub fn new_peer<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
                     retired: vec![],
                 }),
                 staged_packets: spin::Mutex::new(ArrayDeque::new()),
+                shaper: Shaper::new(),
+                counters: Counters::new(),
+                mtu: AtomicUsize::new(0),
             }),
         }
     };
 
+    // add to the peers of the device
+    peer.device.peers.lock().push(peer.clone());
+
     PeerHandle { peer }
 }
 
@@ -240,9 +299,46 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerInner<E,
             None => Err(RouterError::NoEndpoint),
         }
     }
+
+    /// Send a batch of raw messages to the peer (in-order)
+    ///
+    /// Returns the number of messages sent (a prefix of the batch).
+    pub fn send_raw_batch(&self, msgs: &[&[u8]]) -> Result<usize, RouterError> {
+        // send to endpoint (if known)
+        match self.endpoint.lock().as_mut() {
+            Some(endpoint) => {
+                let outbound = self.device.outbound.read();
+                if outbound.0 {
+                    outbound
+                        .1
+                        .as_ref()
+                        .ok_or(RouterError::SendError)
+                        .and_then(|w| {
+                            if w.segmentation(endpoint) {
+                                write_runs(w, msgs, endpoint)
+                            } else {
+                                w.write_batch(msgs, endpoint)
+                            }
+                            .map_err(|_| RouterError::SendError)
+                        })
+                } else {
+                    Ok(msgs.len())
+                }
+            }
+            None => Err(RouterError::NoEndpoint),
+        }
+    }
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T, B> {
+    // Stage a packet until a key is available (evicting the oldest packet if full)
+    fn stage(&self, msg: Vec<u8>) {
+        if let Some(evicted) = self.staged_packets.lock().push_back(msg) {
+            count(&self.counters.tx_staged_drops, 1);
+            self.device.buffers.put(evicted);
+        }
+    }
+
     /// Encrypt and send a message to the peer
     ///
     /// Arguments:
@@ -257,7 +353,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
                 None => {
                     log::debug!("no key encryption key available");
                     if stage {
-                        self.staged_packets.lock().push_back(msg);
+                        self.stage(msg);
                     };
                     (None, true)
                 }
@@ -267,7 +363,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
                         log::debug!("encryption key expired");
                         *enc_key = None;
                         if stage {
-                            self.staged_packets.lock().push_back(msg);
+                            self.stage(msg);
                         }
                         (None, true)
                     } else {
@@ -278,6 +374,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
                             state.nonce += 1;
                             (Some(job), false)
                         } else {
+                            count(&self.counters.tx_queue_drops, 1);
                             (None, false)
                         }
                     }
@@ -297,6 +394,27 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
         }
     }
 
+    /// Shape an outbound message (IP packet read from the TUN device),
+    /// before sending (or staging) it.
+    pub(super) fn send_shaped(&self, msg: Vec<u8>) {
+        // the length of the IP packet (excluding any padding)
+        let packet = &msg[SIZE_MESSAGE_PREFIX..];
+        let size = inner_length(packet).unwrap_or(packet.len());
+        match self.shaper.outbound(size, self.device.clock.now()) {
+            Verdict::Pass => self.send(msg, true),
+            Verdict::Delay(due) => {
+                log::trace!("outbound message delayed by traffic shaping");
+                self.device
+                    .delayed
+                    .push(due, Delayed::Outbound(self.clone(), msg));
+            }
+            Verdict::Drop => {
+                log::trace!("outbound message dropped by traffic shaping");
+                self.device.buffers.put(msg);
+            }
+        }
+    }
+
     // Transmit all staged packets
     fn send_staged(&self) -> bool {
         log::trace!("peer.send_staged");
@@ -347,6 +465,41 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Peer<E, C, T,
         // start transmission of staged packets
         self.send_staged();
     }
+
+    /* Zero all key-material related to the peer
+     * (the receiver ids of the key-wheel are released by the next call to add_keypair).
+     *
+     * Returns true if the peer held key-material.
+     */
+    pub(super) fn zero_keys(&self) -> bool {
+        let mut release: Vec<u32> = Vec::with_capacity(3);
+        let mut keys = self.keys.lock();
+
+        // update key-wheel
+
+        if let Some(k) = mem::replace(&mut keys.next, None) {
+            release.push(k.local_id())
+        }
+        if let Some(k) = mem::replace(&mut keys.current, None) {
+            release.push(k.local_id())
+        }
+        if let Some(k) = mem::replace(&mut keys.previous, None) {
+            release.push(k.local_id())
+        }
+        keys.retired.extend(&release[..]);
+
+        // update inbound "recv" map
+        {
+            let mut recv = self.device.recv.write();
+            for id in &release {
+                recv.remove(id);
+            }
+        }
+
+        // clear encryption state
+        *self.enc_key.lock() = None;
+        !release.is_empty()
+    }
 }
 
 impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E, C, T, B> {
@@ -382,33 +535,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     /// Zero all key-material related to the peer
     pub fn zero_keys(&self) {
         log::trace!("peer.zero_keys");
-
-        let mut release: Vec<u32> = Vec::with_capacity(3);
-        let mut keys = self.peer.keys.lock();
-
-        // update key-wheel
-
-        if let Some(k) = mem::replace(&mut keys.next, None) {
-            release.push(k.local_id())
-        }
-        if let Some(k) = mem::replace(&mut keys.current, None) {
-            release.push(k.local_id())
-        }
-        if let Some(k) = mem::replace(&mut keys.previous, None) {
-            release.push(k.local_id())
-        }
-        keys.retired.extend(&release[..]);
-
-        // update inbound "recv" map
-        {
-            let mut recv = self.peer.device.recv.write();
-            for id in release {
-                recv.remove(&id);
-            }
-        }
-
-        // clear encryption state
-        *self.peer.enc_key.lock() = None;
+        self.peer.zero_keys();
     }
 
     pub fn down(&self) {
@@ -499,7 +626,9 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
 
     pub fn send_keepalive(&self) {
         log::trace!("peer.send_keepalive");
-        self.peer.send(vec![0u8; SIZE_MESSAGE_PREFIX], false)
+        let mut msg = self.peer.device.buffers.get();
+        msg.resize(SIZE_MESSAGE_PREFIX, 0);
+        self.peer.send(msg, false)
     }
 
     /// Map a subnet to the peer
@@ -548,4 +677,114 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PeerHandle<E,
     pub fn purge_staged_packets(&self) {
         self.peer.staged_packets.lock().clear();
     }
+
+    /// Limit the traffic sent to the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `rate`, the limit in bytes (of IP packets) per second, or 0 to disable the limit
+    pub fn set_tx_rate_limit(&self, rate: u64) {
+        self.peer.shaper.set_tx_rate(rate);
+    }
+
+    /// Limit the traffic received from the peer
+    ///
+    /// # Arguments
+    ///
+    /// - `rate`, the limit in bytes (of IP packets) per second, or 0 to disable the limit
+    pub fn set_rx_rate_limit(&self, rate: u64) {
+        self.peer.shaper.set_rx_rate(rate);
+    }
+
+    /// Set the burst size of the rate limits (in bytes),
+    /// 0 selects a default burst size relative to the rate.
+    pub fn set_rate_limit_burst(&self, burst: u64) {
+        self.peer.shaper.set_burst(burst);
+    }
+
+    /// Set the treatment of packets exceeding the rate limits
+    pub fn set_rate_limit_policy(&self, policy: ShapingPolicy) {
+        self.peer.shaper.set_policy(policy);
+    }
+
+    /// Override the MTU of the device for the peer:
+    /// larger packets are dropped (with an ICMP error to the source) and padding is limited to the MTU.
+    ///
+    /// # Arguments
+    ///
+    /// - `mtu`, the MTU of the path to the peer, or 0 to use the MTU of the device
+    pub fn set_mtu(&self, mtu: usize) {
+        self.peer.mtu.store(mtu, Ordering::Relaxed);
+    }
+
+    /// Returns the MTU override of the peer (0 if none)
+    pub fn get_mtu(&self) -> usize {
+        self.peer.mtu.load(Ordering::Relaxed)
+    }
+
+    /// Returns the counters of transport messages exchanged with the peer
+    pub fn get_counters(&self) -> PacketCounters {
+        self.peer.counters.snapshot()
+    }
+
+    /// Returns the rate limits and shaping counters of the peer
+    pub fn get_shaping(&self) -> Shaping {
+        self.peer.shaper.snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex as StdMutex;
+
+    use crate::platform::dummy::{BindError, UnitEndpoint};
+
+    // records the (sizes of the) messages of every write
+    struct SegmentingWriter(StdMutex<Vec<Vec<usize>>>);
+
+    impl udp::Writer<UnitEndpoint> for SegmentingWriter {
+        type Error = BindError;
+
+        fn write(&self, buf: &[u8], _dst: &mut UnitEndpoint) -> Result<(), Self::Error> {
+            self.0.lock().unwrap().push(vec![buf.len()]);
+            Ok(())
+        }
+
+        fn segmentation(&self, _dst: &UnitEndpoint) -> bool {
+            true
+        }
+
+        fn write_segmented(
+            &self,
+            bufs: &[&[u8]],
+            _dst: &mut UnitEndpoint,
+        ) -> Result<usize, Self::Error> {
+            let sizes: Vec<usize> = bufs.iter().map(|msg| msg.len()).collect();
+            let n = sizes.len();
+            self.0.lock().unwrap().push(sizes);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_write_runs() {
+        let writer = SegmentingWriter(StdMutex::new(vec![]));
+        let sizes = [100, 100, 100, 60, 100, 40, 40, 100];
+        let msgs: Vec<Vec<u8>> = sizes.iter().map(|size| vec![0u8; *size]).collect();
+        let msgs: Vec<&[u8]> = msgs.iter().map(|msg| &msg[..]).collect();
+
+        let sent = write_runs(&writer, &msgs[..], &mut UnitEndpoint::new()).unwrap();
+        assert_eq!(sent, sizes.len());
+        assert_eq!(
+            *writer.0.lock().unwrap(),
+            vec![
+                vec![100, 100, 100, 60], // equally sized, the last may be shorter
+                vec![100, 40],
+                vec![40],
+                vec![100]
+            ]
+        );
+    }
 }
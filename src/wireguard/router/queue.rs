@@ -4,12 +4,21 @@ use spin::Mutex;
 use core::mem;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
-use super::constants::INORDER_QUEUE_SIZE;
+use super::constants::{INORDER_QUEUE_SIZE, SEQUENTIAL_BATCH_SIZE};
 
-pub trait SequentialJob {
+pub trait SequentialJob: Sized {
     fn is_ready(&self) -> bool;
 
     fn sequential_work(self);
+
+    /// Process a batch of consecutive ready jobs (in-order), draining the vector.
+    ///
+    /// By default the jobs are processed one at a time.
+    fn sequential_work_batch(jobs: &mut Vec<Self>) {
+        for job in jobs.drain(..) {
+            job.sequential_work();
+        }
+    }
 }
 
 pub trait ParallelJob: Sized + SequentialJob {
@@ -51,6 +60,7 @@ impl<J: SequentialJob> Queue<J> {
 
         // enter the critical section
         let mut contenders = 1; // myself
+        let mut batch = Vec::new();
         while contenders > 0 {
             // check soundness in debug builds
             #[cfg(debug)]
@@ -59,27 +69,28 @@ impl<J: SequentialJob> Queue<J> {
                 .try_lock()
                 .expect("contenders should ensure mutual exclusion");
 
-            // handle every ready element
+            // handle every ready element (in batches)
             loop {
                 let mut queue = self.queue.lock();
 
-                // check if front job is ready
-                match queue.front() {
-                    None => break,
-                    Some(job) => {
-                        if !job.is_ready() {
-                            break;
-                        }
-                    }
-                };
-
-                // take the job out of the queue
-                let job = queue.pop_front().unwrap();
-                debug_assert!(job.is_ready());
+                // take the ready jobs at the front out of the queue
+                while batch.len() < SEQUENTIAL_BATCH_SIZE {
+                    match queue.front() {
+                        Some(job) if job.is_ready() => (),
+                        _ => break,
+                    };
+                    let job = queue.pop_front().unwrap();
+                    debug_assert!(job.is_ready());
+                    batch.push(job);
+                }
                 mem::drop(queue);
 
-                // process element
-                job.sequential_work();
+                // process elements
+                if batch.is_empty() {
+                    break;
+                }
+                J::sequential_work_batch(&mut batch);
+                debug_assert!(batch.is_empty(), "batch should be drained");
             }
 
             #[cfg(debug)]
@@ -168,6 +179,54 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_consume_batch() {
+        struct TestJob {
+            id: usize,
+            ready: bool,
+            log: Arc<Mutex<Vec<Vec<usize>>>>,
+        }
+
+        impl SequentialJob for TestJob {
+            fn is_ready(&self) -> bool {
+                self.ready
+            }
+
+            fn sequential_work(self) {
+                unreachable!("jobs should be processed in batches");
+            }
+
+            fn sequential_work_batch(jobs: &mut Vec<Self>) {
+                let batch = jobs.iter().map(|job| job.id).collect();
+                jobs[0].log.lock().push(batch);
+                jobs.clear();
+            }
+        }
+
+        let queue = Queue::new();
+        let log = Arc::new(Mutex::new(vec![]));
+        let jobs = 2 * SEQUENTIAL_BATCH_SIZE + 1;
+
+        // the last job is not ready and should remain in the queue
+        for id in 0..=jobs {
+            queue.push(TestJob {
+                id,
+                ready: id < jobs,
+                log: log.clone(),
+            });
+        }
+        queue.consume();
+
+        let log = log.lock();
+        assert!(log.iter().all(|batch| batch.len() <= SEQUENTIAL_BATCH_SIZE));
+        assert_eq!(
+            log.concat(),
+            (0..jobs).collect::<Vec<_>>(),
+            "jobs should be processed in-order"
+        );
+        assert_eq!(queue.queue.lock().len(), 1);
+    }
+
     /* Fuzz the Queue */
     #[test]
     fn test_fuzz_queue() {
@@ -1,13 +1,16 @@
+use super::counters::count;
 use super::device::DecryptionState;
 use super::ip::inner_length;
 use super::messages::TransportHeader;
 use super::queue::{ParallelJob, Queue, SequentialJob};
+use super::shaper::{Delayed, Verdict};
 use super::types::Callbacks;
 use super::{REJECT_AFTER_MESSAGES, SIZE_TAG};
 
 use super::super::{tun, udp, Endpoint};
 
 use alloc::sync::Arc;
+use core::mem;
 use core::sync::atomic::{AtomicBool, Ordering};
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
 use spin::Mutex;
@@ -100,16 +103,29 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
                 // attempt to open (and authenticate) the body
                 match key.open_in_place(nonce, Aad::empty(), packet) {
                     Ok(_) => (),
-                    Err(_) => return false,
+                    Err(_) => {
+                        count(&peer.counters.rx_decryption_drops, 1);
+                        return false;
+                    }
                 }
 
                 // check that counter not after reject
                 if header.f_counter.get() >= REJECT_AFTER_MESSAGES {
+                    count(&peer.counters.rx_decryption_drops, 1);
                     return false;
                 }
 
                 // check crypto-key router
-                packet.len() == SIZE_TAG || peer.device.table.check_route(&peer, &packet)
+                if packet.len() != SIZE_TAG && !peer.device.table.check_route(peer, packet) {
+                    count(&peer.counters.rx_allowed_ips_drops, 1);
+                    return false;
+                }
+
+                // clamp the MSS of TCP connections to the MTU
+                let mtu = peer.mtu.load(Ordering::Relaxed);
+                let length = packet.len() - SIZE_TAG;
+                peer.device.mss.apply(&mut packet[..length], mtu);
+                true
             })();
 
             // remove message in case of failure:
@@ -144,42 +160,68 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
         let mut msg = job.buffer.lock();
         let endpoint = msg.0.take();
 
-        // cast transport header
-        let (header, packet): (LayoutVerified<&[u8], TransportHeader>, &[u8]) =
-            match LayoutVerified::new_from_prefix(&msg.1[..]) {
-                Some(v) => v,
-                None => {
-                    // also covers authentication failure (will fail to parse header)
-                    return;
+        // process buffer (the buffer is returned to the pool in any case)
+        (|| {
+            // cast transport header
+            let (header, packet): (LayoutVerified<&[u8], TransportHeader>, &[u8]) =
+                match LayoutVerified::new_from_prefix(&msg.1[..]) {
+                    Some(v) => v,
+                    None => {
+                        // also covers authentication failure (will fail to parse header)
+                        return;
+                    }
+                };
+
+            // check for replay
+            if !job.state.protector.lock().update(header.f_counter.get()) {
+                log::debug!("inbound worker: replay detected");
+                count(&peer.counters.rx_replay_drops, 1);
+                return;
+            }
+            count(&peer.counters.rx_packets, 1);
+
+            // check for confirms key
+            if !job.state.confirmed.swap(true, Ordering::SeqCst) {
+                log::debug!("inbound worker: message confirms key");
+                peer.confirm_key(&job.state.keypair);
+            }
+
+            // update endpoint
+            *peer.endpoint.lock() = endpoint;
+
+            // size of the transport message (the buffer may be held by the delay line)
+            let size = msg.1.len();
+
+            // check if should be written to TUN
+            // (keep-alive and malformed packets will have no inner length)
+            if let Some(inner) = inner_length(packet) {
+                if inner + SIZE_TAG <= packet.len() {
+                    // shape the inbound traffic
+                    match peer.shaper.inbound(inner, peer.device.clock.now()) {
+                        Verdict::Pass => {
+                            let _ = peer.device.inbound.write(&packet[..inner]).map_err(|e| {
+                                log::debug!("failed to write inbound packet to TUN: {:?}", e);
+                            });
+                        }
+                        Verdict::Delay(due) => {
+                            log::trace!("inbound packet delayed by traffic shaping");
+                            let buffer = mem::take(&mut msg.1);
+                            peer.device
+                                .delayed
+                                .push(due, Delayed::Inbound(peer.clone(), buffer, inner));
+                        }
+                        Verdict::Drop => {
+                            log::trace!("inbound packet dropped by traffic shaping");
+                        }
+                    }
                 }
-            };
-
-        // check for replay
-        if !job.state.protector.lock().update(header.f_counter.get()) {
-            log::debug!("inbound worker: replay detected");
-            return;
-        }
-
-        // check for confirms key
-        if !job.state.confirmed.swap(true, Ordering::SeqCst) {
-            log::debug!("inbound worker: message confirms key");
-            peer.confirm_key(&job.state.keypair);
-        }
-
-        // update endpoint
-        *peer.endpoint.lock() = endpoint;
-
-        // check if should be written to TUN
-        // (keep-alive and malformed packets will have no inner length)
-        if let Some(inner) = inner_length(packet) {
-            if inner + SIZE_TAG <= packet.len() {
-                let _ = peer.device.inbound.write(&packet[..inner]).map_err(|e| {
-                    log::debug!("failed to write inbound packet to TUN: {:?}", e);
-                });
             }
-        }
 
-        // trigger callback
-        C::recv(&peer.opaque, msg.1.len(), true, &job.state.keypair);
+            // trigger callback
+            C::recv(&peer.opaque, size, true, &job.state.keypair);
+        })();
+
+        // return the buffer to the pool
+        peer.device.buffers.put(mem::take(&mut msg.1));
     }
 }
@@ -116,23 +116,27 @@ impl<T: Eq + Clone> RoutingTable<T> {
     #[inline(always)]
     pub fn check_route(&self, peer: &T, packet: &[u8]) -> bool {
         match packet.get(0).map(|v| v >> 4) {
-            Some(VERSION_IP4) => LayoutVerified::new_from_prefix(packet)
-                .and_then(|(header, _): (LayoutVerified<&[u8], IPv4Header>, _)| {
-                    self.ipv4
-                        .read()
-                        .longest_match(Ipv4Addr::from(header.f_source))
-                        .map(|(_, _, p)| p == peer)
-                })
-                .is_some(),
-
-            Some(VERSION_IP6) => LayoutVerified::new_from_prefix(packet)
-                .and_then(|(header, _): (LayoutVerified<&[u8], IPv6Header>, _)| {
-                    self.ipv6
-                        .read()
-                        .longest_match(Ipv6Addr::from(header.f_source))
-                        .map(|(_, _, p)| p == peer)
-                })
-                .is_some(),
+            Some(VERSION_IP4) => {
+                LayoutVerified::new_from_prefix(packet).and_then(
+                    |(header, _): (LayoutVerified<&[u8], IPv4Header>, _)| {
+                        self.ipv4
+                            .read()
+                            .longest_match(Ipv4Addr::from(header.f_source))
+                            .map(|(_, _, p)| p == peer)
+                    },
+                ) == Some(true)
+            }
+
+            Some(VERSION_IP6) => {
+                LayoutVerified::new_from_prefix(packet).and_then(
+                    |(header, _): (LayoutVerified<&[u8], IPv6Header>, _)| {
+                        self.ipv6
+                            .read()
+                            .longest_match(Ipv6Addr::from(header.f_source))
+                            .map(|(_, _, p)| p == peer)
+                    },
+                ) == Some(true)
+            }
             _ => false,
         }
     }
@@ -1,19 +1,31 @@
+use super::counters::count;
 use super::messages::{TransportHeader, TYPE_TRANSPORT};
 use super::peer::Peer;
 use super::queue::{ParallelJob, Queue, SequentialJob};
-use super::types::Callbacks;
+use super::types::{Callbacks, RouterError};
 use super::KeyPair;
 use super::{REJECT_AFTER_MESSAGES, SIZE_TAG};
 
 use super::super::{tun, udp, Endpoint};
 
+use super::super::pool::recycle;
+
 use alloc::sync::Arc;
+use core::cell::RefCell;
+use core::mem;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use zerocopy::{AsBytes, LayoutVerified};
 
+type Batch = (Vec<MutexGuard<'static, Vec<u8>>>, Vec<&'static [u8]>);
+
+thread_local! {
+    // vectors of the messages sent as a batch (reused between batches, see sequential_work_batch)
+    static BATCH: RefCell<Batch> = const { RefCell::new((Vec::new(), Vec::new())) };
+}
+
 struct Inner<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
     ready: AtomicBool,
     buffer: Mutex<Vec<u8>>,
@@ -67,6 +79,7 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> ParallelJob
         // encrypt body
         {
             // make space for the tag
+            // (within the capacity reserved by the buffer pool)
             let job = &*self.0;
             let mut msg = job.buffer.lock();
             msg.extend([0u8; SIZE_TAG].iter());
@@ -127,10 +140,81 @@ impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> SequentialJob
 
         // send to peer
         let job = &self.0;
-        let msg = job.buffer.lock();
-        let xmit = job.peer.send_raw(&msg[..]).is_ok();
+        let mut msg = job.buffer.lock();
+        let xmit = match job.peer.send_raw(&msg[..]) {
+            Ok(()) => {
+                count(&job.peer.counters.tx_packets, 1);
+                true
+            }
+            Err(RouterError::NoEndpoint) => {
+                count(&job.peer.counters.tx_no_endpoint_drops, 1);
+                false
+            }
+            Err(_) => {
+                count(&job.peer.counters.tx_send_error_drops, 1);
+                false
+            }
+        };
 
         // trigger callback (for timers)
         C::send(&job.peer.opaque, msg.len(), xmit, &job.keypair, job.counter);
+
+        // return the buffer to the pool
+        job.peer.device.buffers.put(mem::take(&mut *msg));
+    }
+
+    fn sequential_work_batch(jobs: &mut Vec<Self>) {
+        debug_assert!(
+            jobs.iter().all(|job| job.is_ready()),
+            "doing sequential work on an incomplete job"
+        );
+        log::trace!("processing sequential send batch ({} jobs)", jobs.len());
+
+        // send to peer (every job in the in-order queue is for the same peer)
+        let first = match jobs.first() {
+            None => return,
+            Some(first) => &first.0,
+        };
+        let counters = &first.peer.counters;
+        let sent = BATCH.with(|batch| {
+            let mut batch = batch.borrow_mut();
+            let mut msgs: Vec<MutexGuard<Vec<u8>>> = recycle(mem::take(&mut batch.0));
+            let mut bufs: Vec<&[u8]> = recycle(mem::take(&mut batch.1));
+            msgs.extend(jobs.iter().map(|job| job.0.buffer.lock()));
+            bufs.extend(msgs.iter().map(|msg| &msg[..]));
+            let res = first.peer.send_raw_batch(&bufs[..]);
+            batch.1 = recycle(bufs);
+            batch.0 = recycle(msgs);
+            res
+        });
+        let sent = match sent {
+            Ok(sent) => {
+                count(&counters.tx_send_error_drops, (jobs.len() - sent) as u64);
+                sent
+            }
+            Err(RouterError::NoEndpoint) => {
+                count(&counters.tx_no_endpoint_drops, jobs.len() as u64);
+                0
+            }
+            Err(_) => {
+                count(&counters.tx_send_error_drops, jobs.len() as u64);
+                0
+            }
+        };
+        count(&counters.tx_packets, sent as u64);
+
+        // trigger callbacks (for timers) and return the buffers to the pool
+        for (i, job) in jobs.drain(..).enumerate() {
+            let job = &job.0;
+            let msg = mem::take(&mut *job.buffer.lock());
+            C::send(
+                &job.peer.opaque,
+                msg.len(),
+                i < sent,
+                &job.keypair,
+                job.counter,
+            );
+            job.peer.device.buffers.put(msg);
+        }
     }
 }
@@ -0,0 +1,423 @@
+/* Traffic shaping of the packets exchanged with a peer.
+ *
+ * Every direction of a peer is limited by a token bucket,
+ * implemented as the virtual scheduling variant of the generic cell rate algorithm:
+ * the "theoretical arrival time" advances by the transmission time (at the rate limit) of every packet,
+ * and a packet conforms if the theoretical arrival time is at most the burst tolerance ahead of the clock.
+ *
+ * Non-conforming packets are either dropped, or delayed until they conform (for at most SHAPING_MAX_DELAY),
+ * in which case they are held by the delay line of the device.
+ */
+use super::constants::{SHAPING_BURST, SHAPING_MAX_DELAY, SHAPING_QUEUE_SIZE};
+use super::device::Device;
+use super::peer::Peer;
+use super::types::Callbacks;
+use super::SIZE_MESSAGE_PREFIX;
+
+use super::super::{tun, udp, Endpoint};
+
+use core::cmp::{max, Ordering};
+use core::sync::atomic::{self, AtomicU64, AtomicUsize};
+
+// TODO: consider no_std alternatives
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use spin::Mutex;
+
+/// Treatment of packets exceeding the rate limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShapingPolicy {
+    Drop,  // drop the packet
+    Queue, // delay the packet (by at most SHAPING_MAX_DELAY), drop it otherwise
+}
+
+/// A snapshot of the traffic shaping of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shaping {
+    pub tx_rate: u64, // limit on outbound traffic in bytes per second (0 if unlimited)
+    pub rx_rate: u64, // limit on inbound traffic in bytes per second (0 if unlimited)
+    pub burst: u64,   // burst size in bytes (0 for the default of SHAPING_BURST)
+    pub policy: ShapingPolicy, // treatment of packets exceeding the rate limit
+    pub tx_shaped: u64, // number of outbound packets delayed
+    pub tx_dropped: u64, // number of outbound packets dropped
+    pub rx_shaped: u64, // number of inbound packets delayed
+    pub rx_dropped: u64, // number of inbound packets dropped
+}
+
+pub(super) enum Verdict {
+    Pass,           // transmit the packet
+    Delay(Instant), // transmit the packet at the given instant
+    Drop,           // drop the packet
+}
+
+struct Bucket {
+    rate: u64,            // bytes per second (0 if unlimited)
+    tat: Option<Instant>, // theoretical arrival time
+}
+
+struct Direction {
+    bucket: Mutex<Bucket>,
+    delayed: AtomicUsize, // number of packets in the delay line
+    shaped: AtomicU64,
+    dropped: AtomicU64,
+}
+
+pub(super) struct Shaper {
+    config: Mutex<(u64, ShapingPolicy)>, // burst and policy (shared by both directions)
+    tx: Direction,
+    rx: Direction,
+}
+
+// transmission time of the given number of bytes at the rate (in bytes per second)
+fn transmission_time(bytes: u64, rate: u64) -> Duration {
+    let nanos = (bytes as u128) * 1_000_000_000 / (rate as u128);
+    Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+}
+
+impl Direction {
+    fn new() -> Direction {
+        Direction {
+            bucket: Mutex::new(Bucket { rate: 0, tat: None }),
+            delayed: AtomicUsize::new(0),
+            shaped: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn shape(&self, config: (u64, ShapingPolicy), size: usize, now: Instant) -> Verdict {
+        let (burst, policy) = config;
+        let mut bucket = self.bucket.lock();
+        if bucket.rate == 0 {
+            return Verdict::Pass;
+        }
+
+        // earliest instant at which the packet conforms
+        let tolerance = if burst == 0 {
+            SHAPING_BURST
+        } else {
+            transmission_time(burst, bucket.rate)
+        };
+        let tat = bucket.tat.map_or(now, |tat| max(tat, now));
+        let departure = tat.checked_sub(tolerance).map_or(now, |d| max(d, now));
+
+        let verdict = if departure == now {
+            Verdict::Pass
+        } else if policy == ShapingPolicy::Queue
+            && departure - now <= SHAPING_MAX_DELAY
+            && self.delayed.load(atomic::Ordering::Relaxed) < SHAPING_QUEUE_SIZE
+        {
+            self.delayed.fetch_add(1, atomic::Ordering::Relaxed);
+            self.shaped.fetch_add(1, atomic::Ordering::Relaxed);
+            Verdict::Delay(departure)
+        } else {
+            // dropped packets do not consume tokens
+            self.dropped.fetch_add(1, atomic::Ordering::Relaxed);
+            return Verdict::Drop;
+        };
+
+        bucket.tat = Some(tat + transmission_time(size as u64, bucket.rate));
+        verdict
+    }
+
+    fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock();
+        bucket.rate = rate;
+        bucket.tat = None;
+    }
+}
+
+impl Shaper {
+    pub(super) fn new() -> Shaper {
+        Shaper {
+            config: Mutex::new((0, ShapingPolicy::Drop)),
+            tx: Direction::new(),
+            rx: Direction::new(),
+        }
+    }
+
+    /// Shape an outbound packet (of the given size) at the given instant
+    pub(super) fn outbound(&self, size: usize, now: Instant) -> Verdict {
+        let config = *self.config.lock();
+        self.tx.shape(config, size, now)
+    }
+
+    /// Shape an inbound packet (of the given size) at the given instant
+    pub(super) fn inbound(&self, size: usize, now: Instant) -> Verdict {
+        let config = *self.config.lock();
+        self.rx.shape(config, size, now)
+    }
+
+    pub(super) fn set_tx_rate(&self, rate: u64) {
+        self.tx.set_rate(rate);
+    }
+
+    pub(super) fn set_rx_rate(&self, rate: u64) {
+        self.rx.set_rate(rate);
+    }
+
+    pub(super) fn set_burst(&self, burst: u64) {
+        self.config.lock().0 = burst;
+    }
+
+    pub(super) fn set_policy(&self, policy: ShapingPolicy) {
+        self.config.lock().1 = policy;
+    }
+
+    pub(super) fn snapshot(&self) -> Shaping {
+        let (burst, policy) = *self.config.lock();
+        Shaping {
+            tx_rate: self.tx.bucket.lock().rate,
+            rx_rate: self.rx.bucket.lock().rate,
+            burst,
+            policy,
+            tx_shaped: self.tx.shaped.load(atomic::Ordering::Relaxed),
+            tx_dropped: self.tx.dropped.load(atomic::Ordering::Relaxed),
+            rx_shaped: self.rx.shaped.load(atomic::Ordering::Relaxed),
+            rx_dropped: self.rx.dropped.load(atomic::Ordering::Relaxed),
+        }
+    }
+}
+
+/* A packet held by the delay line:
+ * outbound packets are sent (encrypted) once released,
+ * inbound packets (of the given length, following the transport header) are written to the TUN device.
+ */
+pub(super) enum Delayed<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    Outbound(Peer<E, C, T, B>, Vec<u8>),
+    Inbound(Peer<E, C, T, B>, Vec<u8>, usize),
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Delayed<E, C, T, B> {
+    fn peer(&self) -> &Peer<E, C, T, B> {
+        match self {
+            Delayed::Outbound(peer, _) => peer,
+            Delayed::Inbound(peer, _, _) => peer,
+        }
+    }
+
+    fn release(self) {
+        match self {
+            Delayed::Outbound(peer, msg) => {
+                peer.shaper
+                    .tx
+                    .delayed
+                    .fetch_sub(1, atomic::Ordering::Relaxed);
+                peer.send(msg, true);
+            }
+            Delayed::Inbound(peer, msg, len) => {
+                peer.shaper
+                    .rx
+                    .delayed
+                    .fetch_sub(1, atomic::Ordering::Relaxed);
+                let packet = &msg[SIZE_MESSAGE_PREFIX..SIZE_MESSAGE_PREFIX + len];
+                let _ = peer.device.inbound.write(packet).map_err(|e| {
+                    log::debug!("failed to write delayed inbound packet to TUN: {:?}", e);
+                });
+                peer.device.buffers.put(msg);
+            }
+        }
+    }
+}
+
+struct Entry<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    due: Instant,
+    seq: u64, // order of insertion (among packets due at the same instant)
+    packet: Delayed<E, C, T, B>,
+}
+
+/* The binary heap is a max-heap:
+ * the entries are ordered by descending (due, seq), so that the earliest entry is on top.
+ */
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Ord for Entry<E, C, T, B> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PartialOrd
+    for Entry<E, C, T, B>
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> PartialEq for Entry<E, C, T, B> {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> Eq for Entry<E, C, T, B> {}
+
+struct DelayState<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    closed: bool,
+    seq: u64,
+    heap: BinaryHeap<Entry<E, C, T, B>>,
+}
+
+/// The packets delayed by the shapers of the peers of a device
+pub(super) struct DelayLine<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> {
+    state: StdMutex<DelayState<E, C, T, B>>,
+    cond: Condvar,
+}
+
+impl<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>> DelayLine<E, C, T, B> {
+    pub(super) fn new() -> DelayLine<E, C, T, B> {
+        DelayLine {
+            state: StdMutex::new(DelayState {
+                closed: false,
+                seq: 0,
+                heap: BinaryHeap::new(),
+            }),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Hold the packet until the given instant
+    pub(super) fn push(&self, due: Instant, packet: Delayed<E, C, T, B>) {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.seq;
+        state.seq += 1;
+        state.heap.push(Entry { due, seq, packet });
+        self.cond.notify_one();
+    }
+
+    /// Discard the packets of a (removed) peer
+    pub(super) fn purge(&self, peer: &Peer<E, C, T, B>) {
+        self.state
+            .lock()
+            .unwrap()
+            .heap
+            .retain(|entry| entry.packet.peer() != peer);
+    }
+
+    /// Discard all packets and stop the worker
+    pub(super) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.heap.clear();
+        self.cond.notify_all();
+    }
+}
+
+/// Release the packets of the delay line of the device when due
+pub(super) fn delay_worker<E: Endpoint, C: Callbacks, T: tun::Writer, B: udp::Writer<E>>(
+    device: Device<E, C, T, B>,
+) {
+    let line = &device.delayed;
+    let mut state = line.state.lock().unwrap();
+    loop {
+        if state.closed {
+            log::debug!("delay worker stopped");
+            break;
+        }
+
+        // wait for the earliest packet to become due
+        let due = match state.heap.peek() {
+            None => {
+                state = line.cond.wait(state).unwrap();
+                continue;
+            }
+            Some(entry) => entry.due,
+        };
+        let now = device.clock.now();
+        if due > now {
+            state = line.cond.wait_timeout(state, due - now).unwrap().0;
+            continue;
+        }
+
+        // release the packet (without holding the lock)
+        let entry = state.heap.pop().unwrap();
+        drop(state);
+        entry.packet.release();
+        state = line.state.lock().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn test_shaper_unlimited() {
+        let shaper = Shaper::new();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert!(matches!(shaper.outbound(1500, now), Verdict::Pass));
+            assert!(matches!(shaper.inbound(1500, now), Verdict::Pass));
+        }
+        let shaping = shaper.snapshot();
+        assert_eq!(shaping.tx_shaped + shaping.tx_dropped, 0);
+        assert_eq!(shaping.rx_shaped + shaping.rx_dropped, 0);
+    }
+
+    #[test]
+    fn test_shaper_drop() {
+        // 1000 bytes per second with a burst of 2000 bytes
+        let shaper = Shaper::new();
+        shaper.set_tx_rate(1000);
+        shaper.set_burst(2000);
+        let start = Instant::now();
+
+        // the burst (and one packet) passes, after which packets are dropped
+        for _ in 0..3 {
+            assert!(matches!(shaper.outbound(1000, start), Verdict::Pass));
+        }
+        assert!(matches!(shaper.outbound(1000, start), Verdict::Drop));
+        assert!(matches!(
+            shaper.outbound(1000, start + 500 * MS),
+            Verdict::Drop
+        ));
+
+        // one packet per second passes
+        for secs in 1..10 {
+            let now = start + Duration::from_secs(secs);
+            assert!(matches!(shaper.outbound(1000, now), Verdict::Pass));
+            assert!(matches!(shaper.outbound(1000, now), Verdict::Drop));
+        }
+
+        // the inbound direction is not limited
+        assert!(matches!(shaper.inbound(1000, start), Verdict::Pass));
+
+        let shaping = shaper.snapshot();
+        assert_eq!(shaping.tx_shaped, 0);
+        assert_eq!(shaping.tx_dropped, 11);
+        assert_eq!(shaping.rx_dropped, 0);
+    }
+
+    #[test]
+    fn test_shaper_queue() {
+        // 100 kB per second with a burst of 1000 bytes (delaying 10ms per 1000 bytes)
+        let shaper = Shaper::new();
+        shaper.set_rx_rate(100_000);
+        shaper.set_burst(1000);
+        shaper.set_policy(ShapingPolicy::Queue);
+        let start = Instant::now();
+
+        assert!(matches!(shaper.inbound(1000, start), Verdict::Pass));
+        assert!(matches!(shaper.inbound(1000, start), Verdict::Pass));
+
+        // packets are delayed (in order) by at most SHAPING_MAX_DELAY
+        let mut last = start;
+        for _ in 0..10 {
+            match shaper.inbound(1000, start) {
+                Verdict::Delay(due) => {
+                    assert!(due > last);
+                    assert!(due - start <= SHAPING_MAX_DELAY);
+                    last = due;
+                }
+                _ => panic!("packet should be delayed"),
+            }
+        }
+        assert!(matches!(shaper.inbound(1000, start), Verdict::Drop));
+
+        let shaping = shaper.snapshot();
+        assert_eq!(shaping.rx_shaped, 10);
+        assert_eq!(shaping.rx_dropped, 1);
+    }
+}
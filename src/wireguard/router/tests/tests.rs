@@ -480,3 +480,234 @@ fn test_bidirectional() {
         }
     }
 }
+
+#[test]
+fn test_clear_sending_keys() {
+    init();
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    let (_fake, _, tun_writer1, _) = dummy::TunTest::create(false);
+    let (_fake, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+    let router1: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+    router1.set_outbound_writer(bind_writer1);
+
+    let router2: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    router2.set_outbound_writer(bind_writer2);
+
+    let opaque1 = Opaque::new();
+    let opaque2 = Opaque::new();
+
+    // peer1 (at router1) routes to 10.0.0.2, peer2 (at router2) routes to 10.0.0.1
+    let peer1 = router1.new_peer(opaque1.clone());
+    let peer2 = router2.new_peer(opaque2.clone());
+    peer1.add_allowed_ip("10.0.0.2".parse().unwrap(), 32);
+    peer2.add_allowed_ip("10.0.0.1".parse().unwrap(), 32);
+    peer1.set_endpoint(dummy::UnitEndpoint::new());
+    peer2.set_endpoint(dummy::UnitEndpoint::new());
+
+    let forward = |id: u64| {
+        make_packet(
+            SIZE_MSG,
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+            id,
+        )
+    };
+    let backward = |id: u64| {
+        make_packet(
+            SIZE_MSG,
+            "10.0.0.2".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+            id,
+        )
+    };
+
+    let encrypted_size = SIZE_KEEPALIVE + forward(0).len();
+
+    // establish a session: router1 initiates, the keepalive confirms the key at router2
+    peer2.add_keypair(dummy_keypair(false));
+    peer1.add_keypair(dummy_keypair(true));
+    assert_eq!(opaque1.send.wait(TIMEOUT), Some((SIZE_KEEPALIVE, true)));
+    let mut buf = vec![0u8; SIZE_MSG * 2];
+    let (len, from) = bind_reader2.read(&mut buf).unwrap();
+    buf.truncate(len);
+    router2.recv(from, buf).unwrap();
+    assert_eq!(opaque2.recv.wait(TIMEOUT), Some((SIZE_KEEPALIVE, true)));
+    assert_eq!(opaque2.key_confirmed.wait(TIMEOUT), Some(()));
+    no_events!(opaque1);
+    no_events!(opaque2);
+
+    for id in 0..100 {
+        // the private key of router1 changes while traffic flows
+        if id == 50 {
+            router1.clear_sending_keys();
+            assert_eq!(
+                opaque1.need_key.wait(TIMEOUT),
+                Some(()),
+                "a new handshake should be requested for the peer with a session"
+            );
+            no_events!(opaque1);
+            no_events!(opaque2);
+
+            // messages of the old session are no longer decrypted
+            router2.send(pad(&backward(id))).unwrap();
+            assert_eq!(opaque2.send.wait(TIMEOUT), Some((encrypted_size, true)));
+            let mut buf = vec![0u8; SIZE_MSG * 2];
+            let (len, from) = bind_reader1.read(&mut buf).unwrap();
+            buf.truncate(len);
+            assert!(router1.recv(from, buf).is_err());
+
+            // outbound messages are staged until a new key is available
+            router1.send(pad(&forward(id))).unwrap();
+            assert_eq!(opaque1.need_key.wait(TIMEOUT), Some(()));
+            no_events!(opaque1);
+            no_events!(opaque2);
+
+            // the new session confirms using the staged message
+            // (the receiver ids of the old session are still in use at router2)
+            let rekey = |initiator| {
+                let mut keypair = dummy_keypair(initiator);
+                keypair.send.id += 1;
+                keypair.recv.id += 1;
+                keypair
+            };
+            peer2.add_keypair(rekey(false));
+            peer1.add_keypair(rekey(true));
+        } else {
+            router1.send(pad(&forward(id))).unwrap();
+        }
+
+        assert_eq!(
+            opaque1.send.wait(TIMEOUT),
+            Some((encrypted_size, true)),
+            "message should be encrypted (with the new session after the change)"
+        );
+        let mut buf = vec![0u8; SIZE_MSG * 2];
+        let (len, from) = bind_reader2.read(&mut buf).unwrap();
+        buf.truncate(len);
+        router2.recv(from, buf).unwrap();
+        assert_eq!(opaque2.recv.wait(TIMEOUT), Some((encrypted_size, true)));
+        if id == 50 {
+            assert_eq!(opaque2.key_confirmed.wait(TIMEOUT), Some(()));
+        }
+        no_events!(opaque1);
+        no_events!(opaque2);
+    }
+}
+
+#[test]
+fn test_counters() {
+    init();
+
+    use super::super::constants::MAX_QUEUED_PACKETS;
+
+    // wait (a bounded time) for the router to update a counter
+    fn eventually<F: Fn() -> bool>(f: F) -> bool {
+        (0..100).any(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            f()
+        })
+    }
+
+    let ((bind_reader1, bind_writer1), (_bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    let (_fake, _, tun_writer1, _) = dummy::TunTest::create(false);
+    let (_fake, _, tun_writer2, _) = dummy::TunTest::create(false);
+
+    let router1: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer1);
+    router1.set_outbound_writer(bind_writer1);
+
+    let router2: Device<_, TestCallbacks, _, _> = Device::new(1, tun_writer2);
+    router2.set_outbound_writer(bind_writer2);
+
+    let opaque1 = Opaque::new();
+    let opaque2 = Opaque::new();
+    let opaque3 = Opaque::new();
+
+    // peer1 (at router1) routes to 10.0.0.2, peer2 (at router2) routes to 10.0.0.1
+    let peer1 = router1.new_peer(opaque1.clone());
+    let peer2 = router2.new_peer(opaque2.clone());
+    peer1.add_allowed_ip("10.0.0.2".parse().unwrap(), 32);
+    peer2.add_allowed_ip("10.0.0.1".parse().unwrap(), 32);
+
+    let packet = |src: &str, id: u64| {
+        make_packet(
+            SIZE_MSG,
+            src.parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+            id,
+        )
+    };
+
+    // packets for a peer without a key are staged (evicting the oldest when full)
+    let peer3 = router1.new_peer(opaque3.clone());
+    peer3.add_allowed_ip("10.0.0.3".parse().unwrap(), 32);
+    for id in 0..=MAX_QUEUED_PACKETS {
+        let msg = make_packet(
+            SIZE_MSG,
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.3".parse().unwrap(),
+            id as u64,
+        );
+        router1.send(pad(&msg)).unwrap();
+    }
+    assert_eq!(peer3.get_counters().tx_staged_drops, 1);
+
+    // the confirmation of a key is dropped without an endpoint
+    peer1.add_keypair(dummy_keypair(false));
+    peer2.add_keypair(dummy_keypair(true));
+    assert_eq!(opaque2.send.wait(TIMEOUT), Some((SIZE_KEEPALIVE, false)));
+    assert_eq!(peer2.get_counters().tx_no_endpoint_drops, 1);
+    assert_eq!(peer2.get_counters().tx_packets, 0);
+
+    // send messages from peer2 to peer1
+    peer2.set_endpoint(dummy::UnitEndpoint::new());
+    let mut msgs = vec![];
+    for (id, src) in ["10.0.0.2", "10.0.0.2", "10.0.0.2", "10.0.0.9"]
+        .iter()
+        .enumerate()
+    {
+        router2.send(pad(&packet(src, id as u64))).unwrap();
+        let mut buf = vec![0u8; SIZE_MSG * 2];
+        let (len, from) = bind_reader1.read(&mut buf).unwrap();
+        buf.truncate(len);
+        msgs.push((from, buf));
+    }
+    assert!(eventually(|| peer2.get_counters().tx_packets == 4));
+
+    // a valid message is received
+    let (from, msg) = msgs[0].clone();
+    router1.recv(from, msg.clone()).unwrap();
+    assert!(opaque1.recv.wait(TIMEOUT).is_some());
+    assert_eq!(peer1.get_counters().rx_packets, 1);
+
+    // a replayed message is dropped
+    router1.recv(from, msg).unwrap();
+    assert!(eventually(|| peer1.get_counters().rx_replay_drops == 1));
+
+    // a tampered message is dropped
+    let (from, mut msg) = msgs[1].clone();
+    let last = msg.len() - 1;
+    msg[last] ^= 1;
+    router1.recv(from, msg).unwrap();
+    assert!(eventually(|| peer1.get_counters().rx_decryption_drops == 1));
+
+    // a packet from a source address not allowed for the peer is dropped
+    let (from, msg) = msgs[3].clone();
+    router1.recv(from, msg).unwrap();
+    assert!(eventually(|| peer1.get_counters().rx_allowed_ips_drops == 1));
+
+    // while later messages are still received
+    let (from, msg) = msgs[2].clone();
+    router1.recv(from, msg).unwrap();
+    assert!(opaque1.recv.wait(TIMEOUT).is_some());
+
+    let counters = peer1.get_counters();
+    assert_eq!(counters.rx_packets, 2);
+    assert_eq!(counters.rx_replay_drops, 1);
+    assert_eq!(counters.rx_decryption_drops, 1);
+    assert_eq!(counters.rx_allowed_ips_drops, 1);
+    assert_eq!(counters.rx_queue_drops, 0);
+}
@@ -37,6 +37,7 @@ pub trait Callbacks: Send + Sync + 'static {
 #[derive(Debug)]
 pub enum RouterError {
     NoCryptoKeyRoute,
+    PacketTooBig,
     MalformedTransportMessage,
     UnknownReceiverId,
     NoEndpoint,
@@ -47,6 +48,7 @@ impl fmt::Display for RouterError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             RouterError::NoCryptoKeyRoute => write!(f, "No cryptokey route configured for subnet"),
+            RouterError::PacketTooBig => write!(f, "Packet exceeds the MTU of the peer"),
             RouterError::MalformedTransportMessage => write!(f, "Transport header is malformed"),
             RouterError::UnknownReceiverId => {
                 write!(f, "No decryption state associated with receiver id")
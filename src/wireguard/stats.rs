@@ -0,0 +1,109 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use super::constants::DURATION_UNDER_LOAD;
+
+/// Device-wide statistics and health summary
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub handshake_queue_depth: usize, // handshake messages waiting for a handshake worker
+    pub router_queue_depth: usize,    // jobs waiting for a router worker
+    pub under_load: bool,             // is the device currently under load
+    pub under_load_time: Duration,    // total time spent under load
+    pub no_route_drops: u64,          // IP packets without a cryptokey route
+    pub mtu_drops: u64,               // IP packets exceeding the MTU of the device
+    pub unknown_receiver_drops: u64,  // messages for an unknown receiver id
+    pub malformed_drops: u64,         // malformed messages (or of an unknown type)
+}
+
+pub struct Stats {
+    pub no_route_drops: AtomicU64,
+    pub mtu_drops: AtomicU64,
+    pub unknown_receiver_drops: AtomicU64,
+    pub malformed_drops: AtomicU64,
+
+    // time under load (in nanoseconds), including the remainder of the current period
+    under_load_nanos: AtomicU64,
+}
+
+// increment a counter
+#[inline(always)]
+pub fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            no_route_drops: AtomicU64::new(0),
+            mtu_drops: AtomicU64::new(0),
+            unknown_receiver_drops: AtomicU64::new(0),
+            malformed_drops: AtomicU64::new(0),
+            under_load_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /* Records that the device went under load at `now`,
+     * remaining under load for DURATION_UNDER_LOAD
+     * (extending the period starting at `last`, if it has not yet ended).
+     *
+     * Must be called with the lock on the time of the last period held.
+     */
+    pub fn under_load(&self, last: Instant, now: Instant) {
+        let extension = DURATION_UNDER_LOAD.min(now.saturating_duration_since(last));
+        self.under_load_nanos
+            .fetch_add(extension.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /* Returns a snapshot of the counters
+     * (the queue depths are filled in by the caller).
+     *
+     * Must be called with the lock on the time of the last period (`last`) held.
+     */
+    pub fn snapshot(&self, last: Instant, now: Instant) -> DeviceStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        // exclude the part of the current period which is yet to come
+        let remaining = (last + DURATION_UNDER_LOAD).saturating_duration_since(now);
+        let under_load = Duration::from_nanos(load(&self.under_load_nanos));
+        DeviceStats {
+            handshake_queue_depth: 0,
+            router_queue_depth: 0,
+            under_load: DURATION_UNDER_LOAD >= now.saturating_duration_since(last),
+            under_load_time: under_load.checked_sub(remaining).unwrap_or_default(),
+            no_route_drops: load(&self.no_route_drops),
+            mtu_drops: load(&self.mtu_drops),
+            unknown_receiver_drops: load(&self.unknown_receiver_drops),
+            malformed_drops: load(&self.malformed_drops),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_under_load_time() {
+        let stats = Stats::new();
+        let start = Instant::now() + Duration::from_secs(3600);
+        let half = DURATION_UNDER_LOAD / 2;
+
+        // never under load
+        let snapshot = stats.snapshot(start - Duration::from_secs(3600), start);
+        assert!(!snapshot.under_load);
+        assert_eq!(snapshot.under_load_time, Duration::from_secs(0));
+
+        // the current period is only counted as it elapses
+        stats.under_load(start - Duration::from_secs(3600), start);
+        let snapshot = stats.snapshot(start, start + half);
+        assert!(snapshot.under_load);
+        assert_eq!(snapshot.under_load_time, half);
+
+        // overlapping periods are counted once
+        stats.under_load(start, start + half);
+        let snapshot = stats.snapshot(start + half, start + DURATION_UNDER_LOAD * 3);
+        assert!(!snapshot.under_load);
+        assert_eq!(snapshot.under_load_time, DURATION_UNDER_LOAD + half);
+    }
+}
@@ -0,0 +1,535 @@
+use super::dummy;
+use super::wireguard::WireGuard;
+use super::{PeerResolver, ProvisionedPeer, SimulatedWheel, VirtualClock};
+
+use std::convert::TryInto;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use hex;
+use rand_chacha::ChaCha8Rng;
+use rand_core::{RngCore, SeedableRng};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::ipv6::MutableIpv6Packet;
+
+mod network;
+mod timers;
+
+pub fn make_packet(size: usize, src: IpAddr, dst: IpAddr, id: u64) -> Vec<u8> {
+    // expand pseudo random payload
+    let mut rng: _ = ChaCha8Rng::seed_from_u64(id);
+    let mut p: Vec<u8> = vec![0; size];
+    rng.fill_bytes(&mut p);
+
+    // create "IP packet"
+    let mut msg = Vec::with_capacity(size);
+    match dst {
+        IpAddr::V4(dst) => {
+            let length = size + MutableIpv4Packet::minimum_packet_size();
+            msg.resize(length, 0);
+
+            let mut packet = MutableIpv4Packet::new(&mut msg[..]).unwrap();
+            packet.set_destination(dst);
+            packet.set_total_length(length.try_into().expect("length too great for IPv4 packet"));
+            packet.set_source(if let IpAddr::V4(src) = src {
+                src
+            } else {
+                panic!("src.version != dst.version")
+            });
+            packet.set_payload(&p);
+            packet.set_version(4);
+            packet.set_header_length(5);
+        }
+        IpAddr::V6(dst) => {
+            let length = size + MutableIpv6Packet::minimum_packet_size();
+            msg.resize(length, 0);
+
+            let mut packet = MutableIpv6Packet::new(&mut msg[..]).unwrap();
+            packet.set_destination(dst);
+            packet.set_payload_length(size.try_into().expect("length too great for IPv6 packet"));
+            packet.set_source(if let IpAddr::V6(src) = src {
+                src
+            } else {
+                panic!("src.version != dst.version")
+            });
+            packet.set_payload(&p);
+            packet.set_version(6);
+        }
+    }
+    msg
+}
+
+fn init() {
+    let _ = env_logger::builder().is_test(true).try_init();
+}
+
+/* Create and configure
+ * two matching pure (no side-effects) instances of WireGuard.
+ *
+ * Test:
+ *
+ * - Handshaking completes successfully
+ * - All packets up to MTU are delivered
+ * - All packets are delivered in-order
+ */
+#[test]
+fn test_pure_wireguard() {
+    init();
+
+    // create WG instances for dummy TUN devices
+
+    let (fake1, tun_reader1, tun_writer1, _) = dummy::TunTest::create(true);
+    let wg1: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer1);
+    wg1.add_tun_reader(tun_reader1);
+    wg1.up(1500);
+
+    let (fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create(true);
+    let wg2: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer2);
+    wg2.add_tun_reader(tun_reader2);
+    wg2.up(1500);
+
+    // create pair bind to connect the interfaces "over the internet"
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    wg1.set_writer(bind_writer1);
+    wg2.set_writer(bind_writer2);
+
+    wg1.add_udp_reader(bind_reader1);
+    wg2.add_udp_reader(bind_reader2);
+
+    // configure (public, private) key pairs
+
+    let sk1 = StaticSecret::from([
+        0x3f, 0x69, 0x86, 0xd1, 0xc0, 0xec, 0x25, 0xa0, 0x9c, 0x8e, 0x56, 0xb5, 0x1d, 0xb7, 0x3c,
+        0xed, 0x56, 0x8e, 0x59, 0x9d, 0xd9, 0xc3, 0x98, 0x67, 0x74, 0x69, 0x90, 0xc3, 0x43, 0x36,
+        0x78, 0x89,
+    ]);
+
+    let sk2 = StaticSecret::from([
+        0xfb, 0xd1, 0xd6, 0xe4, 0x65, 0x06, 0xd2, 0xe5, 0xc5, 0xdf, 0x6e, 0xab, 0x51, 0x71, 0xd8,
+        0x70, 0xb5, 0xb7, 0x77, 0x51, 0xb4, 0xbe, 0xfb, 0xbc, 0x88, 0x62, 0x40, 0xca, 0x2c, 0xc2,
+        0x66, 0xe2,
+    ]);
+
+    let pk1 = PublicKey::from(&sk1);
+
+    let pk2 = PublicKey::from(&sk2);
+
+    wg1.add_peer(pk2);
+    wg2.add_peer(pk1);
+
+    wg1.set_key(Some(sk1));
+    wg2.set_key(Some(sk2));
+
+    // configure crypto-key router
+
+    {
+        let peers1 = wg1.peers.read();
+        let peers2 = wg2.peers.read();
+
+        let peer2 = peers1.get(&pk2).unwrap();
+        let peer1 = peers2.get(&pk1).unwrap();
+
+        peer1.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+
+        peer2.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+
+        // set endpoint (the other should be learned dynamically)
+
+        peer2.set_endpoint(dummy::UnitEndpoint::new());
+    }
+
+    let num_packets = 20;
+
+    // send IP packets (causing a new handshake)
+
+    {
+        let mut packets: Vec<Vec<u8>> = Vec::with_capacity(num_packets);
+
+        for id in 0..num_packets {
+            packets.push(make_packet(
+                50 * id as usize,                // size
+                "192.168.1.20".parse().unwrap(), // src
+                "192.168.2.10".parse().unwrap(), // dst
+                id as u64,                       // prng seed
+            ));
+        }
+
+        let mut backup = packets.clone();
+
+        while let Some(p) = packets.pop() {
+            println!("send");
+            fake1.write(p);
+        }
+
+        while let Some(p) = backup.pop() {
+            println!("read");
+            assert_eq!(
+                hex::encode(fake2.read()),
+                hex::encode(p),
+                "Failed to receive valid IPv4 packet unmodified and in-order"
+            );
+        }
+    }
+
+    // send IP packets (other direction)
+
+    {
+        let mut packets: Vec<Vec<u8>> = Vec::with_capacity(num_packets);
+
+        for id in 0..num_packets {
+            packets.push(make_packet(
+                50 + 50 * id as usize,           // size
+                "192.168.2.10".parse().unwrap(), // src
+                "192.168.1.20".parse().unwrap(), // dst
+                (id + 100) as u64,               // prng seed
+            ));
+        }
+
+        let mut backup = packets.clone();
+
+        while let Some(p) = packets.pop() {
+            fake2.write(p);
+        }
+
+        while let Some(p) = backup.pop() {
+            assert_eq!(
+                hex::encode(fake1.read()),
+                hex::encode(p),
+                "Failed to receive valid IPv4 packet unmodified and in-order"
+            );
+        }
+    }
+}
+
+/* Create two instances of WireGuard, where the TUN readers are added before the devices are up
+ * (as when the TUN device is created before the interface is configured).
+ *
+ * Test:
+ *
+ * - The first IP packets read after the device comes up are delivered
+ *   (despite being read while the MTU was unknown)
+ */
+#[test]
+fn test_tun_reader_before_up() {
+    init();
+
+    // create WG instances for dummy TUN devices (which are down)
+
+    let (fake1, tun_reader1, tun_writer1, _) = dummy::TunTest::create(true);
+    let wg1: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer1);
+    wg1.add_tun_reader(tun_reader1);
+
+    let (fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create(true);
+    let wg2: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer2);
+    wg2.add_tun_reader(tun_reader2);
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    wg1.set_writer(bind_writer1);
+    wg2.set_writer(bind_writer2);
+
+    wg1.add_udp_reader(bind_reader1);
+    wg2.add_udp_reader(bind_reader2);
+
+    let sk1 = StaticSecret::from([1u8; 32]);
+    let sk2 = StaticSecret::from([2u8; 32]);
+
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+
+    wg1.add_peer(pk2);
+    wg2.add_peer(pk1);
+
+    wg1.set_key(Some(sk1));
+    wg2.set_key(Some(sk2));
+
+    {
+        let peers1 = wg1.peers.read();
+        let peers2 = wg2.peers.read();
+
+        let peer2 = peers1.get(&pk2).unwrap();
+        let peer1 = peers2.get(&pk1).unwrap();
+
+        peer1.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+        peer2.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+        peer2.set_endpoint(dummy::UnitEndpoint::new());
+    }
+
+    // let the TUN workers block in a read, then set the devices up
+
+    thread::sleep(Duration::from_millis(50));
+    wg1.up(1500);
+    wg2.up(1500);
+
+    // send IP packets in both directions (the first to be read by the TUN workers)
+
+    let packet = make_packet(
+        1000,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    );
+    fake1.write(packet.clone());
+    assert_eq!(
+        fake2.read_timeout(Duration::from_secs(5)).map(hex::encode),
+        Some(hex::encode(packet))
+    );
+
+    let packet = make_packet(
+        1000,
+        "192.168.2.10".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        1,
+    );
+    fake2.write(packet.clone());
+    assert_eq!(
+        fake1.read_timeout(Duration::from_secs(5)).map(hex::encode),
+        Some(hex::encode(packet))
+    );
+}
+
+/* Create two instances of WireGuard, where the second is not configured with the first peer,
+ * but provisions it on-demand using a resolver.
+ *
+ * Test:
+ *
+ * - The unknown initiator is provisioned and the handshake completes
+ * - The provisioned allowed IPs are used for crypto-key routing
+ * - Idle provisioned peers are evicted
+ */
+#[test]
+fn test_provisioned_peer() {
+    init();
+
+    struct Resolver(PublicKey);
+
+    impl PeerResolver for Resolver {
+        fn resolve(&self, pk: &PublicKey) -> Option<ProvisionedPeer> {
+            if pk.as_bytes() != self.0.as_bytes() {
+                return None;
+            }
+            Some(ProvisionedPeer {
+                allowed_ips: vec![("192.168.1.0".parse().unwrap(), 24)],
+                ..Default::default()
+            })
+        }
+    }
+
+    // create WG instances for dummy TUN devices
+
+    let (fake1, tun_reader1, tun_writer1, _) = dummy::TunTest::create(true);
+    let wg1: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer1);
+    wg1.add_tun_reader(tun_reader1);
+    wg1.up(1500);
+
+    let (fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create(true);
+    let wg2: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::new(tun_writer2);
+    wg2.add_tun_reader(tun_reader2);
+    wg2.up(1500);
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    wg1.set_writer(bind_writer1);
+    wg2.set_writer(bind_writer2);
+
+    wg1.add_udp_reader(bind_reader1);
+    wg2.add_udp_reader(bind_reader2);
+
+    // only the initiator is configured with the other peer
+
+    let sk1 = StaticSecret::from([1u8; 32]);
+    let sk2 = StaticSecret::from([2u8; 32]);
+
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+
+    wg1.add_peer(pk2);
+    wg1.set_key(Some(sk1));
+    wg2.set_key(Some(sk2));
+
+    {
+        let peers1 = wg1.peers.read();
+        let peer2 = peers1.get(&pk2).unwrap();
+        peer2.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+        peer2.set_endpoint(dummy::UnitEndpoint::new());
+    }
+
+    wg2.set_peer_resolver(Some(Arc::new(Resolver(pk1))), Duration::from_secs(3600));
+
+    // send IP packet (causing a handshake and provisioning)
+
+    let packet = make_packet(
+        100,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    );
+    fake1.write(packet.clone());
+    assert_eq!(hex::encode(fake2.read()), hex::encode(packet));
+    assert!(wg2
+        .peers
+        .read()
+        .get(&pk1)
+        .unwrap()
+        .provisioned
+        .load(Ordering::Relaxed));
+
+    // route a packet using the provisioned allowed IPs
+
+    let packet = make_packet(
+        100,
+        "192.168.2.10".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        1,
+    );
+    fake2.write(packet.clone());
+    assert_eq!(hex::encode(fake1.read()), hex::encode(&packet));
+
+    // peers which are not idle are kept
+
+    wg2.evict_idle_peers();
+    assert!(wg2.peers.read().contains_key(&pk1));
+
+    // idleness is determined by the traffic (not the handshakes) of the peer
+
+    wg2.set_peer_resolver(Some(Arc::new(Resolver(pk1))), Duration::from_millis(500));
+    thread::sleep(Duration::from_millis(600));
+    fake2.write(packet.clone());
+    assert_eq!(hex::encode(fake1.read()), hex::encode(packet));
+    // (the activity is recorded after sending, which may complete after the message is received)
+    thread::sleep(Duration::from_millis(50));
+    wg2.evict_idle_peers();
+    assert!(wg2.peers.read().contains_key(&pk1));
+
+    // idle provisioned peers are evicted
+
+    thread::sleep(Duration::from_millis(600));
+    wg2.evict_idle_peers();
+    assert!(!wg2.peers.read().contains_key(&pk1));
+}
+
+/* Create two instances of WireGuard with seeded RNGs and a shared virtual clock (and timer wheel).
+ *
+ * Test:
+ *
+ * - The identifiers of the devices are determined by the seeds
+ * - Handshaking completes successfully
+ * - The time of the last handshake is read from the virtual clock
+ */
+#[test]
+fn test_deterministic_sources() {
+    init();
+
+    let clock = Arc::new(VirtualClock::new(
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    ));
+    clock.advance(Duration::from_secs(42));
+    let wheel = Arc::new(SimulatedWheel::new(clock.clone()));
+
+    // create WG instances for dummy TUN devices
+
+    let (fake1, tun_reader1, tun_writer1, _) = dummy::TunTest::create(true);
+    let wg1: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::with_sources(
+        tun_writer1,
+        ChaCha8Rng::seed_from_u64(1),
+        clock.clone(),
+        wheel.clone(),
+    );
+    wg1.add_tun_reader(tun_reader1);
+    wg1.up(1500);
+
+    let (fake2, tun_reader2, tun_writer2, _) = dummy::TunTest::create(true);
+    let wg2: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::with_sources(
+        tun_writer2,
+        ChaCha8Rng::seed_from_u64(2),
+        clock.clone(),
+        wheel.clone(),
+    );
+    wg2.add_tun_reader(tun_reader2);
+    wg2.up(1500);
+
+    // the same seed yields the same device
+
+    let (_, _, tun_writer3, _) = dummy::TunTest::create(false);
+    let wg3: WireGuard<dummy::TunTest, dummy::PairBind> = WireGuard::with_sources(
+        tun_writer3,
+        ChaCha8Rng::seed_from_u64(1),
+        clock.clone(),
+        wheel.clone(),
+    );
+    assert_eq!(wg1.id, wg3.id);
+    assert_ne!(wg1.id, wg2.id);
+
+    let ((bind_reader1, bind_writer1), (bind_reader2, bind_writer2)) = dummy::PairBind::pair();
+
+    wg1.set_writer(bind_writer1);
+    wg2.set_writer(bind_writer2);
+
+    wg1.add_udp_reader(bind_reader1);
+    wg2.add_udp_reader(bind_reader2);
+
+    let sk1 = StaticSecret::from([1u8; 32]);
+    let sk2 = StaticSecret::from([2u8; 32]);
+
+    let pk1 = PublicKey::from(&sk1);
+    let pk2 = PublicKey::from(&sk2);
+
+    wg1.add_peer(pk2);
+    wg2.add_peer(pk1);
+
+    wg1.set_key(Some(sk1));
+    wg2.set_key(Some(sk2));
+
+    {
+        let peers1 = wg1.peers.read();
+        let peers2 = wg2.peers.read();
+
+        peers2
+            .get(&pk1)
+            .unwrap()
+            .add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+
+        let peer2 = peers1.get(&pk2).unwrap();
+        peer2.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+        peer2.set_endpoint(dummy::UnitEndpoint::new());
+    }
+
+    // send IP packets in both directions (causing a handshake)
+
+    let packet = make_packet(
+        100,
+        "192.168.1.20".parse().unwrap(),
+        "192.168.2.10".parse().unwrap(),
+        0,
+    );
+    fake1.write(packet.clone());
+    assert_eq!(hex::encode(fake2.read()), hex::encode(packet));
+
+    let packet = make_packet(
+        100,
+        "192.168.2.10".parse().unwrap(),
+        "192.168.1.20".parse().unwrap(),
+        1,
+    );
+    fake2.write(packet.clone());
+    assert_eq!(hex::encode(fake1.read()), hex::encode(packet));
+
+    // the handshake completed at the (virtual) time of the clock
+
+    assert_eq!(
+        *wg1.peers
+            .read()
+            .get(&pk2)
+            .unwrap()
+            .walltime_last_handshake
+            .lock(),
+        Some(UNIX_EPOCH + Duration::from_secs(1_600_000_042))
+    );
+}
@@ -0,0 +1,329 @@
+use super::super::dummy::{self, LinkConfig, Nat, NetworkBind, NetworkEndpoint};
+use super::super::wireguard::WireGuard;
+use super::super::Endpoint;
+use super::super::{SimulatedWheel, VirtualClock};
+use super::{init, make_packet};
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use rand_chacha::ChaCha8Rng;
+use rand_core::SeedableRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/* Two devices connected through the emulated network of the dummy platform.
+ *
+ * Test:
+ *
+ * - Reordered and duplicated transport messages are delivered exactly once
+ * - Lost transport messages do not disrupt the session
+ * - Handshakes complete over lossy links (through retransmission)
+ * - Messages exceeding the MTU of the link are lost
+ * - The responder follows the initiator roaming behind a NAT
+ *
+ * The timers of the devices run on a virtual clock,
+ * which (optionally) runs faster than real time: enabling handshake retransmissions within a test.
+ */
+
+type Device = WireGuard<dummy::TunTest, NetworkBind>;
+
+// Time to wait for a packet (which is expected to arrive)
+const DEADLINE: Duration = Duration::from_secs(5);
+
+// Time to wait for a packet (which is expected to be lost)
+const QUIET: Duration = Duration::from_millis(200);
+
+struct Hosts {
+    network: dummy::Network,
+    wg1: Device,
+    wg2: Device,
+    fake1: dummy::TunFakeIO,
+    fake2: dummy::TunFakeIO,
+    addr1: SocketAddr,
+    addr2: SocketAddr,
+    pk1: PublicKey,
+    pk2: PublicKey,
+    packets: u64,
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for Hosts {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Hosts {
+    /// Create two devices attached to the network
+    ///
+    /// # Arguments
+    ///
+    /// - `nat`: Place the first device behind a NAT (with the given timeout)
+    /// - `speedup`: The rate of the virtual clock relative to real time (0 stops the clock)
+    fn new(nat: Option<Duration>, speedup: u32) -> Hosts {
+        init();
+
+        let network = dummy::Network::new(0);
+        let clock = Arc::new(VirtualClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        ));
+        let wheel = Arc::new(SimulatedWheel::new(clock.clone()));
+
+        // advance the virtual clock in steps of 1ms (real time)
+        let running = Arc::new(AtomicBool::new(true));
+        if speedup > 0 {
+            let wheel = wheel.clone();
+            let running = running.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                    wheel.advance(Duration::from_millis(1) * speedup);
+                }
+            });
+        }
+
+        let nat: Option<Nat> =
+            nat.map(|timeout| network.add_nat("203.0.113.1".parse().unwrap(), timeout));
+
+        let create = |seed, addr: SocketAddr, nat| {
+            let (fake, tun_reader, tun_writer, _) = dummy::TunTest::create(true);
+            let wg: Device = WireGuard::with_sources(
+                tun_writer,
+                ChaCha8Rng::seed_from_u64(seed),
+                clock.clone(),
+                wheel.clone(),
+            );
+            wg.up(1500);
+            wg.add_tun_reader(tun_reader);
+
+            let (reader, writer) = network.bind(addr, LinkConfig::default(), nat);
+            wg.set_writer(writer);
+            wg.add_udp_reader(reader);
+            (fake, wg)
+        };
+
+        let addr1: SocketAddr = "10.0.0.1:51820".parse().unwrap();
+        let addr2: SocketAddr = "198.51.100.1:51820".parse().unwrap();
+        let (fake1, wg1) = create(1, addr1, nat);
+        let (fake2, wg2) = create(2, addr2, None);
+
+        // configure the peers (only the first device knows the endpoint of the other)
+        let sk1 = StaticSecret::from([1u8; 32]);
+        let sk2 = StaticSecret::from([2u8; 32]);
+
+        let pk1 = PublicKey::from(&sk1);
+        let pk2 = PublicKey::from(&sk2);
+
+        wg1.add_peer(pk2);
+        wg2.add_peer(pk1);
+
+        wg1.set_key(Some(sk1));
+        wg2.set_key(Some(sk2));
+
+        {
+            let peers1 = wg1.peers.read();
+            let peers2 = wg2.peers.read();
+
+            let peer2 = peers1.get(&pk2).unwrap();
+            peer2.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+            peer2.set_endpoint(NetworkEndpoint::from_address(addr2));
+
+            let peer1 = peers2.get(&pk1).unwrap();
+            peer1.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+        }
+
+        Hosts {
+            network,
+            wg1,
+            wg2,
+            fake1,
+            fake2,
+            addr1,
+            addr2,
+            pk1,
+            pk2,
+            packets: 0,
+            running,
+        }
+    }
+
+    // Send an IP packet (of the given size) through the tunnel
+    fn send(&mut self, forward: bool, size: usize) -> Vec<u8> {
+        self.packets += 1;
+        let (src, dst) = if forward {
+            ("192.168.1.20", "192.168.2.10")
+        } else {
+            ("192.168.2.10", "192.168.1.20")
+        };
+        let packet = make_packet(
+            size,
+            src.parse().unwrap(),
+            dst.parse().unwrap(),
+            self.packets,
+        );
+        if forward {
+            self.fake1.write(packet.clone());
+        } else {
+            self.fake2.write(packet.clone());
+        }
+        packet
+    }
+
+    // Receive an IP packet from the tunnel (if any arrives before the timeout)
+    fn recv(&self, forward: bool, timeout: Duration) -> Option<Vec<u8>> {
+        if forward {
+            self.fake2.read_timeout(timeout)
+        } else {
+            self.fake1.read_timeout(timeout)
+        }
+    }
+
+    // Establish a session (over the current links)
+    fn connect(&mut self) {
+        let packet = self.send(true, 100);
+        assert_eq!(self.recv(true, DEADLINE), Some(packet));
+        let packet = self.send(false, 100);
+        assert_eq!(self.recv(false, DEADLINE), Some(packet));
+    }
+
+    // The endpoint of the first device (as seen by the second device)
+    fn endpoint1(&self) -> Option<SocketAddr> {
+        self.wg2.peers.read().get(&self.pk1).unwrap().get_endpoint()
+    }
+}
+
+#[test]
+fn network_reorder_duplicate() {
+    let mut hosts = Hosts::new(None, 0);
+    hosts.connect();
+
+    // reordering within the anti-replay window is tolerated,
+    // while duplicates are rejected
+    hosts.network.set_link(
+        hosts.addr1,
+        LinkConfig {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(20),
+            duplicate: 0.3,
+            reorder: 0.3,
+            ..Default::default()
+        },
+    );
+
+    let sent: Vec<Vec<u8>> = (0..25).map(|_| hosts.send(true, 100)).collect();
+    let mut received = HashSet::new();
+    for _ in 0..sent.len() {
+        let packet = hosts.recv(true, DEADLINE).expect("packet lost");
+        assert!(received.insert(packet), "duplicate packet delivered");
+    }
+    assert_eq!(hosts.recv(true, QUIET), None);
+    assert_eq!(received, sent.into_iter().collect());
+}
+
+#[test]
+fn network_loss() {
+    let mut hosts = Hosts::new(None, 0);
+    hosts.connect();
+
+    hosts.network.set_link(
+        hosts.addr1,
+        LinkConfig {
+            loss: 0.5,
+            ..Default::default()
+        },
+    );
+
+    let sent: HashSet<Vec<u8>> = (0..25).map(|_| hosts.send(true, 100)).collect();
+    let mut received = HashSet::new();
+    while let Some(packet) = hosts.recv(true, QUIET) {
+        assert!(sent.contains(&packet));
+        assert!(received.insert(packet), "duplicate packet delivered");
+    }
+    assert!(!received.is_empty() && received.len() < sent.len());
+
+    // the session survives the loss
+    hosts.network.set_link(hosts.addr1, LinkConfig::default());
+    hosts.connect();
+}
+
+#[test]
+fn network_lossy_handshake() {
+    // the virtual clock runs 100 times faster than real time
+    // (REKEY_TIMEOUT passes in 50ms)
+    let mut hosts = Hosts::new(None, 100);
+
+    let lossy = LinkConfig {
+        loss: 0.3,
+        latency: Duration::from_millis(5),
+        ..Default::default()
+    };
+    hosts.network.set_link(hosts.addr1, lossy);
+    hosts.network.set_link(hosts.addr2, lossy);
+
+    // lost handshake messages are retransmitted,
+    // until the session is established and packets get through
+    let delivered = (0..20).any(|_| {
+        let packet = hosts.send(true, 100);
+        hosts.recv(true, QUIET) == Some(packet)
+    });
+    assert!(delivered, "no session established over lossy link");
+}
+
+#[test]
+fn network_mtu() {
+    let mut hosts = Hosts::new(None, 0);
+    hosts.connect();
+
+    // the transport message of a 1000 byte packet exceeds the MTU of the link
+    hosts.network.set_link(
+        hosts.addr1,
+        LinkConfig {
+            mtu: 1000,
+            ..Default::default()
+        },
+    );
+    hosts.send(true, 1000);
+    assert_eq!(hosts.recv(true, QUIET), None);
+
+    let packet = hosts.send(true, 500);
+    assert_eq!(hosts.recv(true, DEADLINE), Some(packet));
+}
+
+#[test]
+fn network_nat_roaming() {
+    let timeout = Duration::from_millis(300);
+    let mut hosts = Hosts::new(Some(timeout), 0);
+
+    // the responder learns the public endpoint of the initiator
+    hosts.connect();
+    let public = hosts.endpoint1().unwrap();
+    assert_eq!(public, "203.0.113.1:1024".parse().unwrap());
+    assert_ne!(public, hosts.addr1);
+
+    // after the mapping expires, packets to the old endpoint are lost
+    thread::sleep(timeout * 2);
+    hosts.send(false, 100);
+    assert_eq!(hosts.recv(false, QUIET), None);
+
+    // until the initiator sends again (from a new public endpoint)
+    let packet = hosts.send(true, 100);
+    assert_eq!(hosts.recv(true, DEADLINE), Some(packet));
+    assert_eq!(hosts.endpoint1(), Some("203.0.113.1:1025".parse().unwrap()));
+
+    let packet = hosts.send(false, 100);
+    assert_eq!(hosts.recv(false, DEADLINE), Some(packet));
+
+    // the initiator is unaware of the NAT
+    let endpoint2 = hosts
+        .wg1
+        .peers
+        .read()
+        .get(&hosts.pk2)
+        .unwrap()
+        .get_endpoint();
+    assert_eq!(endpoint2, Some(hosts.addr2));
+}
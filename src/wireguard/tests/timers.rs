@@ -0,0 +1,483 @@
+use super::super::constants::*;
+use super::super::dummy;
+use super::super::handshake::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
+use super::super::router::{message_data_len, TYPE_TRANSPORT};
+use super::super::udp::{Reader, Writer};
+use super::super::wireguard::WireGuard;
+use super::super::{SimulatedWheel, VirtualClock};
+use super::{init, make_packet};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use rand_chacha::ChaCha8Rng;
+use rand_core::SeedableRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/* Simulation of the timer state machine (see timers.rs),
+ * checked against the timers of the WireGuard whitepaper (section 6):
+ *
+ * - Retransmit handshake initiations after REKEY_TIMEOUT
+ * - Give up handshaking after REKEY_ATTEMPT_TIME
+ * - Send a keepalive, if no packet is sent KEEPALIVE_TIMEOUT after receiving data
+ * - Initiate a new handshake, if no packet is received KEEPALIVE_TIMEOUT + REKEY_TIMEOUT
+ *   after sending data
+ * - Initiate a new handshake (as initiator), when sending with a key older than REKEY_AFTER_TIME
+ *   or receiving with a key older than REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+ * - Zero all key material after 3 * REJECT_AFTER_TIME without a new session
+ * - Send persistent keepalives at the configured interval
+ *
+ * Two devices (sharing a virtual clock and timer wheel) are connected by a link
+ * which records (and optionally drops) every message.
+ * The first device initiates the session.
+ */
+
+type Device = WireGuard<dummy::TunTest, dummy::PairBind>;
+
+// Time allowed for the workers to process the messages in flight
+const SETTLE: Duration = Duration::from_millis(100);
+
+// Time to wait for an expected message
+const DEADLINE: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    Forward,  // first device to second device
+    Backward, // second device to first device
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Message {
+    Initiation,
+    Response,
+    CookieReply,
+    Keepalive,
+    Data,
+}
+
+impl Message {
+    fn parse(msg: &[u8]) -> Message {
+        match LittleEndian::read_u32(msg) {
+            TYPE_INITIATION => Message::Initiation,
+            TYPE_RESPONSE => Message::Response,
+            TYPE_COOKIE_REPLY => Message::CookieReply,
+            TYPE_TRANSPORT if msg.len() == message_data_len(0) => Message::Keepalive,
+            TYPE_TRANSPORT => Message::Data,
+            _ => unreachable!("invalid message type"),
+        }
+    }
+}
+
+struct Simulation {
+    wheel: Arc<SimulatedWheel>,
+    wg1: Device,
+    wg2: Device,
+    fake1: dummy::TunFakeIO,
+    fake2: dummy::TunFakeIO,
+    pk1: PublicKey,
+    pk2: PublicKey,
+    log: Receiver<(Direction, Message)>,
+    drop_forward: Arc<AtomicBool>,
+    drop_backward: Arc<AtomicBool>,
+    packets: u64,
+}
+
+// Forward messages from the reader to the writer, recording every message
+fn link(
+    dir: Direction,
+    reader: dummy::PairReader<dummy::UnitEndpoint>,
+    writer: dummy::PairWriter<dummy::UnitEndpoint>,
+    drop: Arc<AtomicBool>,
+    log: Sender<(Direction, Message)>,
+) {
+    thread::spawn(move || {
+        let mut buf = vec![0u8; 1 << 16];
+        while let Ok((len, _)) = reader.read(&mut buf) {
+            if log.send((dir, Message::parse(&buf[..len]))).is_err() {
+                return;
+            }
+            if !drop.load(Ordering::SeqCst) {
+                let _ = writer.write(&buf[..len], &mut dummy::UnitEndpoint::new());
+            }
+        }
+    });
+}
+
+impl Simulation {
+    fn new() -> Simulation {
+        init();
+
+        let clock = Arc::new(VirtualClock::new(
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        ));
+        let wheel = Arc::new(SimulatedWheel::new(clock.clone()));
+
+        let create = |seed| {
+            let (fake, tun_reader, tun_writer, _) = dummy::TunTest::create(true);
+            let wg: Device = WireGuard::with_sources(
+                tun_writer,
+                ChaCha8Rng::seed_from_u64(seed),
+                clock.clone(),
+                wheel.clone(),
+            );
+            wg.up(1500);
+            wg.add_tun_reader(tun_reader);
+            (fake, wg)
+        };
+
+        let (fake1, wg1) = create(1);
+        let (fake2, wg2) = create(2);
+
+        // connect the devices through the recording link
+        let ((bind_reader1, bind_writer1), (link_reader1, link_writer1)) = dummy::PairBind::pair();
+        let ((bind_reader2, bind_writer2), (link_reader2, link_writer2)) = dummy::PairBind::pair();
+
+        wg1.set_writer(bind_writer1);
+        wg2.set_writer(bind_writer2);
+
+        wg1.add_udp_reader(bind_reader1);
+        wg2.add_udp_reader(bind_reader2);
+
+        let (tx, log) = channel();
+        let drop_forward = Arc::new(AtomicBool::new(false));
+        let drop_backward = Arc::new(AtomicBool::new(false));
+        link(
+            Direction::Forward,
+            link_reader1,
+            link_writer2,
+            drop_forward.clone(),
+            tx.clone(),
+        );
+        link(
+            Direction::Backward,
+            link_reader2,
+            link_writer1,
+            drop_backward.clone(),
+            tx,
+        );
+
+        // configure the peers
+        let sk1 = StaticSecret::from([1u8; 32]);
+        let sk2 = StaticSecret::from([2u8; 32]);
+
+        let pk1 = PublicKey::from(&sk1);
+        let pk2 = PublicKey::from(&sk2);
+
+        wg1.add_peer(pk2);
+        wg2.add_peer(pk1);
+
+        wg1.set_key(Some(sk1));
+        wg2.set_key(Some(sk2));
+
+        {
+            let peers1 = wg1.peers.read();
+            let peers2 = wg2.peers.read();
+
+            let peer2 = peers1.get(&pk2).unwrap();
+            peer2.add_allowed_ip("192.168.2.0".parse().unwrap(), 24);
+            peer2.set_endpoint(dummy::UnitEndpoint::new());
+
+            let peer1 = peers2.get(&pk1).unwrap();
+            peer1.add_allowed_ip("192.168.1.0".parse().unwrap(), 24);
+        }
+
+        Simulation {
+            wheel,
+            wg1,
+            wg2,
+            fake1,
+            fake2,
+            pk1,
+            pk2,
+            log,
+            drop_forward,
+            drop_backward,
+            packets: 0,
+        }
+    }
+
+    // Wait for the messages in flight to be processed
+    fn settle(&self) {
+        thread::sleep(SETTLE);
+    }
+
+    // Advance time (after the messages in flight have been processed)
+    fn advance(&self, duration: Duration) {
+        self.settle();
+        self.wheel.advance(duration);
+    }
+
+    // Send a data packet in the given direction
+    fn send(&mut self, dir: Direction) -> Vec<u8> {
+        self.packets += 1;
+        let (src, dst) = match dir {
+            Direction::Forward => ("192.168.1.20", "192.168.2.10"),
+            Direction::Backward => ("192.168.2.10", "192.168.1.20"),
+        };
+        let packet = make_packet(
+            100,
+            src.parse().unwrap(),
+            dst.parse().unwrap(),
+            self.packets,
+        );
+        match dir {
+            Direction::Forward => self.fake1.write(packet.clone()),
+            Direction::Backward => self.fake2.write(packet.clone()),
+        }
+        packet
+    }
+
+    // Drop every subsequent message in the given direction
+    fn drop(&self, dir: Direction, drop: bool) {
+        match dir {
+            Direction::Forward => self.drop_forward.store(drop, Ordering::SeqCst),
+            Direction::Backward => self.drop_backward.store(drop, Ordering::SeqCst),
+        }
+    }
+
+    // Check the next message sent by the devices
+    fn expect(&self, dir: Direction, msg: Message) {
+        assert_eq!(self.log.recv_timeout(DEADLINE).ok(), Some((dir, msg)));
+    }
+
+    // Check that no message is sent
+    fn expect_none(&self) {
+        self.settle();
+        assert_eq!(self.log.try_recv().ok(), None);
+    }
+
+    // Complete a handshake (initiated by sending a data packet from the first device)
+    fn handshake(&mut self) {
+        let packet = self.send(Direction::Forward);
+        self.expect(Direction::Forward, Message::Initiation);
+        self.expect(Direction::Backward, Message::Response);
+        self.expect(Direction::Forward, Message::Data);
+        assert_eq!(self.fake2.read(), packet);
+    }
+}
+
+const SECOND: Duration = Duration::from_secs(1);
+
+#[test]
+fn timers_retransmit_handshake() {
+    let mut sim = Simulation::new();
+
+    // the second device does not respond
+    sim.drop(Direction::Forward, true);
+    sim.send(Direction::Forward);
+    sim.expect(Direction::Forward, Message::Initiation);
+
+    // the initiation is retransmitted after REKEY_TIMEOUT
+    sim.advance(REKEY_TIMEOUT - SECOND);
+    sim.expect_none();
+    sim.advance(SECOND);
+    sim.expect(Direction::Forward, Message::Initiation);
+    sim.advance(REKEY_TIMEOUT);
+    sim.expect(Direction::Forward, Message::Initiation);
+}
+
+#[test]
+fn timers_give_up_handshake() {
+    let mut sim = Simulation::new();
+
+    // the second device does not respond
+    sim.drop(Direction::Forward, true);
+    sim.send(Direction::Forward);
+    sim.expect(Direction::Forward, Message::Initiation);
+
+    // retransmit for REKEY_ATTEMPT_TIME, then give up
+    // (like the reference implementation, the last retry is sent at REKEY_ATTEMPT_TIME + REKEY_TIMEOUT)
+    for _ in 0..=MAX_TIMER_HANDSHAKES {
+        sim.advance(REKEY_TIMEOUT);
+        sim.expect(Direction::Forward, Message::Initiation);
+    }
+    sim.advance(REKEY_TIMEOUT);
+    sim.expect_none();
+    sim.advance(REKEY_ATTEMPT_TIME);
+    sim.expect_none();
+
+    // the staged packets are purged when giving up
+    sim.drop(Direction::Forward, false);
+    sim.handshake();
+    sim.expect_none();
+}
+
+#[test]
+fn timers_send_keepalive() {
+    let mut sim = Simulation::new();
+    sim.handshake();
+
+    // the second device sends a keepalive KEEPALIVE_TIMEOUT after receiving data
+    sim.advance(KEEPALIVE_TIMEOUT - SECOND);
+    sim.expect_none();
+    sim.advance(SECOND);
+    sim.expect(Direction::Backward, Message::Keepalive);
+
+    // sending data stops the keepalive timer
+    sim.send(Direction::Forward);
+    sim.expect(Direction::Forward, Message::Data);
+    sim.advance(SECOND);
+    let packet = sim.send(Direction::Backward);
+    sim.expect(Direction::Backward, Message::Data);
+    assert_eq!(sim.fake1.read(), packet);
+
+    // the first device received data in return
+    sim.advance(KEEPALIVE_TIMEOUT);
+    sim.expect(Direction::Forward, Message::Keepalive);
+
+    // the keepalives are confirmation enough: no new handshake is initiated
+    sim.advance(KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+    sim.expect_none();
+}
+
+#[test]
+fn timers_new_handshake() {
+    let mut sim = Simulation::new();
+    sim.handshake();
+
+    // the keepalive of the second device is lost
+    sim.drop(Direction::Backward, true);
+    sim.advance(KEEPALIVE_TIMEOUT);
+    sim.expect(Direction::Backward, Message::Keepalive);
+
+    // the first device initiates a new handshake
+    // KEEPALIVE_TIMEOUT + REKEY_TIMEOUT after sending data
+    sim.advance(REKEY_TIMEOUT - SECOND);
+    sim.expect_none();
+    sim.advance(SECOND);
+    sim.expect(Direction::Forward, Message::Initiation);
+    sim.expect(Direction::Backward, Message::Response);
+}
+
+#[test]
+fn timers_rekey_after_time() {
+    let mut sim = Simulation::new();
+    sim.handshake();
+    sim.advance(KEEPALIVE_TIMEOUT);
+    sim.expect(Direction::Backward, Message::Keepalive);
+
+    // sending with a fresh key does not initiate a handshake
+    sim.advance(REKEY_AFTER_TIME - KEEPALIVE_TIMEOUT - SECOND);
+    sim.send(Direction::Forward);
+    sim.expect(Direction::Forward, Message::Data);
+    sim.expect_none();
+
+    // sending with a key older than REKEY_AFTER_TIME initiates a handshake
+    sim.advance(2 * SECOND);
+    sim.send(Direction::Forward);
+    sim.expect(Direction::Forward, Message::Data);
+    sim.expect(Direction::Forward, Message::Initiation);
+    sim.expect(Direction::Backward, Message::Response);
+
+    // nothing is staged: the initiator confirms the new key with a keepalive
+    sim.expect(Direction::Forward, Message::Keepalive);
+    sim.expect_none();
+}
+
+#[test]
+fn timers_reject_after_time() {
+    let mut sim = Simulation::new();
+    sim.handshake();
+    sim.advance(KEEPALIVE_TIMEOUT);
+    sim.expect(Direction::Backward, Message::Keepalive);
+
+    // the responder does not initiate a handshake when sending with an old key
+    sim.advance(REJECT_AFTER_TIME - REKEY_TIMEOUT - KEEPALIVE_TIMEOUT);
+    let packet = sim.send(Direction::Backward);
+    sim.expect(Direction::Backward, Message::Data);
+    assert_eq!(sim.fake1.read(), packet);
+
+    // the initiator initiates a handshake when receiving with a key older than
+    // REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+    sim.expect(Direction::Forward, Message::Initiation);
+    sim.expect(Direction::Backward, Message::Response);
+
+    // nothing is staged: the initiator confirms the new key with a keepalive
+    sim.expect(Direction::Forward, Message::Keepalive);
+    sim.expect_none();
+}
+
+#[test]
+fn timers_reject_after_time_responder() {
+    let mut sim = Simulation::new();
+    sim.handshake();
+    sim.advance(KEEPALIVE_TIMEOUT);
+    sim.expect(Direction::Backward, Message::Keepalive);
+
+    // the responder does not initiate a handshake when receiving with a key older than
+    // REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+    // (the initiator does, as it is sending with a key older than REKEY_AFTER_TIME)
+    sim.advance(REJECT_AFTER_TIME - REKEY_TIMEOUT - KEEPALIVE_TIMEOUT);
+    let packet = sim.send(Direction::Forward);
+    sim.expect(Direction::Forward, Message::Data);
+    assert_eq!(sim.fake2.read(), packet);
+    sim.expect(Direction::Forward, Message::Initiation);
+    sim.expect(Direction::Backward, Message::Response);
+
+    // nothing is staged: the initiator confirms the new key with a keepalive
+    sim.expect(Direction::Forward, Message::Keepalive);
+    sim.expect_none();
+}
+
+#[test]
+fn timers_zero_key_material() {
+    let mut sim = Simulation::new();
+    sim.handshake();
+    sim.advance(KEEPALIVE_TIMEOUT);
+    sim.expect(Direction::Backward, Message::Keepalive);
+
+    // the key material is zeroed silently after 3 * REJECT_AFTER_TIME
+    sim.advance(REJECT_AFTER_TIME * 3 - KEEPALIVE_TIMEOUT);
+    sim.expect_none();
+
+    // hence, sending requires a new handshake
+    let packet = sim.send(Direction::Forward);
+    sim.expect(Direction::Forward, Message::Initiation);
+    sim.expect(Direction::Backward, Message::Response);
+    sim.expect(Direction::Forward, Message::Data);
+    assert_eq!(sim.fake2.read(), packet);
+}
+
+#[test]
+fn timers_persistent_keepalive() {
+    let mut sim = Simulation::new();
+    sim.handshake();
+    sim.advance(KEEPALIVE_TIMEOUT);
+    sim.expect(Direction::Backward, Message::Keepalive);
+
+    // configuring the interval sends a keepalive immediately
+    // (receiving the keepalive above would otherwise postpone it)
+    sim.settle();
+    let interval = 25;
+    sim.wg1
+        .peers
+        .read()
+        .get(&sim.pk2)
+        .unwrap()
+        .set_persistent_keepalive_interval(interval);
+    sim.advance(Duration::from_secs(0));
+    sim.expect(Direction::Forward, Message::Keepalive);
+
+    // and subsequently every interval
+    for _ in 0..3 {
+        sim.advance(Duration::from_secs(interval) - SECOND);
+        sim.expect_none();
+        sim.advance(SECOND);
+        sim.expect(Direction::Forward, Message::Keepalive);
+    }
+
+    // the other device has no persistent keepalive
+    assert_eq!(
+        sim.wg2
+            .peers
+            .read()
+            .get(&sim.pk1)
+            .unwrap()
+            .get_keepalive_interval(),
+        0
+    );
+}
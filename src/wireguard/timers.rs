@@ -1,10 +1,9 @@
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, Instant};
 
 use log::debug;
 
-use hjul::Timer;
 use x25519_dalek::PublicKey;
 
 use super::constants::*;
@@ -13,6 +12,7 @@ use super::router::{message_data_len, Callbacks};
 use super::tun::Tun;
 use super::types::KeyPair;
 use super::udp::UDP;
+use super::wheel::Timer;
 use super::WireGuard;
 
 pub struct Timers {
@@ -24,11 +24,11 @@ pub struct Timers {
     sent_lastminute_handshake: AtomicBool,
     need_another_keepalive: AtomicBool,
 
-    retransmit_handshake: Timer,
-    send_keepalive: Timer,
-    send_persistent_keepalive: Timer,
-    zero_key_material: Timer,
-    new_handshake: Timer,
+    retransmit_handshake: Box<dyn Timer>,
+    send_keepalive: Box<dyn Timer>,
+    send_persistent_keepalive: Box<dyn Timer>,
+    zero_key_material: Box<dyn Timer>,
+    new_handshake: Box<dyn Timer>,
 }
 
 impl Timers {
@@ -111,6 +111,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      */
     pub fn timers_any_authenticated_packet_sent(&self) {
         log::trace!("timers_any_authenticated_packet_sent");
+        *self.last_activity.lock() = self.wg.clock.now();
         let timers = self.timers();
         if timers.enabled {
             timers.send_keepalive.stop()
@@ -124,6 +125,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
      */
     pub fn timers_any_authenticated_packet_received(&self) {
         log::trace!("timers_any_authenticated_packet_received");
+        *self.last_activity.lock() = self.wg.clock.now();
         let timers = self.timers();
         if timers.enabled {
             timers.new_handshake.stop();
@@ -152,7 +154,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
             timers
                 .sent_lastminute_handshake
                 .store(false, Ordering::SeqCst);
-            *self.walltime_last_handshake.lock() = Some(SystemTime::now());
+            *self.walltime_last_handshake.lock() = Some(self.wg.clock.system_time());
         }
     }
 
@@ -192,7 +194,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
     /* Called after a handshake worker sends a handshake initiation to the peer
      */
     pub fn sent_handshake_initiation(&self) {
-        *self.last_handshake_sent.lock() = Instant::now();
+        *self.last_handshake_sent.lock() = self.wg.clock.now();
         self.timers_handshake_initiated();
         self.timers_set_retransmit_handshake();
         self.timers_any_authenticated_packet_traversal();
@@ -200,7 +202,7 @@ impl<T: Tun, B: UDP> PeerInner<T, B> {
     }
 
     pub fn sent_handshake_response(&self) {
-        *self.last_handshake_sent.lock() = Instant::now();
+        *self.last_handshake_sent.lock() = self.wg.clock.now();
         self.timers_any_authenticated_packet_traversal();
         self.timers_any_authenticated_packet_sent();
     }
@@ -257,7 +259,7 @@ impl Timers {
             };
         }
 
-        let runner = wg.runner.lock();
+        let wheel = wg.wheel.clone();
 
         // create a timer instance for the provided peer
         Timers {
@@ -268,7 +270,7 @@ impl Timers {
             handshake_attempts: AtomicUsize::new(0),
             retransmit_handshake: {
                 let wg = wg.clone();
-                runner.timer(move || {
+                wheel.timer(Box::new(move || {
                     // fetch peer by public key
                     fetch_peer!(wg, pk, peer);
                     fetch_timers!(peer, timers);
@@ -295,11 +297,11 @@ impl Timers {
                         peer.clear_src();
                         peer.packet_send_queued_handshake_initiation(true);
                     }
-                })
+                }))
             },
             send_keepalive: {
                 let wg = wg.clone();
-                runner.timer(move || {
+                wheel.timer(Box::new(move || {
                     // fetch peer by public key
                     fetch_peer!(wg, pk, peer);
                     fetch_timers!(peer, timers);
@@ -309,11 +311,11 @@ impl Timers {
                     if timers.need_another_keepalive() {
                         timers.send_keepalive.start(KEEPALIVE_TIMEOUT);
                     }
-                })
+                }))
             },
             new_handshake: {
                 let wg = wg.clone();
-                runner.timer(move || {
+                wheel.timer(Box::new(move || {
                     // fetch peer by public key
                     fetch_peer!(wg, pk, peer);
                     fetch_timers!(peer, timers);
@@ -326,22 +328,22 @@ impl Timers {
                     );
                     peer.clear_src();
                     peer.packet_send_queued_handshake_initiation(false);
-                })
+                }))
             },
             zero_key_material: {
                 let wg = wg.clone();
-                runner.timer(move || {
+                wheel.timer(Box::new(move || {
                     // fetch peer by public key
                     fetch_peer!(wg, pk, peer);
                     log::trace!("{} : timer fired (zero_key_material)", peer);
 
                     // null all key-material
                     peer.zero_keys();
-                })
+                }))
             },
             send_persistent_keepalive: {
                 let wg = wg.clone();
-                runner.timer(move || {
+                wheel.timer(Box::new(move || {
                     // fetch peer by public key
                     fetch_peer!(wg, pk, peer);
                     fetch_timers!(peer, timers);
@@ -356,7 +358,7 @@ impl Timers {
                             .send_persistent_keepalive
                             .start(Duration::from_secs(timers.keepalive_interval));
                     }
-                })
+                }))
             },
         }
     }
@@ -383,12 +385,13 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
 
         // keep_key_fresh
 
-        fn keep_key_fresh(keypair: &Arc<KeyPair>, counter: u64) -> bool {
+        fn keep_key_fresh(keypair: &Arc<KeyPair>, counter: u64, now: Instant) -> bool {
             counter > REKEY_AFTER_MESSAGES
-                || (keypair.initiator && Instant::now() - keypair.birth > REKEY_AFTER_TIME)
+                || (keypair.initiator
+                    && now.saturating_duration_since(keypair.birth) > REKEY_AFTER_TIME)
         }
 
-        if keep_key_fresh(keypair, counter) {
+        if keep_key_fresh(keypair, counter, peer.wg.clock.now()) {
             peer.packet_send_queued_handshake_initiation(false);
         }
     }
@@ -409,18 +412,20 @@ impl<T: Tun, B: UDP> Callbacks for PeerInner<T, B> {
         peer.timers_any_authenticated_packet_traversal();
         peer.timers_any_authenticated_packet_received();
         peer.rx_bytes.fetch_add(size as u64, Ordering::Relaxed);
-        if size > 0 && sent {
+        if size > message_data_len(0) && sent {
             peer.timers_data_received();
         }
 
         // keep_key_fresh
 
         #[inline(always)]
-        fn keep_key_fresh(keypair: &Arc<KeyPair>) -> bool {
-            Instant::now() - keypair.birth > REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
+        fn keep_key_fresh(keypair: &Arc<KeyPair>, now: Instant) -> bool {
+            keypair.initiator
+                && now.saturating_duration_since(keypair.birth)
+                    > REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT
         }
 
-        if keep_key_fresh(keypair)
+        if keep_key_fresh(keypair, peer.wg.clock.now())
             && !peer
                 .timers()
                 .sent_lastminute_handshake
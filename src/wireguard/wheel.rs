@@ -0,0 +1,182 @@
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use spin::Mutex;
+
+use super::clock::{Clock, VirtualClock};
+
+/* The timer wheel drives the timer state machine of every peer (see timers.rs).
+ *
+ * The device uses the hjul timer wheel,
+ * while tests can substitute a simulated wheel which is advanced by hand,
+ * rather than waiting for the (minutes long) WireGuard timeouts in real time.
+ */
+
+/// A single (re-armable) timer
+pub trait Timer: Send + Sync {
+    /// Start the timer, unless it is already pending
+    ///
+    /// # Returns
+    ///
+    /// A bool indicating if the timer was started
+    fn start(&self, duration: Duration) -> bool;
+
+    /// Start the timer, replacing any pending expiration
+    fn reset(&self, duration: Duration);
+
+    /// Stop the timer (if pending)
+    fn stop(&self);
+}
+
+/// A source of timers
+pub trait TimerWheel: Send + Sync {
+    /// Create a new (stopped) timer
+    ///
+    /// # Arguments
+    ///
+    /// - `callback`: Function called every time the timer expires
+    fn timer(&self, callback: Box<dyn Fn() + Send + Sync>) -> Box<dyn Timer>;
+}
+
+/// Timer wheel running in real time (on a separate thread)
+pub struct HjulWheel(Mutex<hjul::Runner>);
+
+impl HjulWheel {
+    /// Create a new timer wheel
+    ///
+    /// # Arguments
+    ///
+    /// - `tick`: Resolution of the wheel
+    /// - `slots`: Number of slots in the wheel
+    /// - `capacity`: Initial capacity of every slot
+    pub fn new(tick: Duration, slots: usize, capacity: usize) -> HjulWheel {
+        HjulWheel(Mutex::new(hjul::Runner::new(tick, slots, capacity)))
+    }
+}
+
+impl Timer for hjul::Timer {
+    fn start(&self, duration: Duration) -> bool {
+        hjul::Timer::start(self, duration)
+    }
+
+    fn reset(&self, duration: Duration) {
+        hjul::Timer::reset(self, duration)
+    }
+
+    fn stop(&self) {
+        hjul::Timer::stop(self)
+    }
+}
+
+impl TimerWheel for HjulWheel {
+    fn timer(&self, callback: Box<dyn Fn() + Send + Sync>) -> Box<dyn Timer> {
+        Box::new(self.0.lock().timer(callback))
+    }
+}
+
+struct SimulatedTimerInner {
+    clock: Arc<VirtualClock>,
+    deadline: Mutex<Option<Instant>>,
+    callback: Box<dyn Fn() + Send + Sync>,
+}
+
+struct SimulatedTimer(Arc<SimulatedTimerInner>);
+
+impl Timer for SimulatedTimer {
+    fn start(&self, duration: Duration) -> bool {
+        let mut deadline = self.0.deadline.lock();
+        if deadline.is_some() {
+            return false;
+        }
+        *deadline = Some(self.0.clock.now() + duration);
+        true
+    }
+
+    fn reset(&self, duration: Duration) {
+        *self.0.deadline.lock() = Some(self.0.clock.now() + duration);
+    }
+
+    fn stop(&self) {
+        *self.0.deadline.lock() = None;
+    }
+}
+
+/// Timer wheel driven by a virtual clock:
+/// timers only expire when the wheel is advanced.
+pub struct SimulatedWheel {
+    clock: Arc<VirtualClock>,
+    timers: Mutex<Vec<Weak<SimulatedTimerInner>>>,
+}
+
+impl SimulatedWheel {
+    /// Create a new simulated timer wheel
+    ///
+    /// # Arguments
+    ///
+    /// - `clock`: The virtual clock (shared with the device)
+    pub fn new(clock: Arc<VirtualClock>) -> SimulatedWheel {
+        SimulatedWheel {
+            clock,
+            timers: Mutex::new(vec![]),
+        }
+    }
+
+    /// Advance the virtual clock, firing every timer which expires (in order of expiration)
+    ///
+    /// The callbacks are executed on the calling thread.
+    ///
+    /// # Arguments
+    ///
+    /// - `duration`: The time to advance the clock by
+    pub fn advance(&self, duration: Duration) {
+        let end = self.clock.now() + duration;
+        loop {
+            // find the first timer to expire (dropping the timers of removed peers)
+            let next = {
+                let mut timers = self.timers.lock();
+                timers.retain(|timer| timer.strong_count() > 0);
+                timers
+                    .iter()
+                    .filter_map(|timer| timer.upgrade())
+                    .filter_map(|timer| {
+                        let deadline = (*timer.deadline.lock())?;
+                        Some((deadline, timer))
+                    })
+                    .filter(|(deadline, _)| *deadline <= end)
+                    .min_by_key(|(deadline, _)| *deadline)
+            };
+
+            let (deadline, timer) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            // move the clock to the expiration and fire the timer
+            // (unless it was modified concurrently)
+            self.clock
+                .advance(deadline.saturating_duration_since(self.clock.now()));
+            {
+                let mut current = timer.deadline.lock();
+                if *current != Some(deadline) {
+                    continue;
+                }
+                *current = None;
+            }
+            (timer.callback)();
+        }
+        self.clock
+            .advance(end.saturating_duration_since(self.clock.now()));
+    }
+}
+
+impl TimerWheel for SimulatedWheel {
+    fn timer(&self, callback: Box<dyn Fn() + Send + Sync>) -> Box<dyn Timer> {
+        let inner = Arc::new(SimulatedTimerInner {
+            clock: self.clock.clone(),
+            deadline: Mutex::new(None),
+            callback,
+        });
+        self.timers.lock().push(Arc::downgrade(&inner));
+        Box::new(SimulatedTimer(inner))
+    }
+}
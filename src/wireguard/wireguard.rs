@@ -1,39 +1,53 @@
+use super::clock::{Clock, SystemClock};
 use super::constants::*;
 use super::handshake;
 use super::peer::PeerInner;
 use super::router;
+use super::stats::{DeviceStats, Stats};
 use super::timers::Timers;
+use super::wheel::{HjulWheel, TimerWheel};
 
+use super::pool::BufferPool;
+use super::provision::Provisioning;
 use super::queue::ParallelQueue;
 use super::workers::HandshakeJob;
 
 use super::tun::Tun;
 use super::udp::UDP;
 
-use super::workers::{handshake_worker, tun_worker, udp_worker};
+use super::workers::{buffer_size, handshake_worker, tun_worker, udp_worker};
 
 use std::fmt;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
 use std::sync::Condvar;
 use std::sync::Mutex as StdMutex;
+use std::sync::{Arc, Weak};
 use std::thread;
 use std::time::Instant;
 
-use hjul::Runner;
-use rand::rngs::OsRng;
-use rand::Rng;
+use rand::rngs::{OsRng, StdRng};
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
 use spin::{Mutex, RwLock};
 
 use x25519_dalek::{PublicKey, StaticSecret};
 
+/// Source of randomness for the device
+/// (e.g. a seeded ChaCha RNG in tests)
+pub trait DeviceRng: RngCore + CryptoRng + Send {}
+
+impl<R: RngCore + CryptoRng + Send> DeviceRng for R {}
+
 pub struct WireguardInner<T: Tun, B: UDP> {
     // identifier (for logging)
     pub id: u32,
 
+    // sources of randomness and time
+    pub rng: Mutex<Box<dyn DeviceRng>>,
+    pub clock: Arc<dyn Clock>,
+
     // timer wheel
-    pub runner: Mutex<Runner>,
+    pub wheel: Arc<dyn TimerWheel>,
 
     // device enabled
     pub enabled: RwLock<bool>,
@@ -56,6 +70,14 @@ pub struct WireguardInner<T: Tun, B: UDP> {
     pub last_under_load: Mutex<Instant>,
     pub pending: AtomicUsize, // number of pending handshake packets in queue
     pub queue: ParallelQueue<HandshakeJob<B::Endpoint>>,
+    pub handshake_buffers: BufferPool, // buffers for queued handshake messages
+
+    // device-wide statistics
+    pub stats: Stats,
+
+    // on-demand provisioning of peers
+    pub provisioning: RwLock<Option<Provisioning>>,
+    pub evictor_running: AtomicBool,
 }
 
 pub struct WireGuard<T: Tun, B: UDP> {
@@ -151,7 +173,9 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         // ensure exclusive access (to avoid race with "up" call)
         let mut enabled = self.enabled.write();
 
-        // set mtu
+        // set mtu (and size the packet buffers accordingly)
+        self.router.set_buffer_capacity(buffer_size(mtu));
+        self.router.set_mtu(mtu);
         self.mtu.store(mtu, Ordering::Relaxed);
 
         // check if already up
@@ -180,8 +204,19 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
     }
 
     pub fn set_key(&self, sk: Option<StaticSecret>) {
-        let mut peers = self.peers.write();
-        peers.set_sk(sk);
+        {
+            let mut peers = self.peers.write();
+            peers.set_sk(sk);
+
+            // handshakes under the new key are not rate limited by those under the old key
+            let past = self.clock.now() - TIME_HORIZON;
+            for (_, peer) in peers.iter() {
+                *peer.last_handshake_sent.lock() = past;
+            }
+        }
+
+        // release the lock before requesting handshakes
+        // (the handshake workers hold the lock while processing the queue)
         self.router.clear_sending_keys();
     }
 
@@ -195,11 +230,77 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
     pub fn set_psk(&self, pk: PublicKey, psk: [u8; 32]) -> bool {
         self.peers.write().set_psk(pk, psk).is_ok()
     }
+
+    pub fn set_admission(&self, admission: Option<Arc<dyn handshake::Admission>>) {
+        self.peers.write().set_admission(admission);
+    }
+
+    pub fn admission_rejections(&self) -> u64 {
+        self.peers.read().admission_rejections()
+    }
+
+    pub fn set_timestamp_store(&self, store: Option<Arc<dyn handshake::TimestampStore>>) {
+        self.peers.write().set_timestamp_store(store);
+    }
+
+    #[cfg(feature = "keylog")]
+    pub fn set_keylog(&self, sink: Option<Box<dyn std::io::Write + Send>>) {
+        self.peers.read().set_keylog(sink)
+    }
+
     pub fn get_psk(&self, pk: &PublicKey) -> Option<[u8; 32]> {
         self.peers.read().get_psk(pk).ok()
     }
 
+    pub fn get_handshake_counters(&self, pk: &PublicKey) -> Option<handshake::HandshakeCounters> {
+        self.peers.read().get_counters(pk).ok()
+    }
+
+    /// Enable/disable ICMP "destination unreachable" errors for packets without a cryptokey route
+    pub fn set_icmp_unreachable(&self, enabled: bool) {
+        self.router.set_icmp_unreachable(enabled);
+    }
+
+    pub fn get_icmp_unreachable(&self) -> bool {
+        self.router.icmp_unreachable()
+    }
+
+    /// Enable/disable clamping of the TCP MSS to the MTU (of the device or peer)
+    pub fn set_mss_clamp(&self, enabled: bool) {
+        self.router.set_mss_clamp(enabled);
+    }
+
+    pub fn get_mss_clamp(&self) -> bool {
+        self.router.mss_clamp()
+    }
+
+    /// Returns the device-wide statistics
+    pub fn get_stats(&self) -> DeviceStats {
+        let last = self.last_under_load.lock();
+        DeviceStats {
+            handshake_queue_depth: self.pending.load(Ordering::SeqCst),
+            router_queue_depth: self.router.queue_depth(),
+            ..self.stats.snapshot(*last, self.clock.now())
+        }
+    }
+
+    /// Add a new peer to the device
+    ///
+    /// If the peer was previously provisioned on-demand,
+    /// it becomes a configured peer (exempt from eviction).
     pub fn add_peer(&self, pk: PublicKey) -> bool {
+        if let Some(peer) = self.peers.read().get(&pk) {
+            peer.provisioned.store(false, Ordering::Relaxed);
+            return false;
+        }
+        self.add_peer_inner(pk, None)
+    }
+
+    pub(super) fn add_peer_inner(
+        &self,
+        pk: PublicKey,
+        provisional: Option<&handshake::Provisional>,
+    ) -> bool {
         let mut peers = self.peers.write();
         if peers.contains_key(&pk) {
             return false;
@@ -214,19 +315,25 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         // create new router peer
         let peer: router::PeerHandle<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
             self.router.new_peer(PeerInner {
-                id: OsRng.gen(),
+                id: self.rng.lock().gen(),
                 pk,
                 wg: self.clone(),
                 walltime_last_handshake: Mutex::new(None),
-                last_handshake_sent: Mutex::new(Instant::now() - TIME_HORIZON),
+                last_handshake_sent: Mutex::new(self.clock.now() - TIME_HORIZON),
                 handshake_queued: AtomicBool::new(false),
+                provisioned: AtomicBool::new(provisional.is_some()),
+                last_activity: Mutex::new(self.clock.now()),
                 rx_bytes: AtomicU64::new(0),
                 tx_bytes: AtomicU64::new(0),
+                name: Mutex::new(None),
                 timers: RwLock::new(timers),
             });
 
         // finally, add the peer to the handshake device
-        peers.add(pk, peer).is_ok()
+        match provisional {
+            Some(provisional) => peers.add_provisional(provisional, peer).is_ok(),
+            None => peers.add(pk, peer).is_ok(),
+        }
     }
 
     /// Begin consuming messages from the reader.
@@ -262,37 +369,87 @@ impl<T: Tun, B: UDP> WireGuard<T, B> {
         self.tun_readers.wait();
     }
 
+    pub(super) fn downgrade(&self) -> Weak<WireguardInner<T, B>> {
+        Arc::downgrade(&self.inner)
+    }
+
+    pub(super) fn from_inner(inner: Arc<WireguardInner<T, B>>) -> WireGuard<T, B> {
+        WireGuard { inner }
+    }
+
     pub fn new(writer: T::Writer) -> WireGuard<T, B> {
+        Self::with_sources(
+            writer,
+            OsRng,
+            Arc::new(SystemClock()),
+            Arc::new(HjulWheel::new(TIMERS_TICK, TIMERS_SLOTS, TIMERS_CAPACITY)),
+        )
+    }
+
+    /// Create a new device with the given sources of randomness and time
+    ///
+    /// Every handshake worker samples randomness from
+    /// an independent CSPRNG seeded from the device RNG.
+    ///
+    /// # Arguments
+    ///
+    /// - `writer`: Writer for the TUN device
+    /// - `rng`: Source of randomness (identifiers, ephemeral keys and cookies)
+    /// - `clock`: Source of time (key lifetimes, rate limiting and handshake timestamps)
+    /// - `wheel`: Timer wheel driving the timers of the peers
+    pub fn with_sources<R: DeviceRng + 'static>(
+        writer: T::Writer,
+        rng: R,
+        clock: Arc<dyn Clock>,
+        wheel: Arc<dyn TimerWheel>,
+    ) -> WireGuard<T, B> {
+        let mut rng: Box<dyn DeviceRng> = Box::new(rng);
+
         // workers equal to number of physical cores
         let cpus = num_cpus::get();
 
         // create handshake queue
-        let (tx, mut rxs) = ParallelQueue::new(cpus, 128);
+        let (tx, mut rxs) = ParallelQueue::new(cpus, HANDSHAKE_QUEUE_SIZE);
 
         // create router
         let router: router::Device<B::Endpoint, PeerInner<T, B>, T::Writer, B::Writer> =
-            router::Device::new(num_cpus::get(), writer);
+            router::Device::with_clock(num_cpus::get(), writer, clock.clone());
+
+        // create handshake device
+        let mut peers = handshake::Device::new();
+        peers.set_clock(clock.clone());
 
         // create arc to state
         let wg = WireGuard {
             inner: Arc::new(WireguardInner {
                 enabled: RwLock::new(false),
                 tun_readers: WaitCounter::new(),
-                id: OsRng.gen(),
+                id: rng.gen(),
                 mtu: AtomicUsize::new(0),
-                last_under_load: Mutex::new(Instant::now() - TIME_HORIZON),
+                last_under_load: Mutex::new(clock.now() - TIME_HORIZON),
+                rng: Mutex::new(rng),
+                clock,
                 router,
                 pending: AtomicUsize::new(0),
-                peers: RwLock::new(handshake::Device::new()),
-                runner: Mutex::new(Runner::new(TIMERS_TICK, TIMERS_SLOTS, TIMERS_CAPACITY)),
+                peers: RwLock::new(peers),
+                wheel,
                 queue: tx,
+                handshake_buffers: BufferPool::new(
+                    HANDSHAKE_BUFFER_POOL_SIZE,
+                    handshake::MAX_HANDSHAKE_MSG_SIZE,
+                ),
+                provisioning: RwLock::new(None),
+                evictor_running: AtomicBool::new(false),
+                stats: Stats::new(),
             }),
         };
 
         // start handshake workers
         while let Some(rx) = rxs.pop() {
             let wg = wg.clone();
-            thread::spawn(move || handshake_worker(&wg, rx));
+            let rng = StdRng::from_rng(&mut *wg.rng.lock())
+                .expect("failed to seed RNG of handshake worker");
+            thread::spawn(move || handshake_worker(&wg, rng, rx));
         }
 
         wg
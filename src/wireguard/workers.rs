@@ -1,10 +1,11 @@
+use std::cmp::{max, min};
+use std::mem;
 use std::sync::atomic::Ordering;
-use std::time::Instant;
 
 use byteorder::{ByteOrder, LittleEndian};
 use crossbeam_channel::Receiver;
 use log::debug;
-use rand::rngs::OsRng;
+use rand::rngs::StdRng;
 use x25519_dalek::PublicKey;
 
 // IO traits
@@ -18,12 +19,14 @@ use super::udp::UDP;
 
 // constants
 use super::constants::{
-    DURATION_UNDER_LOAD, MAX_QUEUED_INCOMING_HANDSHAKES, MESSAGE_PADDING_MULTIPLE,
-    THRESHOLD_UNDER_LOAD,
+    DURATION_UNDER_LOAD, MAX_IP_PACKET_SIZE, MAX_QUEUED_INCOMING_HANDSHAKES,
+    MESSAGE_PADDING_MULTIPLE, THRESHOLD_UNDER_LOAD, TUN_BATCH_SIZE, UDP_BATCH_SIZE,
 };
-use super::handshake::MAX_HANDSHAKE_MSG_SIZE;
+use super::handshake::{HandshakeError, Provisional, MAX_HANDSHAKE_MSG_SIZE};
 use super::handshake::{TYPE_COOKIE_REPLY, TYPE_INITIATION, TYPE_RESPONSE};
-use super::router::{CAPACITY_MESSAGE_POSTFIX, SIZE_MESSAGE_PREFIX, TYPE_TRANSPORT};
+use super::pool::recycle;
+use super::router::{RouterError, CAPACITY_MESSAGE_POSTFIX, SIZE_MESSAGE_PREFIX, TYPE_TRANSPORT};
+use super::stats::count;
 
 use super::wireguard::WireGuard;
 
@@ -54,103 +57,279 @@ const fn padding(size: usize, mtu: usize) -> usize {
     min(mtu, size + (pad - size % pad) % pad)
 }
 
+/* Returns the size of the packet buffers read by the TUN and UDP workers:
+ * large enough for any IP packet up to the MTU (along with the transport message prefix and tag)
+ * and for any message received over UDP.
+ */
+pub fn buffer_size(mtu: usize) -> usize {
+    let transport = mtu + SIZE_MESSAGE_PREFIX + 1 + CAPACITY_MESSAGE_POSTFIX;
+    max(transport, mtu + MAX_HANDSHAKE_MSG_SIZE)
+}
+
 pub fn tun_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: T::Reader) {
+    // read buffers (the buffers handed to the router are replaced from its pool)
+    let mut bufs: Vec<Vec<u8>> = vec![vec![]; TUN_BATCH_SIZE];
+    let mut sizes: Vec<usize> = Vec::with_capacity(TUN_BATCH_SIZE);
+    let mut slices: Vec<&mut [u8]> = Vec::with_capacity(TUN_BATCH_SIZE);
     loop {
-        // create vector big enough for any transport message (based on MTU)
-        let mtu = wg.mtu.load(Ordering::Relaxed);
-        let size = mtu + SIZE_MESSAGE_PREFIX + 1;
-        let mut msg: Vec<u8> = vec![0; size + CAPACITY_MESSAGE_POSTFIX];
-
-        // read a new IP packet
-        let payload = match reader.read(&mut msg[..], SIZE_MESSAGE_PREFIX) {
-            Ok(payload) => payload,
-            Err(e) => {
-                debug!("TUN worker, failed to read from tun device: {}", e);
-                break;
-            }
+        // ensure buffers big enough for any transport message (based on MTU)
+        // (or a single buffer for any IP packet while the device is down: it may come up during the read)
+        let (capacity, batch) = match wg.mtu.load(Ordering::Relaxed) {
+            0 => (MAX_IP_PACKET_SIZE, 1),
+            mtu => (mtu, TUN_BATCH_SIZE),
         };
-        debug!("TUN worker, IP packet of {} bytes (MTU = {})", payload, mtu);
+        let size = capacity + SIZE_MESSAGE_PREFIX + 1;
+        for buf in bufs[..batch].iter_mut() {
+            buf.resize(size + CAPACITY_MESSAGE_POSTFIX, 0);
+        }
 
-        // check if device is down
-        if mtu == 0 {
-            continue;
+        // read new IP packets
+        let mut reads: Vec<&mut [u8]> = recycle(mem::take(&mut slices));
+        reads.extend(bufs[..batch].iter_mut().map(|buf| &mut buf[..]));
+        let res = reader.read(&mut reads[..], &mut sizes, SIZE_MESSAGE_PREFIX);
+        slices = recycle(reads);
+        if let Err(e) = res {
+            debug!("TUN worker, failed to read from tun device: {}", e);
+            break;
         }
 
-        // truncate padding
-        let padded = padding(payload, mtu);
-        log::trace!(
-            "TUN worker, payload length = {}, padded length = {}",
-            payload,
-            padded
-        );
-        msg.truncate(SIZE_MESSAGE_PREFIX + padded);
-        debug_assert!(padded <= mtu);
-        debug_assert_eq!(
-            if padded < mtu {
-                (msg.len() - SIZE_MESSAGE_PREFIX) % MESSAGE_PADDING_MULTIPLE
-            } else {
+        // the MTU may have changed during the read
+        let mtu = min(wg.mtu.load(Ordering::Relaxed), capacity);
+        for (buf, payload) in bufs.iter_mut().zip(sizes.drain(..)) {
+            debug!("TUN worker, IP packet of {} bytes (MTU = {})", payload, mtu);
+
+            // check if device is down
+            if mtu == 0 {
+                continue;
+            }
+
+            // reject packets exceeding the MTU (notifying the source)
+            if payload > mtu {
+                let packet = &buf[SIZE_MESSAGE_PREFIX..SIZE_MESSAGE_PREFIX + payload];
+                wg.router.packet_too_big(packet, mtu);
+                count(&wg.stats.mtu_drops);
+                continue;
+            }
+
+            // truncate padding
+            let padded = padding(payload, mtu);
+            log::trace!(
+                "TUN worker, payload length = {}, padded length = {}",
+                payload,
+                padded
+            );
+            let mut msg = mem::replace(buf, wg.router.buffer());
+            msg.truncate(SIZE_MESSAGE_PREFIX + padded);
+            debug_assert!(padded <= mtu);
+            debug_assert_eq!(
+                if padded < mtu {
+                    (msg.len() - SIZE_MESSAGE_PREFIX) % MESSAGE_PADDING_MULTIPLE
+                } else {
+                    0
+                },
                 0
-            },
-            0
-        );
+            );
 
-        // crypt-key route
-        let e = wg.router.send(msg);
-        debug!("TUN worker, router returned {:?}", e);
+            // crypt-key route
+            let e = wg.router.send(msg);
+            debug!("TUN worker, router returned {:?}", e);
+            if let Err(RouterError::NoCryptoKeyRoute) = e {
+                count(&wg.stats.no_route_drops);
+            }
+        }
     }
 }
 
 pub fn udp_worker<T: Tun, B: UDP>(wg: &WireGuard<T, B>, reader: B::Reader) {
+    // receive buffers (reused between reads, the buffers handed to the router are replaced from its pool)
+    let mut bufs: Vec<Vec<u8>> = vec![vec![]; UDP_BATCH_SIZE];
+    let mut msgs: Vec<(usize, B::Endpoint)> = Vec::with_capacity(UDP_BATCH_SIZE);
+    let mut slices: Vec<&mut [u8]> = Vec::with_capacity(UDP_BATCH_SIZE);
     loop {
-        // create vector big enough for any message given current MTU
-        let mtu = wg.mtu.load(Ordering::Relaxed);
-        let size = mtu + MAX_HANDSHAKE_MSG_SIZE;
-        let mut msg: Vec<u8> = vec![0; size];
-
-        // read UDP packet into vector
-        let (size, src) = match reader.read(&mut msg) {
-            Err(e) => {
-                debug!("Bind reader closed with {}", e);
-                return;
-            }
-            Ok(v) => v,
+        // ensure buffers big enough for any message given current MTU
+        // (or a single buffer for any transport message while the device is down: it may come up during the read)
+        let (size, batch) = match wg.mtu.load(Ordering::Relaxed) {
+            0 => (buffer_size(MAX_IP_PACKET_SIZE), 1),
+            mtu => (buffer_size(mtu), UDP_BATCH_SIZE),
         };
-        msg.truncate(size);
+        for buf in bufs[..batch].iter_mut() {
+            buf.resize(size, 0);
+        }
+
+        // read a batch of UDP packets into the buffers
+        let mut reads: Vec<&mut [u8]> = recycle(mem::take(&mut slices));
+        reads.extend(bufs[..batch].iter_mut().map(|buf| &mut buf[..]));
+        let res = reader.read_batch(&mut reads[..], &mut msgs);
+        slices = recycle(reads);
+        if let Err(e) = res {
+            debug!("Bind reader closed with {}", e);
+            return;
+        }
 
         // TODO: start device down
-        if mtu == 0 {
+        // (the MTU may have changed during the read)
+        if wg.mtu.load(Ordering::Relaxed) == 0 {
+            msgs.clear();
             continue;
         }
 
-        // message type de-multiplexer
-        if msg.len() < std::mem::size_of::<u32>() {
-            continue;
+        for (buf, (size, src)) in bufs.iter_mut().zip(msgs.drain(..)) {
+            let msg = &buf[..size];
+
+            // message type de-multiplexer
+            if msg.len() < std::mem::size_of::<u32>() {
+                count(&wg.stats.malformed_drops);
+                continue;
+            }
+            match LittleEndian::read_u32(msg) {
+                TYPE_COOKIE_REPLY | TYPE_INITIATION | TYPE_RESPONSE => {
+                    debug!("{} : reader, received handshake message", wg);
+
+                    // discard messages which cannot be valid handshake messages
+                    if msg.len() > MAX_HANDSHAKE_MSG_SIZE {
+                        count(&wg.stats.malformed_drops);
+                        continue;
+                    }
+
+                    // copy into a buffer from the pool
+                    let mut job = wg.handshake_buffers.get();
+                    job.extend_from_slice(msg);
+                    wg.pending.fetch_add(1, Ordering::SeqCst);
+                    wg.queue.send(HandshakeJob::Message(job, src));
+                }
+                TYPE_TRANSPORT => {
+                    debug!("{} : reader, received transport message", wg);
+
+                    // transport message (decrypted in-place)
+                    let mut msg = mem::replace(buf, wg.router.buffer());
+                    msg.truncate(size);
+                    let _ = wg.router.recv(src, msg).map_err(|e| {
+                        debug!("Failed to handle incoming transport message: {}", e);
+                        match e {
+                            RouterError::MalformedTransportMessage => {
+                                count(&wg.stats.malformed_drops)
+                            }
+                            RouterError::UnknownReceiverId => {
+                                count(&wg.stats.unknown_receiver_drops)
+                            }
+                            _ => (),
+                        }
+                    });
+                }
+                _ => count(&wg.stats.malformed_drops),
+            }
         }
-        match LittleEndian::read_u32(&msg[..]) {
-            TYPE_COOKIE_REPLY | TYPE_INITIATION | TYPE_RESPONSE => {
-                debug!("{} : reader, received handshake message", wg);
-                wg.pending.fetch_add(1, Ordering::SeqCst);
-                wg.queue.send(HandshakeJob::Message(msg, src));
+    }
+}
+
+/* Processes a single handshake message and updates the state of the associated peer.
+ *
+ * If provisional is set, the message is an initiation already consumed (before the initiator
+ * was provisioned) and only the response is created.
+ *
+ * # Returns
+ *
+ * The consumed initiation of an (authenticated) initiator not present in the device
+ * (when permitted by the resolver), along with the unconsumed source endpoint.
+ */
+fn process_message<T: Tun, B: UDP>(
+    wg: &WireGuard<T, B>,
+    rng: &mut StdRng,
+    msg: &[u8],
+    provisional: Option<&Provisional>,
+    mut src: B::Endpoint,
+    under_load: bool,
+    reply: &mut [u8],
+) -> Option<(Box<Provisional>, B::Endpoint)> {
+    // process message
+    let device = wg.peers.read();
+    let output = match provisional {
+        Some(provisional) => device.complete(rng, provisional, reply),
+        None => device.process(rng, msg, src.into_address(), under_load, reply),
+    };
+    match output {
+        Ok((peer, resp, keypair)) => {
+            // add any new keypair to peer
+            // (before sending the response, which enables the initiator to send transport messages)
+            if let (Some(peer), Some(kp)) = (peer, keypair) {
+                debug!("{} : handshake worker, new keypair for {}", wg, peer);
+
+                // this means that a handshake response was processed or sent
+                peer.opaque().timers_session_derived();
+
+                // free any unused ids
+                for id in peer.add_keypair(kp) {
+                    device.release(id);
+                }
+            }
+
+            // update timers before sending the handshake response
+            // (the initiator may reply with a transport message, which starts the keepalive timer)
+            if let (Some(peer), Some(_)) = (peer, resp) {
+                debug!("{} : handshake worker, handshake response sent", wg);
+                peer.opaque().sent_handshake_response();
             }
-            TYPE_TRANSPORT => {
-                debug!("{} : reader, received transport message", wg);
 
-                // transport message
-                let _ = wg.router.recv(src, msg).map_err(|e| {
-                    debug!("Failed to handle incoming transport message: {}", e);
+            // send response (might be cookie reply or handshake response)
+            let mut resp_len: u64 = 0;
+            if let Some(len) = resp {
+                resp_len = len as u64;
+                // TODO: consider a more elegant solution for accessing the bind
+                let _ = wg.router.send_raw(&reply[..len], &mut src).map_err(|e| {
+                    debug!(
+                        "{} : handshake worker, failed to send response, error = {}",
+                        wg, e
+                    );
                 });
             }
-            _ => (),
+
+            // update peer state
+            if let Some(peer) = peer {
+                // authenticated handshake packet received
+
+                // add to rx_bytes and tx_bytes
+                let req_len = msg.len() as u64;
+                peer.opaque().rx_bytes.fetch_add(req_len, Ordering::Relaxed);
+                peer.opaque()
+                    .tx_bytes
+                    .fetch_add(resp_len, Ordering::Relaxed);
+
+                // update endpoint
+                peer.set_endpoint(src);
+
+                if resp_len == 0 {
+                    // update timers after receiving handshake response
+                    debug!("{} : handshake worker, handshake response was received", wg);
+                    peer.opaque().timers_handshake_complete();
+                }
+            }
+        }
+        Err(HandshakeError::UnknownInitiator(provisional)) => {
+            debug!("{} : handshake worker, initiation from unknown peer", wg);
+            return Some((provisional, src));
+        }
+        Err(e) => {
+            debug!("{} : handshake worker, error = {:?}", wg, e);
+            match e {
+                HandshakeError::InvalidMessageFormat => count(&wg.stats.malformed_drops),
+                HandshakeError::UnknownReceiverId => count(&wg.stats.unknown_receiver_drops),
+                _ => (),
+            }
         }
     }
+    None
 }
 
 pub fn handshake_worker<T: Tun, B: UDP>(
     wg: &WireGuard<T, B>,
+    mut rng: StdRng,
     rx: Receiver<HandshakeJob<B::Endpoint>>,
 ) {
     debug!("{} : handshake worker, started", wg);
 
+    // buffer for outgoing handshake messages (reused between jobs)
+    let mut reply = [0u8; MAX_HANDSHAKE_MSG_SIZE];
+
     // process elements from the handshake queue
     for job in rx {
         // check if under load
@@ -162,13 +341,19 @@ pub fn handshake_worker<T: Tun, B: UDP>(
         // immediate go under load if too many handshakes pending
         if pending > THRESHOLD_UNDER_LOAD {
             log::trace!("{} : handshake worker, under load (above threshold)", wg);
-            *wg.last_under_load.lock() = Instant::now();
+            let now = wg.clock.now();
+            let mut last = wg.last_under_load.lock();
+            wg.stats.under_load(*last, now);
+            *last = now;
             under_load = true;
         }
 
         // remain under load for DURATION_UNDER_LOAD
         if !under_load {
-            let elapsed = wg.last_under_load.lock().elapsed();
+            let elapsed = wg
+                .clock
+                .now()
+                .saturating_duration_since(*wg.last_under_load.lock());
             if DURATION_UNDER_LOAD >= elapsed {
                 log::trace!("{} : handshake worker, under load (recent)", wg);
                 under_load = true;
@@ -177,75 +362,20 @@ pub fn handshake_worker<T: Tun, B: UDP>(
 
         // de-multiplex staged handshake jobs and handshake messages
         match job {
-            HandshakeJob::Message(msg, mut src) => {
+            HandshakeJob::Message(msg, src) => {
                 // process message
-                let device = wg.peers.read();
-                match device.process(
-                    &mut OsRng,
-                    &msg[..],
-                    if under_load {
-                        Some(src.into_address())
-                    } else {
-                        None
-                    },
-                ) {
-                    Ok((peer, resp, keypair)) => {
-                        // send response (might be cookie reply or handshake response)
-                        let mut resp_len: u64 = 0;
-                        if let Some(msg) = resp {
-                            resp_len = msg.len() as u64;
-                            // TODO: consider a more elegant solution for accessing the bind
-                            let _ = wg.router.send_raw(&msg[..], &mut src).map_err(|e| {
-                                debug!(
-                                    "{} : handshake worker, failed to send response, error = {}",
-                                    wg, e
-                                );
-                            });
-                        }
-
-                        // update peer state
-                        if let Some(peer) = peer {
-                            // authenticated handshake packet received
-
-                            // add to rx_bytes and tx_bytes
-                            let req_len = msg.len() as u64;
-                            peer.opaque().rx_bytes.fetch_add(req_len, Ordering::Relaxed);
-                            peer.opaque()
-                                .tx_bytes
-                                .fetch_add(resp_len, Ordering::Relaxed);
-
-                            // update endpoint
-                            peer.set_endpoint(src);
-
-                            if resp_len > 0 {
-                                // update timers after sending handshake response
-                                debug!("{} : handshake worker, handshake response sent", wg);
-                                peer.opaque().sent_handshake_response();
-                            } else {
-                                // update timers after receiving handshake response
-                                debug!(
-                                    "{} : handshake worker, handshake response was received",
-                                    wg
-                                );
-                                peer.opaque().timers_handshake_complete();
-                            }
-
-                            // add any new keypair to peer
-                            if let Some(kp) = keypair {
-                                debug!("{} : handshake worker, new keypair for {}", wg, peer);
-
-                                // this means that a handshake response was processed or sent
-                                peer.opaque().timers_session_derived();
-
-                                // free any unused ids
-                                for id in peer.add_keypair(kp) {
-                                    device.release(id);
-                                }
-                            };
-                        }
+                if let Some((provisional, src)) =
+                    process_message(wg, &mut rng, &msg, None, src, under_load, &mut reply)
+                {
+                    // provision the unknown initiator and respond to the consumed initiation
+                    if wg.provision_peer(&provisional) {
+                        let provisional = Some(&*provisional);
+                        process_message(wg, &mut rng, &msg, provisional, src, false, &mut reply);
                     }
-                    Err(e) => debug!("{} : handshake worker, error = {:?}", wg, e),
                 }
+
+                // return the message buffer to the pool
+                wg.handshake_buffers.put(msg);
             }
             HandshakeJob::New(pk) => {
                 if let Some(peer) = wg.peers.read().get(&pk) {
@@ -253,16 +383,19 @@ pub fn handshake_worker<T: Tun, B: UDP>(
                         "{} : handshake worker, new handshake requested for {}",
                         wg, peer
                     );
+                    // clear the flag before sending the initiation:
+                    // a retransmission requested after it was sent must be queued again
+                    peer.opaque()
+                        .handshake_queued
+                        .store(false, Ordering::SeqCst);
                     let device = wg.peers.read();
-                    let _ = device.begin(&mut OsRng, &pk).map(|msg| {
-                        let _ = peer.send_raw(&msg[..]).map_err(|e| {
+                    let _ = device.begin(&mut rng, &pk, &mut reply[..]).map(|len| {
+                        // update timers before sending the initiation (as for the response)
+                        peer.opaque().sent_handshake_initiation();
+                        let _ = peer.send_raw(&reply[..len]).map_err(|e| {
                             debug!("{} : handshake worker, failed to send handshake initiation, error = {}", wg, e)
                         });
-                        peer.opaque().sent_handshake_initiation();
                     });
-                    peer.opaque()
-                        .handshake_queued
-                        .store(false, Ordering::SeqCst);
                 }
             }
         }